@@ -0,0 +1,402 @@
+//! Converts `measureme` profiles into the Chrome profiler format, as the
+//! `crox` tool does, e.g. for tools that export several formats.
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::io::Write;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use measureme::{
+    EventArgValue, ProfilingData, TimestampKind, THREAD_START_EVENT_KIND, THREAD_STOP_EVENT_KIND,
+};
+
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+
+fn as_micros<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+    let v = (d.as_secs() * 1_000_000) + (d.subsec_nanos() as u64 / 1_000);
+    s.serialize_u64(v)
+}
+
+fn opt_as_micros<S: Serializer>(d: &Option<Duration>, s: S) -> Result<S::Ok, S::Error> {
+    as_micros(d.as_ref().unwrap(), s)
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Serialize)]
+enum EventType {
+    #[serde(rename = "X")]
+    Complete,
+    #[serde(rename = "i")]
+    Instant,
+    #[serde(rename = "C")]
+    Counter,
+    #[serde(rename = "M")]
+    Metadata,
+}
+
+#[derive(Serialize)]
+struct Event {
+    name: String,
+    #[serde(rename = "cat")]
+    category: String,
+    #[serde(rename = "ph")]
+    event_type: EventType,
+    #[serde(rename = "ts", serialize_with = "as_micros")]
+    #[serde()]
+    timestamp: Duration,
+    #[serde(
+        rename = "dur",
+        serialize_with = "opt_as_micros",
+        skip_serializing_if = "Option::is_none"
+    )]
+    duration: Option<Duration>,
+    /// The scope of an instant event, `"t"` meaning "thread"
+    #[serde(rename = "s", skip_serializing_if = "Option::is_none")]
+    scope: Option<&'static str>,
+    #[serde(rename = "pid")]
+    process_id: u32,
+    #[serde(rename = "tid")]
+    thread_id: u64,
+    args: Option<BTreeMap<String, Value>>,
+}
+
+/// Options for `write_chrome_profile()`, matching the flags of `crox`.
+#[derive(Clone, Debug, Default)]
+pub struct ChromeOptions {
+    /// Collapse threads without overlapping events (this drops thread names).
+    pub collapse_threads: bool,
+    /// Filter out events with shorter duration (in microseconds).
+    pub minimum_duration: Option<u128>,
+    /// Also emit instant events, like query cache hits.
+    pub instant_events: bool,
+}
+
+// generate mapping from thread_id to collapsed thread_id or an empty map
+fn generate_thread_to_collapsed_thread_mapping(
+    options: &ChromeOptions,
+    data: &ProfilingData,
+) -> BTreeMap<u64, u64> {
+    let mut thread_to_collapsed_thread: BTreeMap<u64, u64> = BTreeMap::new();
+
+    if options.collapse_threads {
+        // collect start and end times for all threads
+        let mut thread_start_and_end: BTreeMap<u64, (SystemTime, SystemTime)> = BTreeMap::new();
+        for event in data.iter() {
+            thread_start_and_end
+                .entry(event.thread_id)
+                .and_modify(|(start, end)| {
+                    if *start > event.timestamp {
+                        *start = event.timestamp;
+                    } else if *end < event.timestamp {
+                        *end = event.timestamp;
+                    }
+                })
+                .or_insert_with(|| (event.timestamp, event.timestamp));
+        }
+        // collect the the threads in order of the end time
+        let mut end_and_thread = thread_start_and_end
+            .iter()
+            .map(|(&thread_id, &(_start, end))| (end, thread_id))
+            .collect::<Vec<_>>();
+
+        end_and_thread.sort_unstable_by_key(|&(end, _thread_id)| end);
+        let mut next_end_iter = end_and_thread.iter().peekable();
+
+        // collect the the threads in order of the start time
+        let mut start_and_thread = thread_start_and_end
+            .iter()
+            .map(|(&thread_id, &(start, _end))| (start, thread_id))
+            .collect::<Vec<_>>();
+
+        start_and_thread.sort_unstable_by_key(|&(start, _thread_id)| start);
+
+        let mut current_thread_id = 0; // use new thread_ids to avoid strange gaps in the numbers
+        for &(start, thread_id) in start_and_thread.iter() {
+            // safe to unwrap due to end_and_thread and start_and_thread have the same length
+            let (next_end, next_thread_id) = next_end_iter.peek().unwrap();
+            if start > *next_end {
+                next_end_iter.next();
+                // need to lookup the thread_id due to new and collapsed threads
+                let mapped_thread_id = *thread_to_collapsed_thread
+                    .get(next_thread_id)
+                    .unwrap_or(next_thread_id);
+
+                thread_to_collapsed_thread.insert(thread_id, mapped_thread_id);
+            } else {
+                thread_to_collapsed_thread.insert(thread_id, current_thread_id);
+                current_thread_id += 1;
+            }
+        }
+    }
+    thread_to_collapsed_thread
+}
+
+struct ChromeEventBuilder<'a> {
+    options: &'a ChromeOptions,
+    process_id: u32,
+    first_event_timestamp: SystemTime,
+    thread_to_collapsed_thread: BTreeMap<u64, u64>,
+}
+
+// The integer argument of an event, if any, is shown as `arg` in the event
+// details, next to the arguments recorded via `Profiler::record_args_event()`
+fn event_args(event: &measureme::Event<'_>) -> Option<BTreeMap<String, Value>> {
+    let mut args: BTreeMap<_, _> = event
+        .args
+        .iter()
+        .map(|(key, value)| {
+            let value = match value {
+                EventArgValue::Str(s) => Value::from(&s[..]),
+                EventArgValue::Int(i) => Value::from(*i),
+                EventArgValue::Float(f) => Value::from(*f),
+                EventArgValue::Bool(b) => Value::from(*b),
+            };
+            (key.to_string(), value)
+        })
+        .collect();
+
+    if let Some(arg) = event.arg {
+        args.insert("arg".to_string(), Value::from(arg));
+    }
+
+    if args.is_empty() {
+        None
+    } else {
+        Some(args)
+    }
+}
+
+impl<'a> ChromeEventBuilder<'a> {
+    fn thread_id(&self, thread_id: u64) -> u64 {
+        *self
+            .thread_to_collapsed_thread
+            .get(&thread_id)
+            .unwrap_or(&thread_id)
+    }
+
+    fn relative_timestamp(&self, timestamp: SystemTime) -> Duration {
+        timestamp
+            .duration_since(self.first_event_timestamp)
+            .unwrap()
+    }
+
+    fn complete(&self, start: &measureme::Event<'_>, end_timestamp: SystemTime) -> Option<Event> {
+        let duration = end_timestamp
+            .duration_since(start.timestamp)
+            .unwrap_or(Duration::from_nanos(0));
+
+        if let Some(minimum_duration) = self.options.minimum_duration {
+            if duration.as_micros() < minimum_duration {
+                return None;
+            }
+        }
+
+        Some(Event {
+            name: start.label.clone().into_owned(),
+            category: start.category_or_kind().to_string(),
+            event_type: EventType::Complete,
+            timestamp: self.relative_timestamp(start.timestamp),
+            duration: Some(duration),
+            scope: None,
+            process_id: self.process_id,
+            thread_id: self.thread_id(start.thread_id),
+            args: event_args(start),
+        })
+    }
+
+    fn instant(&self, event: &measureme::Event<'_>) -> Event {
+        Event {
+            name: event.label.clone().into_owned(),
+            category: event.event_kind.clone().into_owned(),
+            event_type: EventType::Instant,
+            timestamp: self.relative_timestamp(event.timestamp),
+            duration: None,
+            scope: Some("t"),
+            process_id: self.process_id,
+            thread_id: self.thread_id(event.thread_id),
+            args: event_args(event),
+        }
+    }
+
+    // A marker for the start or stop of a thread recorded via
+    // `Profiler::record_thread_start()` or `Profiler::record_thread_stop()`
+    fn thread_lifetime(&self, event: &measureme::Event<'_>) -> Event {
+        let args = if event.event_kind == THREAD_START_EVENT_KIND {
+            let mut args = BTreeMap::new();
+            args.insert("name".to_string(), Value::from(&*event.label));
+            Some(args)
+        } else {
+            None
+        };
+
+        Event {
+            name: event.event_kind.clone().into_owned(),
+            category: "thread".to_string(),
+            event_type: EventType::Instant,
+            timestamp: self.relative_timestamp(event.timestamp),
+            duration: None,
+            scope: Some("t"),
+            process_id: self.process_id,
+            thread_id: self.thread_id(event.thread_id),
+            args,
+        }
+    }
+
+    fn thread_name(&self, thread_id: u64, name: &str) -> Event {
+        let mut args = BTreeMap::new();
+        args.insert("name".to_string(), Value::from(name));
+
+        Event {
+            name: "thread_name".to_string(),
+            category: String::new(),
+            event_type: EventType::Metadata,
+            timestamp: Duration::from_nanos(0),
+            duration: None,
+            scope: None,
+            process_id: self.process_id,
+            thread_id,
+            args: Some(args),
+        }
+    }
+
+    fn counter(&self, event: &measureme::Event<'_>) -> Event {
+        let mut args = BTreeMap::new();
+        args.insert(
+            event.label.clone().into_owned(),
+            Value::from(event.value.unwrap_or(0)),
+        );
+
+        Event {
+            name: event.label.clone().into_owned(),
+            category: event.event_kind.clone().into_owned(),
+            event_type: EventType::Counter,
+            timestamp: self.relative_timestamp(event.timestamp),
+            duration: None,
+            scope: None,
+            process_id: self.process_id,
+            thread_id: self.thread_id(event.thread_id),
+            args: Some(args),
+        }
+    }
+}
+
+/// Writes the events of `data` to `out` as a Chrome profiler file, i.e. the
+/// contents of `chrome_profiler.json`. Returns warnings about events without
+/// a matching start or end event.
+pub fn write_chrome_profile<W: Write>(
+    data: &ProfilingData,
+    options: &ChromeOptions,
+    mut out: W,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    //find the earliest timestamp (the events of different threads are not
+    //ordered by time in the file, so it isn't necessarily the first event)
+    //subtract one tick so that the start of the event shows in Chrome
+    let first_event_timestamp = match data.iter().map(|event| event.timestamp).min() {
+        Some(timestamp) => timestamp - Duration::from_micros(1),
+        None => SystemTime::UNIX_EPOCH,
+    };
+
+    let builder = ChromeEventBuilder {
+        options,
+        process_id: data.metadata().process_id,
+        first_event_timestamp,
+        thread_to_collapsed_thread: generate_thread_to_collapsed_thread_mapping(options, data),
+    };
+
+    // Collapsed threads are made up of several threads, so there is no single
+    // name that could be used for them
+    let thread_name_events: Vec<_> = if options.collapse_threads {
+        Vec::new()
+    } else {
+        data.thread_names()
+            .into_iter()
+            .map(|(thread_id, name)| builder.thread_name(thread_id, name))
+            .collect()
+    };
+
+    let mut event_iterator = data.iter();
+    let mut thread_stacks: HashMap<u64, Vec<measureme::Event<'_>>> = HashMap::new();
+    let mut last_timestamp = first_event_timestamp;
+    let mut unmatched_start_events: Option<Vec<measureme::Event<'_>>> = None;
+    let mut warnings = Vec::new();
+
+    //create an iterator so we can avoid allocating a Vec with every Event for serialization
+    let json_event_iterator = std::iter::from_fn(|| {
+        for event in event_iterator.by_ref() {
+            last_timestamp = last_timestamp.max(event.timestamp);
+
+            match event.timestamp_kind {
+                TimestampKind::Start => {
+                    thread_stacks
+                        .entry(event.thread_id)
+                        .or_default()
+                        .push(event);
+                }
+                TimestampKind::End => {
+                    let start = thread_stacks
+                        .get_mut(&event.thread_id)
+                        .and_then(|stack| stack.pop());
+
+                    match start {
+                        Some(start) => {
+                            if let Some(event) = builder.complete(&start, event.timestamp) {
+                                return Some(event);
+                            }
+                        }
+                        None => warnings.push(format!(
+                            "ignoring end event `{}` without a matching start event",
+                            event.label
+                        )),
+                    }
+                }
+                // There are only a few of these, so they are always emitted
+                TimestampKind::Instant
+                    if event.event_kind == THREAD_START_EVENT_KIND
+                        || event.event_kind == THREAD_STOP_EVENT_KIND =>
+                {
+                    return Some(builder.thread_lifetime(&event));
+                }
+                // Chrome does not seem to like how many QueryCacheHit events we generate
+                // so instant events are only emitted on request
+                TimestampKind::Instant => {
+                    if options.instant_events {
+                        return Some(builder.instant(&event));
+                    }
+                }
+                TimestampKind::Counter => return Some(builder.counter(&event)),
+            }
+        }
+
+        // Events that were started but never ended (e.g. because the profiled
+        // process crashed) are closed at the last timestamp in the profile.
+        let unmatched_start_events = unmatched_start_events.get_or_insert_with(|| {
+            let mut events: Vec<_> = thread_stacks.drain().flat_map(|(_, stack)| stack).collect();
+
+            if !events.is_empty() {
+                warnings.push(format!(
+                    "{} start event(s) without a matching end event",
+                    events.len()
+                ));
+            }
+
+            events.sort_by_key(|event| std::cmp::Reverse(event.timestamp));
+            events
+        });
+
+        while let Some(start) = unmatched_start_events.pop() {
+            if let Some(event) = builder.complete(&start, last_timestamp) {
+                return Some(event);
+            }
+        }
+
+        None
+    });
+
+    out.write_all(br#"{"traceEvents":"#)?;
+    serde_json::Serializer::new(&mut out)
+        .collect_seq(thread_name_events.into_iter().chain(json_event_iterator))?;
+    out.write_all(b"}")?;
+
+    Ok(warnings)
+}
@@ -0,0 +1,260 @@
+use std::error::Error;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use measureme::ProfilingData;
+
+use structopt::StructOpt;
+
+use crox::ChromeOptions;
+use flamegraph::RenderOptions;
+use tools_lib::profile::load_profile;
+
+const FORMATS: &[&str] = &[
+    "flamegraph",
+    "chrome",
+    "speedscope",
+    "csv",
+    "folded",
+    "summary",
+];
+
+/// The formats a profile can be exported to, each written by the library of
+/// the standalone tool for that format.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    /// An SVG flamegraph, like `flamegraph` writes
+    Flamegraph,
+    /// A Chrome profiler file, like `crox` writes
+    Chrome,
+    /// A speedscope file, like `speedscope` writes
+    Speedscope,
+    /// The interval events as CSV, like `to_csv` writes
+    Csv,
+    /// The collapsed stacks in the folded format, like `flamegraph --folded-output` writes
+    Folded,
+    /// The table that `summarize summarize` prints
+    Summary,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "flamegraph" => Ok(Format::Flamegraph),
+            "chrome" => Ok(Format::Chrome),
+            "speedscope" => Ok(Format::Speedscope),
+            "csv" => Ok(Format::Csv),
+            "folded" => Ok(Format::Folded),
+            "summary" => Ok(Format::Summary),
+            _ => Err(format!(
+                "unknown format `{}`, valid values are: {}",
+                s,
+                FORMATS.join(", ")
+            )),
+        }
+    }
+}
+
+impl Format {
+    /// The suffix of the default output file, or `None` to write to stdout.
+    fn default_suffix(self) -> Option<&'static str> {
+        match self {
+            Format::Flamegraph => Some(".svg"),
+            Format::Chrome => Some(".chrome.json"),
+            Format::Speedscope => Some(".speedscope.json"),
+            Format::Csv => Some(".csv"),
+            Format::Folded => Some(".folded"),
+            Format::Summary => None,
+        }
+    }
+}
+
+#[derive(StructOpt, Debug)]
+struct Opt {
+    file_prefix: PathBuf,
+
+    /// The format to export to: flamegraph, chrome, speedscope, csv, folded, or summary
+    #[structopt(short = "f", long = "format")]
+    format: Format,
+
+    /// The output file, or `-` for stdout, defaults to `<file_prefix>` with an extension for the format, or stdout for `summary`
+    #[structopt(short = "o", long = "output")]
+    output: Option<PathBuf>,
+
+    /// The sampling interval of `flamegraph` and `folded` in milliseconds
+    #[structopt(short = "i", long = "interval", default_value = "1")]
+    interval: u64,
+
+    /// Also export instant events, like query cache hits, for `chrome` and `csv`
+    #[structopt(long = "instant-events")]
+    instant_events: bool,
+
+    /// Filter the `summary` to items whose self-time is greater than this percentage
+    #[structopt(long = "percent-above", default_value = "0.0")]
+    percent_above: f64,
+}
+
+fn output_path(opt: &Opt) -> Option<PathBuf> {
+    match &opt.output {
+        Some(output) if output == Path::new("-") => None,
+        Some(output) => Some(output.clone()),
+        None => opt.format.default_suffix().map(|suffix| {
+            let mut path = OsString::from(&opt.file_prefix);
+            path.push(suffix);
+            PathBuf::from(path)
+        }),
+    }
+}
+
+/// Writes `data` to `out` in the format selected by `opt`. Returns the
+/// warnings of the renderer.
+fn export<W: Write>(
+    opt: &Opt,
+    data: ProfilingData,
+    mut out: W,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let interval = Duration::from_millis(opt.interval);
+
+    let warnings = match opt.format {
+        Format::Flamegraph => {
+            let title = opt.file_prefix.display().to_string();
+            flamegraph::render(&data, interval, RenderOptions::new().title(title), out)?
+        }
+        Format::Chrome => {
+            let options = ChromeOptions {
+                instant_events: opt.instant_events,
+                ..ChromeOptions::default()
+            };
+            crox::write_chrome_profile(&data, &options, out)?
+        }
+        Format::Speedscope => {
+            let name = opt.file_prefix.to_string_lossy().into_owned();
+            speedscope::write_speedscope(&data, name, out)?
+        }
+        Format::Csv => to_csv::write_csv(&data, opt.instant_events, &mut out)?,
+        Format::Folded => {
+            let (graph, warnings) =
+                flamegraph::collapse(&data.into(), interval, &RenderOptions::new())?;
+            flamegraph::write_folded(&graph, out)?;
+            warnings
+        }
+        Format::Summary => {
+            let results = summarize::analysis::perform_analysis(data);
+            let clamped_durations = results.clamped_durations;
+            summarize::write_summary_table(results, opt.percent_above, &mut out)?;
+            let mut warnings = Vec::new();
+            if clamped_durations > 0 {
+                warnings.push(format!(
+                    "counted {} negative duration(s) as zero",
+                    clamped_durations
+                ));
+            }
+            warnings
+        }
+    };
+
+    Ok(warnings)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let opt = Opt::from_args();
+
+    if opt.interval == 0 {
+        return Err("the sampling interval must not be zero".into());
+    }
+    if !(0.0..=100.0).contains(&opt.percent_above) {
+        return Err("the percentage of the total time must be between 0.0 and 100.0".into());
+    }
+
+    let data = load_profile(&opt.file_prefix)?;
+
+    let mut out: Box<dyn Write> = match output_path(&opt) {
+        Some(output) => Box::new(BufWriter::new(File::create(output)?)),
+        None => Box::new(io::stdout().lock()),
+    };
+    let warnings = export(&opt, data, &mut out)?;
+    out.flush()?;
+
+    for warning in warnings {
+        eprintln!("warning: {}", warning);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use measureme::ProfilingDataBuilder;
+
+    fn opt(format: Format) -> Opt {
+        Opt {
+            file_prefix: PathBuf::from("regex"),
+            format,
+            output: None,
+            interval: 1,
+            instant_events: false,
+            percent_above: 0.0,
+        }
+    }
+
+    fn profile() -> ProfilingData {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "typeck", 0, 0, 10_000_000, |b| {
+            b.interval("Query", "mir_borrowck", 0, 2_000_000, 6_000_000, |_| {});
+        });
+        b.into_profiling_data()
+    }
+
+    fn export_to_string(format: Format) -> String {
+        let mut out = Vec::new();
+        let warnings = export(&opt(format), profile(), &mut out).unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn parse_format() {
+        for format in FORMATS {
+            assert!(format.parse::<Format>().is_ok());
+        }
+        assert!("svg".parse::<Format>().unwrap_err().contains("flamegraph"));
+    }
+
+    #[test]
+    fn default_output() {
+        let mut summary = opt(Format::Summary);
+        assert_eq!(output_path(&summary), None);
+
+        summary.output = Some(PathBuf::from("summary.txt"));
+        assert_eq!(output_path(&summary), Some(PathBuf::from("summary.txt")));
+
+        let mut speedscope = opt(Format::Speedscope);
+        assert_eq!(
+            output_path(&speedscope),
+            Some(PathBuf::from("regex.speedscope.json"))
+        );
+
+        speedscope.output = Some(PathBuf::from("-"));
+        assert_eq!(output_path(&speedscope), None);
+    }
+
+    #[test]
+    fn export_each_format() {
+        assert!(export_to_string(Format::Flamegraph).contains("<svg"));
+        assert!(export_to_string(Format::Chrome).contains(r#""name":"mir_borrowck""#));
+        assert!(export_to_string(Format::Speedscope).contains(r#""name":"regex""#));
+        assert!(export_to_string(Format::Csv).contains("mir_borrowck"));
+        assert_eq!(
+            export_to_string(Format::Folded),
+            "rustc;typeck 6\nrustc;typeck;mir_borrowck 4\n"
+        );
+        assert!(export_to_string(Format::Summary).contains("Total cpu time: 10ms"));
+    }
+}
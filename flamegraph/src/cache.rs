@@ -0,0 +1,419 @@
+//! The `<file_prefix>.collapsed` cache, which holds the collapsed stacks of
+//! the profiles a flamegraph was last generated from. Regenerating the
+//! flamegraph with different presentation options, like colors or the title,
+//! then only needs to read the cache instead of collapsing the profiles
+//! again.
+//!
+//! The cache is only used if it was written for the same options that
+//! affect the collapsed stacks and the source files of the profiles still
+//! have the size and modification time they had back then. The file starts
+//! with `CACHE_MAGIC` and the format version, followed by the key and the
+//! cached data. Integers are little endian, strings are prefixed with their
+//! length as a `u32`.
+
+use std::convert::TryInto;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use measureme::ProfilerFiles;
+
+use flamegraph::Graph;
+use tools_lib::stack_collapse::FoldOptions;
+
+use crate::Threads;
+
+const CACHE_MAGIC: &[u8; 4] = b"MMFC";
+const CACHE_VERSION: u32 = 2;
+
+/// The result of collapsing the stacks of the profiles, along with what
+/// else the flamegraph needs from them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Collapsed {
+    /// The title derived from the metadata of the profiles
+    pub default_title: String,
+    /// The warnings printed while reading the profiles, printed again when
+    /// the cache is used
+    pub warnings: Vec<String>,
+    /// A single flamegraph, or one per thread with `--per-thread`
+    pub graphs: Vec<Graph>,
+}
+
+/// Identifies the inputs the collapsed stacks were computed from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CacheKey(Vec<u8>);
+
+impl CacheKey {
+    pub fn new(
+        file_prefixes: &[PathBuf],
+        interval: Duration,
+        split_threads: bool,
+        align_start: bool,
+        threads: &Threads,
+        fold: &FoldOptions,
+    ) -> CacheKey {
+        let mut key = Vec::new();
+        write_u64(&mut key, interval.as_nanos() as u64);
+        key.push(split_threads as u8);
+        key.push(align_start as u8);
+
+        match threads {
+            Threads::All => key.push(0),
+            Threads::Id(thread_id) => {
+                key.push(1);
+                write_u64(&mut key, *thread_id);
+            }
+            Threads::Name(thread_name) => {
+                key.push(2);
+                write_str(&mut key, thread_name);
+            }
+            Threads::PerThread => key.push(3),
+        }
+
+        key.push(fold.fold_recursion as u8);
+        match fold.max_depth {
+            Some(max_depth) => {
+                key.push(1);
+                write_u64(&mut key, max_depth as u64);
+            }
+            None => key.push(0),
+        }
+
+        write_u32(&mut key, file_prefixes.len() as u32);
+        for file_prefix in file_prefixes {
+            let files = source_files(file_prefix);
+            write_u32(&mut key, files.len() as u32);
+            for file in files {
+                write_str(&mut key, &file.to_string_lossy());
+                write_fingerprint(&mut key, &file);
+            }
+        }
+
+        CacheKey(key)
+    }
+}
+
+// All files a profile may consist of, whether they exist or not, so that
+// the cache is invalidated when e.g. a single-file profile is replaced by a
+// sharded one.
+fn source_files(file_prefix: &Path) -> Vec<PathBuf> {
+    let paths = ProfilerFiles::new(file_prefix);
+    let mut files = vec![
+        paths.events_file,
+        paths.string_data_file,
+        paths.string_index_file,
+    ];
+
+    // `.0.events` onwards for sharded profiles, `.1.events` onwards for
+    // rotated ones
+    for shard in 0.. {
+        let shard_file = ProfilerFiles::shard_events_file(file_prefix, shard);
+        let exists = shard_file.exists();
+        files.push(shard_file);
+        if !exists && shard > 0 {
+            break;
+        }
+    }
+
+    files
+}
+
+fn write_fingerprint(key: &mut Vec<u8>, file: &Path) {
+    let metadata = match fs::metadata(file) {
+        Ok(metadata) => metadata,
+        Err(_) => {
+            key.push(0);
+            return;
+        }
+    };
+
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .unwrap_or_default();
+
+    key.push(1);
+    write_u64(key, metadata.len());
+    write_u64(key, modified.as_secs());
+    write_u32(key, modified.subsec_nanos());
+}
+
+/// The path of the cache for the profiles starting with `file_prefix`.
+pub fn cache_path(file_prefix: &Path) -> PathBuf {
+    let mut path = file_prefix.as_os_str().to_owned();
+    path.push(".collapsed");
+    PathBuf::from(path)
+}
+
+/// Reads the cache at `path`. Returns `None` if there is none or if it was
+/// written for a different key.
+pub fn read(path: &Path, key: &CacheKey) -> Option<Collapsed> {
+    decode(&fs::read(path).ok()?, key)
+}
+
+pub fn write(path: &Path, key: &CacheKey, collapsed: &Collapsed) -> io::Result<()> {
+    fs::write(path, encode(key, collapsed))
+}
+
+fn encode(key: &CacheKey, collapsed: &Collapsed) -> Vec<u8> {
+    let mut bytes = CACHE_MAGIC.to_vec();
+    write_u32(&mut bytes, CACHE_VERSION);
+
+    write_u32(&mut bytes, key.0.len() as u32);
+    bytes.extend_from_slice(&key.0);
+
+    write_str(&mut bytes, &collapsed.default_title);
+    write_u32(&mut bytes, collapsed.warnings.len() as u32);
+    for warning in &collapsed.warnings {
+        write_str(&mut bytes, warning);
+    }
+    write_u32(&mut bytes, collapsed.graphs.len() as u32);
+    for graph in &collapsed.graphs {
+        match graph.thread_id {
+            Some(thread_id) => {
+                bytes.push(1);
+                write_u64(&mut bytes, thread_id);
+            }
+            None => bytes.push(0),
+        }
+        match &graph.thread_name {
+            Some(thread_name) => {
+                bytes.push(1);
+                write_str(&mut bytes, thread_name);
+            }
+            None => bytes.push(0),
+        }
+        write_u32(&mut bytes, graph.stacks.len() as u32);
+        for (stack, samples) in &graph.stacks {
+            write_str(&mut bytes, stack);
+            write_u64(&mut bytes, *samples as u64);
+        }
+    }
+
+    bytes
+}
+
+fn decode(bytes: &[u8], key: &CacheKey) -> Option<Collapsed> {
+    let mut reader = Reader(bytes);
+
+    if reader.bytes(CACHE_MAGIC.len())? != CACHE_MAGIC || reader.u32()? != CACHE_VERSION {
+        return None;
+    }
+
+    let key_len = reader.u32()? as usize;
+    if reader.bytes(key_len)? != key.0 {
+        return None;
+    }
+
+    let default_title = reader.string()?;
+    let warnings = (0..reader.u32()?)
+        .map(|_| reader.string())
+        .collect::<Option<_>>()?;
+    let graphs = (0..reader.u32()?)
+        .map(|_| {
+            let thread_id = match reader.u8()? {
+                0 => None,
+                _ => Some(reader.u64()?),
+            };
+            let thread_name = match reader.u8()? {
+                0 => None,
+                _ => Some(reader.string()?),
+            };
+            let stacks = (0..reader.u32()?)
+                .map(|_| Some((reader.string()?, reader.u64()? as usize)))
+                .collect::<Option<_>>()?;
+
+            Some(Graph {
+                thread_id,
+                thread_name,
+                stacks,
+            })
+        })
+        .collect::<Option<_>>()?;
+
+    if !reader.0.is_empty() {
+        return None;
+    }
+
+    Some(Collapsed {
+        default_title,
+        warnings,
+        graphs,
+    })
+}
+
+fn write_u32(bytes: &mut Vec<u8>, value: u32) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_u64(bytes: &mut Vec<u8>, value: u64) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_str(bytes: &mut Vec<u8>, s: &str) {
+    write_u32(bytes, s.len() as u32);
+    bytes.extend_from_slice(s.as_bytes());
+}
+
+// Reads the values written by the `write_*()` functions, returning `None`
+// at the end of the data
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec()).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collapsed() -> Collapsed {
+        Collapsed {
+            default_title: "rustc 1234 — rustc --crate-name regex".to_string(),
+            warnings: vec!["thread 1 has 1 start and 0 end event(s)".to_string()],
+            graphs: vec![
+                Graph {
+                    thread_id: None,
+                    thread_name: None,
+                    stacks: vec![
+                        ("rustc".to_string(), 10),
+                        ("rustc;typeck;type_of".to_string(), 3),
+                    ],
+                },
+                Graph {
+                    thread_id: Some(2),
+                    thread_name: Some("worker".to_string()),
+                    stacks: vec![("rustc;codegen".to_string(), 7)],
+                },
+            ],
+        }
+    }
+
+    fn key(interval: Duration) -> CacheKey {
+        CacheKey::new(
+            &[PathBuf::from("does-not-exist")],
+            interval,
+            false,
+            false,
+            &Threads::All,
+            &FoldOptions::default(),
+        )
+    }
+
+    #[test]
+    fn round_trip() {
+        let key = key(Duration::from_millis(1));
+        let bytes = encode(&key, &collapsed());
+
+        assert_eq!(decode(&bytes, &key), Some(collapsed()));
+    }
+
+    #[test]
+    fn different_interval() {
+        let bytes = encode(&key(Duration::from_millis(1)), &collapsed());
+
+        assert_eq!(decode(&bytes, &key(Duration::from_millis(2))), None);
+    }
+
+    #[test]
+    fn different_threads() {
+        let key = |threads| {
+            CacheKey::new(
+                &[PathBuf::from("does-not-exist")],
+                Duration::from_millis(1),
+                false,
+                false,
+                &threads,
+                &FoldOptions::default(),
+            )
+        };
+        let bytes = encode(&key(Threads::Id(1)), &collapsed());
+
+        assert_eq!(decode(&bytes, &key(Threads::Id(1))), Some(collapsed()));
+        assert_eq!(decode(&bytes, &key(Threads::Id(2))), None);
+        assert_eq!(decode(&bytes, &key(Threads::PerThread)), None);
+    }
+
+    #[test]
+    fn different_fold() {
+        let key = |fold| {
+            CacheKey::new(
+                &[PathBuf::from("does-not-exist")],
+                Duration::from_millis(1),
+                false,
+                false,
+                &Threads::All,
+                &fold,
+            )
+        };
+        let folded = FoldOptions {
+            fold_recursion: true,
+            max_depth: Some(10),
+        };
+        let bytes = encode(&key(folded), &collapsed());
+
+        assert_eq!(decode(&bytes, &key(folded)), Some(collapsed()));
+        assert_eq!(decode(&bytes, &key(FoldOptions::default())), None);
+    }
+
+    #[test]
+    fn changed_source_file() {
+        let file_prefix =
+            std::env::temp_dir().join(format!("flamegraph-cache-{}", std::process::id()));
+        let events_file = ProfilerFiles::new(&file_prefix).events_file;
+        let key = || {
+            CacheKey::new(
+                std::slice::from_ref(&file_prefix),
+                Duration::from_millis(1),
+                false,
+                false,
+                &Threads::All,
+                &FoldOptions::default(),
+            )
+        };
+
+        fs::write(&events_file, b"events").unwrap();
+        let bytes = encode(&key(), &collapsed());
+        assert_eq!(decode(&bytes, &key()), Some(collapsed()));
+
+        fs::write(&events_file, b"more events").unwrap();
+        let result = decode(&bytes, &key());
+        fs::remove_file(&events_file).unwrap();
+        assert_eq!(result, None);
+    }
+
+    #[test]
+    fn truncated() {
+        let key = key(Duration::from_millis(1));
+        let bytes = encode(&key, &collapsed());
+
+        assert_eq!(decode(&bytes[..bytes.len() - 1], &key), None);
+    }
+}
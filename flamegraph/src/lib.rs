@@ -0,0 +1,540 @@
+//! Generates flamegraphs of `measureme` profiles, as the `flamegraph` tool
+//! does, so that programs can produce them in-process instead of running
+//! the tool.
+//!
+//! `render()` writes the flamegraph of a profile as an SVG in one go.
+//! `collapse()` and `write_svg()` do the same in two steps, collapsing the
+//! stacks of merged profiles and drawing them, so that the collapsed stacks
+//! can be kept around and drawn several times. `write_folded()` writes the
+//! collapsed stacks in the folded format instead, for other flamegraph tools.
+
+use std::error::Error;
+use std::io::{self, Write};
+use std::time::Duration;
+
+use measureme::{Event, MergedProfilingData, ProfilingData};
+
+use tools_lib::stack_collapse::{collapse_stacks_with_root, FoldOptions};
+
+use inferno::flamegraph::{from_lines, Direction, Options as FlamegraphOptions};
+
+pub use inferno::flamegraph::color::Palette;
+
+/// The threads whose events a flamegraph is generated from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ThreadFilter {
+    All,
+    /// Only the thread with this id
+    Id(u64),
+    /// Only the threads with this name
+    Name(String),
+}
+
+/// Which events a flamegraph shows and how it is drawn. Options that aren't
+/// set keep the defaults of `inferno`, e.g.
+/// `RenderOptions::new().title("regex").inverted(true)`.
+#[derive(Clone, Debug)]
+pub struct RenderOptions {
+    title: Option<String>,
+    subtitle: Option<String>,
+    colors: Option<Palette>,
+    frame_height: Option<usize>,
+    min_width: Option<f64>,
+    inverted: bool,
+    reverse: bool,
+    split_threads: bool,
+    threads: ThreadFilter,
+    fold: FoldOptions,
+}
+
+impl Default for RenderOptions {
+    fn default() -> RenderOptions {
+        RenderOptions::new()
+    }
+}
+
+impl RenderOptions {
+    pub fn new() -> RenderOptions {
+        RenderOptions {
+            title: None,
+            subtitle: None,
+            colors: None,
+            frame_height: None,
+            min_width: None,
+            inverted: false,
+            reverse: false,
+            split_threads: false,
+            threads: ThreadFilter::All,
+            fold: FoldOptions::default(),
+        }
+    }
+
+    pub fn title(mut self, title: impl Into<String>) -> RenderOptions {
+        self.title = Some(title.into());
+        self
+    }
+
+    pub fn subtitle(mut self, subtitle: impl Into<String>) -> RenderOptions {
+        self.subtitle = Some(subtitle.into());
+        self
+    }
+
+    pub fn colors(mut self, colors: Palette) -> RenderOptions {
+        self.colors = Some(colors);
+        self
+    }
+
+    /// The height of each frame in pixels.
+    pub fn frame_height(mut self, frame_height: usize) -> RenderOptions {
+        self.frame_height = Some(frame_height);
+        self
+    }
+
+    /// Omits frames that are narrower than `min_width` pixels.
+    pub fn min_width(mut self, min_width: f64) -> RenderOptions {
+        self.min_width = Some(min_width);
+        self
+    }
+
+    /// Plots the flamegraph top-down (icicle graph) instead of bottom-up.
+    pub fn inverted(mut self, inverted: bool) -> RenderOptions {
+        self.inverted = inverted;
+        self
+    }
+
+    /// Reverses the order of the frames in each stack, so that common
+    /// callees are merged.
+    pub fn reverse(mut self, reverse: bool) -> RenderOptions {
+        self.reverse = reverse;
+        self
+    }
+
+    /// Gives each thread its own root frame, named after the thread, instead
+    /// of merging all stacks under a single `rustc` frame.
+    pub fn split_threads(mut self, split_threads: bool) -> RenderOptions {
+        self.split_threads = split_threads;
+        self
+    }
+
+    pub fn threads(mut self, threads: ThreadFilter) -> RenderOptions {
+        self.threads = threads;
+        self
+    }
+
+    /// Replaces runs of identical adjacent frames, e.g. of a recursive query,
+    /// by a single frame, `b;b;b` by `b (x3)`. The folded frame is as wide as
+    /// the frames it replaces together.
+    pub fn fold_recursion(mut self, fold_recursion: bool) -> RenderOptions {
+        self.fold.fold_recursion = fold_recursion;
+        self
+    }
+
+    /// Shows at most `max_depth` frames of each stack, including the root
+    /// frame, followed by a `…` frame as wide as the deeper frames together.
+    /// The depth is counted after folding recursion.
+    pub fn max_depth(mut self, max_depth: usize) -> RenderOptions {
+        self.fold.max_depth = Some(max_depth);
+        self
+    }
+}
+
+/// The collapsed stacks of a single flamegraph.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Graph {
+    /// The thread of a graph returned by `collapse_per_thread()`, `None`
+    /// otherwise
+    pub thread_id: Option<u64>,
+    pub thread_name: Option<String>,
+    /// Ordered by stack
+    pub stacks: Vec<(String, usize)>,
+}
+
+// What collapsing the stacks needs from `ProfilingData` and
+// `MergedProfilingData`
+trait Profile: Sync {
+    fn events(&self) -> Box<dyn Iterator<Item = Event<'_>> + '_>;
+    fn thread_events(&self, thread_id: u64) -> Box<dyn Iterator<Item = Event<'_>> + '_>;
+    fn thread_ids(&self) -> Vec<u64>;
+    fn thread_names(&self) -> Vec<(u64, &str)>;
+    fn thread_name(&self, thread_id: u64) -> Option<&str>;
+}
+
+impl Profile for ProfilingData {
+    fn events(&self) -> Box<dyn Iterator<Item = Event<'_>> + '_> {
+        Box::new(self.iter())
+    }
+
+    fn thread_events(&self, thread_id: u64) -> Box<dyn Iterator<Item = Event<'_>> + '_> {
+        Box::new(self.iter_thread(thread_id))
+    }
+
+    fn thread_ids(&self) -> Vec<u64> {
+        ProfilingData::thread_ids(self)
+    }
+
+    fn thread_names(&self) -> Vec<(u64, &str)> {
+        ProfilingData::thread_names(self)
+    }
+
+    fn thread_name(&self, thread_id: u64) -> Option<&str> {
+        ProfilingData::thread_name(self, thread_id)
+    }
+}
+
+impl Profile for MergedProfilingData {
+    fn events(&self) -> Box<dyn Iterator<Item = Event<'_>> + '_> {
+        Box::new(self.iter())
+    }
+
+    fn thread_events(&self, thread_id: u64) -> Box<dyn Iterator<Item = Event<'_>> + '_> {
+        Box::new(self.iter_thread(thread_id))
+    }
+
+    fn thread_ids(&self) -> Vec<u64> {
+        MergedProfilingData::thread_ids(self)
+    }
+
+    fn thread_names(&self) -> Vec<(u64, &str)> {
+        MergedProfilingData::thread_names(self)
+    }
+
+    fn thread_name(&self, thread_id: u64) -> Option<&str> {
+        MergedProfilingData::thread_name(self, thread_id)
+    }
+}
+
+fn thread_list(thread_ids: &[u64], profiling_data: &impl Profile) -> String {
+    let threads: Vec<_> = thread_ids
+        .iter()
+        .map(|&thread_id| match profiling_data.thread_name(thread_id) {
+            Some(thread_name) => format!("{} ({})", thread_id, thread_name),
+            None => thread_id.to_string(),
+        })
+        .collect();
+
+    threads.join(", ")
+}
+
+// The events of the threads selected by `threads`
+fn selected_events<'a>(
+    profiling_data: &'a impl Profile,
+    threads: &ThreadFilter,
+) -> Result<Box<dyn Iterator<Item = Event<'a>> + 'a>, Box<dyn Error>> {
+    let thread_ids = match threads {
+        ThreadFilter::All => return Ok(profiling_data.events()),
+        ThreadFilter::Id(thread_id) => {
+            let thread_ids = profiling_data.thread_ids();
+            if !thread_ids.contains(thread_id) {
+                return Err(format!(
+                    "thread {} has no events, the threads with events are: {}",
+                    thread_id,
+                    thread_list(&thread_ids, profiling_data)
+                )
+                .into());
+            }
+            vec![*thread_id]
+        }
+        ThreadFilter::Name(thread_name) => {
+            let named: Vec<_> = profiling_data
+                .thread_names()
+                .into_iter()
+                .filter(|(_, name)| name == thread_name)
+                .map(|(thread_id, _)| thread_id)
+                .collect();
+            if named.is_empty() {
+                let thread_ids = profiling_data.thread_ids();
+                return Err(format!(
+                    "there is no thread named `{}`, the threads with events are: {}",
+                    thread_name,
+                    thread_list(&thread_ids, profiling_data)
+                )
+                .into());
+            }
+            named
+        }
+    };
+
+    // Stacks are collapsed per thread, so the events of one thread can
+    // follow those of another
+    Ok(Box::new(thread_ids.into_iter().flat_map(
+        move |thread_id| profiling_data.thread_events(thread_id),
+    )))
+}
+
+// Collapses the stacks of `events`, adding a warning for each thread whose
+// start and end events don't match up
+fn collapse_events<'a>(
+    profiling_data: &'a impl Profile,
+    events: impl Iterator<Item = Event<'a>>,
+    interval: Duration,
+    options: &RenderOptions,
+    thread_id: Option<u64>,
+    warnings: &mut Vec<String>,
+) -> Graph {
+    let root_frame = |thread_id| {
+        if !options.split_threads {
+            return "rustc".to_string();
+        }
+
+        match profiling_data.thread_name(thread_id) {
+            Some(thread_name) => thread_name.to_string(),
+            None => format!("thread {}", thread_id),
+        }
+    };
+
+    let collapsed = collapse_stacks_with_root(events, interval, root_frame).fold(&options.fold);
+
+    for (thread_id, unmatched) in &collapsed.unmatched {
+        warnings.push(format!(
+            "thread {} has {}, its stacks may be inaccurate",
+            thread_id, unmatched
+        ));
+    }
+
+    Graph {
+        thread_id,
+        thread_name: thread_id
+            .and_then(|thread_id| profiling_data.thread_name(thread_id))
+            .map(str::to_string),
+        stacks: collapsed
+            .sorted_stacks()
+            .into_iter()
+            .map(|(stack, samples)| (stack.to_string(), samples))
+            .collect(),
+    }
+}
+
+/// Collapses the stacks of the events of the threads selected by `options`,
+/// with frames as wide as the time spent in them in samples of `interval`.
+/// Also returns warnings about threads whose start and end events don't
+/// match up, the stacks of which may be inaccurate. Fails if the threads to
+/// select don't exist.
+pub fn collapse(
+    profiling_data: &MergedProfilingData,
+    interval: Duration,
+    options: &RenderOptions,
+) -> Result<(Graph, Vec<String>), Box<dyn Error>> {
+    let mut warnings = Vec::new();
+    let events = selected_events(profiling_data, &options.threads)?;
+    let graph = collapse_events(
+        profiling_data,
+        events,
+        interval,
+        options,
+        None,
+        &mut warnings,
+    );
+
+    Ok((graph, warnings))
+}
+
+/// Like `collapse()`, but collapses the stacks of all threads, ignoring the
+/// thread filter of `options`, into one graph per thread. The profiles are
+/// only read once, see `MergedProfilingData::group_by_thread()`.
+pub fn collapse_per_thread(
+    profiling_data: &MergedProfilingData,
+    interval: Duration,
+    options: &RenderOptions,
+) -> (Vec<Graph>, Vec<String>) {
+    let mut warnings = Vec::new();
+    let mut events_by_thread = profiling_data.group_by_thread();
+    let graphs = profiling_data
+        .thread_ids()
+        .into_iter()
+        .map(|thread_id| {
+            let events = events_by_thread.remove(&thread_id).unwrap_or_default();
+            collapse_events(
+                profiling_data,
+                events.into_iter(),
+                interval,
+                options,
+                Some(thread_id),
+                &mut warnings,
+            )
+        })
+        .collect();
+
+    (graphs, warnings)
+}
+
+/// Draws the collapsed stacks of `graph` as an SVG and writes it to `out`.
+pub fn write_svg<W: Write>(
+    graph: &Graph,
+    options: &RenderOptions,
+    out: W,
+) -> Result<(), Box<dyn Error>> {
+    let recorded_stacks = graph
+        .stacks
+        .iter()
+        .map(|(unique_stack, count)| format!("{} {}", unique_stack, count))
+        .collect::<Vec<_>>();
+
+    let mut flamegraph_options = FlamegraphOptions {
+        subtitle: options.subtitle.clone(),
+        ..FlamegraphOptions::default()
+    };
+
+    if let Some(title) = &options.title {
+        flamegraph_options.title = title.clone();
+    }
+    if let Some(colors) = options.colors {
+        flamegraph_options.colors = colors;
+    }
+    if let Some(frame_height) = options.frame_height {
+        flamegraph_options.frame_height = frame_height;
+    }
+    if let Some(min_width) = options.min_width {
+        flamegraph_options.min_width = min_width;
+    }
+    if options.inverted {
+        flamegraph_options.direction = Direction::Inverted;
+    }
+    // Both of these only change how the collapsed stacks are presented, the
+    // sample counts computed from the sampling interval stay the same.
+    flamegraph_options.reverse_stack_order = options.reverse;
+
+    from_lines(
+        &mut flamegraph_options,
+        recorded_stacks.iter().map(|s| s.as_ref()),
+        out,
+    )
+    .map_err(|e| {
+        format!(
+            "unable to generate a flamegraph from the collapsed stack data: {}",
+            e
+        )
+    })?;
+
+    Ok(())
+}
+
+/// Writes the collapsed stacks of `graph` to `out` in the folded format of
+/// Brendan Gregg's `stackcollapse` scripts, one `frame;frame count` line per
+/// stack, as read by `flamegraph.pl`, `inferno-flamegraph`, or speedscope.
+pub fn write_folded<W: Write>(graph: &Graph, mut out: W) -> io::Result<()> {
+    for (unique_stack, count) in &graph.stacks {
+        writeln!(out, "{} {}", unique_stack, count)?;
+    }
+    out.flush()
+}
+
+/// Writes the flamegraph of the events of `profiling_data` to `out` as an
+/// SVG, see `collapse()` and `write_svg()`. Returns the warnings about
+/// threads whose stacks may be inaccurate.
+pub fn render<W: Write>(
+    profiling_data: &ProfilingData,
+    interval: Duration,
+    options: RenderOptions,
+    out: W,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut warnings = Vec::new();
+    let events = selected_events(profiling_data, &options.threads)?;
+    let graph = collapse_events(
+        profiling_data,
+        events,
+        interval,
+        &options,
+        None,
+        &mut warnings,
+    );
+
+    write_svg(&graph, &options, out)?;
+    Ok(warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use measureme::ProfilingDataBuilder;
+
+    #[test]
+    fn render_svg() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "typeck", 0, 0, 10_000_000, |b| {
+            b.interval("Query", "mir_borrowck", 0, 2_000_000, 6_000_000, |_| {});
+        })
+        .interval("Query", "codegen", 1, 0, 5_000_000, |_| {});
+        let profiling_data = b.into_profiling_data();
+
+        let mut svg = Vec::new();
+        let options = RenderOptions::new()
+            .title("my flamegraph")
+            .threads(ThreadFilter::Id(0));
+        let warnings =
+            render(&profiling_data, Duration::from_millis(1), options, &mut svg).unwrap();
+        let svg = String::from_utf8(svg).unwrap();
+
+        assert!(warnings.is_empty());
+        assert!(svg.starts_with("<?xml"));
+        assert!(svg.contains("my flamegraph"));
+        assert!(svg.contains("mir_borrowck"));
+        assert!(!svg.contains("codegen"));
+
+        let missing = RenderOptions::new().threads(ThreadFilter::Name("main".to_string()));
+        let error = render(
+            &profiling_data,
+            Duration::from_millis(1),
+            missing,
+            Vec::new(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "there is no thread named `main`, the threads with events are: 0, 1"
+        );
+    }
+
+    #[test]
+    fn fold_recursion() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "typeck", 0, 0, 10_000_000, |b| {
+            b.interval("Query", "type_of", 0, 1_000_000, 9_000_000, |b| {
+                b.interval("Query", "type_of", 0, 2_000_000, 8_000_000, |b| {
+                    b.interval("Query", "type_of", 0, 3_000_000, 7_000_000, |_| {});
+                });
+            });
+        });
+        let profiling_data = ProfilingData::merge(vec![b.into_profiling_data()]);
+
+        let options = RenderOptions::new().fold_recursion(true);
+        let (graph, _) = collapse(&profiling_data, Duration::from_millis(1), &options).unwrap();
+        assert_eq!(
+            graph.stacks,
+            [
+                ("rustc;typeck".to_string(), 2),
+                ("rustc;typeck;type_of".to_string(), 2),
+                ("rustc;typeck;type_of (x2)".to_string(), 2),
+                ("rustc;typeck;type_of (x3)".to_string(), 4),
+            ]
+        );
+
+        let options = options.max_depth(2);
+        let (graph, _) = collapse(&profiling_data, Duration::from_millis(1), &options).unwrap();
+        assert_eq!(
+            graph.stacks,
+            [
+                ("rustc;typeck".to_string(), 2),
+                ("rustc;typeck;…".to_string(), 8),
+            ]
+        );
+    }
+
+    #[test]
+    fn folded_output() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "typeck", 0, 0, 10_000_000, |b| {
+            b.interval("Query", "mir_borrowck", 0, 2_000_000, 6_000_000, |_| {});
+        });
+        let profiling_data = ProfilingData::merge(vec![b.into_profiling_data()]);
+
+        let options = RenderOptions::new();
+        let (graph, _) = collapse(&profiling_data, Duration::from_millis(1), &options).unwrap();
+
+        let mut folded = Vec::new();
+        write_folded(&graph, &mut folded).unwrap();
+        assert_eq!(
+            String::from_utf8(folded).unwrap(),
+            "rustc;typeck 6\nrustc;typeck;mir_borrowck 4\n"
+        );
+    }
+}
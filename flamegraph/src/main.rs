@@ -1,47 +1,364 @@
 use std::error::Error;
+use std::ffi::OsString;
 use std::fs::File;
-use std::io::BufWriter;
-use std::path::PathBuf;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use measureme::ProfilingData;
+use measureme::{ProfileMetadata, ProfilingData};
 
 use structopt::StructOpt;
 
-use tools_lib::stack_collapse::collapse_stacks;
+use flamegraph::{Graph, Palette, RenderOptions, ThreadFilter};
 
-use inferno::flamegraph::{from_lines, Options as FlamegraphOptions};
+use tools_lib::profile::profile_warnings;
+use tools_lib::stack_collapse::FoldOptions;
+
+mod cache;
+
+use cache::{CacheKey, Collapsed};
+
+/// The number of characters of a command line that are shown in the default
+/// title, long `rustc` command lines would be cut off anyway.
+const MAX_TITLE_CMD_LEN: usize = 100;
+
+const PALETTES: &[&str] = &[
+    "hot", "mem", "io", "wakeup", "java", "js", "perl", "red", "green", "blue", "aqua", "yellow",
+    "purple", "orange",
+];
+
+fn parse_palette(s: &str) -> Result<Palette, String> {
+    s.parse().map_err(|_| {
+        format!(
+            "unknown color palette `{}`, valid values are: {}",
+            s,
+            PALETTES.join(", ")
+        )
+    })
+}
+
+// E.g. `rustc 1234 — rustc --crate-name regex ...`, or the file prefix if
+// the profile has no metadata
+fn default_title(file_prefixes: &[PathBuf], profiles: &[ProfilingData]) -> String {
+    let titles: Vec<_> = file_prefixes
+        .iter()
+        .zip(profiles)
+        .map(|(file_prefix, data)| match data.try_metadata() {
+            Ok(metadata) => process_title(&metadata),
+            Err(_) => file_prefix.display().to_string(),
+        })
+        .collect();
+
+    titles.join(", ")
+}
+
+fn process_title(metadata: &ProfileMetadata) -> String {
+    let program = metadata
+        .cmd
+        .split(' ')
+        .next()
+        .and_then(|program| Path::new(program).file_name())
+        .map_or_else(|| "process".into(), |program| program.to_string_lossy());
+
+    let mut cmd: String = metadata.cmd.chars().take(MAX_TITLE_CMD_LEN).collect();
+    if cmd.len() < metadata.cmd.len() {
+        cmd.push_str("...");
+    }
+
+    format!("{} {} — {}", program, metadata.process_id, cmd)
+}
 
 #[derive(StructOpt, Debug)]
 struct Opt {
-    file_prefix: PathBuf,
+    /// One or more profiles, which are merged into a single flamegraph
+    #[structopt(raw(required = "true"))]
+    file_prefix: Vec<PathBuf>,
 
     /// The sampling interval in milliseconds
     #[structopt(short = "i", long = "interval", default_value = "1")]
     interval: u64,
+
+    /// The sampling interval in microseconds, overrides `--interval`
+    #[structopt(long = "interval-us")]
+    interval_us: Option<u64>,
+
+    /// The output file, defaults to `<file_prefix>.svg` for the first profile
+    #[structopt(short = "o", long = "output", raw(conflicts_with = r#""per_thread""#))]
+    output: Option<PathBuf>,
+
+    /// Write the collapsed stacks in the folded format to this file, or to stdout for `-`, instead of a flamegraph
+    #[structopt(
+        long = "folded-output",
+        raw(conflicts_with_all = r#"&["output", "per_thread"]"#)
+    )]
+    folded_output: Option<PathBuf>,
+
+    /// The title of the flamegraph, defaults to the process id and command line of each profile
+    #[structopt(long = "title")]
+    title: Option<String>,
+
+    /// The subtitle of the flamegraph
+    #[structopt(long = "subtitle")]
+    subtitle: Option<String>,
+
+    /// The color palette to use, e.g. `hot`, `mem`, or `io`
+    #[structopt(long = "colors", parse(try_from_str = "parse_palette"))]
+    colors: Option<Palette>,
+
+    /// The height of each frame in pixels
+    #[structopt(long = "frame-height")]
+    frame_height: Option<usize>,
+
+    /// Omit frames that are narrower than this many pixels
+    #[structopt(long = "min-width")]
+    min_width: Option<f64>,
+
+    /// Plot the flamegraph top-down (icicle graph) instead of bottom-up
+    #[structopt(long = "inverted")]
+    inverted: bool,
+
+    /// Reverse the order of the frames in each stack, so that common callees are merged
+    #[structopt(long = "reverse")]
+    reverse: bool,
+
+    /// Give each thread its own root frame, named after the thread
+    #[structopt(long = "split-threads")]
+    split_threads: bool,
+
+    /// Only include the events of the thread with this id
+    #[structopt(
+        long = "thread",
+        raw(conflicts_with_all = r#"&["thread_name", "per_thread"]"#)
+    )]
+    thread: Option<u64>,
+
+    /// Only include the events of the threads with this name
+    #[structopt(long = "thread-name", raw(conflicts_with = r#""per_thread""#))]
+    thread_name: Option<String>,
+
+    /// Generate one flamegraph per thread, written to `<file_prefix>.thread<id>.svg`
+    #[structopt(long = "per-thread")]
+    per_thread: bool,
+
+    /// Replace runs of identical adjacent frames, e.g. of recursive queries, by a single frame like `b (x3)`
+    #[structopt(long = "fold-recursion")]
+    fold_recursion: bool,
+
+    /// Show at most this many frames of each stack, including the root frame, followed by a `…` frame
+    #[structopt(long = "max-depth")]
+    max_depth: Option<usize>,
+
+    /// Align the timestamps of multiple profiles to the start of the earliest process
+    #[structopt(long = "align-start")]
+    align_start: bool,
+
+    /// Collapse the stacks of the profiles even if `<file_prefix>.collapsed` has them already, and don't write it
+    #[structopt(long = "no-cache")]
+    no_cache: bool,
+}
+
+/// The threads whose events the flamegraphs are generated from.
+#[derive(Debug)]
+pub enum Threads {
+    All,
+    Id(u64),
+    Name(String),
+    PerThread,
+}
+
+impl Threads {
+    fn new(opt: &Opt) -> Threads {
+        match (opt.thread, &opt.thread_name) {
+            _ if opt.per_thread => Threads::PerThread,
+            (Some(thread_id), _) => Threads::Id(thread_id),
+            (None, Some(thread_name)) => Threads::Name(thread_name.clone()),
+            (None, None) => Threads::All,
+        }
+    }
+}
+
+// Everything but the title, which depends on the profiles and the graph
+fn render_options(opt: &Opt) -> RenderOptions {
+    let mut options = RenderOptions::new()
+        .inverted(opt.inverted)
+        .reverse(opt.reverse)
+        .split_threads(opt.split_threads)
+        .fold_recursion(opt.fold_recursion);
+
+    if let Some(subtitle) = &opt.subtitle {
+        options = options.subtitle(subtitle.clone());
+    }
+    if let Some(colors) = opt.colors {
+        options = options.colors(colors);
+    }
+    if let Some(frame_height) = opt.frame_height {
+        options = options.frame_height(frame_height);
+    }
+    if let Some(min_width) = opt.min_width {
+        options = options.min_width(min_width);
+    }
+    if let Some(max_depth) = opt.max_depth {
+        options = options.max_depth(max_depth);
+    }
+
+    match Threads::new(opt) {
+        Threads::All | Threads::PerThread => options,
+        Threads::Id(thread_id) => options.threads(ThreadFilter::Id(thread_id)),
+        Threads::Name(thread_name) => options.threads(ThreadFilter::Name(thread_name)),
+    }
+}
+
+// Reads the profiles and collapses their stacks
+fn collapse(opt: &Opt, interval: Duration) -> Result<Collapsed, Box<dyn Error>> {
+    let mut warnings = Vec::new();
+
+    let profiles = opt
+        .file_prefix
+        .iter()
+        .map(|file_prefix| {
+            let data = ProfilingData::new(file_prefix)?;
+            warnings.extend(profile_warnings(file_prefix, &data));
+            Ok(data)
+        })
+        .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+
+    let default_title = default_title(&opt.file_prefix, &profiles);
+
+    let mut profiling_data = ProfilingData::merge(profiles);
+    if opt.align_start {
+        profiling_data.align_start();
+    }
+
+    let options = render_options(opt);
+    let graphs = match Threads::new(opt) {
+        Threads::PerThread => {
+            let (graphs, graph_warnings) =
+                flamegraph::collapse_per_thread(&profiling_data, interval, &options);
+            warnings.extend(graph_warnings);
+            graphs
+        }
+        _ => {
+            let (graph, graph_warnings) =
+                flamegraph::collapse(&profiling_data, interval, &options)?;
+            warnings.extend(graph_warnings);
+            vec![graph]
+        }
+    };
+
+    Ok(Collapsed {
+        default_title,
+        warnings,
+        graphs,
+    })
+}
+
+fn write_flamegraph(
+    opt: &Opt,
+    title: String,
+    graph: &Graph,
+    output: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let mut file = BufWriter::new(File::create(output)?);
+    flamegraph::write_svg(graph, &render_options(opt).title(title), &mut file)?;
+    file.flush()?;
+    Ok(())
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     let opt = Opt::from_args();
 
-    let profiling_data = ProfilingData::new(&opt.file_prefix)?;
+    let interval = match opt.interval_us {
+        Some(interval_us) => Duration::from_micros(interval_us),
+        None => Duration::from_millis(opt.interval),
+    };
+    if interval == Duration::from_secs(0) {
+        return Err("the sampling interval must not be zero".into());
+    }
+    if opt.max_depth == Some(0) {
+        return Err("the maximum depth must not be zero".into());
+    }
 
-    let recorded_stacks = collapse_stacks(profiling_data.iter(), opt.interval)
-        .iter()
-        .map(|(unique_stack, count)| format!("{} {}", unique_stack, count))
-        .collect::<Vec<_>>();
-
-    let file = BufWriter::new(File::create("rustc.svg")?);
-    let mut flamegraph_options = FlamegraphOptions::default();
-
-    from_lines(
-        &mut flamegraph_options,
-        recorded_stacks.iter().map(|s| s.as_ref()),
-        file,
-    )
-    .expect(
-        "unable to generate a flamegraph \
-         from the collapsed stack data",
+    // Only the options that affect the collapsed stacks are part of the key
+    let cache_path = cache::cache_path(&opt.file_prefix[0]);
+    let cache_key = CacheKey::new(
+        &opt.file_prefix,
+        interval,
+        opt.split_threads,
+        opt.align_start,
+        &Threads::new(&opt),
+        &FoldOptions {
+            fold_recursion: opt.fold_recursion,
+            max_depth: opt.max_depth,
+        },
     );
 
+    let cached = if opt.no_cache {
+        None
+    } else {
+        cache::read(&cache_path, &cache_key)
+    };
+
+    let collapsed = match cached {
+        Some(collapsed) => collapsed,
+        None => {
+            let collapsed = collapse(&opt, interval)?;
+            if !opt.no_cache {
+                if let Err(e) = cache::write(&cache_path, &cache_key, &collapsed) {
+                    eprintln!("warning: couldn't write `{}`: {}", cache_path.display(), e);
+                }
+            }
+            collapsed
+        }
+    };
+
+    for warning in &collapsed.warnings {
+        eprintln!("warning: {}", warning);
+    }
+
+    if let Some(folded_output) = &opt.folded_output {
+        // There is a single graph without `--per-thread`
+        let graph = &collapsed.graphs[0];
+        if folded_output == Path::new("-") {
+            flamegraph::write_folded(graph, io::stdout().lock())?;
+        } else {
+            flamegraph::write_folded(graph, BufWriter::new(File::create(folded_output)?))?;
+        }
+        return Ok(());
+    }
+
+    let title = opt.title.clone().unwrap_or(collapsed.default_title);
+
+    for graph in &collapsed.graphs {
+        if let (Some(thread_id), true) = (graph.thread_id, graph.stacks.is_empty()) {
+            eprintln!(
+                "warning: thread {} has no interval events, skipping its flamegraph",
+                thread_id
+            );
+            continue;
+        }
+
+        let (title, output) = match graph.thread_id {
+            Some(thread_id) => {
+                let thread = match &graph.thread_name {
+                    Some(thread_name) => format!("thread {} ({})", thread_id, thread_name),
+                    None => format!("thread {}", thread_id),
+                };
+                let mut path = OsString::from(&opt.file_prefix[0]);
+                path.push(format!(".thread{}.svg", thread_id));
+                (format!("{} — {}", title, thread), PathBuf::from(path))
+            }
+            None => {
+                let output = opt.output.clone().unwrap_or_else(|| {
+                    let mut path = OsString::from(&opt.file_prefix[0]);
+                    path.push(".svg");
+                    PathBuf::from(path)
+                });
+                (title.clone(), output)
+            }
+        };
+
+        write_flamegraph(&opt, title, graph, &output)?;
+    }
+
     Ok(())
 }
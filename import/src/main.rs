@@ -0,0 +1,395 @@
+use std::cell::Cell;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::SystemTime;
+
+use measureme::{Clock, FileSerializationSink, ProfileMetadata, Profiler, StringId, TimestampKind};
+
+use serde::{Deserialize, Deserializer};
+use serde_json::{Map, Value};
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+struct Opt {
+    /// The Chrome trace file, e.g. `chrome_profiler.json`
+    trace_file: PathBuf,
+
+    /// The file prefix of the profile to write, defaults to the trace file
+    /// without its `.json` extension
+    #[structopt(short = "o", long = "output")]
+    output: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct TraceEvent {
+    #[serde(default)]
+    name: String,
+    #[serde(rename = "cat", default)]
+    category: String,
+    #[serde(rename = "ph")]
+    phase: String,
+    /// In microseconds
+    #[serde(rename = "ts", default)]
+    timestamp: f64,
+    /// In microseconds, only for complete (`X`) events
+    #[serde(rename = "dur")]
+    duration: Option<f64>,
+    #[serde(default)]
+    pid: u64,
+    #[serde(default)]
+    tid: u64,
+    #[serde(default, deserialize_with = "args")]
+    args: Map<String, Value>,
+}
+
+// crox writes `null` for events without arguments
+fn args<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Map<String, Value>, D::Error> {
+    Ok(Option::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// A `Clock` that is set to the timestamp of each event right before the
+/// event is recorded.
+#[derive(Clone, Default)]
+struct ReplayClock(Rc<Cell<u64>>);
+
+impl Clock for ReplayClock {
+    fn now_nanos(&self) -> u64 {
+        self.0.get()
+    }
+}
+
+enum ItemKind {
+    Interval { end_ns: u64 },
+    Instant,
+    Counter { value: u64 },
+}
+
+// An event of a single thread, with its timestamp relative to the start of
+// the trace
+struct Item {
+    start_ns: u64,
+    kind: ItemKind,
+    event_kind: String,
+    event_id: String,
+}
+
+impl Item {
+    fn end_ns(&self) -> u64 {
+        match self.kind {
+            ItemKind::Interval { end_ns } => end_ns,
+            _ => self.start_ns,
+        }
+    }
+}
+
+// Event ids are the name of the event, followed by its arguments, if any
+fn event_id(event: &TraceEvent) -> String {
+    if event.args.is_empty() {
+        event.name.clone()
+    } else {
+        format!("{} {}", event.name, Value::Object(event.args.clone()))
+    }
+}
+
+fn counter_value(value: &Value) -> Option<u64> {
+    value.as_u64().or_else(|| {
+        value
+            .as_f64()
+            .filter(|value| *value >= 0.0)
+            .map(|value| value.round() as u64)
+    })
+}
+
+struct Importer {
+    profiler: Profiler<FileSerializationSink, ReplayClock>,
+    clock: ReplayClock,
+    strings: HashMap<String, StringId>,
+}
+
+impl Importer {
+    fn string_id(&mut self, s: &str) -> StringId {
+        if let Some(&id) = self.strings.get(s) {
+            return id;
+        }
+
+        let id = self.profiler.alloc_string(s);
+        self.strings.insert(s.to_string(), id);
+        id
+    }
+
+    // Records the events of a thread in an order that keeps intervals
+    // properly nested, so that tools matching start and end events, like
+    // stack_collapse, see the same stacks as the trace viewer. That's why the
+    // start and end of each interval are recorded separately instead of via
+    // `Profiler::record_interval()`. Returns the number of intervals that
+    // had to be cut short because they ended after their parent.
+    fn record_thread(&mut self, thread_id: u64, mut items: Vec<Item>) -> usize {
+        items.sort_by_key(|item| (item.start_ns, Reverse(item.end_ns())));
+
+        let mut open: Vec<(u64, StringId, StringId)> = Vec::new();
+        let mut cut_short = 0;
+
+        for item in items {
+            while let Some(&(end_ns, event_kind, event_id)) = open.last() {
+                if end_ns > item.start_ns {
+                    break;
+                }
+                self.record_end(thread_id, end_ns, event_kind, event_id);
+                open.pop();
+            }
+
+            let event_kind = self.string_id(&item.event_kind);
+            let event_id = self.string_id(&item.event_id);
+            self.clock.0.set(item.start_ns);
+
+            match item.kind {
+                ItemKind::Interval { mut end_ns } => {
+                    if let Some(&(parent_end_ns, _, _)) = open.last() {
+                        if end_ns > parent_end_ns {
+                            end_ns = parent_end_ns;
+                            cut_short += 1;
+                        }
+                    }
+
+                    self.profiler.record_event(
+                        event_kind,
+                        event_id,
+                        thread_id,
+                        TimestampKind::Start,
+                    );
+                    open.push((end_ns, event_kind, event_id));
+                }
+                ItemKind::Instant => self
+                    .profiler
+                    .record_instant_event(event_kind, event_id, thread_id),
+                ItemKind::Counter { value } => {
+                    self.profiler.record_counter(event_kind, thread_id, value)
+                }
+            }
+        }
+
+        while let Some((end_ns, event_kind, event_id)) = open.pop() {
+            self.record_end(thread_id, end_ns, event_kind, event_id);
+        }
+
+        cut_short
+    }
+
+    fn record_end(&self, thread_id: u64, end_ns: u64, event_kind: StringId, event_id: StringId) {
+        self.clock.0.set(end_ns);
+        self.profiler
+            .record_event(event_kind, event_id, thread_id, TimestampKind::End);
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let opt = Opt::from_args();
+
+    let contents = fs::read(&opt.trace_file)
+        .map_err(|e| format!("couldn't read `{}`: {}", opt.trace_file.display(), e))?;
+    let not_a_trace = |e: &dyn fmt::Display| {
+        format!(
+            "`{}` is not a Chrome trace: {}",
+            opt.trace_file.display(),
+            e
+        )
+    };
+
+    // Both forms of the trace event format are accepted: a plain array of
+    // events, or an object with the events in `traceEvents`, as written by
+    // crox
+    let events = match serde_json::from_slice(&contents).map_err(|e| not_a_trace(&e))? {
+        Value::Object(mut object) => object
+            .remove("traceEvents")
+            .ok_or_else(|| not_a_trace(&"expected a `traceEvents` field"))?,
+        events => events,
+    };
+    let events: Vec<TraceEvent> = serde_json::from_value(events).map_err(|e| not_a_trace(&e))?;
+
+    // Thread ids are only unique within a process, so the process id is
+    // made part of them if the trace covers several processes
+    let pids: BTreeSet<u64> = events.iter().map(|event| event.pid).collect();
+    let thread_id = |event: &TraceEvent| {
+        if pids.len() > 1 {
+            (event.pid << 32) | event.tid
+        } else {
+            event.tid
+        }
+    };
+
+    let first_timestamp = events
+        .iter()
+        .filter(|event| event.phase != "M")
+        .map(|event| event.timestamp)
+        .fold(f64::INFINITY, f64::min);
+    let nanos = |micros: f64| ((micros - first_timestamp) * 1000.0).max(0.0).round() as u64;
+
+    let mut threads: BTreeMap<u64, Vec<Item>> = BTreeMap::new();
+    let mut thread_names = BTreeMap::new();
+    let mut begin_events: HashMap<u64, Vec<&TraceEvent>> = HashMap::new();
+    let mut process_name = None;
+    let mut last_ns = 0;
+    let mut unmatched_end_events = 0;
+    let mut invalid_counters = 0;
+    let mut ignored_phases = BTreeSet::new();
+
+    for event in &events {
+        let thread_id = thread_id(event);
+
+        if event.phase == "M" {
+            let name = event.args.get("name").and_then(Value::as_str);
+            match (&event.name[..], name) {
+                ("thread_name", Some(name)) => {
+                    thread_names.insert(thread_id, name.to_string());
+                }
+                ("process_name", Some(name)) => process_name = Some(name.to_string()),
+                _ => {}
+            }
+            continue;
+        }
+
+        let start_ns = nanos(event.timestamp);
+        last_ns = last_ns.max(start_ns);
+
+        let item = |kind| Item {
+            start_ns,
+            kind,
+            event_kind: event.category.clone(),
+            event_id: event_id(event),
+        };
+        let items = threads.entry(thread_id).or_default();
+
+        match &event.phase[..] {
+            "X" => {
+                let end_ns =
+                    start_ns + (event.duration.unwrap_or(0.0).max(0.0) * 1000.0).round() as u64;
+                last_ns = last_ns.max(end_ns);
+                items.push(item(ItemKind::Interval { end_ns }));
+            }
+            "B" => begin_events.entry(thread_id).or_default().push(event),
+            "E" => match begin_events
+                .get_mut(&thread_id)
+                .and_then(|stack| stack.pop())
+            {
+                Some(begin) => items.push(Item {
+                    start_ns: nanos(begin.timestamp),
+                    kind: ItemKind::Interval { end_ns: start_ns },
+                    event_kind: begin.category.clone(),
+                    event_id: event_id(begin),
+                }),
+                None => unmatched_end_events += 1,
+            },
+            "i" | "I" => items.push(item(ItemKind::Instant)),
+            // Each argument of a counter event is a separate series
+            "C" => {
+                for (key, value) in &event.args {
+                    let value = match counter_value(value) {
+                        Some(value) => value,
+                        None => {
+                            invalid_counters += 1;
+                            continue;
+                        }
+                    };
+                    let name = if *key == event.name {
+                        event.name.clone()
+                    } else {
+                        format!("{}.{}", event.name, key)
+                    };
+                    items.push(Item {
+                        start_ns,
+                        kind: ItemKind::Counter { value },
+                        event_kind: name.clone(),
+                        event_id: name,
+                    });
+                }
+            }
+            phase => {
+                ignored_phases.insert(phase.to_string());
+            }
+        }
+    }
+
+    if unmatched_end_events > 0 {
+        eprintln!(
+            "warning: ignoring {} end event(s) without a matching begin event",
+            unmatched_end_events
+        );
+    }
+
+    // Events that were begun but never ended are closed at the last
+    // timestamp in the trace, like crox does
+    let unmatched_begin_events: usize = begin_events.values().map(Vec::len).sum();
+    if unmatched_begin_events > 0 {
+        eprintln!(
+            "warning: {} begin event(s) without a matching end event",
+            unmatched_begin_events
+        );
+    }
+    for (thread_id, stack) in begin_events {
+        let items = threads.entry(thread_id).or_default();
+        items.extend(stack.into_iter().map(|begin| Item {
+            start_ns: nanos(begin.timestamp),
+            kind: ItemKind::Interval { end_ns: last_ns },
+            event_kind: begin.category.clone(),
+            event_id: event_id(begin),
+        }));
+    }
+
+    if invalid_counters > 0 {
+        eprintln!(
+            "warning: ignoring {} counter value(s) that are not non-negative numbers",
+            invalid_counters
+        );
+    }
+    if !ignored_phases.is_empty() {
+        let phases: Vec<_> = ignored_phases.into_iter().collect();
+        eprintln!(
+            "warning: ignoring events of unsupported phase(s) {}",
+            phases.join(", ")
+        );
+    }
+
+    let output = opt
+        .output
+        .clone()
+        .unwrap_or_else(|| opt.trace_file.with_extension(""));
+    let clock = ReplayClock::default();
+    let mut importer = Importer {
+        profiler: Profiler::with_clock(&output, clock.clone())?,
+        clock,
+        strings: HashMap::new(),
+    };
+
+    // The trace doesn't say when it was recorded, so the start time is left
+    // at the Unix epoch
+    importer.profiler.set_metadata(&ProfileMetadata {
+        start_time: SystemTime::UNIX_EPOCH,
+        process_id: pids.iter().next().copied().unwrap_or(0) as u32,
+        cmd: process_name.unwrap_or_else(|| opt.trace_file.display().to_string()),
+        ..ProfileMetadata::default()
+    });
+
+    for (&thread_id, name) in &thread_names {
+        importer.profiler.set_thread_name(thread_id, name);
+    }
+
+    let mut cut_short = 0;
+    for (thread_id, items) in threads {
+        cut_short += importer.record_thread(thread_id, items);
+    }
+    if cut_short > 0 {
+        eprintln!(
+            "warning: {} interval(s) ended after their parent and were cut short",
+            cut_short
+        );
+    }
+
+    importer.profiler.finish()?;
+
+    Ok(())
+}
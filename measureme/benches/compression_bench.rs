@@ -0,0 +1,72 @@
+#![feature(test)]
+
+extern crate test;
+
+use measureme::{
+    testing_common, CompressedSink, FileSerializationSink, InMemorySink, SerializationSink,
+};
+use std::path::Path;
+
+fn total_file_size(file_name_stem: &str) -> u64 {
+    ["events", "string_data", "string_index"]
+        .iter()
+        .map(|ext| {
+            let path = Path::new("test-tmp/end_to_end_serialization")
+                .join(format!("{}.{}", file_name_stem, ext));
+            std::fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+        })
+        .sum()
+}
+
+#[bench]
+fn bench_plain_file_sink(bencher: &mut test::Bencher) {
+    bencher.iter(|| {
+        testing_common::run_end_to_end_serialization_test::<FileSerializationSink>(
+            "plain_file_sink_bench",
+        );
+    });
+
+    eprintln!(
+        "plain file sink: {} bytes",
+        total_file_size("plain_file_sink_bench")
+    );
+}
+
+#[bench]
+fn bench_compressed_file_sink(bencher: &mut test::Bencher) {
+    bencher.iter(|| {
+        testing_common::run_end_to_end_serialization_test::<CompressedSink<FileSerializationSink>>(
+            "compressed_file_sink_bench",
+        );
+    });
+
+    eprintln!(
+        "compressed file sink: {} bytes",
+        total_file_size("compressed_file_sink_bench")
+    );
+}
+
+// Compresses the `.string_data` file of a real profile, e.g. one recorded
+// with `rustc -Zself-profile`, given by the `MEASUREME_BENCH_STRING_DATA`
+// environment variable. Does nothing without it.
+#[bench]
+fn bench_compress_string_data(bencher: &mut test::Bencher) {
+    let path = match std::env::var_os("MEASUREME_BENCH_STRING_DATA") {
+        Some(path) => path,
+        None => return,
+    };
+    let string_data = std::fs::read(path).unwrap();
+
+    let compress = || {
+        let sink = CompressedSink::new(InMemorySink::new());
+        sink.write_bytes(&string_data);
+        sink.into_inner().into_bytes()
+    };
+    bencher.iter(compress);
+
+    eprintln!(
+        "string data: {} bytes, compressed: {} bytes",
+        string_data.len(),
+        compress().len()
+    );
+}
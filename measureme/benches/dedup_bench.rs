@@ -0,0 +1,80 @@
+#![feature(test)]
+
+extern crate test;
+
+use measureme::{InMemorySink, StringTableBuilder};
+use std::sync::Arc;
+
+// Approximates the event ids of a rustc profile: a handful of queries that
+// are invoked on many different keys, where the same key recurs over and
+// over, e.g. `typeck` and `type_of` of the same item.
+fn event_ids() -> Vec<String> {
+    const QUERIES: &[&str] = &[
+        "typeck",
+        "type_of",
+        "predicates_of",
+        "mir_built",
+        "optimized_mir",
+        "layout_of",
+        "is_copy_raw",
+        "param_env",
+    ];
+
+    (0..100_000u64)
+        .map(|i| {
+            // Cheap pseudo random numbers that favor small keys
+            let x = i.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 40;
+            let key = (x % 2000) * (x % 7) / 6;
+            format!(
+                "{}(DefId(0:{} ~ foo[1234]::bar::baz))",
+                QUERIES[(x % QUERIES.len() as u64) as usize],
+                key
+            )
+        })
+        .collect()
+}
+
+fn string_table_size(deduplicated: bool, event_ids: &[String]) -> usize {
+    let data_sink = Arc::new(InMemorySink::new());
+    let index_sink = Arc::new(InMemorySink::new());
+
+    {
+        let builder = if deduplicated {
+            StringTableBuilder::new_deduplicated(data_sink.clone(), index_sink.clone())
+        } else {
+            StringTableBuilder::new(data_sink.clone(), index_sink.clone())
+        };
+
+        for event_id in event_ids {
+            builder.alloc(&event_id[..]);
+        }
+    }
+
+    let data_size = Arc::try_unwrap(data_sink).unwrap().into_bytes().len();
+    let index_size = Arc::try_unwrap(index_sink).unwrap().into_bytes().len();
+    data_size + index_size
+}
+
+#[bench]
+fn bench_string_table(bencher: &mut test::Bencher) {
+    let event_ids = event_ids();
+
+    bencher.iter(|| string_table_size(false, &event_ids));
+
+    eprintln!(
+        "string table: {} bytes",
+        string_table_size(false, &event_ids)
+    );
+}
+
+#[bench]
+fn bench_deduplicated_string_table(bencher: &mut test::Bencher) {
+    let event_ids = event_ids();
+
+    bencher.iter(|| string_table_size(true, &event_ids));
+
+    eprintln!(
+        "deduplicated string table: {} bytes",
+        string_table_size(true, &event_ids)
+    );
+}
@@ -0,0 +1,59 @@
+#![feature(test)]
+
+extern crate test;
+
+use measureme::{InMemorySink, Profiler};
+use std::sync::Arc;
+use test::black_box;
+
+const EVENTS: u64 = 1000;
+
+// The cost of recording interval events with a `Profiler` that records
+// nothing, which should be close to that of the empty loop below.
+#[bench]
+fn bench_null_profiler(bencher: &mut test::Bencher) {
+    let profiler = Profiler::null();
+    let event_kind = profiler.alloc_string("Kind");
+    let event_id = profiler.alloc_string("Id");
+
+    bencher.iter(|| {
+        for thread_id in 0..EVENTS {
+            let _guard = profiler.start_recording_interval_event(
+                black_box(event_kind),
+                black_box(event_id),
+                black_box(thread_id),
+            );
+        }
+    });
+}
+
+#[bench]
+fn bench_empty_loop(bencher: &mut test::Bencher) {
+    bencher.iter(|| {
+        for thread_id in 0..EVENTS {
+            black_box(thread_id);
+        }
+    });
+}
+
+// For comparison, the same events recorded into memory
+#[bench]
+fn bench_in_memory_profiler(bencher: &mut test::Bencher) {
+    let profiler = Profiler::with_sinks(
+        Arc::new(InMemorySink::new()),
+        Arc::new(InMemorySink::new()),
+        Arc::new(InMemorySink::new()),
+    );
+    let event_kind = profiler.alloc_string("Kind");
+    let event_id = profiler.alloc_string("Id");
+
+    bencher.iter(|| {
+        for thread_id in 0..EVENTS {
+            let _guard = profiler.start_recording_interval_event(
+                black_box(event_kind),
+                black_box(event_id),
+                black_box(thread_id),
+            );
+        }
+    });
+}
@@ -0,0 +1,268 @@
+// Measures the cost of the operations that profiled programs call all the
+// time, in nanoseconds per call, with a `Profiler` writing to files and with
+// one that records nothing.
+//
+// Set `MEASUREME_BENCH_BASELINE` to the path of a baseline, usually
+// `benches/overhead_baseline.json`, to fail if an operation got slower than
+// its baseline by more than `MEASUREME_BENCH_THRESHOLD` percent (50 by
+// default) and more than a nanosecond. The threshold is generous because the
+// timings of shared CI machines vary a lot, it is meant to catch operations
+// getting several times slower. Set `MEASUREME_BENCH_SAVE_BASELINE` to a path
+// to write the measured times as a new baseline. Absolute timings depend on
+// the machine, so the baseline has to be recorded on the kind of machine it
+// is compared on.
+
+use criterion::measurement::WallTime;
+use criterion::{black_box, criterion_group, BatchSize, BenchmarkGroup, Criterion};
+use measureme::{
+    FileSerializationSink, NullSink, Profiler, SerializationSink, StringTableBuilder, TimestampKind,
+};
+use serde_json::{Map, Value};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+
+const DEFAULT_THRESHOLD_PERCENT: f64 = 50.0;
+
+// Operations of the null profiler take less than a nanosecond, so that
+// noise alone can make them look much slower in relative terms
+const MIN_REGRESSION_NANOS: f64 = 1.0;
+
+// The benchmarks are named `<operation>/<sink>`, e.g. `record_event/file`
+const SINKS: &[&str] = &["file", "null"];
+const OPERATIONS: &[&str] = &[
+    "record_event",
+    "interval_event",
+    "alloc_string_cold",
+    "alloc_string_deduped",
+];
+
+fn bench_dir() -> PathBuf {
+    Path::new(env!("CARGO_TARGET_TMPDIR")).join("overhead_bench")
+}
+
+fn file_profiler(name: &str) -> Profiler<FileSerializationSink> {
+    Profiler::new(&bench_dir().join(name)).unwrap()
+}
+
+fn file_string_table(name: &str) -> StringTableBuilder<FileSerializationSink> {
+    let path = |extension| bench_dir().join(format!("{}.{}", name, extension));
+    StringTableBuilder::new_deduplicated(
+        Arc::new(FileSerializationSink::from_path(&path("string_data")).unwrap()),
+        Arc::new(FileSerializationSink::from_path(&path("string_index")).unwrap()),
+    )
+}
+
+fn null_string_table() -> StringTableBuilder<NullSink> {
+    StringTableBuilder::new_deduplicated(Arc::new(NullSink), Arc::new(NullSink))
+}
+
+fn bench_record_event<S: SerializationSink>(
+    group: &mut BenchmarkGroup<'_, WallTime>,
+    sink: &str,
+    profiler: Profiler<S>,
+) {
+    let event_kind = profiler.alloc_string("Kind");
+    let event_id = profiler.alloc_string("Id");
+
+    group.bench_function(sink, |b| {
+        b.iter(|| {
+            profiler.record_event(
+                black_box(event_kind),
+                black_box(event_id),
+                black_box(0),
+                TimestampKind::Instant,
+            )
+        })
+    });
+}
+
+fn bench_interval_event<S: SerializationSink>(
+    group: &mut BenchmarkGroup<'_, WallTime>,
+    sink: &str,
+    profiler: Profiler<S>,
+) {
+    let event_kind = profiler.alloc_string("Kind");
+    let event_id = profiler.alloc_string("Id");
+
+    group.bench_function(sink, |b| {
+        b.iter(|| {
+            drop(profiler.start_recording_interval_event(
+                black_box(event_kind),
+                black_box(event_id),
+                black_box(0),
+            ))
+        })
+    });
+}
+
+// Every string is allocated for the first time, as for a `Profiler` without
+// deduplication. Creating the strings isn't measured.
+fn bench_alloc_string_cold<S: SerializationSink>(
+    group: &mut BenchmarkGroup<'_, WallTime>,
+    sink: &str,
+    profiler: Profiler<S>,
+) {
+    let mut next = 0u64;
+
+    group.bench_function(sink, |b| {
+        b.iter_batched(
+            || {
+                next += 1;
+                format!("typeck(DefId(0:{} ~ foo[1234]::bar))", next)
+            },
+            |s| profiler.alloc_string(&s[..]),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+// The same string is allocated over and over, so every allocation after the
+// first is answered from the deduplication cache.
+fn bench_alloc_string_deduped<S: SerializationSink>(
+    group: &mut BenchmarkGroup<'_, WallTime>,
+    sink: &str,
+    string_table: StringTableBuilder<S>,
+) {
+    group.bench_function(sink, |b| {
+        b.iter(|| string_table.alloc(black_box("typeck(DefId(0:42 ~ foo[1234]::bar))")))
+    });
+}
+
+fn benches(c: &mut Criterion) {
+    let mut group = c.benchmark_group("record_event");
+    bench_record_event(&mut group, "file", file_profiler("record_event"));
+    bench_record_event(&mut group, "null", Profiler::null());
+    group.finish();
+
+    let mut group = c.benchmark_group("interval_event");
+    bench_interval_event(&mut group, "file", file_profiler("interval_event"));
+    bench_interval_event(&mut group, "null", Profiler::null());
+    group.finish();
+
+    let mut group = c.benchmark_group("alloc_string_cold");
+    bench_alloc_string_cold(&mut group, "file", file_profiler("alloc_string_cold"));
+    bench_alloc_string_cold(&mut group, "null", Profiler::null());
+    group.finish();
+
+    let mut group = c.benchmark_group("alloc_string_deduped");
+    bench_alloc_string_deduped(
+        &mut group,
+        "file",
+        file_string_table("alloc_string_deduped"),
+    );
+    bench_alloc_string_deduped(&mut group, "null", null_string_table());
+    group.finish();
+
+    let _ = fs::remove_dir_all(bench_dir());
+}
+
+criterion_group! {
+    name = overhead;
+    config = Criterion::default()
+        .warm_up_time(Duration::from_secs(1))
+        .measurement_time(Duration::from_secs(2));
+    targets = benches
+}
+
+fn benchmark_names() -> impl Iterator<Item = String> {
+    OPERATIONS.iter().flat_map(|operation| {
+        SINKS
+            .iter()
+            .map(move |sink| format!("{}/{}", operation, sink))
+    })
+}
+
+// The median time per call that criterion measured for `name`, in
+// nanoseconds, which is less affected by outliers than the mean
+fn measured_nanos(name: &str) -> Option<f64> {
+    let target_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).parent()?;
+    let estimates = target_dir
+        .join("criterion")
+        .join(name)
+        .join("new")
+        .join("estimates.json");
+    let estimates: Value = serde_json::from_slice(&fs::read(estimates).ok()?).ok()?;
+
+    estimates["median"]["point_estimate"].as_f64()
+}
+
+fn save_baseline(path: &str) {
+    let baseline: Map<String, Value> = benchmark_names()
+        .filter_map(|name| {
+            let nanos = measured_nanos(&name)?;
+            Some((name, Value::from((nanos * 100.0).round() / 100.0)))
+        })
+        .collect();
+
+    let json = serde_json::to_string_pretty(&baseline).unwrap();
+    fs::write(path, json + "\n").unwrap();
+    println!("saved the baseline to `{}`", path);
+}
+
+// Returns whether all benchmarks are within the threshold of the baseline
+fn check_baseline(path: &str, threshold_percent: f64) -> bool {
+    let baseline: Map<String, Value> = match fs::read(path)
+        .map_err(|e| e.to_string())
+        .and_then(|json| serde_json::from_slice(&json).map_err(|e| e.to_string()))
+    {
+        Ok(baseline) => baseline,
+        Err(e) => {
+            eprintln!("error: couldn't read the baseline `{}`: {}", path, e);
+            return false;
+        }
+    };
+
+    let mut ok = true;
+    for name in benchmark_names() {
+        let (baseline_nanos, nanos) = match (baseline.get(&name), measured_nanos(&name)) {
+            (Some(baseline_nanos), Some(nanos)) => (baseline_nanos.as_f64().unwrap_or(0.0), nanos),
+            // New, or never run
+            _ => continue,
+        };
+
+        let change_percent = (nanos / baseline_nanos - 1.0) * 100.0;
+        let regressed =
+            change_percent > threshold_percent && nanos - baseline_nanos > MIN_REGRESSION_NANOS;
+        println!(
+            "{:<28} {:>10.2} ns, baseline {:>10.2} ns, {:+7.1}%{}",
+            name,
+            nanos,
+            baseline_nanos,
+            change_percent,
+            if regressed { "  REGRESSED" } else { "" }
+        );
+        ok &= !regressed;
+    }
+
+    if !ok {
+        eprintln!(
+            "error: some operations got more than {}% slower than the baseline `{}`",
+            threshold_percent, path
+        );
+    }
+    ok
+}
+
+fn main() {
+    overhead();
+    Criterion::default().configure_from_args().final_summary();
+
+    if let Ok(path) = env::var("MEASUREME_BENCH_SAVE_BASELINE") {
+        save_baseline(&path);
+    }
+
+    if let Ok(path) = env::var("MEASUREME_BENCH_BASELINE") {
+        let threshold_percent = env::var("MEASUREME_BENCH_THRESHOLD")
+            .ok()
+            .and_then(|threshold| threshold.parse().ok())
+            .unwrap_or(DEFAULT_THRESHOLD_PERCENT);
+
+        if !check_baseline(&path, threshold_percent) {
+            process::exit(1);
+        }
+    }
+}
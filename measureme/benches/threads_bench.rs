@@ -0,0 +1,78 @@
+#![feature(test)]
+
+extern crate test;
+
+use measureme::{FileSerializationSink, Profiler, SerializationSink};
+use std::path::Path;
+
+const THREADS: u64 = 8;
+const EVENTS_PER_THREAD: u64 = 10_000;
+
+fn bench_dir() -> &'static Path {
+    Path::new("test-tmp/threads_bench")
+}
+
+fn record_from_many_threads<S: SerializationSink + Send + Sync>(profiler: &Profiler<S>) {
+    let event_kind = profiler.alloc_string("Kind");
+    let event_id = profiler.alloc_string("Id");
+
+    std::thread::scope(|scope| {
+        for thread_id in 0..THREADS {
+            scope.spawn(move || {
+                for _ in 0..EVENTS_PER_THREAD {
+                    let _guard =
+                        profiler.start_recording_interval_event(event_kind, event_id, thread_id);
+                }
+            });
+        }
+    });
+}
+
+// Records interval events from several threads at once through `Profiler`,
+// which collects the events of each thread in a thread-local buffer.
+#[bench]
+fn bench_profiler_many_threads(bencher: &mut test::Bencher) {
+    bencher.iter(|| {
+        let profiler =
+            Profiler::<FileSerializationSink>::new(&bench_dir().join("profiler")).unwrap();
+        record_from_many_threads(&profiler);
+    });
+}
+
+// The same, but with one event shard per thread, so that the threads don't
+// share an event sink.
+#[bench]
+fn bench_sharded_profiler_many_threads(bencher: &mut test::Bencher) {
+    bencher.iter(|| {
+        let profiler = Profiler::<FileSerializationSink>::new_sharded(
+            &bench_dir().join("sharded_profiler"),
+            THREADS as usize,
+        )
+        .unwrap();
+        record_from_many_threads(&profiler);
+    });
+}
+
+// The same amount of event data written by several threads directly to a
+// shared sink, one `write_atomic()` call per event, for comparison.
+#[bench]
+fn bench_shared_sink_many_threads(bencher: &mut test::Bencher) {
+    bencher.iter(|| {
+        let sink =
+            FileSerializationSink::from_path(&bench_dir().join("shared_sink.events")).unwrap();
+
+        std::thread::scope(|scope| {
+            for thread_id in 0..THREADS {
+                let sink = &sink;
+                scope.spawn(move || {
+                    // A start and an end event per interval
+                    for _ in 0..2 * EVENTS_PER_THREAD {
+                        sink.write_atomic(32, |bytes| {
+                            bytes[..8].copy_from_slice(&thread_id.to_le_bytes());
+                        });
+                    }
+                });
+            }
+        });
+    });
+}
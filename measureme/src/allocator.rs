@@ -0,0 +1,266 @@
+//! A `GlobalAlloc` wrapper that counts the allocated and freed bytes, so
+//! that they can be recorded as counters on the timeline of a `Profiler`.
+
+use crate::clock::Clock;
+use crate::profiler::{current_thread_id, Profiler};
+use crate::serialization::SerializationSink;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// The counter kind of the total number of bytes allocated.
+pub const ALLOCATED_BYTES_COUNTER: &str = "allocated bytes";
+
+/// The counter kind of the total number of bytes freed.
+pub const FREED_BYTES_COUNTER: &str = "freed bytes";
+
+/// The name of the thread that records the counters.
+pub const ALLOCATION_RECORDER_THREAD_NAME: &str = "measureme-allocations";
+
+/// Wraps another `GlobalAlloc` (behind the `allocator` feature) and counts
+/// the bytes allocated and freed through it since the start of the program.
+///
+/// Recording an event on every allocation would allocate itself and produce
+/// far more events than the rest of the profile, so the allocator only
+/// updates two atomic counters. `start_recording()` spawns a thread that
+/// reads them at a fixed interval and records them into a `Profiler` as the
+/// `ALLOCATED_BYTES_COUNTER` and `FREED_BYTES_COUNTER` counters. The bytes
+/// in use at any time are the difference of the two.
+///
+/// Each allocation and deallocation costs a relaxed atomic addition on top of
+/// the wrapped allocator. Threads that allocate a lot in parallel contend on
+/// the counters, which can be noticeable for allocation heavy programs. The
+/// recording thread writes at most two counter events per interval, and none
+/// while the counters don't change.
+///
+/// ```no_run
+/// use measureme::{FileSerializationSink, MeasuremeAllocator, Profiler};
+/// use std::alloc::System;
+/// use std::path::Path;
+/// use std::sync::Arc;
+/// use std::time::Duration;
+///
+/// #[global_allocator]
+/// static ALLOCATOR: MeasuremeAllocator<System> = MeasuremeAllocator::new(System);
+///
+/// let profiler = Profiler::<FileSerializationSink>::new(Path::new("my_program")).unwrap();
+/// let profiler = Arc::new(profiler);
+/// let recorder = ALLOCATOR
+///     .start_recording(profiler.clone(), Duration::from_millis(1))
+///     .unwrap();
+/// // ...
+/// drop(recorder);
+/// ```
+pub struct MeasuremeAllocator<A = System> {
+    inner: A,
+    allocated: AtomicU64,
+    freed: AtomicU64,
+}
+
+impl<A> MeasuremeAllocator<A> {
+    pub const fn new(inner: A) -> MeasuremeAllocator<A> {
+        MeasuremeAllocator {
+            inner,
+            allocated: AtomicU64::new(0),
+            freed: AtomicU64::new(0),
+        }
+    }
+
+    /// The number of bytes allocated so far.
+    pub fn allocated_bytes(&self) -> u64 {
+        self.allocated.load(Ordering::Relaxed)
+    }
+
+    /// The number of bytes freed so far.
+    pub fn freed_bytes(&self) -> u64 {
+        self.freed.load(Ordering::Relaxed)
+    }
+}
+
+impl<A: Sync> MeasuremeAllocator<A> {
+    /// Spawns a thread that records the counters into `profiler` every
+    /// `interval`, until the returned `AllocationRecorder` is dropped. The
+    /// thread is named `ALLOCATION_RECORDER_THREAD_NAME` in the profile.
+    pub fn start_recording<S, C>(
+        &'static self,
+        profiler: Arc<Profiler<S, C>>,
+        interval: Duration,
+    ) -> io::Result<AllocationRecorder>
+    where
+        S: SerializationSink + Send + Sync + 'static,
+        C: Clock + Send + Sync + 'static,
+    {
+        let (stop, stopped) = channel::<()>();
+
+        let thread = thread::Builder::new()
+            .name(ALLOCATION_RECORDER_THREAD_NAME.to_string())
+            .spawn(move || {
+                let thread_id = current_thread_id();
+                profiler.set_thread_name(thread_id, ALLOCATION_RECORDER_THREAD_NAME);
+                let allocated_id = profiler.alloc_string(ALLOCATED_BYTES_COUNTER);
+                let freed_id = profiler.alloc_string(FREED_BYTES_COUNTER);
+
+                let mut last_sample = None;
+                let mut record = || {
+                    let sample = (self.allocated_bytes(), self.freed_bytes());
+                    if last_sample != Some(sample) {
+                        profiler.record_counter(allocated_id, thread_id, sample.0);
+                        profiler.record_counter(freed_id, thread_id, sample.1);
+                        last_sample = Some(sample);
+                    }
+                };
+
+                record();
+                // Disconnected once the `AllocationRecorder` is dropped
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    record();
+                }
+                record();
+            })?;
+
+        Ok(AllocationRecorder {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for MeasuremeAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() {
+            self.allocated
+                .fetch_add(layout.size() as u64, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            self.allocated
+                .fetch_add(layout.size() as u64, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout);
+        self.freed
+            .fetch_add(layout.size() as u64, Ordering::Relaxed);
+    }
+
+    // Counted as freeing the old allocation and allocating a new one, even
+    // if the wrapped allocator resizes it in place
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = self.inner.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            self.allocated.fetch_add(new_size as u64, Ordering::Relaxed);
+            self.freed
+                .fetch_add(layout.size() as u64, Ordering::Relaxed);
+        }
+        new_ptr
+    }
+}
+
+/// Returned by `MeasuremeAllocator::start_recording()`. Dropping it records
+/// the counters one last time and stops the recording thread.
+pub struct AllocationRecorder {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for AllocationRecorder {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InMemorySink, ProfilingData, TimestampKind};
+
+    #[test]
+    fn counts_bytes() {
+        let allocator = MeasuremeAllocator::new(System);
+
+        unsafe {
+            let ptr = allocator.alloc(Layout::from_size_align(100, 8).unwrap());
+            let ptr = allocator.realloc(ptr, Layout::from_size_align(100, 8).unwrap(), 300);
+            allocator.dealloc(ptr, Layout::from_size_align(300, 8).unwrap());
+            let ptr = allocator.alloc_zeroed(Layout::from_size_align(50, 8).unwrap());
+            assert_eq!(*ptr, 0);
+            allocator.dealloc(ptr, Layout::from_size_align(50, 8).unwrap());
+        }
+
+        assert_eq!(allocator.allocated_bytes(), 450);
+        assert_eq!(allocator.freed_bytes(), 450);
+    }
+
+    #[test]
+    fn records_counters() {
+        // Not the global allocator, so only the allocations below are counted
+        let allocator: &'static _ = Box::leak(Box::new(MeasuremeAllocator::new(System)));
+        let layout = Layout::from_size_align(100, 8).unwrap();
+
+        let event_sink = Arc::new(InMemorySink::new());
+        let string_data_sink = Arc::new(InMemorySink::new());
+        let string_index_sink = Arc::new(InMemorySink::new());
+
+        {
+            let profiler = Arc::new(Profiler::with_sinks(
+                event_sink.clone(),
+                string_data_sink.clone(),
+                string_index_sink.clone(),
+            ));
+
+            unsafe {
+                let ptr = allocator.alloc(layout);
+                allocator.dealloc(ptr, layout);
+                allocator.alloc(layout);
+            }
+
+            // The counters don't change while recording, so they are only
+            // recorded once
+            let recorder = allocator
+                .start_recording(profiler, Duration::from_millis(1))
+                .unwrap();
+            thread::sleep(Duration::from_millis(20));
+            drop(recorder);
+        }
+
+        let profiling_data = ProfilingData::from_buffers(
+            Arc::try_unwrap(event_sink).unwrap().into_bytes(),
+            Arc::try_unwrap(string_data_sink).unwrap().into_bytes(),
+            Arc::try_unwrap(string_index_sink).unwrap().into_bytes(),
+        )
+        .unwrap();
+
+        let counters: Vec<_> = profiling_data
+            .iter()
+            .map(|e| {
+                assert_eq!(e.timestamp_kind, TimestampKind::Counter);
+                assert_eq!(
+                    profiling_data.thread_name(e.thread_id),
+                    Some(ALLOCATION_RECORDER_THREAD_NAME)
+                );
+                (e.event_kind.into_owned(), e.value.unwrap())
+            })
+            .collect();
+        assert_eq!(
+            counters,
+            [
+                (ALLOCATED_BYTES_COUNTER.to_string(), 200),
+                (FREED_BYTES_COUNTER.to_string(), 100),
+            ]
+        );
+    }
+}
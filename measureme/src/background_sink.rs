@@ -0,0 +1,314 @@
+//! A `SerializationSink` that hands the data written to it to a dedicated
+//! writer thread, so that the threads recording events never wait for I/O.
+
+use crate::serialization::{Addr, SerializationSink};
+use std::io;
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+
+/// The amount of data that is collected before it is passed to the writer
+/// thread as one chunk.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The number of chunks that can be queued for the writer thread by default,
+/// before writing to a `BackgroundSink` blocks.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 64;
+
+/// Wraps another `SerializationSink` and writes to it from a dedicated
+/// thread. Data is collected into chunks of `CHUNK_SIZE` bytes, which are
+/// sent to the writer thread through a bounded queue.
+///
+/// Chunks are queued in the order their addresses are handed out, so the
+/// data ends up in the wrapped sink exactly as if it had been written to it
+/// directly, and the events of each thread stay in order. When the writer
+/// thread can't keep up and the queue is full, writing blocks until there is
+/// room again instead of buffering without limit. `blocked_sends()` counts
+/// how often that happened.
+///
+/// Dropping the `BackgroundSink` writes the remaining data and waits for the
+/// writer thread to finish.
+pub struct BackgroundSink<S: SerializationSink + Send + 'static> {
+    state: Mutex<State>,
+    // Only `None` after `into_inner()` has been called
+    writer: Option<JoinHandle<S>>,
+}
+
+struct State {
+    buffer: Vec<u8>,
+    // The number of bytes written so far
+    addr: u32,
+    // Only `None` after `into_inner()` has been called
+    sender: Option<SyncSender<Message>>,
+    blocked_sends: u64,
+}
+
+enum Message {
+    Chunk(Vec<u8>),
+    Flush(SyncSender<io::Result<()>>),
+}
+
+impl<S: SerializationSink + Send + 'static> BackgroundSink<S> {
+    /// Wraps `inner` and spawns the writer thread, with a queue of
+    /// `DEFAULT_QUEUE_CAPACITY` chunks.
+    pub fn new(inner: S) -> io::Result<BackgroundSink<S>> {
+        BackgroundSink::with_queue_capacity(inner, DEFAULT_QUEUE_CAPACITY)
+    }
+
+    /// Like `new()`, but queues at most `queue_capacity` chunks, i.e. up to
+    /// `queue_capacity * CHUNK_SIZE` bytes that haven't been written yet.
+    pub fn with_queue_capacity(inner: S, queue_capacity: usize) -> io::Result<BackgroundSink<S>> {
+        let (sender, receiver) = sync_channel(queue_capacity);
+
+        let writer = thread::Builder::new()
+            .name("measureme-writer".to_string())
+            .spawn(move || write_chunks(inner, receiver))?;
+
+        Ok(BackgroundSink {
+            state: Mutex::new(State {
+                buffer: Vec::with_capacity(CHUNK_SIZE),
+                addr: 0,
+                sender: Some(sender),
+                blocked_sends: 0,
+            }),
+            writer: Some(writer),
+        })
+    }
+
+    /// The number of times writing had to wait for the writer thread because
+    /// the queue was full.
+    pub fn blocked_sends(&self) -> u64 {
+        self.state.lock().unwrap().blocked_sends
+    }
+
+    /// Writes any buffered data, waits for the writer thread to finish and
+    /// returns the wrapped sink.
+    pub fn into_inner(mut self) -> S {
+        self.stop_writer()
+            .unwrap()
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    }
+
+    // Queues the remaining data and closes the queue, which makes the
+    // writer thread exit once it has written everything.
+    fn stop_writer(&mut self) -> Option<JoinHandle<S>> {
+        let state = match self.state.get_mut() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        if state.sender.is_some() {
+            let _ = send_buffer(state);
+            state.sender = None;
+        }
+
+        self.writer.take()
+    }
+}
+
+impl<S: SerializationSink + Send + 'static> BackgroundSink<S> {
+    // Accounts for `num_bytes` just appended to the buffer and returns their
+    // address. Queues the buffer once it is full.
+    fn advance(&self, state: &mut State, num_bytes: usize) -> Addr {
+        let addr = state.addr;
+        state.addr += num_bytes as u32;
+
+        if state.buffer.len() >= CHUNK_SIZE {
+            send_buffer(state).expect("background writer thread exited");
+        }
+
+        Addr(addr)
+    }
+}
+
+// Sends the buffered data to the writer thread, waiting for room in the
+// queue if necessary. Fails if the writer thread is gone, e.g. because the
+// wrapped sink panicked.
+fn send_buffer(state: &mut State) -> Result<(), ()> {
+    if state.buffer.is_empty() {
+        return Ok(());
+    }
+
+    let chunk = std::mem::replace(&mut state.buffer, Vec::with_capacity(CHUNK_SIZE));
+    send(state, Message::Chunk(chunk))
+}
+
+fn send(state: &mut State, message: Message) -> Result<(), ()> {
+    let sender = state.sender.as_ref().ok_or(())?;
+
+    match sender.try_send(message) {
+        Ok(()) => Ok(()),
+        Err(TrySendError::Full(message)) => {
+            state.blocked_sends += 1;
+            sender.send(message).map_err(|_| ())
+        }
+        Err(TrySendError::Disconnected(_)) => Err(()),
+    }
+}
+
+fn write_chunks<S: SerializationSink>(inner: S, receiver: Receiver<Message>) -> S {
+    for message in receiver {
+        match message {
+            Message::Chunk(chunk) => {
+                inner.write_bytes(&chunk);
+            }
+            Message::Flush(done) => {
+                let _ = done.send(inner.flush());
+            }
+        }
+    }
+
+    // Errors can't be reported from here, `flush()` returns them instead
+    let _ = inner.flush();
+    inner
+}
+
+impl<S: SerializationSink + Send + 'static> SerializationSink for BackgroundSink<S> {
+    fn from_path(path: &Path) -> io::Result<Self> {
+        BackgroundSink::new(S::from_path(path)?)
+    }
+
+    fn write_atomic<W>(&self, num_bytes: usize, write: W) -> Addr
+    where
+        W: FnOnce(&mut [u8]),
+    {
+        let mut state = self.state.lock().unwrap();
+
+        let start = state.buffer.len();
+        state.buffer.resize(start + num_bytes, 0);
+        write(&mut state.buffer[start..]);
+
+        self.advance(&mut state, num_bytes)
+    }
+
+    fn write_bytes(&self, data: &[u8]) -> Addr {
+        let mut state = self.state.lock().unwrap();
+
+        state.buffer.extend_from_slice(data);
+
+        self.advance(&mut state, data.len())
+    }
+
+    /// Queues the buffered data, even if it is smaller than `CHUNK_SIZE`, and
+    /// waits until the writer thread has written it and flushed the wrapped
+    /// sink.
+    fn flush(&self) -> io::Result<()> {
+        let writer_exited = || io::Error::other("writer thread exited");
+
+        let (done_sender, done) = sync_channel(1);
+        {
+            let mut state = self.state.lock().unwrap();
+            send_buffer(&mut state).map_err(|()| writer_exited())?;
+            send(&mut state, Message::Flush(done_sender)).map_err(|()| writer_exited())?;
+        }
+
+        done.recv().map_err(|_| writer_exited())?
+    }
+}
+
+impl<S: SerializationSink + Send + 'static> Drop for BackgroundSink<S> {
+    fn drop(&mut self) {
+        if let Some(writer) = self.stop_writer() {
+            // A panic of the writer thread has been reported already
+            let _ = writer.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InMemorySink, Profiler, ProfilingData, TimestampKind};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    // Writes to a shared `InMemorySink`, taking a while for every write like
+    // a slow disk
+    struct SlowSink(Arc<InMemorySink>);
+
+    impl SerializationSink for SlowSink {
+        fn from_path(_path: &Path) -> io::Result<Self> {
+            Ok(SlowSink(Arc::new(InMemorySink::new())))
+        }
+
+        fn write_atomic<W>(&self, num_bytes: usize, write: W) -> Addr
+        where
+            W: FnOnce(&mut [u8]),
+        {
+            thread::sleep(Duration::from_millis(2));
+            self.0.write_atomic(num_bytes, write)
+        }
+    }
+
+    #[test]
+    fn no_events_lost_under_back_pressure() {
+        const THREADS: u64 = 4;
+        const EVENTS_PER_THREAD: u64 = 20_000;
+
+        let event_data = Arc::new(InMemorySink::new());
+        let string_data = Arc::new(InMemorySink::new());
+        let string_index_data = Arc::new(InMemorySink::new());
+        let slow_sink = |data: &Arc<InMemorySink>| {
+            Arc::new(BackgroundSink::with_queue_capacity(SlowSink(data.clone()), 1).unwrap())
+        };
+
+        let event_sink = slow_sink(&event_data);
+        let profiler = Profiler::with_sinks(
+            event_sink.clone(),
+            slow_sink(&string_data),
+            slow_sink(&string_index_data),
+        );
+        let counter = profiler.alloc_string("counter");
+
+        thread::scope(|scope| {
+            for thread_id in 0..THREADS {
+                let profiler = &profiler;
+                scope.spawn(move || {
+                    for value in 0..EVENTS_PER_THREAD {
+                        profiler.record_counter(counter, thread_id, value);
+                    }
+                });
+            }
+        });
+
+        profiler.finish().unwrap();
+        assert!(event_sink.blocked_sends() > 0);
+        // Waits for the writer thread
+        drop(event_sink);
+
+        let into_bytes = |data: Arc<InMemorySink>| Arc::try_unwrap(data).unwrap().into_bytes();
+        let profiling_data = ProfilingData::from_buffers(
+            into_bytes(event_data),
+            into_bytes(string_data),
+            into_bytes(string_index_data),
+        )
+        .unwrap();
+        assert!(profiling_data.is_complete());
+
+        let mut next_values = vec![0; THREADS as usize];
+        for event in profiling_data.iter() {
+            assert_eq!(event.timestamp_kind, TimestampKind::Counter);
+            let next_value = &mut next_values[event.thread_id as usize];
+            assert_eq!(event.value, Some(*next_value));
+            *next_value += 1;
+        }
+        assert_eq!(next_values, vec![EVENTS_PER_THREAD; THREADS as usize]);
+    }
+
+    #[test]
+    fn flush_waits_for_writer_thread() {
+        let data = Arc::new(InMemorySink::new());
+        let sink = BackgroundSink::new(SlowSink(data.clone())).unwrap();
+
+        assert_eq!(sink.write_bytes(b"abc"), Addr(0));
+        assert_eq!(sink.write_bytes(b"de"), Addr(3));
+        sink.flush().unwrap();
+        assert_eq!(&data.bytes()[..], b"abcde");
+
+        sink.write_bytes(b"f");
+        let inner = sink.into_inner();
+        assert_eq!(&inner.0.bytes()[..], b"abcdef");
+    }
+}
@@ -0,0 +1,43 @@
+//! The contents of a trace file, either read into memory or memory-mapped.
+
+use std::ops::Deref;
+
+pub(crate) enum ByteBuffer {
+    Owned(Vec<u8>),
+    #[cfg(not(target_arch = "wasm32"))]
+    Mapped(memmap2::Mmap),
+}
+
+impl ByteBuffer {
+    /// Maps the given file read-only.
+    ///
+    /// The mapping must not be modified while it is in use. Trace files are
+    /// only written by a `Profiler` while it is alive, so this holds as long
+    /// as nobody reads a profile that is still being recorded, or truncates
+    /// or rewrites the file concurrently.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn map(path: &std::path::Path) -> std::io::Result<ByteBuffer> {
+        let file = std::fs::File::open(path)?;
+        let mmap = unsafe { memmap2::Mmap::map(&file)? };
+        Ok(ByteBuffer::Mapped(mmap))
+    }
+}
+
+impl Deref for ByteBuffer {
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &[u8] {
+        match self {
+            ByteBuffer::Owned(data) => data,
+            #[cfg(not(target_arch = "wasm32"))]
+            ByteBuffer::Mapped(mmap) => mmap,
+        }
+    }
+}
+
+impl From<Vec<u8>> for ByteBuffer {
+    fn from(data: Vec<u8>) -> ByteBuffer {
+        ByteBuffer::Owned(data)
+    }
+}
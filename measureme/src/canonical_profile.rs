@@ -0,0 +1,206 @@
+//! Rewrites a profile so that its string table only depends on which strings
+//! were allocated, not on the order in which the threads of the profiled
+//! program happened to allocate them.
+
+use crate::file_header::{write_file_header, FILE_MAGIC_EVENT_STREAM};
+use crate::profiling_data::ProfilingData;
+use crate::raw_event::{RawEvent, RAW_EVENT_SIZE};
+use crate::serialization::{InMemorySink, SerializationSink};
+use crate::stringtable::{
+    internal_string_ids, StringId, StringTableBuilder, FIRST_REGULAR_STRING_ID, METADATA_STRING_ID,
+};
+use crate::ProfilerFiles;
+use rustc_hash::FxHashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+/// The three streams of a profile whose string table has been put into a
+/// canonical form, see `CanonicalProfile::new()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CanonicalProfile {
+    pub events: Vec<u8>,
+    pub string_data: Vec<u8>,
+    pub string_index: Vec<u8>,
+}
+
+impl CanonicalProfile {
+    /// Rewrites the string table of `profile` so that two runs of the same
+    /// workload produce byte-identical `.string_data` and `.string_index`
+    /// files, e.g. to verify that a build is reproducible.
+    ///
+    /// The ids handed out by `StringTableBuilder::alloc()` depend on the
+    /// order in which the threads allocated their strings, so they are
+    /// replaced: the strings are sorted by their contents and get new ids in
+    /// that order, and the events are rewritten to refer to the new ids.
+    /// This changes the `StringId`s, so ids of the original profile that were
+    /// kept elsewhere don't refer to the same strings anymore. Reserved ids,
+    /// which the profiled program chose itself, keep their values.
+    ///
+    /// Every string is written as a single component, since the components
+    /// strings were made of are an artifact of how they were allocated. This
+    /// makes the string data larger if many strings share components.
+    /// Strings with the same contents are merged into one. Events whose
+    /// strings are missing, which `ProfilingData` skips, are dropped.
+    ///
+    /// Only the string table is made canonical. The timestamps, thread ids
+    /// and order of the events stay as they were, and so does the metadata,
+    /// which contains the start time and the process id unless they were
+    /// replaced via `Profiler::set_metadata()`.
+    pub fn new(profile: &ProfilingData) -> CanonicalProfile {
+        let string_table = profile.string_table();
+
+        let mut reserved_ids = Vec::new();
+        let mut regular_strings = Vec::new();
+        for id in string_table.ids() {
+            if id.as_u32() < METADATA_STRING_ID {
+                reserved_ids.push(id);
+            } else if id.as_u32() >= FIRST_REGULAR_STRING_ID {
+                regular_strings.push((string_table.get(id).to_string(), id));
+            }
+        }
+        reserved_ids.sort_unstable_by_key(|id| id.as_u32());
+        regular_strings.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+
+        let string_data_sink = Arc::new(InMemorySink::new());
+        let string_index_sink = Arc::new(InMemorySink::new());
+        let builder = StringTableBuilder::new(string_data_sink.clone(), string_index_sink.clone());
+
+        for &id in &reserved_ids {
+            builder.alloc_with_reserved_id(id, &*string_table.get(id).to_string());
+        }
+        for id in internal_string_ids().filter(|&id| string_table.contains(id)) {
+            builder.alloc_internal(id, &*string_table.get(id).to_string());
+        }
+
+        let mut new_ids = FxHashMap::default();
+        let mut previous: Option<(&str, StringId)> = None;
+        for (s, id) in &regular_strings {
+            let new_id = match previous {
+                Some((previous_s, new_id)) if previous_s == s => new_id,
+                _ => builder.alloc(&**s),
+            };
+            new_ids.insert(*id, new_id);
+            previous = Some((s, new_id));
+        }
+
+        builder.flush().expect("writing to memory can't fail");
+        drop(builder);
+
+        let remap = |id: StringId| new_ids.get(&id).copied().unwrap_or(id);
+        let event_sink = InMemorySink::new();
+        write_file_header(&event_sink, FILE_MAGIC_EVENT_STREAM);
+        for raw_event in profile.resolvable_raw_events() {
+            let raw_event = RawEvent {
+                event_kind: remap(raw_event.event_kind),
+                id: remap(raw_event.id),
+                ..raw_event
+            };
+            event_sink.write_atomic(RAW_EVENT_SIZE, |bytes| raw_event.serialize(bytes));
+        }
+
+        CanonicalProfile {
+            events: event_sink.into_bytes(),
+            string_data: Arc::try_unwrap(string_data_sink).unwrap().into_bytes(),
+            string_index: Arc::try_unwrap(string_index_sink).unwrap().into_bytes(),
+        }
+    }
+
+    /// Writes the profile to the three files of `path_stem`, which
+    /// `ProfilingData::new()` reads.
+    pub fn write_to(&self, path_stem: &Path) -> io::Result<()> {
+        let paths = ProfilerFiles::new(path_stem);
+        if let Some(parent) = path_stem.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        fs::write(paths.events_file, &self.events)?;
+        fs::write(paths.string_data_file, &self.string_data)?;
+        fs::write(paths.string_index_file, &self.string_index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ProfileMetadata, Profiler, StringComponent};
+    use std::time::{Duration, SystemTime};
+
+    // Records the same events in both runs, but allocates the strings in the
+    // given order
+    fn record(allocation_order: &[usize]) -> ProfilingData {
+        let event_sink = Arc::new(InMemorySink::new());
+        let string_data_sink = Arc::new(InMemorySink::new());
+        let string_index_sink = Arc::new(InMemorySink::new());
+
+        {
+            let profiler = Profiler::with_sinks(
+                event_sink.clone(),
+                string_data_sink.clone(),
+                string_index_sink.clone(),
+            );
+            profiler.set_metadata(&ProfileMetadata {
+                start_time: SystemTime::UNIX_EPOCH + Duration::from_secs(1),
+                process_id: 1234,
+                cmd: "rustc --crate-name regex".to_string(),
+                ..ProfileMetadata::default()
+            });
+
+            let mut ids = [StringId::INVALID; 3];
+            for &i in allocation_order {
+                ids[i] = match i {
+                    0 => profiler.alloc_string("Query"),
+                    1 => profiler.alloc_string("typeck"),
+                    _ => profiler.alloc_string(
+                        &[
+                            StringComponent::Ref(ids[1]),
+                            StringComponent::Value("(foo)"),
+                        ][..],
+                    ),
+                };
+            }
+
+            profiler.set_thread_name(1, "main");
+            profiler.record_interval(ids[0], ids[1], 1, 100, 200);
+            profiler.record_interval(ids[0], ids[2], 1, 120, 180);
+            profiler.finish().unwrap();
+        }
+
+        ProfilingData::from_buffers(
+            Arc::try_unwrap(event_sink).unwrap().into_bytes(),
+            Arc::try_unwrap(string_data_sink).unwrap().into_bytes(),
+            Arc::try_unwrap(string_index_sink).unwrap().into_bytes(),
+        )
+        .unwrap()
+    }
+
+    fn labels(profile: &ProfilingData) -> Vec<(String, String)> {
+        profile
+            .iter()
+            .map(|e| (e.event_kind.into_owned(), e.label.into_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn interleaved_allocations() {
+        let first = record(&[0, 1, 2]);
+        let second = record(&[1, 2, 0]);
+
+        let first_canonical = CanonicalProfile::new(&first);
+        let second_canonical = CanonicalProfile::new(&second);
+        assert_eq!(first_canonical.string_data, second_canonical.string_data);
+        assert_eq!(first_canonical.string_index, second_canonical.string_index);
+
+        let canonical = ProfilingData::from_buffers(
+            first_canonical.events,
+            first_canonical.string_data,
+            first_canonical.string_index,
+        )
+        .unwrap();
+        assert_eq!(labels(&canonical), labels(&first));
+        assert_eq!(canonical.thread_name(1), Some("main"));
+        assert_eq!(canonical.metadata(), first.metadata());
+        assert!(canonical.is_complete());
+    }
+}
@@ -0,0 +1,39 @@
+use std::time::Instant;
+
+/// The source of the timestamps a `Profiler` records. Timestamps are
+/// nanoseconds relative to an arbitrary, fixed point in time, usually the
+/// creation of the `Profiler`, and must not decrease over time.
+///
+/// Implementing this allows deterministic timestamps in tests, or aligning
+/// the timestamps with those of another tracing system.
+pub trait Clock {
+    fn now_nanos(&self) -> u64;
+}
+
+/// The default `Clock`, which measures the time since its creation via
+/// `std::time::Instant`.
+pub struct InstantClock {
+    start_time: Instant,
+}
+
+impl InstantClock {
+    pub fn new() -> InstantClock {
+        InstantClock {
+            start_time: Instant::now(),
+        }
+    }
+}
+
+impl Default for InstantClock {
+    fn default() -> InstantClock {
+        InstantClock::new()
+    }
+}
+
+impl Clock for InstantClock {
+    #[inline]
+    fn now_nanos(&self) -> u64 {
+        let duration_since_start = self.start_time.elapsed();
+        duration_since_start.as_secs() * 1_000_000_000 + duration_since_start.subsec_nanos() as u64
+    }
+}
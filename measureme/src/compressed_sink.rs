@@ -0,0 +1,266 @@
+//! A `SerializationSink` that zstd-compresses everything written to it.
+//!
+//! The data written to the wrapped sink looks like this:
+//!
+//! ```text
+//! [FILE_MAGIC_COMPRESSED, version: u32, block, block, ...]
+//! ```
+//!
+//! where each block is
+//!
+//! ```text
+//! [uncompressed_len: u32, compressed_len: u32, zstd frame: compressed_len bytes]
+//! ```
+//!
+//! Since every block records its own length, a reader can skip blocks without
+//! decompressing them. The addresses returned by `write_atomic()` refer to
+//! the uncompressed data, so e.g. the string table index stays valid.
+//! Readers detect compressed files by their magic, so each file of a profile
+//! can be compressed or not independently of the others.
+
+use crate::file_header::{read_file_header, write_file_header, FILE_MAGIC_COMPRESSED};
+use crate::serialization::{Addr, SerializationSink};
+use byteorder::{ByteOrder, LittleEndian};
+use std::error::Error;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// The amount of uncompressed data that is collected before it is compressed
+/// into a block and passed on to the wrapped sink.
+const BLOCK_SIZE: usize = 256 * 1024;
+
+const BLOCK_HEADER_SIZE: usize = 8;
+
+/// Wraps another `SerializationSink` and zstd-compresses the data written to
+/// it in blocks of `BLOCK_SIZE` bytes. Any remaining data is compressed and
+/// written when the `CompressedSink` is dropped.
+pub struct CompressedSink<S: SerializationSink> {
+    // Only `None` after `into_inner()` has been called
+    inner: Option<S>,
+    // `false` for `CompressedSink::pass_through()`
+    compress: bool,
+    state: Mutex<State>,
+}
+
+struct State {
+    buffer: Vec<u8>,
+    // The number of uncompressed bytes written so far
+    addr: u32,
+}
+
+impl<S: SerializationSink> CompressedSink<S> {
+    /// Wraps `inner`, which must not have been written to yet.
+    pub fn new(inner: S) -> CompressedSink<S> {
+        write_file_header(&inner, FILE_MAGIC_COMPRESSED);
+
+        CompressedSink {
+            inner: Some(inner),
+            compress: true,
+            state: Mutex::new(State {
+                buffer: Vec::with_capacity(BLOCK_SIZE),
+                addr: 0,
+            }),
+        }
+    }
+
+    /// Wraps `inner` without compressing anything, the data is passed on
+    /// unchanged. A `Profiler` uses the same kind of sink for all of its
+    /// files, this allows it to compress only some of them, see
+    /// `Profiler::new_with_compressed_strings()`.
+    pub fn pass_through(inner: S) -> CompressedSink<S> {
+        CompressedSink {
+            inner: Some(inner),
+            compress: false,
+            state: Mutex::new(State {
+                buffer: Vec::new(),
+                addr: 0,
+            }),
+        }
+    }
+
+    /// Writes any buffered data and returns the wrapped sink.
+    pub fn into_inner(mut self) -> S {
+        self.flush_buffer();
+        self.inner.take().unwrap()
+    }
+
+    fn flush_buffer(&mut self) {
+        let state = match self.state.get_mut() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        if let Some(inner) = &self.inner {
+            if !state.buffer.is_empty() {
+                write_block(inner, &state.buffer);
+                state.buffer.clear();
+            }
+        }
+    }
+}
+
+impl<S: SerializationSink> CompressedSink<S> {
+    // Accounts for `num_bytes` just appended to the buffer and returns their
+    // address. Writes a block once the buffer is full.
+    fn advance(&self, state: &mut State, num_bytes: usize) -> Addr {
+        let addr = state.addr;
+        state.addr += num_bytes as u32;
+
+        if state.buffer.len() >= BLOCK_SIZE {
+            write_block(self.inner.as_ref().unwrap(), &state.buffer);
+            state.buffer.clear();
+        }
+
+        Addr(addr)
+    }
+}
+
+fn write_block<S: SerializationSink>(sink: &S, data: &[u8]) {
+    let compressed = zstd::bulk::compress(data, 0).expect("failed to compress block");
+
+    sink.write_atomic(BLOCK_HEADER_SIZE + compressed.len(), |bytes| {
+        LittleEndian::write_u32(&mut bytes[0..4], data.len() as u32);
+        LittleEndian::write_u32(&mut bytes[4..8], compressed.len() as u32);
+        bytes[BLOCK_HEADER_SIZE..].copy_from_slice(&compressed);
+    });
+}
+
+impl<S: SerializationSink> SerializationSink for CompressedSink<S> {
+    fn from_path(path: &Path) -> io::Result<Self> {
+        Ok(CompressedSink::new(S::from_path(path)?))
+    }
+
+    fn write_atomic<W>(&self, num_bytes: usize, write: W) -> Addr
+    where
+        W: FnOnce(&mut [u8]),
+    {
+        if !self.compress {
+            return self.inner.as_ref().unwrap().write_atomic(num_bytes, write);
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        let start = state.buffer.len();
+        state.buffer.resize(start + num_bytes, 0);
+        write(&mut state.buffer[start..]);
+
+        self.advance(&mut state, num_bytes)
+    }
+
+    fn write_bytes(&self, data: &[u8]) -> Addr {
+        if !self.compress {
+            return self.inner.as_ref().unwrap().write_bytes(data);
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        state.buffer.extend_from_slice(data);
+
+        self.advance(&mut state, data.len())
+    }
+
+    /// Compresses the buffered data into a block, even if it is smaller than
+    /// `BLOCK_SIZE`, and flushes the wrapped sink.
+    fn flush(&self) -> io::Result<()> {
+        let inner = self.inner.as_ref().unwrap();
+        if !self.compress {
+            return inner.flush();
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        if !state.buffer.is_empty() {
+            write_block(inner, &state.buffer);
+            state.buffer.clear();
+        }
+
+        inner.flush()
+    }
+}
+
+impl<S: SerializationSink> Drop for CompressedSink<S> {
+    fn drop(&mut self) {
+        self.flush_buffer();
+    }
+}
+
+/// Decompresses data written by a `CompressedSink`.
+pub(crate) fn decompress(data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    read_file_header(data, FILE_MAGIC_COMPRESSED)?;
+
+    let mut output = Vec::new();
+    let mut pos = crate::file_header::FILE_HEADER_SIZE;
+
+    while pos < data.len() {
+        if pos + BLOCK_HEADER_SIZE > data.len() {
+            Err("truncated block header in compressed data")?;
+        }
+
+        let uncompressed_len = LittleEndian::read_u32(&data[pos..pos + 4]) as usize;
+        let compressed_len = LittleEndian::read_u32(&data[pos + 4..pos + 8]) as usize;
+        pos += BLOCK_HEADER_SIZE;
+
+        if pos + compressed_len > data.len() {
+            Err("truncated block in compressed data")?;
+        }
+
+        let block = zstd::bulk::decompress(&data[pos..pos + compressed_len], uncompressed_len)?;
+        output.extend_from_slice(&block);
+        pos += compressed_len;
+    }
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::InMemorySink;
+
+    #[test]
+    fn roundtrip() {
+        let sink = CompressedSink::new(InMemorySink::new());
+
+        // Enough data for several blocks
+        let mut expected = Vec::new();
+        for i in 0..200_000u32 {
+            let bytes = i.to_le_bytes();
+            // Alternate between both ways of writing
+            let addr = if i % 2 == 0 {
+                sink.write_atomic(bytes.len(), |mem| mem.copy_from_slice(&bytes))
+            } else {
+                sink.write_bytes(&bytes)
+            };
+            assert_eq!(addr.as_usize(), expected.len());
+            expected.extend_from_slice(&bytes);
+        }
+
+        let compressed = sink.into_inner().into_bytes();
+        assert!(compressed.len() < expected.len());
+        assert_eq!(decompress(&compressed).unwrap(), expected);
+    }
+
+    #[test]
+    fn pass_through() {
+        let sink = CompressedSink::pass_through(InMemorySink::new());
+        assert_eq!(sink.write_bytes(b"abcd").as_usize(), 0);
+        assert_eq!(
+            sink.write_atomic(2, |mem| mem.copy_from_slice(b"ef"))
+                .as_usize(),
+            4
+        );
+
+        assert_eq!(sink.into_inner().into_bytes(), b"abcdef");
+    }
+
+    #[test]
+    fn truncated_data() {
+        let sink = CompressedSink::new(InMemorySink::new());
+        sink.write_atomic(4, |mem| mem.copy_from_slice(b"abcd"));
+
+        let mut compressed = sink.into_inner().into_bytes();
+        compressed.pop();
+        assert!(decompress(&compressed).is_err());
+    }
+}
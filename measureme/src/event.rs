@@ -1,13 +1,116 @@
+use crate::event_args::EventArgValue;
+use crate::profiler::TASK_ID_FLAG;
 use crate::raw_event::TimestampKind;
+use crate::string_id::StringId;
 use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
 use std::time::SystemTime;
 
-#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+/// An event as yielded by `ProfilingData::iter()` and the other event
+/// iterators. Its strings are already resolved against the string table of
+/// the profile, so no separate lookup is needed. The string ids they were
+/// resolved from are kept in `event_kind_id` and `label_id`.
+///
+/// Events compare equal if everything but their string ids is equal, so
+/// that events of different profiles, whose ids differ, can be compared.
+///
+/// With the `serde` feature, events can be serialized but not deserialized,
+/// because `additional_data` borrows from the profile.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Event<'a> {
+    /// The string recorded as `event_kind`, e.g. `Query`.
     pub event_kind: Cow<'a, str>,
+    /// The string recorded as `event_id`, i.e. the name of the event.
     pub label: Cow<'a, str>,
+    /// The id of the string recorded as `event_kind`, e.g. for looking up
+    /// per-kind data without comparing strings.
+    pub event_kind_id: StringId,
+    /// The id of the string recorded as `event_id`. Composite event ids,
+    /// e.g. ones with query keys built via `EventIdBuilder`, have the id of
+    /// the whole string here.
+    pub label_id: StringId,
     pub additional_data: &'a [Cow<'a, str>],
     pub timestamp: SystemTime,
     pub timestamp_kind: TimestampKind,
+    /// The thread the event was recorded for. Events recorded via
+    /// `Profiler::start_task_event()` have `TASK_ID_FLAG | task_id` here.
     pub thread_id: u64,
+    /// The value of a `TimestampKind::Counter` event, `None` for all other
+    /// kinds of events.
+    pub value: Option<u64>,
+    /// The integer argument of the event, if it was recorded with one.
+    pub arg: Option<u64>,
+    /// The category of a start event recorded via
+    /// `Profiler::start_recording_interval_event_with_category()`, for
+    /// grouping events in trace viewers. `None` for all other events.
+    pub category: Option<Cow<'a, str>>,
+    /// The arguments attached to the event via `Profiler::record_args_event()`,
+    /// by key, empty for events without arguments.
+    pub args: BTreeMap<Cow<'a, str>, EventArgValue<'a>>,
+}
+
+impl<'a> Event<'a> {
+    /// The event kind of the event, the same as `event_kind`.
+    pub fn kind_str(&self) -> Cow<'_, str> {
+        Cow::Borrowed(&self.event_kind)
+    }
+
+    /// The event id of the event, the same as `label`.
+    pub fn id_str(&self) -> Cow<'_, str> {
+        Cow::Borrowed(&self.label)
+    }
+
+    /// The id of the task the event was recorded for via
+    /// `Profiler::start_task_event()`, or `None` for events of threads.
+    pub fn task_id(&self) -> Option<u64> {
+        task_id(self.thread_id)
+    }
+
+    /// The category of the event, or its event kind if it has none, as
+    /// exporters use for the category of trace viewers.
+    pub fn category_or_kind(&self) -> &str {
+        self.category.as_deref().unwrap_or(&self.event_kind)
+    }
+}
+
+impl PartialEq for Event<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.event_kind == other.event_kind
+            && self.label == other.label
+            && self.additional_data == other.additional_data
+            && self.timestamp == other.timestamp
+            && self.timestamp_kind == other.timestamp_kind
+            && self.thread_id == other.thread_id
+            && self.value == other.value
+            && self.arg == other.arg
+            && self.category == other.category
+            && self.args == other.args
+    }
+}
+
+impl Eq for Event<'_> {}
+
+impl Hash for Event<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.event_kind.hash(state);
+        self.label.hash(state);
+        self.additional_data.hash(state);
+        self.timestamp.hash(state);
+        self.timestamp_kind.hash(state);
+        self.thread_id.hash(state);
+        self.value.hash(state);
+        self.arg.hash(state);
+        self.category.hash(state);
+        self.args.hash(state);
+    }
+}
+
+pub(crate) fn task_id(thread_id: u64) -> Option<u64> {
+    if thread_id & TASK_ID_FLAG != 0 {
+        Some(thread_id & !TASK_ID_FLAG)
+    } else {
+        None
+    }
 }
@@ -0,0 +1,141 @@
+//! Key/value arguments attached to events via `Profiler::record_args_event()`.
+//!
+//! The arguments of an event are written as a tagged record, see `RawEvent`,
+//! right after the event in the event stream:
+//!
+//! ```text
+//! offset  0: 0x8000_0003, the event kind of tagged records
+//! offset  4: EVENT_ARGS_RECORD_TAG, i.e. `b"ARGS"` as a little-endian u32
+//! offset  8: body_len: u64, 16 bytes per argument
+//! offset 16: thread_id: u64, the thread of the event
+//! offset 24: 0
+//! offset 32: the arguments
+//! ```
+//!
+//! Each argument is encoded as
+//!
+//! ```text
+//! offset  0: key:   u32, the `StringId` of the key
+//! offset  4: type:  u32, 0 = `Str`, 1 = `Int`, 2 = `Float`, 3 = `Bool`
+//! offset  8: value: u64, the `StringId`, the integer, the bits of the float,
+//!            or 0 or 1
+//! ```
+
+use crate::raw_event::{RawEvent, Timestamp, RAW_EVENT_SIZE};
+use crate::stringtable::{StringId, TAGGED_RECORD_EVENT_KIND};
+use byteorder::{ByteOrder, LittleEndian};
+use std::borrow::Cow;
+use std::hash::{Hash, Hasher};
+
+/// The tag of the records holding the arguments of an event.
+pub(crate) const EVENT_ARGS_RECORD_TAG: u32 = u32::from_le_bytes(*b"ARGS");
+
+const ARG_SIZE: usize = 16;
+
+/// The value of an argument passed to `Profiler::record_args_event()`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ArgValue {
+    Str(StringId),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+/// The value of an argument in `Event::args`, with its string resolved.
+///
+/// Floats are compared and hashed by their bits, so that `Event` can
+/// implement `Eq` and `Hash`.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(untagged))]
+pub enum EventArgValue<'a> {
+    Str(Cow<'a, str>),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+impl PartialEq for EventArgValue<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (EventArgValue::Str(a), EventArgValue::Str(b)) => a == b,
+            (EventArgValue::Int(a), EventArgValue::Int(b)) => a == b,
+            (EventArgValue::Float(a), EventArgValue::Float(b)) => a.to_bits() == b.to_bits(),
+            (EventArgValue::Bool(a), EventArgValue::Bool(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for EventArgValue<'_> {}
+
+impl Hash for EventArgValue<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            EventArgValue::Str(s) => s.hash(state),
+            EventArgValue::Int(i) => i.hash(state),
+            EventArgValue::Float(f) => f.to_bits().hash(state),
+            EventArgValue::Bool(b) => b.hash(state),
+        }
+    }
+}
+
+/// The record holding `args` for the last event of `thread_id`, padded to a
+/// multiple of `RAW_EVENT_SIZE`.
+pub(crate) fn serialize_args_record(thread_id: u64, args: &[(StringId, ArgValue)]) -> Vec<u8> {
+    let body_len = args.len() * ARG_SIZE;
+    let mut record = vec![0; RAW_EVENT_SIZE + body_len.next_multiple_of(RAW_EVENT_SIZE)];
+
+    RawEvent {
+        event_kind: TAGGED_RECORD_EVENT_KIND,
+        id: StringId::reserved(EVENT_ARGS_RECORD_TAG),
+        thread_id: body_len as u64,
+        timestamp: Timestamp::from_bits(thread_id),
+        payload: 0,
+    }
+    .serialize(&mut record[..RAW_EVENT_SIZE]);
+
+    let body = &mut record[RAW_EVENT_SIZE..RAW_EVENT_SIZE + body_len];
+    for ((key, value), bytes) in args.iter().zip(body.chunks_exact_mut(ARG_SIZE)) {
+        let (value_type, value) = match *value {
+            ArgValue::Str(id) => (0, u64::from(id.as_u32())),
+            ArgValue::Int(i) => (1, i as u64),
+            ArgValue::Float(f) => (2, f.to_bits()),
+            ArgValue::Bool(b) => (3, b as u64),
+        };
+        LittleEndian::write_u32(&mut bytes[0..4], key.as_u32());
+        LittleEndian::write_u32(&mut bytes[4..8], value_type);
+        LittleEndian::write_u64(&mut bytes[8..16], value);
+    }
+
+    record
+}
+
+/// The thread of the event that the tagged record with the header
+/// `raw_event` holds the arguments of, or `None` for other records.
+pub(crate) fn args_record_thread(raw_event: &RawEvent) -> Option<u64> {
+    if raw_event.event_kind == TAGGED_RECORD_EVENT_KIND
+        && raw_event.id.as_u32() == EVENT_ARGS_RECORD_TAG
+    {
+        Some(raw_event.timestamp.to_bits())
+    } else {
+        None
+    }
+}
+
+/// Decodes the body of an arguments record. Arguments of types this version
+/// of `measureme` doesn't know are skipped.
+pub(crate) fn deserialize_args(body: &[u8]) -> impl Iterator<Item = (StringId, ArgValue)> + '_ {
+    body.chunks_exact(ARG_SIZE).filter_map(|bytes| {
+        let key = StringId::reserved(LittleEndian::read_u32(&bytes[0..4]));
+        let value = LittleEndian::read_u64(&bytes[8..16]);
+        let value = match LittleEndian::read_u32(&bytes[4..8]) {
+            0 => ArgValue::Str(StringId::reserved(value as u32)),
+            1 => ArgValue::Int(value as i64),
+            2 => ArgValue::Float(f64::from_bits(value)),
+            3 => ArgValue::Bool(value != 0),
+            _ => return None,
+        };
+        Some((key, value))
+    })
+}
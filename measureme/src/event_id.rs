@@ -0,0 +1,113 @@
+//! Event ids made up of several components, e.g. the name of a query and the
+//! key it was invoked with. Each component is a string of its own, and the
+//! event id only refers to the components, so components that many event ids
+//! have in common are only stored once.
+
+use crate::serialization::SerializationSink;
+use crate::stringtable::{StringComponent, StringId, StringTableBuilder};
+
+/// Builds composite event ids. Use `Profiler::event_id_builder()` to create
+/// one for a `Profiler`.
+///
+/// The string of a composite event id is the concatenation of its
+/// components, which `ProfilingData::resolve_components()` returns
+/// individually.
+pub struct EventIdBuilder<'st, S: SerializationSink> {
+    string_table: &'st StringTableBuilder<S>,
+    components: Vec<StringId>,
+}
+
+impl<'st, S: SerializationSink> EventIdBuilder<'st, S> {
+    pub fn new(string_table: &'st StringTableBuilder<S>) -> EventIdBuilder<'st, S> {
+        EventIdBuilder {
+            string_table,
+            components: Vec::new(),
+        }
+    }
+
+    /// Appends a component that has already been allocated. Allocating
+    /// recurring components, like the name of a query, once and appending
+    /// them via this method is what makes composite event ids compact.
+    pub fn push_id(&mut self, id: StringId) -> &mut Self {
+        self.components.push(id);
+        self
+    }
+
+    /// Allocates `s` as a separate string and appends it as a component.
+    pub fn push_str(&mut self, s: &str) -> &mut Self {
+        let id = self.string_table.alloc(s);
+        self.push_id(id)
+    }
+
+    /// Allocates the event id for the components appended so far and clears
+    /// the builder, so that it can be used for the next event id.
+    pub fn finish(&mut self) -> StringId {
+        let components: Vec<_> = self
+            .components
+            .drain(..)
+            .map(StringComponent::Ref)
+            .collect();
+
+        self.string_table.alloc(&components[..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{InMemorySink, Profiler, ProfilingData};
+    use std::sync::Arc;
+
+    #[test]
+    fn composite_event_ids() {
+        let event_sink = Arc::new(InMemorySink::new());
+        let string_data_sink = Arc::new(InMemorySink::new());
+        let string_index_sink = Arc::new(InMemorySink::new());
+
+        let (first_id, second_id) = {
+            let profiler = Profiler::with_sinks(
+                event_sink.clone(),
+                string_data_sink.clone(),
+                string_index_sink.clone(),
+            );
+            let event_kind = profiler.alloc_string("Query");
+            let typeck = profiler.alloc_string("typeck");
+            let on = profiler.alloc_string(" on ");
+
+            let mut builder = profiler.event_id_builder();
+            let first_id = builder
+                .push_id(typeck)
+                .push_id(on)
+                .push_str("DefId(5)")
+                .finish();
+            let second_id = builder
+                .push_id(typeck)
+                .push_id(on)
+                .push_str("DefId(6)")
+                .finish();
+
+            profiler.record_instant_event(event_kind, first_id, 0);
+            profiler.record_instant_event(event_kind, second_id, 0);
+
+            (first_id, second_id)
+        };
+
+        let profiling_data = ProfilingData::from_buffers(
+            Arc::try_unwrap(event_sink).unwrap().into_bytes(),
+            Arc::try_unwrap(string_data_sink).unwrap().into_bytes(),
+            Arc::try_unwrap(string_index_sink).unwrap().into_bytes(),
+        )
+        .unwrap();
+
+        let labels: Vec<_> = profiling_data.iter().map(|e| e.label).collect();
+        assert_eq!(labels, ["typeck on DefId(5)", "typeck on DefId(6)"]);
+
+        assert_eq!(
+            profiling_data.resolve_components(first_id).unwrap(),
+            ["typeck", " on ", "DefId(5)"]
+        );
+        assert_eq!(
+            profiling_data.resolve_components(second_id).unwrap(),
+            ["typeck", " on ", "DefId(6)"]
+        );
+    }
+}
@@ -0,0 +1,37 @@
+//! Event kinds given as the discriminant of one of a small, fixed set of
+//! kinds instead of as a `StringId`, see `Profiler::register_event_kinds()`.
+
+use crate::stringtable::StringId;
+
+/// The event kind of an event. The methods of `Profiler` that record events
+/// accept either a `StringId` or the `u16` discriminant of one of the kinds
+/// registered via `Profiler::register_event_kinds()`, e.g.
+/// `QueryKind::Provider as u16`. Recording with a discriminant that wasn't
+/// registered is treated like recording with `StringId::INVALID`.
+///
+/// A discriminant is recorded as the `StringId` its name was allocated with
+/// when it was registered, so the `.events` file is the same either way and
+/// `ProfilingData` yields the registered name as `Event::event_kind`. This
+/// doesn't make `RawEvent` any smaller, its fields are 8-byte aligned and a
+/// `u16` kind would leave it at 32 bytes. What discriminants save is
+/// allocating the strings of the kinds up front and keeping their
+/// `StringId`s around.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    Id(StringId),
+    Discriminant(u16),
+}
+
+impl From<StringId> for EventKind {
+    #[inline]
+    fn from(id: StringId) -> EventKind {
+        EventKind::Id(id)
+    }
+}
+
+impl From<u16> for EventKind {
+    #[inline]
+    fn from(discriminant: u16) -> EventKind {
+        EventKind::Discriminant(discriminant)
+    }
+}
@@ -1,36 +1,100 @@
 //! All binary files generated by measureme have a simple file header that
-//! consists of a 4 byte file magic string and a 4 byte little-endian version
-//! number.
+//! consists of a 4 byte file magic string, a 1 byte file format version and
+//! the major, minor and patch version of the `measureme` crate that wrote the
+//! file, 1 byte each. Files of format version `0` and `1` have zeros instead
+//! of the crate version, their 4 bytes after the magic were a little-endian
+//! file format version.
+//!
+//! Writing file headers works without the `std` feature, reading and
+//! checking them needs it.
 
+#[cfg(feature = "std")]
 use crate::serialization::SerializationSink;
-use byteorder::{ByteOrder, LittleEndian};
+#[cfg(feature = "std")]
 use std::error::Error;
+#[cfg(feature = "std")]
+use std::fmt;
+#[cfg(feature = "std")]
+use std::path::{Path, PathBuf};
 
-pub const CURRENT_FILE_FORMAT_VERSION: u32 = 0;
+/// The version of the binary format. Version `1` added the `payload` field to
+/// `RawEvent` and the "has arg" flag to `Timestamp`, and stores events
+/// little-endian instead of in the native byte order. Version `2` added the
+/// version of the writing crate to the file header.
+pub const CURRENT_FILE_FORMAT_VERSION: u32 = 2;
+/// The oldest version of the binary format that can still be read. Event
+/// streams of version `0` are converted to the current layout when read.
+#[cfg(feature = "std")]
+pub const OLDEST_SUPPORTED_FILE_FORMAT_VERSION: u32 = 0;
 pub const FILE_MAGIC_EVENT_STREAM: &[u8; 4] = b"MMES";
+#[cfg(feature = "std")]
 pub const FILE_MAGIC_STRINGTABLE_DATA: &[u8; 4] = b"MMSD";
+#[cfg(feature = "std")]
 pub const FILE_MAGIC_STRINGTABLE_INDEX: &[u8; 4] = b"MMSI";
+/// The magic of data written by a `CompressedSink`. The decompressed data
+/// starts with one of the other file headers.
+#[cfg(feature = "std")]
+pub const FILE_MAGIC_COMPRESSED: &[u8; 4] = b"MMCZ";
+/// The magic of a `.mm_profile` file, see `PackedProfile`.
+#[cfg(feature = "std")]
+pub const FILE_MAGIC_PACKED_PROFILE: &[u8; 4] = b"MMPP";
 
 /// The size of the file header in bytes. Note that functions in this module
 /// rely on this size to be `8`.
 pub const FILE_HEADER_SIZE: usize = 8;
 
-pub fn write_file_header<S: SerializationSink>(s: &S, file_magic: &[u8; 4]) {
+/// The version of this crate as written into file headers. Pre-release and
+/// build suffixes are left out.
+const WRITER_VERSION: [u8; 3] = [
+    version_component(env!("CARGO_PKG_VERSION_MAJOR")),
+    version_component(env!("CARGO_PKG_VERSION_MINOR")),
+    version_component(env!("CARGO_PKG_VERSION_PATCH")),
+];
+
+const fn version_component(s: &str) -> u8 {
+    let bytes = s.as_bytes();
+    let mut value = 0u32;
+    let mut i = 0;
+    while i < bytes.len() {
+        value = value * 10 + (bytes[i] - b'0') as u32;
+        assert!(
+            value <= u8::MAX as u32,
+            "version component doesn't fit into the file header"
+        );
+        i += 1;
+    }
+    value as u8
+}
+
+/// The file header of a file of the current format version written by this
+/// version of `measureme`.
+pub fn file_header(file_magic: &[u8; 4]) -> [u8; FILE_HEADER_SIZE] {
     // The implementation here relies on FILE_HEADER_SIZE to have the value 8.
     // Let's make sure this assumption cannot be violated without being noticed.
     assert_eq!(FILE_HEADER_SIZE, 8);
 
-    s.write_atomic(FILE_HEADER_SIZE, |bytes| {
-        bytes[0..4].copy_from_slice(file_magic);
-        LittleEndian::write_u32(&mut bytes[4..8], CURRENT_FILE_FORMAT_VERSION);
-    });
+    let mut header = [0; FILE_HEADER_SIZE];
+    header[0..4].copy_from_slice(file_magic);
+    header[4] = CURRENT_FILE_FORMAT_VERSION as u8;
+    header[5..8].copy_from_slice(&WRITER_VERSION);
+    header
+}
+
+#[cfg(feature = "std")]
+pub fn write_file_header<S: SerializationSink>(s: &S, file_magic: &[u8; 4]) {
+    s.write_bytes(&file_header(file_magic));
 }
 
+#[cfg(feature = "std")]
 pub fn read_file_header(bytes: &[u8], expected_magic: &[u8; 4]) -> Result<u32, Box<dyn Error>> {
     // The implementation here relies on FILE_HEADER_SIZE to have the value 8.
     // Let's make sure this assumption cannot be violated without being noticed.
     assert_eq!(FILE_HEADER_SIZE, 8);
 
+    if bytes.len() < FILE_HEADER_SIZE {
+        return Err(From::from("File is too short to contain a file header"));
+    }
+
     let actual_magic = &bytes[0..4];
 
     if actual_magic != expected_magic {
@@ -44,9 +108,123 @@ pub fn read_file_header(bytes: &[u8], expected_magic: &[u8; 4]) -> Result<u32, B
         return Err(From::from(msg));
     }
 
-    Ok(LittleEndian::read_u32(&bytes[4..8]))
+    Ok(bytes[4] as u32)
+}
+
+/// The version of the `measureme` crate that wrote the file starting with
+/// `bytes`, e.g. `"0.4.0"`, or `None` if the file is too old to record it.
+/// Only meant for diagnostics, compatibility is decided by the file format
+/// version.
+#[cfg(feature = "std")]
+pub fn read_writer_version(bytes: &[u8]) -> Option<String> {
+    let version = bytes.get(5..FILE_HEADER_SIZE)?;
+    if version == [0, 0, 0] {
+        return None;
+    }
+
+    Some(format!("{}.{}.{}", version[0], version[1], version[2]))
+}
+
+/// The error returned when reading a file whose format version isn't
+/// supported, usually because it was written by a newer version of
+/// `measureme`.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnsupportedVersion {
+    /// The version in the header of the file.
+    pub found: u32,
+    /// The newest version this version of `measureme` can read. Versions
+    /// down to `OLDEST_SUPPORTED_FILE_FORMAT_VERSION` are supported as well.
+    pub supported: u32,
+}
+
+#[cfg(feature = "std")]
+impl fmt::Display for UnsupportedVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "File format version '{}' is not supported by this version of `measureme`, \
+             which reads versions '{}' to '{}'",
+            self.found, OLDEST_SUPPORTED_FILE_FORMAT_VERSION, self.supported
+        )
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for UnsupportedVersion {}
+
+/// The error returned when a file that should be part of a profile doesn't
+/// start with the expected file magic, e.g. because a tool was pointed at a
+/// file that wasn't written by `measureme`.
+#[cfg(feature = "std")]
+#[derive(Clone, PartialEq, Eq)]
+pub struct NotAMeasuremeFile {
+    pub path: PathBuf,
+    pub expected_magic: [u8; 4],
+    /// The first bytes of the file, at most `FILE_HEADER_SIZE` of them.
+    pub found: Vec<u8>,
+}
+
+#[cfg(feature = "std")]
+impl fmt::Display for NotAMeasuremeFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "`{}` is not a measureme file: expected it to start with `{}` but found `{}`",
+            self.path.display(),
+            self.expected_magic.escape_ascii(),
+            self.found.escape_ascii()
+        )
+    }
+}
+
+// Shows the message instead of the fields, so that the error reads well when
+// it is returned from `main()`.
+#[cfg(feature = "std")]
+impl fmt::Debug for NotAMeasuremeFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for NotAMeasuremeFile {}
+
+/// Checks that `data`, read from `path`, starts with `expected_magic`. Data
+/// written by a `CompressedSink` is accepted as well, its contents are checked
+/// when they are decompressed.
+#[cfg(feature = "std")]
+pub fn check_file_magic(
+    path: &Path,
+    data: &[u8],
+    expected_magic: &[u8; 4],
+) -> Result<(), NotAMeasuremeFile> {
+    let magic = data.get(0..4);
+    if magic == Some(&expected_magic[..]) || magic == Some(&FILE_MAGIC_COMPRESSED[..]) {
+        return Ok(());
+    }
+
+    Err(NotAMeasuremeFile {
+        path: path.to_path_buf(),
+        expected_magic: *expected_magic,
+        found: data[..data.len().min(FILE_HEADER_SIZE)].to_vec(),
+    })
 }
 
+/// Checks that files of the format version `version` can be read.
+#[cfg(feature = "std")]
+pub fn check_file_format_version(version: u32) -> Result<(), UnsupportedVersion> {
+    if (OLDEST_SUPPORTED_FILE_FORMAT_VERSION..=CURRENT_FILE_FORMAT_VERSION).contains(&version) {
+        Ok(())
+    } else {
+        Err(UnsupportedVersion {
+            found: version,
+            supported: CURRENT_FILE_FORMAT_VERSION,
+        })
+    }
+}
+
+#[cfg(feature = "std")]
 pub fn strip_file_header(data: &[u8]) -> &[u8] {
     &data[FILE_HEADER_SIZE..]
 }
@@ -54,11 +232,11 @@ pub fn strip_file_header(data: &[u8]) -> &[u8] {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::serialization::ByteVecSink;
+    use crate::serialization::InMemorySink;
 
     #[test]
     fn roundtrip() {
-        let data_sink = ByteVecSink::new();
+        let data_sink = InMemorySink::new();
 
         write_file_header(&data_sink, FILE_MAGIC_EVENT_STREAM);
 
@@ -68,11 +246,15 @@ mod tests {
             read_file_header(&data, FILE_MAGIC_EVENT_STREAM).unwrap(),
             CURRENT_FILE_FORMAT_VERSION
         );
+        assert_eq!(
+            read_writer_version(&data).unwrap(),
+            env!("CARGO_PKG_VERSION")
+        );
     }
 
     #[test]
     fn invalid_magic() {
-        let data_sink = ByteVecSink::new();
+        let data_sink = InMemorySink::new();
         write_file_header(&data_sink, FILE_MAGIC_STRINGTABLE_DATA);
         let mut data = data_sink.into_bytes();
 
@@ -83,7 +265,7 @@ mod tests {
 
     #[test]
     fn other_version() {
-        let data_sink = ByteVecSink::new();
+        let data_sink = InMemorySink::new();
 
         write_file_header(&data_sink, FILE_MAGIC_STRINGTABLE_INDEX);
 
@@ -96,7 +278,46 @@ mod tests {
         data[7] = 0xFF;
         assert_eq!(
             read_file_header(&data, FILE_MAGIC_STRINGTABLE_INDEX).unwrap(),
-            0xFFFF_FFFF
+            0xFF
         );
+        assert_eq!(read_writer_version(&data).unwrap(), "255.255.255");
+    }
+
+    #[test]
+    fn version_1_header() {
+        let data = b"MMES\x01\0\0\0";
+        assert_eq!(read_file_header(data, FILE_MAGIC_EVENT_STREAM).unwrap(), 1);
+        assert_eq!(read_writer_version(data), None);
+    }
+
+    #[test]
+    fn supported_versions() {
+        assert!(check_file_format_version(0).is_ok());
+        assert!(check_file_format_version(CURRENT_FILE_FORMAT_VERSION).is_ok());
+        assert_eq!(
+            check_file_format_version(CURRENT_FILE_FORMAT_VERSION + 1),
+            Err(UnsupportedVersion {
+                found: CURRENT_FILE_FORMAT_VERSION + 1,
+                supported: CURRENT_FILE_FORMAT_VERSION,
+            })
+        );
+    }
+
+    #[test]
+    fn not_a_measureme_file() {
+        let path = Path::new("profile.string_data");
+        assert!(check_file_magic(path, b"MMSD\x01\0\0\0", FILE_MAGIC_STRINGTABLE_DATA).is_ok());
+        assert!(check_file_magic(path, b"MMCZ\x01\0\0\0", FILE_MAGIC_STRINGTABLE_DATA).is_ok());
+
+        let error = check_file_magic(path, b"<html><body>", FILE_MAGIC_EVENT_STREAM).unwrap_err();
+        assert_eq!(error.found, b"<html><b");
+        assert_eq!(
+            error.to_string(),
+            "`profile.string_data` is not a measureme file: \
+             expected it to start with `MMES` but found `<html><b`"
+        );
+
+        let error = check_file_magic(path, b"\xffM", FILE_MAGIC_EVENT_STREAM).unwrap_err();
+        assert!(error.to_string().ends_with("but found `\\xffM`"));
     }
 }
@@ -1,25 +1,146 @@
+use crate::file_header::FILE_HEADER_SIZE;
 use crate::serialization::{Addr, SerializationSink};
-use std::error::Error;
+use std::convert::TryFrom;
 use std::fs;
-use std::io::{BufWriter, Write};
-use std::path::Path;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
 
+/// The number of bytes `FileSerializationSink::from_path()` buffers before
+/// writing to the file.
+const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+
 pub struct FileSerializationSink {
-    data: Mutex<(BufWriter<fs::File>, u32)>,
+    data: Mutex<FileData>,
 }
 
-impl SerializationSink for FileSerializationSink {
-    fn from_path(path: &Path) -> Result<Self, Box<dyn Error>> {
+struct FileData {
+    file: BufWriter<fs::File>,
+    // The number of bytes written to `file`
+    addr: u32,
+    // Set by `FileSerializationSink::with_rotation()`
+    rotation: Option<Rotation>,
+}
+
+struct Rotation {
+    max_file_size: u32,
+    // Returns the path of the file of the given segment
+    segment_path: Box<dyn Fn(usize) -> PathBuf + Send + Sync>,
+    segment: usize,
+    // The first `FILE_HEADER_SIZE` bytes written, repeated at the start of
+    // every segment
+    header: Vec<u8>,
+}
+
+impl FileSerializationSink {
+    /// Like `from_path()`, but buffers up to `buffer_size` bytes before
+    /// writing to the file. A smaller buffer loses less data if the process
+    /// crashes, a larger one makes fewer system calls.
+    pub fn with_buffer_size(path: &Path, buffer_size: usize) -> io::Result<Self> {
         fs::create_dir_all(path.parent().unwrap())?;
 
         let file = fs::File::create(path)?;
 
         Ok(FileSerializationSink {
-            data: Mutex::new((BufWriter::new(file), 0)),
+            data: Mutex::new(FileData {
+                file: BufWriter::with_capacity(buffer_size, file),
+                addr: 0,
+                rotation: None,
+            }),
         })
     }
 
+    /// Like `from_path()`, but once writing would make the file larger than
+    /// `max_file_size` bytes, continues in a new file instead, the path of
+    /// which `segment_path` returns for segments `1`, `2`, and so on. Each
+    /// segment starts with the file header written at the start of the
+    /// first file, and a single write never gets split between segments, so
+    /// a segment only becomes larger than `max_file_size` if a single write
+    /// is. Addresses start over at the start of each segment.
+    pub fn with_rotation(
+        path: &Path,
+        max_file_size: u32,
+        segment_path: impl Fn(usize) -> PathBuf + Send + Sync + 'static,
+    ) -> io::Result<Self> {
+        let sink = FileSerializationSink::from_path(path)?;
+        sink.data.lock().unwrap().rotation = Some(Rotation {
+            max_file_size,
+            segment_path: Box::new(segment_path),
+            segment: 0,
+            header: Vec::with_capacity(FILE_HEADER_SIZE),
+        });
+
+        Ok(sink)
+    }
+
+    /// Opens the existing file at `path` and writes after its current end.
+    /// Addresses continue from the size of the file, so they stay absolute
+    /// positions in it.
+    pub fn open_append(path: &Path) -> io::Result<Self> {
+        let file = fs::OpenOptions::new().append(true).open(path)?;
+        let len = file.metadata()?.len();
+        let addr = u32::try_from(len).map_err(|_| io::Error::other("file is too large"))?;
+
+        Ok(FileSerializationSink {
+            data: Mutex::new(FileData {
+                file: BufWriter::with_capacity(DEFAULT_BUFFER_SIZE, file),
+                addr,
+                rotation: None,
+            }),
+        })
+    }
+}
+
+impl FileData {
+    // Continues in the next segment if writing `num_bytes` would make the
+    // current one too large
+    fn rotate_if_needed(&mut self, num_bytes: usize) -> io::Result<()> {
+        let rotation = match &mut self.rotation {
+            Some(rotation) => rotation,
+            None => return Ok(()),
+        };
+
+        let header_len = rotation.header.len();
+        let new_len = self.addr as u64 + num_bytes as u64;
+        if new_len <= rotation.max_file_size as u64 || self.addr as usize <= header_len {
+            return Ok(());
+        }
+
+        self.file.flush()?;
+        rotation.segment += 1;
+        let file = fs::File::create((rotation.segment_path)(rotation.segment))?;
+        self.file = BufWriter::with_capacity(DEFAULT_BUFFER_SIZE, file);
+        self.file.write_all(&rotation.header)?;
+        self.addr = header_len as u32;
+
+        Ok(())
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Addr {
+        self.rotate_if_needed(bytes.len())
+            .expect("failed to start a new segment");
+
+        if let Some(rotation) = &mut self.rotation {
+            let missing = FILE_HEADER_SIZE - rotation.header.len();
+            if missing > 0 {
+                let len = missing.min(bytes.len());
+                rotation.header.extend_from_slice(&bytes[..len]);
+            }
+        }
+
+        let curr_addr = self.addr;
+        self.file.write_all(bytes).expect("failed to write buffer");
+        self.addr += bytes.len() as u32;
+
+        Addr(curr_addr)
+    }
+}
+
+impl SerializationSink for FileSerializationSink {
+    fn from_path(path: &Path) -> io::Result<Self> {
+        FileSerializationSink::with_buffer_size(path, DEFAULT_BUFFER_SIZE)
+    }
+
     #[inline]
     fn write_atomic<W>(&self, num_bytes: usize, write: W) -> Addr
     where
@@ -28,14 +149,17 @@ impl SerializationSink for FileSerializationSink {
         let mut buffer = vec![0; num_bytes];
         write(buffer.as_mut_slice());
 
-        let mut data = self.data.lock().expect("couldn't acquire lock");
-        let curr_addr = data.1;
-        let file = &mut data.0;
-
-        file.write_all(&buffer).expect("failed to write buffer");
+        self.write_bytes(&buffer)
+    }
 
-        data.1 += num_bytes as u32;
+    #[inline]
+    fn write_bytes(&self, bytes: &[u8]) -> Addr {
+        let mut data = self.data.lock().expect("couldn't acquire lock");
+        data.write(bytes)
+    }
 
-        Addr(curr_addr)
+    fn flush(&self) -> io::Result<()> {
+        let mut data = self.data.lock().expect("couldn't acquire lock");
+        data.file.flush()
     }
 }
@@ -9,6 +9,10 @@
 //!   2. A `.string_data` file which contains all the strings referenced by events.
 //!   3. A `.string_index` file which maps `StringId` values to offsets into the `.string_data` file.
 //!
+//! Alternatively, [`Profiler::new_packed()`] writes all three into a single `.mm_profile` file, which is easier to handle
+//! as one artifact. Its layout is described by [`PackedProfile`], and [`ProfilingData::from_packed()`] reads it. A packed
+//! profile is kept in memory until the [`Profiler`] is finished or dropped, so nothing is written if the process crashes.
+//!
 //! # Writing event trace files
 //!
 //! The main entry point for writing event trace files is the [`Profiler`] struct.
@@ -21,14 +25,146 @@
 //!   - `thread_id`: a `u64` id of the thread which is recording this event
 //!   - `timestamp_kind`: a [`TimestampKind`] which specifies how this event should be treated by `measureme` tooling
 //!
+//! [`Profiler::start_recording_interval_event_with_arg()`] additionally stores an integer argument in the
+//! "start" event, which is available as `Event::arg` when reading the trace files.
+//! [`Profiler::start_recording_interval_event_with_category()`] stores a category instead, available as
+//! `Event::category`, which exporters use to group events in trace viewers.
+//! [`Profiler::start_recording_interval_event_lazy()`] takes a closure that builds the event's name, which is
+//! only called if the profiler actually records something.
+//! [`Profiler::record_args_event()`] attaches key/value pairs of [`ArgValue`]s to the event recorded last on a thread,
+//! available as `Event::args`, which `crox` exports as the `args` of the event. They take a separate record in the
+//! event stream, so they are meant for rare events.
+//!
+//! Callers that don't manage their own thread numbering can use [`Profiler::record_event_for_current_thread()`]
+//! and [`Profiler::start_recording_interval_event_for_current_thread()`] instead, which derive the `thread_id`
+//! from the calling thread. The derived id is stable within a process, but not across runs.
+//!
+//! When profiling is disabled, [`Profiler::null()`] returns a [`Profiler`] that records nothing, backed by a
+//! [`NullSink`]. Recording events with it costs next to nothing, so call sites don't need to check whether profiling is
+//! enabled.
+//!
+//! By default, the timestamps of events are measured relative to the creation of the [`Profiler`]. A different source of
+//! timestamps, e.g. a fake clock in tests, can be supplied via [`Profiler::with_clock()`] and the [`Clock`] trait.
+//!
+//! [`Profiler::start_wall_time()`] is the wall clock time that corresponds to a timestamp of zero. It is recorded in the
+//! profile as [`ProfileMetadata::start_time`], from which [`ProfileMetadata::wall_time()`] computes the wall clock time of
+//! each event, e.g. to line events up with those of other tools.
+//!
+//! The profile also records the process id and the command line of the profiled process. To leave out or redact the
+//! arguments, or to add custom fields, create the [`Profiler`] via [`Profiler::new_with_metadata()`] and a
+//! [`ProfileMetadataBuilder`]. The custom fields are returned by [`ProfileMetadata::extra()`].
+//!
+//! Events that mark a single point in time, without a corresponding "end" event, can be recorded via
+//! the [`Profiler::record_instant_event()`] method.
+//!
+//! Counters, such as the number of bytes allocated so far, can be recorded via the
+//! [`Profiler::record_counter()`] method. The value of a counter event is available as `Event::value`
+//! when reading the trace files.
+//!
+//! Threads can be given a human readable name via the [`Profiler::set_thread_name()`] method, which
+//! tools can look up via [`ProfilingData::thread_name()`].
+//!
 //! Alternatively, events can also be recorded via the [`Profiler::start_recording_interval_event()`] method. This
 //! method records a "start" event and returns a `TimingGuard` object that will automatically record
-//! the corresponding "end" event when it is dropped.
+//! the corresponding "end" event when it is dropped. [`TimingGuard::finish()`] records the "end" event right away, and
+//! [`TimingGuard::cancel()`] discards the guard without recording one, e.g. for an aborted operation.
+//!
+//! A `TimingGuard` belongs to the thread it was created on, which doesn't fit async tasks that move between threads
+//! at `.await` points. [`Profiler::start_task_event()`] records the start of an interval for a task id instead, and
+//! [`Profiler::end_task_event()`] its end, on whatever thread the task runs on. Task events are stored with
+//! [`TASK_ID_FLAG`] set in their thread id, so tools show each task like a thread of its own, and
+//! [`Event::task_id()`] tells tasks and threads apart.
+//!
+//! Intervals that were measured elsewhere, e.g. spans imported from another tracing system, can be recorded with their
+//! timestamps as they are via the [`Profiler::record_interval()`] method. [`Profiler::record_event_at()`] does the same
+//! for single events, e.g. to record the processes of a distributed build against a clock they share, so that their
+//! merged profiles line up.
+//!
+//! [`Profiler::record_thread_start()`] and [`Profiler::record_thread_stop()`] record when a thread starts and stops,
+//! e.g. the workers of a thread pool, as instant events of the kinds [`THREAD_START_EVENT_KIND`] and
+//! [`THREAD_STOP_EVENT_KIND`]. Starting a thread also sets its name.
+//!
+//! To react to events while they are being recorded, e.g. for a live dashboard, [`Profiler::set_observer()`] installs a
+//! callback that is called with each recorded event as a [`RawEvent`]. It runs on the recording thread, so it must be
+//! fast. Without an observer, recording only costs an extra check.
+//!
+//! Instrumenting a tight loop can make recording the intervals dominate the runtime. [`Profiler::set_throttle()`]
+//! bounds this per event kind with a [`Throttle`]: `Throttle::OneIn(n)` records only every `n`th interval of the kind,
+//! and `Throttle::MinDuration` only the intervals that last at least as long as the given duration. The tools then
+//! only see the recorded intervals, so the totals of a throttled kind are understated, see [`Throttle`].
+//!
+//! Trace files are written through buffered sinks, and strings are additionally collected in batches before being
+//! written. Call [`Profiler::flush()`] to make sure that the events and strings recorded so far end up in the files,
+//! e.g. before doing something that might crash the process. [`Profiler::with_buffer_size()`] controls how much data
+//! is buffered. The reader ignores a partially written event at the end of the `.events` file, as well
+//! as events that refer to strings that were never written, and reports this via [`ProfilingData::is_truncated()`]. When
+//! a [`Profiler`] is dropped, it writes a footer record after all events, which [`ProfilingData::is_complete()`] checks
+//! for. Profiles without the footer, e.g. of crashed processes, can be read all the same. Dropping a [`Profiler`] also
+//! writes all outstanding data, but can't report errors. Call [`Profiler::finish()`] instead to find out whether the
+//! profile was written completely.
+//!
+//! To keep recording threads from contending with each other, the [`Profiler`] collects the events of each thread in a
+//! thread-local buffer and writes them to the `.events` file in batches. The events recorded by one thread stay in order,
+//! but the events of different threads are interleaved in batches, so readers must not assume that the whole file is
+//! ordered by time. Events still buffered when the process crashes are lost unless [`Profiler::flush()`] was called.
+//!
+//! Applications that are instrumented with the `tracing` crate can record their spans and events via a
+//! [`MeasuremeLayer`] (behind the `tracing` feature), which is a `tracing_subscriber::Layer`. Spans become interval
+//! events named after the span, with their fields as event id, and events become instant events.
+//!
+//! Similarly, a [`MeasuremeLogger`] (behind the `log` feature) records the records of the `log` crate as instant events,
+//! with the level as event kind and the message as event id, so that log lines show up on the same timeline as the
+//! events around them. [`MeasuremeLogger::init()`] installs it as the global logger.
+//!
+//! To see memory usage on the same timeline, a [`MeasuremeAllocator`] (behind the `allocator` feature) can wrap the
+//! global allocator. It counts the bytes allocated and freed, and [`MeasuremeAllocator::start_recording()`] records the
+//! totals as counters at a fixed interval.
+//!
+//! On machines with many cores, [`Profiler::new_sharded()`] spreads the events over several `.events` files, one per
+//! shard, with each thread writing to a single shard. [`ProfilingData::new()`] finds the shards and merges them.
+//!
+//! For long-running programs, [`Profiler::with_rotation()`] limits the size of the `.events` file by continuing in
+//! `.1.events`, `.2.events`, and so on. The segments share one string table, and [`ProfilingData::new()`] reads them
+//! in order.
+//!
+//! [`Profiler::open_append()`] resumes an existing profile, e.g. one written by an earlier run of the same program.
+//! New strings get ids after the existing ones, and the timestamps of new events continue after the last recorded one.
+//!
+//! For always-on profiling, [`Profiler::new_ring_buffer()`] keeps only the most recent events in memory, in a
+//! [`RingBufferSink`], and [`Profiler::dump_snapshot()`] writes them out as a profile on demand, e.g. when a server
+//! notices an anomaly. The string table is kept in full, so that the snapshot can resolve the strings of all events.
+//!
+//! To keep the recording threads from waiting for disk I/O, [`Profiler::new_background()`] wraps the file sinks in
+//! [`BackgroundSink`]s, which write from a dedicated thread. The data is passed to it through a bounded queue, so
+//! recording blocks when the disk can't keep up instead of buffering without limit.
+//!
+//! Any sink can be wrapped in a [`CompressedSink`] (behind the `compression` feature) to zstd-compress
+//! the data as it is written. [`ProfilingData`] detects compressed files and decompresses them transparently.
+//! [`Profiler::new_with_compressed_strings()`] only compresses the string data, which usually compresses far better
+//! than the events, so that the events stay uncompressed.
 //!
 //! To create a [`StringId`], call one of the string allocation methods:
 //!   - [`Profiler::alloc_string()`]: allocates a string and returns the [`StringId`] that refers to it
 //!   - [`Profiler::alloc_string_with_reserved_id()`]: allocates a string using the specified [`StringId`].
 //!     It is up to the caller to make sure the specified [`StringId`] hasn't already been used.
+//!   - [`Profiler::alloc_static_string()`]: allocates a string literal, returning the same [`StringId`] when it is
+//!     allocated again without copying or hashing the string
+//!
+//! [`StringId::INVALID`] never refers to a string. It can stand in for ids whose string hasn't been allocated yet:
+//! recording an event with it trips a debug assertion, and [`ProfilingData`] reads it back as `<unknown>`.
+//!
+//! A block of consecutive ids for use with [`Profiler::alloc_string_with_reserved_id()`], e.g. one per variant of an enum,
+//! can be obtained from [`Profiler::reserve_string_ids()`]. [`StringId::from_reserved_index()`] then maps each variant
+//! to its id without a lookup.
+//!
+//! Programs that have a fixed set of event kinds can register it once via [`Profiler::register_event_kinds()`] and then
+//! pass the `u16` discriminant of a kind wherever an event kind is expected, instead of keeping the [`StringId`] of each
+//! kind around. Both are accepted as an [`EventKind`].
+//!
+//! Event ids that are made up of several parts, like the name of a query and its key, can be built from separately
+//! allocated components via an [`EventIdBuilder`] returned by [`Profiler::event_id_builder()`]. Components that many
+//! event ids have in common are then only stored once. [`ProfilingData::resolve_components()`] returns the components
+//! of such an event id.
 //!
 //! # Reading event trace files
 //!
@@ -36,48 +172,332 @@
 //!
 //! To create a [`ProfilingData`], call the [`ProfilingData::new()`] function and provide a `Path` with the directory and file name for the trace files.
 //!
-//! To retrieve an `Iterator` of all of the events in the file, call the [`ProfilingData::iter()`] method.
+//! [`ProfilingData::from_readers()`] reads the three streams from any `Read` implementation instead, e.g. from an archive.
+//!
+//! Profiles written by older versions of `measureme` can be read as long as their file format version is still
+//! supported, in which case they are converted to the current format while reading. Profiles written by newer versions
+//! of `measureme` are rejected with an [`UnsupportedVersion`] error. The file header also records the version of
+//! `measureme` that wrote the file, which [`ProfilingData::writer_version()`] returns, e.g. to include it in bug reports.
+//!
+//! If one of the files doesn't start with the expected file magic, e.g. because the path stem points at the wrong file,
+//! a [`NotAMeasuremeFile`] error is returned. If only the `.events` file is left, [`ProfilingData::new()`] still reads
+//! the events, but shows their raw string ids instead of their strings, see [`ProfilingData::without_strings()`].
+//!
+//! Tools that parse the `.events` file themselves, e.g. in another language, can rely on the layout documented for
+//! [`RawEvent`] and [`Timestamp`]: after the file header, the file consists of little-endian [`RAW_EVENT_SIZE`] byte
+//! records, so profiles can be read on machines of either byte order and word size. [`RawEvent::serialize()`] and
+//! [`RawEvent::deserialize()`] convert between events and these records.
+//!
+//! Records that today's readers don't know, e.g. written by a newer version of `measureme` that added a kind of record,
+//! are framed as tagged records with a type tag and a length, as documented for [`RawEvent`]. [`ProfilingData`] skips
+//! them and still reads the events around them. [`ProfilerEventIterator::skipped_records()`] tells how many records
+//! were skipped, e.g. to warn that a profile may contain data that only a newer version can show.
+//!
+//! For very large trace files, [`ProfilingData::from_mmap()`] memory-maps the files instead of reading them into memory.
+//! [`ProfilingData::approx_event_count()`] and [`ProfilingData::file_sizes()`] tell how large a profile is without
+//! iterating its events, and [`ProfilingData::string_table_stats()`] how many strings its string table holds and how
+//! large they are, e.g. to tell whether deduplicating or compressing the strings is worth it.
+//!
+//! To retrieve an `Iterator` of all of the events in the file, call the [`ProfilingData::iter()`] method. The yielded
+//! [`Event`]s carry their event kind and event id as strings, `Event::event_kind` and `Event::label`, which are resolved
+//! while iterating, along with the string ids they were resolved from, `Event::event_kind_id` and `Event::label_id`.
 //!
 //! To retrieve an `Iterator` of only matching start/stop events, call the [`ProfilingData::iter_matching_events()`] method.
 //!
+//! To look up the string of a [`StringId`], call the [`ProfilingData::resolve_string()`] method. The strings that `measureme`
+//! records about the profiling session itself are available via [`ProfilingData::metadata_strings()`], and
+//! [`ProfilingData::metadata()`] parses the start time, process id, and command line of the profiled process.
+//!
+//! Several profiles, e.g. of the processes of a distributed build, can be combined via [`ProfilingData::merge()`].
+//!
+//! The ids of strings depend on the order in which the threads of the profiled program allocated them, so two runs of
+//! the same workload end up with different string tables. [`CanonicalProfile::new()`] sorts the strings by their
+//! contents and gives them new ids in that order, rewriting the events to match, so that the string tables of both runs
+//! are byte-identical, e.g. for verifying reproducible builds.
+//!
+//! To retrieve an `Iterator` of only the events within a time window, call the [`ProfilingData::iter_in_range()`] method.
+//! If the window is marked by instant events, e.g. ones labeled `"phase_start"` and `"phase_end"`,
+//! [`ProfilingData::slice_between()`] finds the markers by their labels instead of taking timestamps.
+//!
+//! For snapshot tests of instrumented code, [`ProfilingData::to_debug_text()`] lists the events as text, grouped by
+//! thread and with all strings resolved, optionally with timestamps relative to the first event.
+//!
+//! [`ProfilingData::iter_intervals()`] pairs up the start and end events of intervals, taking nesting into account, and
+//! yields each interval as a [`MatchedEvent`]. Start and end events that can't be paired are reported separately.
+//!
+//! To retrieve an `Iterator` of only the events of certain kinds, call the [`ProfilingData::iter_matching()`] or
+//! [`ProfilingData::iter_matching_kind()`] methods. [`ProfilingData::iter_in_range_matching()`] combines both filters.
+//! [`ProfilingData::iter_thread()`] only yields the events of a single thread, and [`ProfilingData::thread_ids()`] lists
+//! the threads that recorded events. [`ProfilingData::group_by_thread()`] sorts all events by thread in a single pass.
+//! Events are only ordered by time within each thread; [`ProfilingData::iter_sorted()`] yields them ordered by time
+//! across all threads, buffering profiles that are too large to sort in memory in temporary files.
+//!
+//! If the clock of the profiled process wasn't monotonic, e.g. because `Instant` went backwards when a thread moved to
+//! another core, durations computed from the profile are wrong. [`ProfilingData::validate()`] reports intervals that end
+//! before they start, overlapping intervals, and events that are out of order on their thread.
+//! [`ProfilingData::unresolvable_events()`] counts the events whose strings can't be resolved, e.g. because the string
+//! table was truncated.
+//!
+//! With the `serde` feature, the types yielded while reading a profile, like [`Event`], [`MatchedEvent`], and
+//! [`ProfileMetadata`], implement `serde::Serialize`, and all of them except those containing an [`Event`] also
+//! implement `serde::Deserialize`. A [`StringId`] is serialized as a plain integer.
+//!
+//! # `no_std`
+//!
+//! Everything above needs the `std` feature, which is enabled by default. Without it, the crate is `no_std` and only
+//! needs `alloc`, so that events can be recorded on targets without an operating system, e.g. by an embedded scheduler,
+//! and analyzed elsewhere. What's left is the encoding of events, [`RawEvent`] and [`Timestamp`], the
+//! [`SerializationSink`] trait without the methods that deal with files, [`NullSink`], and [`RingBufferSink`], which
+//! keeps the most recent events in a fixed amount of memory. [`RingBufferSink::to_event_stream()`] returns them as the
+//! contents of an `.events` file, which [`ProfilingData::without_strings()`] reads.
+//!
+//! [`ArgValue`]: enum.ArgValue.html
+//! [`BackgroundSink`]: struct.BackgroundSink.html
+//! [`CanonicalProfile::new()`]: struct.CanonicalProfile.html#method.new
+//! [`Clock`]: trait.Clock.html
+//! [`CompressedSink`]: struct.CompressedSink.html
+//! [`Event`]: struct.Event.html
+//! [`Event::task_id()`]: struct.Event.html#method.task_id
+//! [`EventIdBuilder`]: struct.EventIdBuilder.html
+//! [`EventKind`]: enum.EventKind.html
+//! [`MeasuremeAllocator`]: struct.MeasuremeAllocator.html
+//! [`MeasuremeAllocator::start_recording()`]: struct.MeasuremeAllocator.html#method.start_recording
+//! [`MeasuremeLayer`]: struct.MeasuremeLayer.html
+//! [`MeasuremeLogger`]: struct.MeasuremeLogger.html
+//! [`MeasuremeLogger::init()`]: struct.MeasuremeLogger.html#method.init
+//! [`NotAMeasuremeFile`]: struct.NotAMeasuremeFile.html
+//! [`NullSink`]: struct.NullSink.html
+//! [`PackedProfile`]: struct.PackedProfile.html
 //! [`Profiler`]: struct.Profiler.html
 //! [`Profiler::alloc_string()`]: struct.Profiler.html#method.alloc_string
 //! [`Profiler::alloc_string_with_reserved_id()`]: struct.Profiler.html#method.alloc_string_with_reserved_id
+//! [`Profiler::alloc_static_string()`]: struct.Profiler.html#method.alloc_static_string
+//! [`Profiler::dump_snapshot()`]: struct.Profiler.html#method.dump_snapshot
+//! [`Profiler::end_task_event()`]: struct.Profiler.html#method.end_task_event
+//! [`Profiler::event_id_builder()`]: struct.Profiler.html#method.event_id_builder
+//! [`Profiler::finish()`]: struct.Profiler.html#method.finish
+//! [`Profiler::flush()`]: struct.Profiler.html#method.flush
 //! [`Profiler::new()`]: struct.Profiler.html#method.new
+//! [`Profiler::new_background()`]: struct.Profiler.html#method.new_background
+//! [`Profiler::new_packed()`]: struct.Profiler.html#method.new_packed
+//! [`Profiler::new_ring_buffer()`]: struct.Profiler.html#method.new_ring_buffer
+//! [`Profiler::new_sharded()`]: struct.Profiler.html#method.new_sharded
+//! [`Profiler::new_with_compressed_strings()`]: struct.Profiler.html#method.new_with_compressed_strings
+//! [`Profiler::new_with_metadata()`]: struct.Profiler.html#method.new_with_metadata
+//! [`Profiler::null()`]: struct.Profiler.html#method.null
+//! [`Profiler::open_append()`]: struct.Profiler.html#method.open_append
+//! [`Profiler::record_args_event()`]: struct.Profiler.html#method.record_args_event
+//! [`Profiler::record_counter()`]: struct.Profiler.html#method.record_counter
 //! [`Profiler::record_event()`]: struct.Profiler.html#method.record_event
+//! [`Profiler::record_event_at()`]: struct.Profiler.html#method.record_event_at
+//! [`Profiler::record_event_for_current_thread()`]: struct.Profiler.html#method.record_event_for_current_thread
+//! [`Profiler::record_instant_event()`]: struct.Profiler.html#method.record_instant_event
+//! [`Profiler::record_interval()`]: struct.Profiler.html#method.record_interval
+//! [`Profiler::record_thread_start()`]: struct.Profiler.html#method.record_thread_start
+//! [`Profiler::record_thread_stop()`]: struct.Profiler.html#method.record_thread_stop
+//! [`Profiler::register_event_kinds()`]: struct.Profiler.html#method.register_event_kinds
+//! [`Profiler::reserve_string_ids()`]: struct.Profiler.html#method.reserve_string_ids
+//! [`Profiler::set_observer()`]: struct.Profiler.html#method.set_observer
+//! [`Profiler::set_thread_name()`]: struct.Profiler.html#method.set_thread_name
+//! [`Profiler::set_throttle()`]: struct.Profiler.html#method.set_throttle
+//! [`Profiler::start_wall_time()`]: struct.Profiler.html#method.start_wall_time
 //! [`Profiler::start_recording_interval_event()`]: struct.Profiler.html#method.start_recording_interval_event
+//! [`Profiler::start_recording_interval_event_lazy()`]: struct.Profiler.html#method.start_recording_interval_event_lazy
+//! [`Profiler::start_recording_interval_event_with_arg()`]: struct.Profiler.html#method.start_recording_interval_event_with_arg
+//! [`Profiler::start_recording_interval_event_with_category()`]: struct.Profiler.html#method.start_recording_interval_event_with_category
+//! [`Profiler::start_recording_interval_event_for_current_thread()`]: struct.Profiler.html#method.start_recording_interval_event_for_current_thread
+//! [`Profiler::start_task_event()`]: struct.Profiler.html#method.start_task_event
+//! [`Profiler::with_clock()`]: struct.Profiler.html#method.with_clock
+//! [`Profiler::with_buffer_size()`]: struct.Profiler.html#method.with_buffer_size
+//! [`Profiler::with_rotation()`]: struct.Profiler.html#method.with_rotation
+//! [`MatchedEvent`]: struct.MatchedEvent.html
+//! [`ProfilerEventIterator::skipped_records()`]: struct.ProfilerEventIterator.html#method.skipped_records
+//! [`ProfileMetadata`]: struct.ProfileMetadata.html
+//! [`ProfileMetadata::extra()`]: struct.ProfileMetadata.html#method.extra
+//! [`ProfileMetadata::start_time`]: struct.ProfileMetadata.html#structfield.start_time
+//! [`ProfileMetadata::wall_time()`]: struct.ProfileMetadata.html#method.wall_time
+//! [`ProfileMetadataBuilder`]: struct.ProfileMetadataBuilder.html
 //! [`ProfilingData`]: struct.ProfilingData.html
+//! [`ProfilingData::approx_event_count()`]: struct.ProfilingData.html#method.approx_event_count
+//! [`ProfilingData::file_sizes()`]: struct.ProfilingData.html#method.file_sizes
+//! [`ProfilingData::from_mmap()`]: struct.ProfilingData.html#method.from_mmap
+//! [`ProfilingData::from_packed()`]: struct.ProfilingData.html#method.from_packed
+//! [`ProfilingData::from_readers()`]: struct.ProfilingData.html#method.from_readers
+//! [`ProfilingData::group_by_thread()`]: struct.ProfilingData.html#method.group_by_thread
+//! [`ProfilingData::is_complete()`]: struct.ProfilingData.html#method.is_complete
+//! [`ProfilingData::is_truncated()`]: struct.ProfilingData.html#method.is_truncated
+//! [`ProfilingData::merge()`]: struct.ProfilingData.html#method.merge
+//! [`ProfilingData::new()`]: struct.ProfilingData.html#method.new
 //! [`ProfilingData::iter()`]: struct.ProfilingData.html#method.iter
+//! [`ProfilingData::iter_intervals()`]: struct.ProfilingData.html#method.iter_intervals
+//! [`ProfilingData::iter_in_range()`]: struct.ProfilingData.html#method.iter_in_range
+//! [`ProfilingData::iter_in_range_matching()`]: struct.ProfilingData.html#method.iter_in_range_matching
+//! [`ProfilingData::iter_matching()`]: struct.ProfilingData.html#method.iter_matching
 //! [`ProfilingData::iter_matching_events()`]: struct.ProfilingData.html#method.iter_matching_events
+//! [`ProfilingData::iter_matching_kind()`]: struct.ProfilingData.html#method.iter_matching_kind
+//! [`ProfilingData::iter_sorted()`]: struct.ProfilingData.html#method.iter_sorted
+//! [`ProfilingData::iter_thread()`]: struct.ProfilingData.html#method.iter_thread
+//! [`ProfilingData::metadata()`]: struct.ProfilingData.html#method.metadata
+//! [`ProfilingData::metadata_strings()`]: struct.ProfilingData.html#method.metadata_strings
+//! [`ProfilingData::resolve_components()`]: struct.ProfilingData.html#method.resolve_components
+//! [`ProfilingData::resolve_string()`]: struct.ProfilingData.html#method.resolve_string
+//! [`ProfilingData::slice_between()`]: struct.ProfilingData.html#method.slice_between
+//! [`ProfilingData::string_table_stats()`]: struct.ProfilingData.html#method.string_table_stats
+//! [`ProfilingData::thread_ids()`]: struct.ProfilingData.html#method.thread_ids
+//! [`ProfilingData::thread_name()`]: struct.ProfilingData.html#method.thread_name
+//! [`ProfilingData::to_debug_text()`]: struct.ProfilingData.html#method.to_debug_text
+//! [`ProfilingData::unresolvable_events()`]: struct.ProfilingData.html#method.unresolvable_events
+//! [`ProfilingData::validate()`]: struct.ProfilingData.html#method.validate
+//! [`ProfilingData::without_strings()`]: struct.ProfilingData.html#method.without_strings
+//! [`ProfilingData::writer_version()`]: struct.ProfilingData.html#method.writer_version
+//! [`RAW_EVENT_SIZE`]: constant.RAW_EVENT_SIZE.html
+//! [`RawEvent`]: struct.RawEvent.html
+//! [`RawEvent::deserialize()`]: struct.RawEvent.html#method.deserialize
+//! [`RawEvent::serialize()`]: struct.RawEvent.html#method.serialize
+//! [`RingBufferSink`]: struct.RingBufferSink.html
+//! [`RingBufferSink::to_event_stream()`]: struct.RingBufferSink.html#method.to_event_stream
+//! [`SerializationSink`]: trait.SerializationSink.html
 //! [`StringId`]: struct.StringId.html
+//! [`StringId::INVALID`]: struct.StringId.html#associatedconstant.INVALID
+//! [`StringId::from_reserved_index()`]: struct.StringId.html#method.from_reserved_index
+//! [`TASK_ID_FLAG`]: constant.TASK_ID_FLAG.html
+//! [`THREAD_START_EVENT_KIND`]: constant.THREAD_START_EVENT_KIND.html
+//! [`THREAD_STOP_EVENT_KIND`]: constant.THREAD_STOP_EVENT_KIND.html
+//! [`Throttle`]: enum.Throttle.html
+//! [`Timestamp`]: struct.Timestamp.html
 //! [`TimestampKind`]: enum.TimestampKind.html
+//! [`TimingGuard::cancel()`]: struct.TimingGuard.html#method.cancel
+//! [`TimingGuard::finish()`]: struct.TimingGuard.html#method.finish
+//! [`UnsupportedVersion`]: struct.UnsupportedVersion.html
 
 #![deny(warnings)]
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
 
+#[cfg(feature = "allocator")]
+mod allocator;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+mod background_sink;
+#[cfg(feature = "std")]
+mod byte_buffer;
+#[cfg(feature = "std")]
+mod canonical_profile;
+#[cfg(feature = "std")]
+mod clock;
+#[cfg(feature = "compression")]
+mod compressed_sink;
+#[cfg(feature = "std")]
 mod event;
+#[cfg(feature = "std")]
+mod event_args;
+#[cfg(feature = "std")]
+mod event_id;
+#[cfg(feature = "std")]
+mod event_kind;
 mod file_header;
-#[cfg(any(not(target_arch = "wasm32"), target_os = "wasi"))]
+#[cfg(all(feature = "std", any(not(target_arch = "wasm32"), target_os = "wasi")))]
 mod file_serialization_sink;
-#[cfg(not(target_arch = "wasm32"))]
+#[cfg(feature = "log")]
+mod logger;
+#[cfg(feature = "std")]
+mod merged_profiling_data;
+#[cfg(feature = "std")]
+mod metadata;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
 mod mmap_serialization_sink;
+#[cfg(feature = "std")]
+mod packed_profile;
+#[cfg(feature = "std")]
 mod profiler;
+#[cfg(feature = "std")]
 mod profiling_data;
 mod raw_event;
+mod ring_buffer_sink;
 mod serialization;
+mod string_id;
+#[cfg(feature = "std")]
 mod stringtable;
+#[cfg(feature = "std")]
+mod throttle;
+#[cfg(feature = "tracing")]
+mod tracing_layer;
 
 pub mod rustc;
+#[cfg(feature = "std")]
 pub mod testing_common;
 
+#[cfg(feature = "allocator")]
+pub use crate::allocator::{
+    AllocationRecorder, MeasuremeAllocator, ALLOCATED_BYTES_COUNTER,
+    ALLOCATION_RECORDER_THREAD_NAME, FREED_BYTES_COUNTER,
+};
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub use crate::background_sink::{BackgroundSink, DEFAULT_QUEUE_CAPACITY};
+#[cfg(feature = "std")]
+pub use crate::canonical_profile::CanonicalProfile;
+#[cfg(feature = "std")]
+pub use crate::clock::{Clock, InstantClock};
+#[cfg(feature = "compression")]
+pub use crate::compressed_sink::CompressedSink;
+#[cfg(feature = "std")]
 pub use crate::event::Event;
-#[cfg(any(not(target_arch = "wasm32"), target_os = "wasi"))]
+#[cfg(feature = "std")]
+pub use crate::event_args::{ArgValue, EventArgValue};
+#[cfg(feature = "std")]
+pub use crate::event_id::EventIdBuilder;
+#[cfg(feature = "std")]
+pub use crate::event_kind::EventKind;
+#[cfg(feature = "std")]
+pub use crate::file_header::{NotAMeasuremeFile, UnsupportedVersion};
+#[cfg(all(feature = "std", any(not(target_arch = "wasm32"), target_os = "wasi")))]
 pub use crate::file_serialization_sink::FileSerializationSink;
-#[cfg(not(target_arch = "wasm32"))]
+#[cfg(feature = "log")]
+pub use crate::logger::{MeasuremeLogger, DEFAULT_MESSAGE_LIMIT, MESSAGE_LIMIT_EXCEEDED};
+#[cfg(feature = "std")]
+pub use crate::merged_profiling_data::MergedProfilingData;
+#[cfg(feature = "std")]
+pub use crate::metadata::{ProfileMetadata, ProfileMetadataBuilder};
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
 pub use crate::mmap_serialization_sink::MmapSerializationSink;
-pub use crate::profiler::{Profiler, ProfilerFiles, TimingGuard};
-pub use crate::profiling_data::{MatchingEvent, ProfilingData, ProfilingDataBuilder};
-pub use crate::raw_event::{RawEvent, Timestamp, TimestampKind};
-pub use crate::serialization::{Addr, ByteVecSink, SerializationSink};
+#[cfg(feature = "std")]
+pub use crate::packed_profile::PackedProfile;
+#[cfg(feature = "std")]
+pub use crate::profiler::{
+    current_thread_id, Profiler, ProfilerError, ProfilerFiles, TaskEvent, TimingGuard,
+    TASK_ID_FLAG, THREAD_START_EVENT_KIND, THREAD_STOP_EVENT_KIND,
+};
+#[cfg(feature = "std")]
+pub use crate::profiling_data::{
+    IntervalError, IntervalIterator, MarkerError, MatchedEvent, MatchingEvent, ProfileFileSizes,
+    ProfilerEventIterator, ProfilingData, ProfilingDataBuilder, SortedEventIterator,
+    StringTableStats, ValidationWarning,
+};
+pub use crate::raw_event::{
+    RawEvent, Timestamp, TimestampKind, MAX_TIMESTAMP_NANOS, RAW_EVENT_SIZE,
+};
+pub use crate::ring_buffer_sink::RingBufferSink;
+#[cfg(feature = "std")]
+pub use crate::serialization::InMemorySink;
+pub use crate::serialization::{Addr, NullSink, SerializationSink};
+pub use crate::string_id::StringId;
+#[cfg(feature = "std")]
 pub use crate::stringtable::{
-    SerializableString, StringId, StringRef, StringTable, StringTableBuilder,
+    SerializableString, StringComponent, StringRef, StringTable, StringTableBuilder,
 };
+#[cfg(feature = "std")]
+pub use crate::throttle::Throttle;
+#[cfg(feature = "tracing")]
+pub use crate::tracing_layer::MeasuremeLayer;
+
+/// The version of the binary format of the files written by this version of
+/// `measureme`. Files of older versions, down to version `0`, can be read as
+/// well.
+pub const FILE_FORMAT_VERSION: u32 = file_header::CURRENT_FILE_FORMAT_VERSION;
+
+/// The version of the `measureme` crate, e.g. `"0.4.0"`. It is recorded in
+/// the header of every file the crate writes, without any pre-release suffix,
+/// see `ProfilingData::writer_version()`.
+pub fn version() -> &'static str {
+    env!("CARGO_PKG_VERSION")
+}
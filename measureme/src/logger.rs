@@ -0,0 +1,214 @@
+//! A `log::Log` implementation that records log records into a `Profiler`.
+
+use crate::clock::{Clock, InstantClock};
+use crate::profiler::{current_thread_id, Profiler};
+use crate::serialization::SerializationSink;
+use crate::stringtable::StringId;
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use rustc_hash::FxHashMap;
+use std::sync::{Arc, Mutex};
+
+/// The number of distinct messages a `MeasuremeLogger` records by default.
+pub const DEFAULT_MESSAGE_LIMIT: usize = 10_000;
+
+/// The event id of log records that exceed the message limit.
+pub const MESSAGE_LIMIT_EXCEEDED: &str = "<message limit exceeded>";
+
+/// Records each log record as an instant event (behind the `log` feature),
+/// with the level of the record, e.g. `WARN`, as event kind and the
+/// formatted message as event id.
+///
+/// Messages are interned, so a message that is logged repeatedly is only
+/// stored once. To keep messages with varying contents, like
+/// `"read 3 bytes"`, from flooding the string table, only the first
+/// `message_limit` distinct messages are stored. Records with a new message
+/// beyond that are recorded with `MESSAGE_LIMIT_EXCEEDED` as event id and
+/// counted by `dropped_messages()`.
+pub struct MeasuremeLogger<S: SerializationSink, C: Clock = InstantClock> {
+    profiler: Arc<Profiler<S, C>>,
+    max_level: LevelFilter,
+    message_limit: usize,
+    // Indexed by `Level as usize - 1`
+    level_ids: [StringId; 5],
+    limit_exceeded_id: StringId,
+    messages: Mutex<MessageTable>,
+}
+
+struct MessageTable {
+    ids: FxHashMap<String, StringId>,
+    dropped: u64,
+}
+
+impl<S: SerializationSink, C: Clock> MeasuremeLogger<S, C> {
+    /// Creates a logger that records records of all levels, up to
+    /// `DEFAULT_MESSAGE_LIMIT` distinct messages.
+    pub fn new(profiler: Arc<Profiler<S, C>>) -> MeasuremeLogger<S, C> {
+        let level_ids = [
+            Level::Error,
+            Level::Warn,
+            Level::Info,
+            Level::Debug,
+            Level::Trace,
+        ]
+        .map(|level| profiler.alloc_string(level.as_str()));
+        let limit_exceeded_id = profiler.alloc_string(MESSAGE_LIMIT_EXCEEDED);
+
+        MeasuremeLogger {
+            profiler,
+            max_level: LevelFilter::Trace,
+            message_limit: DEFAULT_MESSAGE_LIMIT,
+            level_ids,
+            limit_exceeded_id,
+            messages: Mutex::new(MessageTable {
+                ids: FxHashMap::default(),
+                dropped: 0,
+            }),
+        }
+    }
+
+    /// Only records records up to `max_level`.
+    pub fn with_max_level(mut self, max_level: LevelFilter) -> MeasuremeLogger<S, C> {
+        self.max_level = max_level;
+        self
+    }
+
+    /// Stores at most `message_limit` distinct messages.
+    pub fn with_message_limit(mut self, message_limit: usize) -> MeasuremeLogger<S, C> {
+        self.message_limit = message_limit;
+        self
+    }
+
+    /// The `Profiler` the logger records into.
+    pub fn profiler(&self) -> &Arc<Profiler<S, C>> {
+        &self.profiler
+    }
+
+    /// The number of records whose message wasn't stored because the
+    /// message limit was reached.
+    pub fn dropped_messages(&self) -> u64 {
+        self.messages.lock().unwrap().dropped
+    }
+
+    fn message_id(&self, message: String) -> StringId {
+        let mut messages = self.messages.lock().unwrap();
+
+        if let Some(&id) = messages.ids.get(&message) {
+            return id;
+        }
+
+        if messages.ids.len() >= self.message_limit {
+            messages.dropped += 1;
+            return self.limit_exceeded_id;
+        }
+
+        let id = self.profiler.alloc_string(&message[..]);
+        messages.ids.insert(message, id);
+        id
+    }
+}
+
+impl<S, C> MeasuremeLogger<S, C>
+where
+    S: SerializationSink + Send + Sync + 'static,
+    C: Clock + Send + Sync + 'static,
+{
+    /// Installs the logger as the global logger of the `log` crate and sets
+    /// the global maximum level to the logger's. Fails if a global logger
+    /// was already set.
+    pub fn init(self) -> Result<(), SetLoggerError> {
+        let max_level = self.max_level;
+        log::set_boxed_logger(Box::new(self))?;
+        log::set_max_level(max_level);
+        Ok(())
+    }
+}
+
+impl<S, C> Log for MeasuremeLogger<S, C>
+where
+    S: SerializationSink + Send + Sync,
+    C: Clock + Send + Sync,
+{
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= self.max_level
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let event_kind = self.level_ids[record.level() as usize - 1];
+        let event_id = self.message_id(record.args().to_string());
+        self.profiler
+            .record_instant_event(event_kind, event_id, current_thread_id());
+    }
+
+    fn flush(&self) {
+        // `Log::flush()` can't report errors, `Profiler::finish()` does
+        let _ = self.profiler.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InMemorySink, ProfilingData, TimestampKind};
+
+    #[test]
+    fn records_as_instant_events() {
+        let event_sink = Arc::new(InMemorySink::new());
+        let string_data_sink = Arc::new(InMemorySink::new());
+        let string_index_sink = Arc::new(InMemorySink::new());
+
+        {
+            let profiler = Arc::new(Profiler::with_sinks(
+                event_sink.clone(),
+                string_data_sink.clone(),
+                string_index_sink.clone(),
+            ));
+            let logger = MeasuremeLogger::new(profiler)
+                .with_max_level(LevelFilter::Info)
+                .with_message_limit(2);
+
+            let log = |level, message: &str| {
+                logger.log(
+                    &Record::builder()
+                        .level(level)
+                        .args(format_args!("{}", message))
+                        .build(),
+                )
+            };
+            log(Level::Warn, "first");
+            log(Level::Debug, "filtered out");
+            log(Level::Info, "second");
+            log(Level::Error, "first");
+            log(Level::Info, "third");
+
+            assert_eq!(logger.dropped_messages(), 1);
+        }
+
+        let profiling_data = ProfilingData::from_buffers(
+            Arc::try_unwrap(event_sink).unwrap().into_bytes(),
+            Arc::try_unwrap(string_data_sink).unwrap().into_bytes(),
+            Arc::try_unwrap(string_index_sink).unwrap().into_bytes(),
+        )
+        .unwrap();
+
+        let events: Vec<_> = profiling_data
+            .iter()
+            .map(|e| {
+                assert_eq!(e.timestamp_kind, TimestampKind::Instant);
+                (e.event_kind.into_owned(), e.label.into_owned())
+            })
+            .collect();
+        assert_eq!(
+            events,
+            [
+                ("WARN".to_string(), "first".to_string()),
+                ("INFO".to_string(), "second".to_string()),
+                ("ERROR".to_string(), "first".to_string()),
+                ("INFO".to_string(), MESSAGE_LIMIT_EXCEEDED.to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,276 @@
+use crate::event::Event;
+use crate::profiling_data::ProfilingData;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Several `ProfilingData` objects combined into one logical profile, as
+/// created by `ProfilingData::merge()`.
+///
+/// The thread ids of each profile are offset so that they don't collide with
+/// the thread ids of the other profiles. Timestamps are kept in each
+/// profile's own clock domain, i.e. relative to the start of the respective
+/// process, unless `align_start()` is called.
+pub struct MergedProfilingData {
+    sources: Vec<Source>,
+}
+
+struct Source {
+    data: ProfilingData,
+    thread_id_offset: u64,
+    time_offset: Duration,
+}
+
+impl MergedProfilingData {
+    pub(crate) fn new(profiles: Vec<ProfilingData>) -> MergedProfilingData {
+        let mut next_thread_id_offset = 0u64;
+
+        let sources = profiles
+            .into_iter()
+            .map(|data| {
+                let thread_id_offset = next_thread_id_offset;
+                if let Some(max_thread_id) = data.max_thread_id() {
+                    next_thread_id_offset = next_thread_id_offset
+                        .checked_add(max_thread_id)
+                        .and_then(|offset| offset.checked_add(1))
+                        .expect("thread ids of the merged profiles don't fit into a u64");
+                }
+
+                Source {
+                    data,
+                    thread_id_offset,
+                    time_offset: Duration::from_nanos(0),
+                }
+            })
+            .collect();
+
+        MergedProfilingData { sources }
+    }
+
+    /// Rebases the timestamps of all profiles onto a common timeline, using
+    /// the start time recorded in each profile's metadata. Afterwards, a
+    /// timestamp of zero corresponds to the start of the earliest process.
+    pub fn align_start(&mut self) {
+        let start_times: Vec<_> = self
+            .sources
+            .iter()
+            .map(|source| {
+                source
+                    .data
+                    .try_metadata()
+                    .ok()
+                    .map(|metadata| metadata.start_time)
+            })
+            .collect();
+
+        let earliest_start_time = match start_times.iter().flatten().min() {
+            Some(&start_time) => start_time,
+            None => return,
+        };
+
+        for (source, start_time) in self.sources.iter_mut().zip(start_times) {
+            source.time_offset = start_time
+                .and_then(|start_time| start_time.duration_since(earliest_start_time).ok())
+                .unwrap_or(Duration::from_nanos(0));
+        }
+    }
+
+    /// The number of profiles that were merged.
+    pub fn profile_count(&self) -> usize {
+        self.sources.len()
+    }
+
+    /// Iterates the events of all profiles, one profile after the other.
+    pub fn iter(&self) -> impl Iterator<Item = Event<'_>> {
+        self.sources.iter().flat_map(|source| {
+            source.data.iter().map(move |mut event| {
+                event.thread_id += source.thread_id_offset;
+                event.timestamp += source.time_offset;
+                event
+            })
+        })
+    }
+
+    /// Iterates the events of the (offset) `thread_id`, see
+    /// `ProfilingData::iter_thread()`.
+    pub fn iter_thread(&self, thread_id: u64) -> impl Iterator<Item = Event<'_>> {
+        self.source(thread_id).into_iter().flat_map(move |source| {
+            source
+                .data
+                .iter_thread(thread_id - source.thread_id_offset)
+                .map(move |mut event| {
+                    event.thread_id += source.thread_id_offset;
+                    event.timestamp += source.time_offset;
+                    event
+                })
+        })
+    }
+
+    /// Sorts the events of all profiles by their (offset) thread id, see
+    /// `ProfilingData::group_by_thread()`.
+    pub fn group_by_thread(&self) -> HashMap<u64, Vec<Event<'_>>> {
+        let mut threads: HashMap<u64, Vec<Event<'_>>> = HashMap::new();
+        for event in self.iter() {
+            threads.entry(event.thread_id).or_default().push(event);
+        }
+        threads
+    }
+
+    /// The (offset) ids of all threads that recorded events, in ascending
+    /// order.
+    pub fn thread_ids(&self) -> Vec<u64> {
+        self.sources
+            .iter()
+            .flat_map(|source| {
+                source
+                    .data
+                    .thread_ids()
+                    .into_iter()
+                    .map(move |thread_id| thread_id + source.thread_id_offset)
+            })
+            .collect()
+    }
+
+    /// Returns all recorded thread names with their (offset) thread ids,
+    /// ordered by thread id.
+    pub fn thread_names(&self) -> Vec<(u64, &str)> {
+        self.sources
+            .iter()
+            .flat_map(|source| {
+                source
+                    .data
+                    .thread_names()
+                    .into_iter()
+                    .map(move |(thread_id, name)| (thread_id + source.thread_id_offset, name))
+            })
+            .collect()
+    }
+
+    /// Returns the process id recorded in the metadata of the profile that
+    /// the (offset) `thread_id` belongs to.
+    pub fn process_id(&self, thread_id: u64) -> Option<u32> {
+        let source = self.source(thread_id)?;
+        source
+            .data
+            .try_metadata()
+            .ok()
+            .map(|metadata| metadata.process_id)
+    }
+
+    /// Returns the name recorded for the (offset) `thread_id`, if any.
+    pub fn thread_name(&self, thread_id: u64) -> Option<&str> {
+        let source = self.source(thread_id)?;
+        source.data.thread_name(thread_id - source.thread_id_offset)
+    }
+
+    fn source(&self, thread_id: u64) -> Option<&Source> {
+        self.sources
+            .iter()
+            .rev()
+            .find(|source| source.thread_id_offset <= thread_id)
+    }
+}
+
+impl From<ProfilingData> for MergedProfilingData {
+    fn from(data: ProfilingData) -> MergedProfilingData {
+        MergedProfilingData::new(vec![data])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{InMemorySink, Profiler, ProfilingData, ProfilingDataBuilder, TimestampKind};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn in_memory_profile(f: impl FnOnce(&Profiler<InMemorySink>)) -> ProfilingData {
+        let event_sink = Arc::new(InMemorySink::new());
+        let string_data_sink = Arc::new(InMemorySink::new());
+        let string_index_sink = Arc::new(InMemorySink::new());
+
+        {
+            let profiler = Profiler::with_sinks(
+                event_sink.clone(),
+                string_data_sink.clone(),
+                string_index_sink.clone(),
+            );
+            f(&profiler);
+        }
+
+        ProfilingData::from_buffers(
+            Arc::try_unwrap(event_sink).unwrap().into_bytes(),
+            Arc::try_unwrap(string_data_sink).unwrap().into_bytes(),
+            Arc::try_unwrap(string_index_sink).unwrap().into_bytes(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn merge_offsets_thread_ids() {
+        let mut b1 = ProfilingDataBuilder::new();
+        b1.interval("k1", "id1", 0, 10, 20, |_| {})
+            .interval("k1", "id2", 2, 10, 20, |_| {})
+            .thread_name(2, "worker");
+
+        let mut b2 = ProfilingDataBuilder::new();
+        b2.interval("k2", "id3", 0, 5, 15, |_| {});
+
+        let merged = ProfilingData::merge(vec![b1.into_profiling_data(), b2.into_profiling_data()]);
+
+        assert_eq!(merged.profile_count(), 2);
+
+        let events: Vec<_> = merged
+            .iter()
+            .map(|e| (e.label.into_owned(), e.thread_id, e.timestamp_kind))
+            .collect();
+
+        assert_eq!(
+            events,
+            [
+                ("id1".to_string(), 0, TimestampKind::Start),
+                ("id1".to_string(), 0, TimestampKind::End),
+                ("id2".to_string(), 2, TimestampKind::Start),
+                ("id2".to_string(), 2, TimestampKind::End),
+                ("id3".to_string(), 3, TimestampKind::Start),
+                ("id3".to_string(), 3, TimestampKind::End),
+            ]
+        );
+
+        assert_eq!(merged.thread_name(2), Some("worker"));
+        assert_eq!(merged.thread_name(3), None);
+        assert_eq!(merged.thread_names(), [(2, "worker")]);
+        assert_eq!(merged.thread_ids(), [0, 2, 3]);
+
+        let events: Vec<_> = merged
+            .iter_thread(3)
+            .map(|e| (e.label.into_owned(), e.thread_id))
+            .collect();
+        assert_eq!(events, [("id3".to_string(), 3), ("id3".to_string(), 3)]);
+
+        // `ProfilingDataBuilder` doesn't write any metadata
+        assert_eq!(merged.process_id(0), None);
+    }
+
+    #[test]
+    fn merge_with_metadata() {
+        let record = |profiler: &Profiler<InMemorySink>| {
+            let kind = profiler.alloc_string("Kind");
+            profiler.record_instant_event(kind, kind, 0);
+        };
+
+        let p1 = in_memory_profile(record);
+        std::thread::sleep(Duration::from_millis(10));
+        let p2 = in_memory_profile(record);
+
+        let mut merged = ProfilingData::merge(vec![p1, p2]);
+
+        assert_eq!(merged.process_id(0), Some(std::process::id()));
+        assert_eq!(merged.process_id(1), Some(std::process::id()));
+
+        let unaligned: Vec<_> = merged.iter().map(|e| e.timestamp).collect();
+        merged.align_start();
+        let aligned: Vec<_> = merged.iter().map(|e| e.timestamp).collect();
+
+        assert_eq!(unaligned[0], aligned[0]);
+        assert!(aligned[1] >= unaligned[1] + Duration::from_millis(10));
+    }
+}
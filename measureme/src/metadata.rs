@@ -0,0 +1,485 @@
+//! The metadata record that `Profiler` writes at `METADATA_STRING_ID`. It is
+//! a flat JSON object:
+//!
+//! ```text
+//! { "start_time": <nanos since UNIX_EPOCH>, "process_id": <pid>, "cmd": "<command line>" }
+//! ```
+//!
+//! followed by the custom fields added via `ProfileMetadataBuilder::field()`,
+//! as further string members. Readers that don't know a field ignore it.
+//!
+//! Older versions of `measureme` escaped `cmd` via `str::escape_default()`,
+//! which is not always valid JSON, so the parser also accepts the `\u{...}`
+//! and `\'` escapes produced by it.
+
+use crate::event::Event;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime};
+
+/// The information `Profiler` records about the profiled process, as
+/// returned by `ProfilingData::metadata()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ProfileMetadata {
+    /// The wall clock time at which the `Profiler` was created, i.e. at which
+    /// its clock read zero. See `wall_time()`.
+    pub start_time: SystemTime,
+    pub process_id: u32,
+    /// The command line of the profiled process, with arguments separated by
+    /// spaces.
+    pub cmd: String,
+    /// The custom fields added via `ProfileMetadataBuilder::field()`, see
+    /// `extra()`.
+    pub custom: BTreeMap<String, String>,
+}
+
+/// The names of the fields that `ProfileMetadata` always has.
+const RESERVED_FIELDS: &[&str] = &["start_time", "process_id", "cmd"];
+
+impl Default for ProfileMetadata {
+    fn default() -> ProfileMetadata {
+        ProfileMetadata {
+            start_time: SystemTime::UNIX_EPOCH,
+            process_id: 0,
+            cmd: String::new(),
+            custom: BTreeMap::new(),
+        }
+    }
+}
+
+impl ProfileMetadata {
+    /// The wall clock time of an event. `start_time` is the wall clock time
+    /// that corresponds to a timestamp of zero, so this is
+    /// `start_time + (event.timestamp - UNIX_EPOCH)`.
+    ///
+    /// `start_time` is read from the system clock once, when the `Profiler` is
+    /// created, while the timestamps of events come from a monotonic clock.
+    /// Adjustments of the system clock, e.g. by NTP, while the profiled process
+    /// runs, and time the system spends suspended, are therefore not reflected
+    /// here, so the result can drift from the wall clock over long profiles.
+    /// It is also only as precise as the system clock.
+    pub fn wall_time(&self, event: &Event<'_>) -> SystemTime {
+        let nanos = event
+            .timestamp
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+
+        self.start_time + nanos
+    }
+
+    /// The value of the custom field `key`, if it was added via
+    /// `ProfileMetadataBuilder::field()`.
+    pub fn extra(&self, key: &str) -> Option<&str> {
+        self.custom.get(key).map(|value| &value[..])
+    }
+
+    pub(crate) fn to_json(&self) -> String {
+        let start_time = self
+            .start_time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+
+        let mut json = format!(
+            r#"{{ "start_time": {}, "process_id": {}, "cmd": "{}""#,
+            start_time,
+            self.process_id,
+            escape_json(&self.cmd),
+        );
+        for (key, value) in &self.custom {
+            json.push_str(&format!(
+                r#", "{}": "{}""#,
+                escape_json(key),
+                escape_json(value)
+            ));
+        }
+        json.push_str(" }");
+
+        json
+    }
+
+    pub(crate) fn parse(json: &str) -> Result<ProfileMetadata, String> {
+        let mut start_time = None;
+        let mut process_id = None;
+        let mut cmd = None;
+        let mut custom = BTreeMap::new();
+
+        let mut parser = Parser { rest: json };
+        parser.expect('{')?;
+
+        if !parser.eat('}') {
+            loop {
+                let key = parser.string()?;
+                parser.expect(':')?;
+
+                match &key[..] {
+                    "start_time" => start_time = Some(parser.number()?),
+                    "process_id" => process_id = Some(parser.number()?),
+                    "cmd" => cmd = Some(parser.string()?),
+                    _ if parser.at_string() => {
+                        custom.insert(key, parser.string()?);
+                    }
+                    _ => parser.skip_value()?,
+                }
+
+                if parser.eat('}') {
+                    break;
+                }
+                parser.expect(',')?;
+            }
+        }
+
+        let start_time = start_time.ok_or("missing `start_time`")?;
+        let process_id = process_id.ok_or("missing `process_id`")?;
+
+        Ok(ProfileMetadata {
+            start_time: SystemTime::UNIX_EPOCH + Duration::from_nanos(start_time),
+            process_id: u32::try_from(process_id)
+                .map_err(|_| format!("invalid `process_id` {}", process_id))?,
+            cmd: cmd.ok_or("missing `cmd`")?,
+            custom,
+        })
+    }
+}
+
+type Redact = Box<dyn Fn(&str) -> String>;
+
+/// Chooses what `Profiler::new_with_metadata()` records about the profiled
+/// process. By default, this is what `Profiler::new()` records: the start
+/// time, the process id, and the command line, including all arguments.
+/// Profiles are often shared, and the arguments can contain e.g. paths or
+/// tokens that shouldn't be, so they can be left out or redacted:
+///
+/// ```
+/// use measureme::ProfileMetadataBuilder;
+///
+/// let metadata = ProfileMetadataBuilder::new()
+///     .redact(|arg| {
+///         if arg.starts_with("--token=") {
+///             "--token=<redacted>".to_string()
+///         } else {
+///             arg.to_string()
+///         }
+///     })
+///     .field("build_id", "1234");
+/// ```
+pub struct ProfileMetadataBuilder {
+    include_args: bool,
+    cmd: Option<String>,
+    redact: Option<Redact>,
+    custom: BTreeMap<String, String>,
+}
+
+impl Default for ProfileMetadataBuilder {
+    fn default() -> ProfileMetadataBuilder {
+        ProfileMetadataBuilder::new()
+    }
+}
+
+impl ProfileMetadataBuilder {
+    pub fn new() -> ProfileMetadataBuilder {
+        ProfileMetadataBuilder {
+            include_args: true,
+            cmd: None,
+            redact: None,
+            custom: BTreeMap::new(),
+        }
+    }
+
+    /// Whether the command line includes the arguments of the process, or
+    /// only the program. Defaults to `true`.
+    pub fn include_args(mut self, include_args: bool) -> ProfileMetadataBuilder {
+        self.include_args = include_args;
+        self
+    }
+
+    /// Records `cmd` as the command line instead of the one of the current
+    /// process, e.g. one that was redacted already.
+    pub fn cmd(mut self, cmd: impl Into<String>) -> ProfileMetadataBuilder {
+        self.cmd = Some(cmd.into());
+        self
+    }
+
+    /// Replaces each argument of the current process by what `redact`
+    /// returns for it before it is recorded. The program itself is recorded
+    /// as it is.
+    pub fn redact(mut self, redact: impl Fn(&str) -> String + 'static) -> ProfileMetadataBuilder {
+        self.redact = Some(Box::new(redact));
+        self
+    }
+
+    /// Adds a custom field, which `ProfileMetadata::extra()` returns. Adding
+    /// the same key again replaces the value. Panics if `key` is the name of
+    /// one of the fields every profile has, `start_time`, `process_id`, or
+    /// `cmd`.
+    pub fn field(
+        mut self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+    ) -> ProfileMetadataBuilder {
+        let key = key.into();
+        assert!(
+            !RESERVED_FIELDS.contains(&&key[..]),
+            "`{}` is not a custom metadata field",
+            key
+        );
+
+        self.custom.insert(key, value.into());
+        self
+    }
+
+    /// The metadata of the current process, for a profile started at
+    /// `start_time`.
+    pub(crate) fn build(&self, start_time: SystemTime) -> ProfileMetadata {
+        ProfileMetadata {
+            start_time,
+            process_id: std::process::id(),
+            cmd: match &self.cmd {
+                Some(cmd) => cmd.clone(),
+                None => self.process_cmd(std::env::args()),
+            },
+            custom: self.custom.clone(),
+        }
+    }
+
+    // The command line made of `args`, the first of which is the program
+    fn process_cmd(&self, mut args: impl Iterator<Item = String>) -> String {
+        let mut cmd = args.next().unwrap_or_default();
+        if !self.include_args {
+            return cmd;
+        }
+
+        for arg in args {
+            cmd.push(' ');
+            match &self.redact {
+                Some(redact) => cmd.push_str(&redact(&arg)),
+                None => cmd.push_str(&arg),
+            }
+        }
+
+        cmd
+    }
+}
+
+fn escape_json(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+struct Parser<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_whitespace(&mut self) {
+        self.rest = self.rest.trim_start();
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+
+        match self.rest.strip_prefix(c) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(format!("expected `{}`", c))
+        }
+    }
+
+    fn number(&mut self) -> Result<u64, String> {
+        self.skip_whitespace();
+
+        let len = self
+            .rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(self.rest.len());
+        let (digits, rest) = self.rest.split_at(len);
+        self.rest = rest;
+
+        digits
+            .parse()
+            .map_err(|_| format!("invalid number `{}`", digits))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+
+        let mut value = String::new();
+        let mut chars = self.rest.char_indices();
+
+        while let Some((idx, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.rest = &self.rest[idx + 1..];
+                    return Ok(value);
+                }
+                '\\' => {
+                    let escaped = match chars.next() {
+                        Some((_, 'u')) => {
+                            let rest = chars.as_str();
+                            let (code, len) = if let Some(braced) = rest.strip_prefix('{') {
+                                // `\u{...}` as written by `escape_default()`
+                                let end = braced.find('}').ok_or("unterminated `\\u{` escape")?;
+                                (&braced[..end], end + 2)
+                            } else {
+                                (rest.get(..4).ok_or("truncated `\\u` escape")?, 4)
+                            };
+
+                            for _ in 0..len {
+                                chars.next();
+                            }
+
+                            // Surrogates are rejected here, which is fine for
+                            // the metadata that `measureme` writes itself.
+                            u32::from_str_radix(code, 16)
+                                .ok()
+                                .and_then(std::char::from_u32)
+                                .ok_or_else(|| format!("invalid escape `\\u{}`", code))?
+                        }
+                        Some((_, 'n')) => '\n',
+                        Some((_, 'r')) => '\r',
+                        Some((_, 't')) => '\t',
+                        Some((_, 'b')) => '\u{8}',
+                        Some((_, 'f')) => '\u{c}',
+                        Some((_, c @ ('"' | '\\' | '/' | '\''))) => c,
+                        Some((_, c)) => return Err(format!("invalid escape `\\{}`", c)),
+                        None => break,
+                    };
+                    value.push(escaped);
+                }
+                c => value.push(c),
+            }
+        }
+
+        Err("unterminated string".to_string())
+    }
+
+    fn at_string(&mut self) -> bool {
+        self.skip_whitespace();
+        self.rest.starts_with('"')
+    }
+
+    fn skip_value(&mut self) -> Result<(), String> {
+        self.skip_whitespace();
+
+        if self.rest.starts_with('"') {
+            self.string().map(|_| ())
+        } else {
+            self.number().map(|_| ())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let metadata = ProfileMetadata {
+            start_time: SystemTime::UNIX_EPOCH + Duration::from_nanos(1_234_567_890),
+            process_id: 42,
+            cmd: "rustc \"quoted\" C:\\path\ttab é".to_string(),
+            custom: BTreeMap::new(),
+        };
+
+        assert_eq!(ProfileMetadata::parse(&metadata.to_json()), Ok(metadata));
+    }
+
+    #[test]
+    fn escape_default_cmd() {
+        let json = r#"{ "start_time": 10, "process_id": 7, "cmd": "rustc \u{e9} \'x\' " }"#;
+        let metadata = ProfileMetadata::parse(json).unwrap();
+
+        assert_eq!(metadata.process_id, 7);
+        assert_eq!(metadata.cmd, "rustc é 'x' ");
+    }
+
+    #[test]
+    fn custom_fields() {
+        let metadata = ProfileMetadataBuilder::new()
+            .cmd("rustc")
+            .field("build_id", "1234")
+            .field("branch", "fix \"quotes\"")
+            .build(SystemTime::UNIX_EPOCH + Duration::from_secs(1));
+
+        let parsed = ProfileMetadata::parse(&metadata.to_json()).unwrap();
+        assert_eq!(parsed, metadata);
+        assert_eq!(parsed.extra("build_id"), Some("1234"));
+        assert_eq!(parsed.extra("branch"), Some("fix \"quotes\""));
+        assert_eq!(parsed.extra("cmd"), None);
+
+        // Fields of other types, e.g. added by a later version, are ignored
+        let json = r#"{ "start_time": 10, "process_id": 7, "cmd": "rustc", "count": 3 }"#;
+        assert!(ProfileMetadata::parse(json).unwrap().custom.is_empty());
+    }
+
+    #[test]
+    fn redacted_cmd() {
+        let args = || {
+            vec!["rustc", "--token=secret", "main.rs"]
+                .into_iter()
+                .map(String::from)
+        };
+        let redact = |arg: &str| {
+            if arg.starts_with("--token=") {
+                "--token=<redacted>".to_string()
+            } else {
+                arg.to_string()
+            }
+        };
+
+        let builder = ProfileMetadataBuilder::new();
+        assert_eq!(builder.process_cmd(args()), "rustc --token=secret main.rs");
+        let builder = ProfileMetadataBuilder::new().redact(redact);
+        assert_eq!(
+            builder.process_cmd(args()),
+            "rustc --token=<redacted> main.rs"
+        );
+        let builder = ProfileMetadataBuilder::new().include_args(false);
+        assert_eq!(builder.process_cmd(args()), "rustc");
+    }
+
+    #[test]
+    #[should_panic(expected = "`cmd` is not a custom metadata field")]
+    fn reserved_custom_field() {
+        let _ = ProfileMetadataBuilder::new().field("cmd", "rustc");
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(ProfileMetadata::parse("").is_err());
+        assert!(ProfileMetadata::parse("{ \"start_time\": 10 }").is_err());
+        assert!(
+            ProfileMetadata::parse("{ \"start_time\": 10, \"process_id\": 1, \"cmd\": \"x")
+                .is_err()
+        );
+        assert!(ProfileMetadata::parse(
+            "{ \"process_id\": 99999999999, \"start_time\": 0, \"cmd\": \"\" }"
+        )
+        .is_err());
+    }
+}
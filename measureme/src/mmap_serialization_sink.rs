@@ -1,8 +1,7 @@
 use crate::serialization::{Addr, SerializationSink};
-use memmap::MmapMut;
-use std::error::Error;
+use memmap2::MmapMut;
 use std::fs::File;
-use std::io::{BufWriter, Write};
+use std::io::{self, BufWriter, Write};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
 
@@ -13,7 +12,7 @@ pub struct MmapSerializationSink {
 }
 
 impl SerializationSink for MmapSerializationSink {
-    fn from_path(path: &Path) -> Result<Self, Box<dyn Error>> {
+    fn from_path(path: &Path) -> io::Result<Self> {
         // Lazily allocate 1 GB :O
         let file_size = 1 << 30;
 
@@ -42,7 +41,7 @@ impl SerializationSink for MmapSerializationSink {
         // because our runtime checks guarantee that we have exclusive access
         // to the byte range in question.
         let bytes: &mut [u8] = unsafe {
-            let start: *mut u8 = self.mapped_file.as_ptr().offset(pos as isize) as *mut u8;
+            let start: *mut u8 = self.mapped_file.as_ptr().add(pos) as *mut u8;
             std::slice::from_raw_parts_mut(start, num_bytes)
         };
 
@@ -0,0 +1,143 @@
+//! The single-file container format written by `Profiler::new_packed()`.
+
+use crate::file_header::{
+    file_header, read_file_header, UnsupportedVersion, CURRENT_FILE_FORMAT_VERSION,
+    FILE_HEADER_SIZE, FILE_MAGIC_PACKED_PROFILE,
+};
+use byteorder::{ByteOrder, LittleEndian};
+use std::error::Error;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// The size of the container header, including the file header.
+pub const PACKED_PROFILE_HEADER_SIZE: usize = FILE_HEADER_SIZE + 3 * 16;
+
+/// The contents of the three streams stored in a `.mm_profile` file. The file
+/// starts with a header that lists where each stream is stored:
+///
+/// ```text
+/// [magic: b"MMPP", version: u32,
+///  events_offset: u64, events_len: u64,
+///  string_data_offset: u64, string_data_len: u64,
+///  string_index_offset: u64, string_index_len: u64]
+/// ```
+///
+/// All numbers are little-endian, `version` is the same file format version
+/// as in the headers of the other files, and the offsets are relative to the
+/// start of the file. The header is followed by the contents of the events,
+/// string_data, and string_index streams, each exactly as it would have been
+/// written to its own file, i.e. including its own file header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PackedProfile<'a> {
+    pub events: &'a [u8],
+    pub string_data: &'a [u8],
+    pub string_index: &'a [u8],
+}
+
+impl<'a> PackedProfile<'a> {
+    /// The path of the `.mm_profile` file that belongs to `path_stem`.
+    pub fn file_path(path_stem: &Path) -> PathBuf {
+        path_stem.with_extension("mm_profile")
+    }
+
+    /// Splits the contents of a `.mm_profile` file into its three streams.
+    pub fn parse(bytes: &'a [u8]) -> Result<PackedProfile<'a>, Box<dyn Error>> {
+        let version = read_file_header(bytes, FILE_MAGIC_PACKED_PROFILE)?;
+        // Packed profiles were introduced with version `1`
+        if !(1..=CURRENT_FILE_FORMAT_VERSION).contains(&version) {
+            Err(UnsupportedVersion {
+                found: version,
+                supported: CURRENT_FILE_FORMAT_VERSION,
+            })?;
+        }
+
+        if bytes.len() < PACKED_PROFILE_HEADER_SIZE {
+            Err("Packed profile is too short to contain its header")?;
+        }
+
+        let section = |index: usize| -> Result<&'a [u8], Box<dyn Error>> {
+            let pos = FILE_HEADER_SIZE + index * 16;
+            let offset = LittleEndian::read_u64(&bytes[pos..]);
+            let len = LittleEndian::read_u64(&bytes[pos + 8..]);
+
+            offset
+                .checked_add(len)
+                .filter(|&end| end <= bytes.len() as u64)
+                .map(|end| &bytes[offset as usize..end as usize])
+                .ok_or_else(|| "Packed profile section is out of bounds".into())
+        };
+
+        Ok(PackedProfile {
+            events: section(0)?,
+            string_data: section(1)?,
+            string_index: section(2)?,
+        })
+    }
+
+    /// Writes the header, followed by the three streams.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let mut header = [0; PACKED_PROFILE_HEADER_SIZE];
+        header[..FILE_HEADER_SIZE].copy_from_slice(&file_header(FILE_MAGIC_PACKED_PROFILE));
+
+        let mut offset = PACKED_PROFILE_HEADER_SIZE as u64;
+        let sections = [self.events, self.string_data, self.string_index];
+        for (index, section) in sections.iter().enumerate() {
+            let pos = FILE_HEADER_SIZE + index * 16;
+            LittleEndian::write_u64(&mut header[pos..], offset);
+            LittleEndian::write_u64(&mut header[pos + 8..], section.len() as u64);
+            offset += section.len() as u64;
+        }
+
+        writer.write_all(&header)?;
+        for section in &sections {
+            writer.write_all(section)?;
+        }
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let packed = PackedProfile {
+            events: b"events",
+            string_data: b"",
+            string_index: b"index",
+        };
+
+        let mut bytes = Vec::new();
+        packed.write_to(&mut bytes).unwrap();
+
+        assert_eq!(bytes.len(), PACKED_PROFILE_HEADER_SIZE + 11);
+        assert_eq!(PackedProfile::parse(&bytes).unwrap(), packed);
+    }
+
+    #[test]
+    fn malformed() {
+        let packed = PackedProfile {
+            events: b"events",
+            string_data: b"data",
+            string_index: b"index",
+        };
+
+        let mut bytes = Vec::new();
+        packed.write_to(&mut bytes).unwrap();
+
+        // Truncated in the middle of the last section
+        assert!(PackedProfile::parse(&bytes[..bytes.len() - 1]).is_err());
+        // Truncated in the middle of the header
+        assert!(PackedProfile::parse(&bytes[..PACKED_PROFILE_HEADER_SIZE - 1]).is_err());
+
+        // A length that overflows when added to the offset
+        let mut overflowing = bytes.clone();
+        LittleEndian::write_u64(&mut overflowing[FILE_HEADER_SIZE + 8..], u64::MAX);
+        assert!(PackedProfile::parse(&overflowing).is_err());
+
+        // Wrong magic
+        bytes[0] = 0;
+        assert!(PackedProfile::parse(&bytes).is_err());
+    }
+}
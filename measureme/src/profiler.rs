@@ -1,11 +1,45 @@
+#[cfg(not(target_arch = "wasm32"))]
+use crate::background_sink::BackgroundSink;
+use crate::clock::{Clock, InstantClock};
+#[cfg(all(
+    feature = "compression",
+    any(not(target_arch = "wasm32"), target_os = "wasi")
+))]
+use crate::compressed_sink::CompressedSink;
+use crate::event_args::{serialize_args_record, ArgValue};
+use crate::event_id::EventIdBuilder;
+use crate::event_kind::EventKind;
+#[cfg(any(not(target_arch = "wasm32"), target_os = "wasi"))]
+use crate::file_header::{
+    check_file_magic, read_file_header, CURRENT_FILE_FORMAT_VERSION, FILE_HEADER_SIZE,
+    FILE_MAGIC_COMPRESSED, FILE_MAGIC_STRINGTABLE_DATA, FILE_MAGIC_STRINGTABLE_INDEX,
+};
 use crate::file_header::{write_file_header, FILE_MAGIC_EVENT_STREAM};
-use crate::raw_event::{RawEvent, Timestamp, TimestampKind};
-use crate::serialization::SerializationSink;
-use crate::stringtable::{SerializableString, StringId, StringTableBuilder};
+#[cfg(any(not(target_arch = "wasm32"), target_os = "wasi"))]
+use crate::file_serialization_sink::FileSerializationSink;
+use crate::metadata::{ProfileMetadata, ProfileMetadataBuilder};
+use crate::packed_profile::PackedProfile;
+#[cfg(any(not(target_arch = "wasm32"), target_os = "wasi"))]
+use crate::profiling_data::ProfilingData;
+use crate::raw_event::{RawEvent, Timestamp, TimestampKind, RAW_EVENT_SIZE};
+use crate::ring_buffer_sink::RingBufferSink;
+use crate::serialization::{InMemorySink, NullSink, SerializationSink};
+use crate::stringtable::{
+    SerializableString, StringId, StringTableBuilder, CLEAN_SHUTDOWN_EVENT_KIND,
+    THREAD_NAME_EVENT_KIND, THREAD_START_KIND_ID, THREAD_STOP_KIND_ID,
+};
+use crate::throttle::{KindThrottle, Throttle};
+use rustc_hash::FxHasher;
+use std::cell::RefCell;
 use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use std::time::Instant;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Once, OnceLock};
+use std::time::{Duration, SystemTime};
 
 pub struct ProfilerFiles {
     pub events_file: PathBuf,
@@ -21,50 +55,419 @@ impl ProfilerFiles {
             string_index_file: path_stem.with_extension("string_index"),
         }
     }
+
+    /// The path of the events file of shard `shard`, e.g. `<path_stem>.0.events`,
+    /// as written by a `Profiler` created via `Profiler::new_sharded()`.
+    pub fn shard_events_file(path_stem: &Path, shard: usize) -> PathBuf {
+        path_stem.with_extension(format!("{}.events", shard))
+    }
+
+    /// The path of segment `segment` of the events file, e.g.
+    /// `<path_stem>.1.events`, as written by a `Profiler` created via
+    /// `Profiler::with_rotation()`. The first segment is the `.events` file.
+    pub fn rotated_events_file(path_stem: &Path, segment: usize) -> PathBuf {
+        debug_assert!(segment > 0, "the first segment is the `.events` file");
+        path_stem.with_extension(format!("{}.events", segment))
+    }
+}
+
+/// The error returned by `Profiler::new()` and `Profiler::new_packed()` if
+/// one of the trace files could not be created, or its file header could not
+/// be written.
+#[derive(Debug)]
+pub enum ProfilerError {
+    EventsFile(io::Error),
+    StringDataFile(io::Error),
+    StringIndexFile(io::Error),
+    PackedFile(io::Error),
+    HeaderWrite(io::Error),
+}
+
+impl fmt::Display for ProfilerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProfilerError::EventsFile(e) => write!(f, "couldn't create events file: {}", e),
+            ProfilerError::StringDataFile(e) => {
+                write!(f, "couldn't create string_data file: {}", e)
+            }
+            ProfilerError::StringIndexFile(e) => {
+                write!(f, "couldn't create string_index file: {}", e)
+            }
+            ProfilerError::PackedFile(e) => write!(f, "couldn't create mm_profile file: {}", e),
+            ProfilerError::HeaderWrite(e) => write!(f, "couldn't write file header: {}", e),
+        }
+    }
+}
+
+impl Error for ProfilerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ProfilerError::EventsFile(e)
+            | ProfilerError::StringDataFile(e)
+            | ProfilerError::StringIndexFile(e)
+            | ProfilerError::PackedFile(e)
+            | ProfilerError::HeaderWrite(e) => Some(e),
+        }
+    }
+}
+
+/// The bit that is set in the thread id of events recorded via
+/// `Profiler::start_task_event()`, which tells the tasks apart from threads.
+/// The rest of the thread id is the task id.
+pub const TASK_ID_FLAG: u64 = 1 << 63;
+
+/// The event kind of the instant events recorded by
+/// `Profiler::record_thread_start()`. Their label is the name of the thread.
+pub const THREAD_START_EVENT_KIND: &str = "ThreadStart";
+
+/// The event kind of the instant events recorded by
+/// `Profiler::record_thread_stop()`.
+pub const THREAD_STOP_EVENT_KIND: &str = "ThreadStop";
+
+/// Returns a `u64` id for the calling thread, as used by the `*_for_current_thread()`
+/// methods of `Profiler`. The id is derived from `std::thread::ThreadId`, so it
+/// is stable for the lifetime of the thread within one process, but the same
+/// thread may get a different id in another run of the program. It never has
+/// the `TASK_ID_FLAG` set.
+pub fn current_thread_id() -> u64 {
+    thread_local! {
+        static CURRENT_THREAD_ID: u64 = {
+            let mut hasher = FxHasher::default();
+            std::thread::current().id().hash(&mut hasher);
+            hasher.finish() & !TASK_ID_FLAG
+        };
+    }
+
+    CURRENT_THREAD_ID.with(|id| *id)
+}
+
+/// The number of bytes of events each thread collects before writing them to
+/// the event sink in one go.
+const THREAD_BUFFER_SIZE: usize = 128 * std::mem::size_of::<RawEvent>();
+
+type ThreadBuffer = Arc<Mutex<ThreadEvents>>;
+
+type Observer = Box<dyn Fn(&RawEvent) + Send + Sync>;
+
+struct ThreadEvents {
+    events: Vec<u8>,
+    // The event sink the events are written to, see `Profiler::new_sharded()`
+    shard: usize,
+    // The number of bytes appended so far, minus those removed again via
+    // `Profiler::remove_last_event()`
+    appended: u64,
+    // The thread id of the last event that wasn't recorded because of a
+    // `Throttle`, unless an event was appended since. Arguments recorded for
+    // that thread id are dropped, instead of being attached to the event
+    // before the skipped one.
+    skipped: Option<u64>,
+}
+
+// Where an event was appended to the buffer of a thread: the address of the
+// buffer, and its `appended` count right after the event
+#[derive(Clone, Copy)]
+struct BufferPosition {
+    buffer: usize,
+    appended: u64,
+}
+
+static NEXT_PROFILER_ID: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    // The event buffers of the current thread, one for each `Profiler` (by
+    // id) the thread has recorded events with. The `Profiler` keeps another
+    // reference to each buffer, so that it can write out the buffers of all
+    // threads when it is flushed or dropped.
+    static THREAD_BUFFERS: RefCell<Vec<(u64, ThreadBuffer)>> = const { RefCell::new(Vec::new()) };
 }
 
-pub struct Profiler<S: SerializationSink> {
-    event_sink: Arc<S>,
+/// Records events into the event sink and strings into the string table.
+///
+/// Events are first collected in a buffer per thread, which only that thread
+/// writes to, and written to the event sink in batches. This keeps threads
+/// from contending for the event sink. It preserves the order of the events
+/// recorded on each thread, but the events of different threads can end up
+/// interleaved in any order in the `.events` file. The buffers are written
+/// out when the `Profiler` is flushed or dropped.
+pub struct Profiler<S: SerializationSink, C: Clock = InstantClock> {
+    id: u64,
+    // A single sink, unless created via `Profiler::new_sharded()`
+    event_sinks: Vec<Arc<S>>,
+    thread_buffers: Mutex<Vec<ThreadBuffer>>,
+    next_shard: AtomicUsize,
     string_table: StringTableBuilder<S>,
-    start_time: Instant,
+    clock: C,
+    // Added to the readings of `clock`, so that the events of a profile
+    // resumed via `Profiler::open_append()` continue after the existing ones
+    clock_offset: u64,
+    start_wall_time: SystemTime,
+    timestamp_overflowed: AtomicBool,
+    finished: bool,
+    // Set by `Profiler::new_packed()`, writes the `.mm_profile` file
+    write_packed: Option<Box<dyn Fn() -> io::Result<()> + Send + Sync>>,
+    // Set by `Profiler::set_observer()`
+    observer: Option<Observer>,
+    // The first id and the number of the kinds registered via
+    // `Profiler::register_event_kinds()`
+    event_kinds: OnceLock<(StringId, u32)>,
+    // Allocates `THREAD_START_EVENT_KIND` and `THREAD_STOP_EVENT_KIND` the
+    // first time a thread start or stop is recorded
+    thread_lifecycle_kinds: Once,
+    // Set by `Profiler::set_throttle()`, at most one per event kind
+    throttles: Vec<KindThrottle>,
 }
 
 impl<S: SerializationSink> Profiler<S> {
-    pub fn new(path_stem: &Path) -> Result<Profiler<S>, Box<dyn Error>> {
+    pub fn new(path_stem: &Path) -> Result<Profiler<S>, ProfilerError> {
+        Profiler::with_clock(path_stem, InstantClock::new())
+    }
+
+    /// Like `Profiler::new()`, but records the metadata chosen by `metadata`
+    /// about the profiled process, e.g. to leave out or redact its arguments,
+    /// or to add custom fields.
+    pub fn new_with_metadata(
+        path_stem: &Path,
+        metadata: ProfileMetadataBuilder,
+    ) -> Result<Profiler<S>, ProfilerError> {
+        Profiler::with_sink_constructor(path_stem, S::from_path, InstantClock::new(), &metadata)
+    }
+
+    /// Creates a `Profiler` that writes into the given, already opened sinks
+    /// instead of creating them from a path.
+    pub fn with_sinks(
+        event_sink: Arc<S>,
+        string_data_sink: Arc<S>,
+        string_index_sink: Arc<S>,
+    ) -> Profiler<S> {
+        Profiler::with_sinks_and_clock(
+            event_sink,
+            string_data_sink,
+            string_index_sink,
+            InstantClock::new(),
+        )
+    }
+
+    /// Like `Profiler::new()`, but spreads the events over `shard_count`
+    /// events files, `<path_stem>.0.events`, `<path_stem>.1.events`, and so
+    /// on, so that threads on many-core machines don't contend for a single
+    /// event sink. `ProfilingData::new()` reads and merges all shards.
+    ///
+    /// Each thread is assigned a shard, round-robin, when it records its
+    /// first event, and the buffered events of the thread are always written
+    /// to that shard. Since a thread's buffer keeps its events in the order
+    /// they were recorded, the events of a thread end up in order in a single
+    /// shard, and the order between shards doesn't matter. Only events of a
+    /// thread id that is used by several threads, or events recorded while a
+    /// thread is shutting down, which are written to the first shard, are
+    /// spread over several shards. `ProfilingData` orders those by their
+    /// timestamps.
+    pub fn new_sharded(path_stem: &Path, shard_count: usize) -> Result<Profiler<S>, ProfilerError> {
+        assert!(shard_count > 0, "a profiler needs at least one event shard");
+
+        let paths = ProfilerFiles::new(path_stem);
+        let event_sinks = (0..shard_count)
+            .map(|shard| S::from_path(&ProfilerFiles::shard_events_file(path_stem, shard)))
+            .map(|sink| sink.map(Arc::new))
+            .collect::<io::Result<Vec<_>>>()
+            .map_err(ProfilerError::EventsFile)?;
+        let string_data_sink =
+            S::from_path(&paths.string_data_file).map_err(ProfilerError::StringDataFile)?;
+        let string_index_sink =
+            S::from_path(&paths.string_index_file).map_err(ProfilerError::StringIndexFile)?;
+
+        Profiler::with_shards_and_clock(
+            event_sinks,
+            Arc::new(string_data_sink),
+            Arc::new(string_index_sink),
+            InstantClock::new(),
+            &ProfileMetadataBuilder::new(),
+        )
+        .with_written_headers()
+    }
+}
+
+impl Profiler<NullSink> {
+    /// Creates a `Profiler` that records nothing. Recording events and
+    /// allocating strings with it costs next to nothing, so it can stand in
+    /// for a real `Profiler` when profiling is disabled. No files are created.
+    pub fn null() -> Profiler<NullSink> {
+        Profiler::with_sinks(Arc::new(NullSink), Arc::new(NullSink), Arc::new(NullSink))
+    }
+}
+
+impl<S: SerializationSink, C: Clock> Profiler<S, C> {
+    /// Like `Profiler::new()`, but takes the timestamps of events from
+    /// `clock` instead of measuring the time since the `Profiler` was created.
+    pub fn with_clock(path_stem: &Path, clock: C) -> Result<Profiler<S, C>, ProfilerError> {
+        Profiler::with_sink_constructor(
+            path_stem,
+            S::from_path,
+            clock,
+            &ProfileMetadataBuilder::new(),
+        )
+    }
+
+    fn with_sink_constructor(
+        path_stem: &Path,
+        make_sink: impl Fn(&Path) -> io::Result<S>,
+        clock: C,
+        metadata: &ProfileMetadataBuilder,
+    ) -> Result<Profiler<S, C>, ProfilerError> {
         let paths = ProfilerFiles::new(path_stem);
-        let event_sink = Arc::new(S::from_path(&paths.events_file)?);
+        let event_sink = make_sink(&paths.events_file).map_err(ProfilerError::EventsFile)?;
+        let string_data_sink =
+            make_sink(&paths.string_data_file).map_err(ProfilerError::StringDataFile)?;
+        let string_index_sink =
+            make_sink(&paths.string_index_file).map_err(ProfilerError::StringIndexFile)?;
+
+        Profiler::with_shards_and_clock(
+            vec![Arc::new(event_sink)],
+            Arc::new(string_data_sink),
+            Arc::new(string_index_sink),
+            clock,
+            metadata,
+        )
+        .with_written_headers()
+    }
+
+    // Flushes the file headers that were just written, so that failing to
+    // write them is reported by the constructor instead of being lost
+    fn with_written_headers(self) -> Result<Profiler<S, C>, ProfilerError> {
+        self.flush().map_err(ProfilerError::HeaderWrite)?;
+        Ok(self)
+    }
+
+    /// Combines `Profiler::with_sinks()` and `Profiler::with_clock()`.
+    pub fn with_sinks_and_clock(
+        event_sink: Arc<S>,
+        string_data_sink: Arc<S>,
+        string_index_sink: Arc<S>,
+        clock: C,
+    ) -> Profiler<S, C> {
+        Profiler::with_shards_and_clock(
+            vec![event_sink],
+            string_data_sink,
+            string_index_sink,
+            clock,
+            &ProfileMetadataBuilder::new(),
+        )
+    }
 
+    fn with_shards_and_clock(
+        event_sinks: Vec<Arc<S>>,
+        string_data_sink: Arc<S>,
+        string_index_sink: Arc<S>,
+        clock: C,
+        metadata: &ProfileMetadataBuilder,
+    ) -> Profiler<S, C> {
         // The first thing in every file we generate must be the file header.
-        write_file_header(&*event_sink, FILE_MAGIC_EVENT_STREAM);
+        for event_sink in &event_sinks {
+            write_file_header(&**event_sink, FILE_MAGIC_EVENT_STREAM);
+        }
 
-        let string_table = StringTableBuilder::new(
-            Arc::new(S::from_path(&paths.string_data_file)?),
-            Arc::new(S::from_path(&paths.string_index_file)?),
-        );
+        let string_table = StringTableBuilder::new(string_data_sink, string_index_sink);
 
-        let profiler = Profiler {
-            event_sink,
+        // The wall clock time at which `clock` read zero, so that the
+        // timestamps of events can be converted to wall clock time exactly
+        let now = SystemTime::now();
+        let start_wall_time = now
+            .checked_sub(Duration::from_nanos(clock.now_nanos()))
+            .unwrap_or(now);
+
+        let profiler = Profiler::from_parts(event_sinks, string_table, clock, 0, start_wall_time);
+        profiler.alloc_process_metadata(metadata);
+
+        profiler
+    }
+
+    fn from_parts(
+        event_sinks: Vec<Arc<S>>,
+        string_table: StringTableBuilder<S>,
+        clock: C,
+        clock_offset: u64,
+        start_wall_time: SystemTime,
+    ) -> Profiler<S, C> {
+        string_table.alloc_internal(THREAD_NAME_EVENT_KIND, "ThreadName");
+
+        Profiler {
+            id: NEXT_PROFILER_ID.fetch_add(1, Ordering::Relaxed),
+            event_sinks,
+            thread_buffers: Mutex::new(Vec::new()),
+            next_shard: AtomicUsize::new(0),
             string_table,
-            start_time: Instant::now(),
-        };
+            clock,
+            clock_offset,
+            start_wall_time,
+            timestamp_overflowed: AtomicBool::new(false),
+            finished: false,
+            write_packed: None,
+            observer: None,
+            event_kinds: OnceLock::new(),
+            thread_lifecycle_kinds: Once::new(),
+            throttles: Vec::new(),
+        }
+    }
+
+    // Records the metadata of the current process chosen by `metadata`,
+    // starting at `start_wall_time`
+    fn alloc_process_metadata(&self, metadata: &ProfileMetadataBuilder) {
+        let metadata = metadata.build(self.start_wall_time);
+        self.string_table.alloc_metadata(&*metadata.to_json());
+    }
+
+    /// Writes all buffered data, including the events buffered by each thread,
+    /// to the trace files. After this returns, the
+    /// events recorded so far can be read even if the process crashes before
+    /// the `Profiler` is dropped. The string table is flushed first, so that
+    /// flushed events never refer to strings that haven't been written yet.
+    pub fn flush(&self) -> io::Result<()> {
+        self.write_thread_buffers();
+        self.string_table.flush()?;
+        self.flush_event_sinks()
+    }
+
+    /// Writes all outstanding data, marks the profile as complete and flushes
+    /// the trace files. Dropping the `Profiler` does the same but has to
+    /// ignore errors, so call this to make sure that the profile was written
+    /// completely.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.shut_down()
+    }
 
-        let mut args = String::new();
-        for arg in std::env::args() {
-            args.push_str(&arg.escape_default().to_string());
-            args.push(' ');
+    fn shut_down(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
         }
+        self.finished = true;
 
-        profiler.string_table.alloc_metadata(&*format!(
-            r#"{{ "start_time": {}, "process_id": {}, "cmd": "{}" }}"#,
-            std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_nanos(),
-            std::process::id(),
-            args,
-        ));
+        self.write_thread_buffers();
+        self.string_table.flush()?;
 
-        Ok(profiler)
+        // Written last, so that `ProfilingData::is_complete()` can tell that
+        // no events are missing
+        let footer = RawEvent {
+            event_kind: CLEAN_SHUTDOWN_EVENT_KIND,
+            id: CLEAN_SHUTDOWN_EVENT_KIND,
+            thread_id: 0,
+            timestamp: self.timestamp(TimestampKind::Instant),
+            payload: 0,
+        };
+        for event_sink in &self.event_sinks {
+            event_sink.write_bytes(&footer.to_bytes());
+        }
+
+        self.flush_event_sinks()?;
+
+        match &self.write_packed {
+            Some(write_packed) => write_packed(),
+            None => Ok(()),
+        }
+    }
+
+    /// Reserves a block of `count` consecutive string ids and returns the
+    /// first one, see `StringTableBuilder::reserve_range()`.
+    pub fn reserve_string_ids(&self, count: u32) -> StringId {
+        self.string_table.reserve_range(count)
     }
 
     #[inline(always)]
@@ -81,79 +484,2595 @@ impl<S: SerializationSink> Profiler<S> {
         self.string_table.alloc(s)
     }
 
+    /// Allocates a string literal, see `StringTableBuilder::alloc_static()`.
+    #[inline(always)]
+    pub fn alloc_static_string(&self, s: &'static str) -> StringId {
+        self.string_table.alloc_static(s)
+    }
+
+    /// Returns an `EventIdBuilder` for allocating event ids that are made up
+    /// of several components.
+    pub fn event_id_builder(&self) -> EventIdBuilder<'_, S> {
+        EventIdBuilder::new(&self.string_table)
+    }
+
+    /// Registers the names of a fixed set of event kinds, e.g. of the
+    /// variants of an enum, so that events can be recorded with the `u16`
+    /// discriminant of their kind instead of a `StringId`, see `EventKind`.
+    /// `names[i]` is the name of discriminant `i`. The names are allocated
+    /// right away, with ids reserved via `reserve_string_ids()`.
+    ///
+    /// Panics if event kinds were registered with this `Profiler` before.
+    pub fn register_event_kinds(&self, names: &[&str]) {
+        assert!(
+            names.len() <= u16::MAX as usize + 1,
+            "more event kinds than `u16` discriminants"
+        );
+        assert!(
+            self.event_kinds.get().is_none(),
+            "event kinds were registered already"
+        );
+
+        let first = self.reserve_string_ids(names.len() as u32);
+        for (index, name) in names.iter().enumerate() {
+            let id = StringId::from_reserved_index(first.reserved_index() + index as u32);
+            self.alloc_string_with_reserved_id(id, *name);
+        }
+
+        self.event_kinds
+            .set((first, names.len() as u32))
+            .expect("event kinds were registered already");
+    }
+
+    #[inline]
+    fn event_kind_id(&self, event_kind: impl Into<EventKind>) -> StringId {
+        match event_kind.into() {
+            EventKind::Id(id) => id,
+            // Unknown discriminants trip `debug_assert_allocated()`
+            EventKind::Discriminant(discriminant) => match self.event_kinds.get() {
+                Some(&(first, count)) if u32::from(discriminant) < count => {
+                    StringId::from_reserved_index(first.reserved_index() + u32::from(discriminant))
+                }
+                _ => StringId::INVALID,
+            },
+        }
+    }
+
     /// Records an event with the given parameters. The event time is computed
     /// automatically.
     pub fn record_event(
         &self,
-        event_kind: StringId,
+        event_kind: impl Into<EventKind>,
         event_id: StringId,
         thread_id: u64,
         timestamp_kind: TimestampKind,
     ) {
-        let duration_since_start = self.start_time.elapsed();
-        let nanos_since_start = duration_since_start.as_secs() * 1_000_000_000
-            + duration_since_start.subsec_nanos() as u64;
-        let timestamp = Timestamp::new(nanos_since_start, timestamp_kind);
+        let event_kind = self.event_kind_id(event_kind);
+        debug_assert_allocated(event_kind, event_id);
 
         let raw_event = RawEvent {
             event_kind,
             id: event_id,
             thread_id,
-            timestamp,
+            timestamp: self.timestamp(timestamp_kind),
+            payload: 0,
         };
 
-        self.event_sink
-            .write_atomic(std::mem::size_of::<RawEvent>(), |bytes| {
-                debug_assert_eq!(bytes.len(), std::mem::size_of::<RawEvent>());
+        self.write_raw_event(&raw_event);
+    }
 
-                let raw_event_bytes: &[u8] = unsafe {
-                    std::slice::from_raw_parts(
-                        &raw_event as *const _ as *const u8,
-                        std::mem::size_of::<RawEvent>(),
-                    )
-                };
+    /// Like `record_event()`, but with a timestamp the caller measured
+    /// instead of the time of the profiler's `Clock`, e.g. to place the
+    /// events of several processes of a distributed build on one timeline.
+    /// `nanos` are nanoseconds since `Profiler::start_wall_time()`, which is
+    /// recorded as `ProfileMetadata::start_time`, so a timestamp taken from a
+    /// clock shared by the processes has to be converted by subtracting the
+    /// start time of this profile on that clock. `ProfilingData::merge()`
+    /// followed by `MergedProfilingData::align_start()` then puts the events
+    /// of all processes in the right order.
+    ///
+    /// The timestamps are stored as they are, apart from the offset of a
+    /// profile resumed via `Profiler::open_append()`, and it is up to the
+    /// caller to keep them consistent: the shared clock has to be monotonic
+    /// and synchronized with the start time, the events of a thread should be
+    /// recorded in order, and events recorded with the profiler's own clock
+    /// only line up with them if that clock measures the same time.
+    pub fn record_event_at(
+        &self,
+        event_kind: impl Into<EventKind>,
+        event_id: StringId,
+        thread_id: u64,
+        timestamp_kind: TimestampKind,
+        nanos: u64,
+    ) {
+        let event_kind = self.event_kind_id(event_kind);
+        debug_assert_allocated(event_kind, event_id);
 
-                bytes.copy_from_slice(raw_event_bytes);
-            });
+        let raw_event = RawEvent {
+            event_kind,
+            id: event_id,
+            thread_id,
+            timestamp: self.timestamp_at(nanos, timestamp_kind),
+            payload: 0,
+        };
+
+        self.write_raw_event(&raw_event);
     }
 
-    /// Creates a "start" event and returns a `TimingGuard` that will create
-    /// the corresponding "end" event when it is dropped.
-    pub fn start_recording_interval_event<'a>(
-        &'a self,
-        event_kind: StringId,
+    /// Like `record_event()`, but uses the id of the calling thread as
+    /// returned by `current_thread_id()`.
+    #[inline]
+    pub fn record_event_for_current_thread(
+        &self,
+        event_kind: impl Into<EventKind>,
+        event_id: StringId,
+        timestamp_kind: TimestampKind,
+    ) {
+        if S::DISCARDS_DATA {
+            return;
+        }
+
+        self.record_event(event_kind, event_id, current_thread_id(), timestamp_kind);
+    }
+
+    /// Records the current value of the counter identified by `counter_kind`.
+    /// The event time is computed automatically.
+    pub fn record_counter(&self, counter_kind: impl Into<EventKind>, thread_id: u64, value: u64) {
+        let counter_kind = self.event_kind_id(counter_kind);
+        debug_assert_allocated(counter_kind, counter_kind);
+
+        let raw_event = RawEvent {
+            event_kind: counter_kind,
+            id: counter_kind,
+            thread_id,
+            timestamp: self.timestamp(TimestampKind::Counter),
+            payload: value,
+        };
+
+        self.write_raw_event(&raw_event);
+    }
+
+    /// Records an interval event whose start and end were measured by the
+    /// caller, e.g. when importing spans from another tracing system.
+    /// `start_ns` and `end_ns` are nanoseconds on the timeline of the
+    /// profiler's `Clock` and are stored as they are, apart from the offset
+    /// of a profile resumed via `Profiler::open_append()`.
+    pub fn record_interval(
+        &self,
+        event_kind: impl Into<EventKind>,
         event_id: StringId,
         thread_id: u64,
-    ) -> TimingGuard<'a, S> {
-        self.record_event(event_kind, event_id, thread_id, TimestampKind::Start);
+        start_ns: u64,
+        end_ns: u64,
+    ) {
+        debug_assert!(
+            end_ns >= start_ns,
+            "interval ends at {}ns, before its start at {}ns",
+            end_ns,
+            start_ns
+        );
+        let event_kind = self.event_kind_id(event_kind);
+        debug_assert_allocated(event_kind, event_id);
 
-        TimingGuard {
-            profiler: self,
-            event_id,
+        if S::DISCARDS_DATA {
+            return;
+        }
+
+        let start = RawEvent {
             event_kind,
+            id: event_id,
             thread_id,
+            timestamp: self.timestamp_at(start_ns, TimestampKind::Start),
+            payload: 0,
+        };
+        let end = RawEvent {
+            timestamp: self.timestamp_at(end_ns, TimestampKind::End),
+            ..start
+        };
+
+        self.write_raw_event(&start);
+        self.write_raw_event(&end);
+    }
+
+    // Timestamps that don't fit into a `Timestamp` are saturated instead of
+    // corrupting the kind bits, which is reported by `timestamp_overflowed()`.
+    #[inline]
+    fn timestamp(&self, timestamp_kind: TimestampKind) -> Timestamp {
+        // The event will be discarded anyway, so don't bother reading the clock
+        if S::DISCARDS_DATA {
+            return Timestamp::new(0, timestamp_kind);
         }
+
+        self.timestamp_at(self.clock.now_nanos(), timestamp_kind)
     }
-}
 
-/// When dropped, this `TimingGuard` will record an "end" event in the
-/// `Profiler` it was created by.
-#[must_use]
-pub struct TimingGuard<'a, S: SerializationSink> {
-    profiler: &'a Profiler<S>,
-    event_id: StringId,
-    event_kind: StringId,
-    thread_id: u64,
-}
+    #[inline]
+    fn timestamp_at(&self, nanos: u64, timestamp_kind: TimestampKind) -> Timestamp {
+        let nanos = nanos.saturating_add(self.clock_offset);
+        let (timestamp, overflowed) = Timestamp::saturating_new(nanos, timestamp_kind);
+
+        if overflowed {
+            self.timestamp_overflowed.store(true, Ordering::Relaxed);
+        }
+
+        timestamp
+    }
+
+    /// Replaces the metadata that was recorded about the current process when
+    /// the `Profiler` was created, e.g. when converting a profile of another
+    /// process. The original record stays in the string table, but readers
+    /// only see the last one.
+    pub fn set_metadata(&self, metadata: &ProfileMetadata) {
+        self.string_table.alloc_metadata(&*metadata.to_json());
+    }
+
+    /// Calls `observer` with every event recorded from now on, right after
+    /// the event was written, e.g. to pass events on to a live dashboard
+    /// while the profile is being recorded. Replaces any previous observer.
+    ///
+    /// The observer runs on the thread that records the event, in the middle
+    /// of the profiled code, so it must be fast and must not block. It is
+    /// called for the events of the profiled program, but not for the
+    /// records the `Profiler` writes for itself, like thread names. The
+    /// timestamps are the ones written to the profile, see `RawEvent`.
+    /// Profilers that discard their data, like `Profiler::null()`, never
+    /// call the observer. Without an observer, recording an event costs a
+    /// single extra check.
+    pub fn set_observer(&mut self, observer: Box<dyn Fn(&RawEvent) + Send + Sync>) {
+        self.observer = Some(observer);
+    }
+
+    /// Records only some of the intervals of kind `event_kind` from now on,
+    /// as chosen by `throttle`, to bound the overhead of instrumenting e.g. a
+    /// tight loop. Replaces any previous throttle of the kind, and
+    /// `Throttle::OneIn(1)` records all intervals again. See `Throttle` for
+    /// how this affects the totals computed from the profile.
+    ///
+    /// Only intervals recorded via `start_recording_interval_event()` and its
+    /// variants are throttled, not instant events, counters, task events, or
+    /// intervals recorded via `record_interval()`. Arguments recorded for a
+    /// skipped interval via `record_args_event()` are dropped, see there. The
+    /// observer, see `set_observer()`, is not called for skipped intervals,
+    /// but is for the "start" events that `Throttle::MinDuration` removes
+    /// again.
+    ///
+    /// Without throttles, recording an interval costs a single extra check,
+    /// and a few more with throttles of other kinds.
+    ///
+    /// Panics for `Throttle::OneIn(0)`.
+    pub fn set_throttle(&mut self, event_kind: impl Into<EventKind>, throttle: Throttle) {
+        let event_kind = self.event_kind_id(event_kind);
+        let throttle = KindThrottle::new(event_kind, throttle);
+
+        self.throttles.retain(|t| t.event_kind != event_kind);
+        self.throttles.push(throttle);
+    }
 
-impl<'a, S: SerializationSink> Drop for TimingGuard<'a, S> {
     #[inline]
-    fn drop(&mut self) {
-        self.profiler.record_event(
-            self.event_kind,
-            self.event_id,
-            self.thread_id,
-            TimestampKind::End,
+    fn throttle(&self, event_kind: StringId) -> Option<&KindThrottle> {
+        if S::DISCARDS_DATA || self.throttles.is_empty() {
+            return None;
+        }
+
+        self.throttles.iter().find(|t| t.event_kind == event_kind)
+    }
+
+    /// The wall clock time that corresponds to a timestamp of zero, which is
+    /// recorded as `ProfileMetadata::start_time`. The wall clock time of an
+    /// event is `start_wall_time() + Duration::from_nanos(timestamp_nanos)`,
+    /// see `ProfileMetadata::wall_time()` for the caveats.
+    pub fn start_wall_time(&self) -> SystemTime {
+        self.start_wall_time
+    }
+
+    /// Returns whether the clock went past `MAX_TIMESTAMP_NANOS`, in which
+    /// case the affected events were recorded with that timestamp instead.
+    pub fn timestamp_overflowed(&self) -> bool {
+        self.timestamp_overflowed.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    fn write_raw_event(&self, raw_event: &RawEvent) -> Option<BufferPosition> {
+        if S::DISCARDS_DATA {
+            return None;
+        }
+
+        let position = self.write_buffered(&raw_event.to_bytes(), None);
+
+        if let Some(observer) = &self.observer {
+            if raw_event.event_kind != THREAD_NAME_EVENT_KIND {
+                observer(raw_event);
+            }
+        }
+
+        position
+    }
+
+    // Appends `bytes` to the buffer of the current thread. A full buffer is
+    // written out before the next record is appended rather than right
+    // after the last one, so that a record that is attached to the last
+    // event of the thread `attached_to`, like the arguments of an event,
+    // ends up in the same batch. Returns where `bytes` were appended, unless
+    // they were written to the event sink right away or dropped because the
+    // event they are attached to was skipped.
+    #[inline]
+    fn write_buffered(&self, bytes: &[u8], attached_to: Option<u64>) -> Option<BufferPosition> {
+        let buffered = THREAD_BUFFERS.try_with(|thread_buffers| {
+            let mut thread_buffers = thread_buffers.borrow_mut();
+            let idx = self.thread_buffer_idx(&mut thread_buffers);
+
+            let thread_buffer = &thread_buffers[idx].1;
+            let mut buffer = thread_buffer.lock().unwrap();
+            match attached_to {
+                Some(thread_id) if buffer.skipped == Some(thread_id) => return None,
+                Some(_) => {}
+                None => {
+                    if buffer.events.len() >= THREAD_BUFFER_SIZE {
+                        self.write_thread_buffer(&mut buffer);
+                    }
+                    buffer.skipped = None;
+                }
+            }
+
+            buffer.events.extend_from_slice(bytes);
+            buffer.appended += bytes.len() as u64;
+
+            Some(BufferPosition {
+                buffer: Arc::as_ptr(thread_buffer) as usize,
+                appended: buffer.appended,
+            })
+        });
+
+        // The thread-local buffers are not available anymore while the thread
+        // is shutting down, e.g. if a `TimingGuard` is dropped by the
+        // destructor of another thread-local.
+        if buffered.is_err() {
+            self.event_sinks[0].write_bytes(bytes);
+        }
+
+        buffered.ok().flatten()
+    }
+
+    // Notes that an event of `thread_id` wasn't recorded because of a
+    // `Throttle`, see `ThreadEvents::skipped`
+    fn skip_event(&self, thread_id: u64) {
+        if S::DISCARDS_DATA {
+            return;
+        }
+
+        let _ = THREAD_BUFFERS.try_with(|thread_buffers| {
+            let mut thread_buffers = thread_buffers.borrow_mut();
+            let idx = self.thread_buffer_idx(&mut thread_buffers);
+            thread_buffers[idx].1.lock().unwrap().skipped = Some(thread_id);
+        });
+    }
+
+    // The index of the buffer of the current thread in `thread_buffers`,
+    // which is registered when the thread records its first event
+    #[inline]
+    fn thread_buffer_idx(&self, thread_buffers: &mut Vec<(u64, ThreadBuffer)>) -> usize {
+        match thread_buffers.iter().position(|(id, _)| *id == self.id) {
+            Some(idx) => idx,
+            None => self.register_thread_buffer(thread_buffers),
+        }
+    }
+
+    // Removes the event that was appended at `position` from the buffer of
+    // the current thread, if nothing was appended after it and the buffer
+    // wasn't written out since. Returns whether the event was removed.
+    fn remove_last_event(&self, position: BufferPosition) -> bool {
+        THREAD_BUFFERS
+            .try_with(|thread_buffers| {
+                let thread_buffers = thread_buffers.borrow();
+                let thread_buffer = match thread_buffers.iter().find(|(id, _)| *id == self.id) {
+                    Some((_, thread_buffer)) => thread_buffer,
+                    None => return false,
+                };
+                // The `TimingGuard` was dropped on another thread
+                if Arc::as_ptr(thread_buffer) as usize != position.buffer {
+                    return false;
+                }
+
+                let mut buffer = thread_buffer.lock().unwrap();
+                // A buffer that was written out since is empty, or has other
+                // events appended
+                if buffer.appended != position.appended || buffer.events.len() < RAW_EVENT_SIZE {
+                    return false;
+                }
+
+                let len = buffer.events.len() - RAW_EVENT_SIZE;
+                buffer.events.truncate(len);
+                buffer.appended -= RAW_EVENT_SIZE as u64;
+                true
+            })
+            .unwrap_or(false)
+    }
+
+    #[cold]
+    fn register_thread_buffer(&self, thread_buffers: &mut Vec<(u64, ThreadBuffer)>) -> usize {
+        // Buffers of `Profiler`s that have been dropped aren't needed anymore
+        thread_buffers.retain(|(_, buffer)| Arc::strong_count(buffer) > 1);
+
+        let shard = self.next_shard.fetch_add(1, Ordering::Relaxed) % self.event_sinks.len();
+        let buffer = Arc::new(Mutex::new(ThreadEvents {
+            events: Vec::with_capacity(THREAD_BUFFER_SIZE),
+            shard,
+            appended: 0,
+            skipped: None,
+        }));
+        self.thread_buffers.lock().unwrap().push(buffer.clone());
+        thread_buffers.push((self.id, buffer));
+
+        thread_buffers.len() - 1
+    }
+
+    fn write_thread_buffer(&self, buffer: &mut ThreadEvents) {
+        if buffer.events.is_empty() {
+            return;
+        }
+
+        self.event_sinks[buffer.shard].write_bytes(&buffer.events);
+
+        buffer.events.clear();
+    }
+
+    fn write_thread_buffers(&self) {
+        for buffer in self.thread_buffers.lock().unwrap().iter() {
+            self.write_thread_buffer(&mut buffer.lock().unwrap());
+        }
+    }
+
+    fn flush_event_sinks(&self) -> io::Result<()> {
+        for event_sink in &self.event_sinks {
+            event_sink.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Associates a human readable name with the given thread id. Calling this
+    /// again for the same thread id replaces the previous name.
+    pub fn set_thread_name(&self, thread_id: u64, name: &str) {
+        let name = self.string_table.alloc(name);
+
+        let raw_event = RawEvent {
+            event_kind: THREAD_NAME_EVENT_KIND,
+            id: name,
+            thread_id,
+            timestamp: self.timestamp(TimestampKind::Instant),
+            payload: 0,
+        };
+
+        self.write_raw_event(&raw_event);
+    }
+
+    /// Records that the thread `thread_id` started, as an instant event of
+    /// kind `THREAD_START_EVENT_KIND` labeled `name`, and names the thread
+    /// like `set_thread_name()`. Together with `record_thread_stop()` this
+    /// shows when the threads of e.g. a thread pool come and go.
+    ///
+    /// Recording thread starts and stops is optional, profiles without them
+    /// are read as before. Apart from allocating the two event kinds the
+    /// first time either is called, this costs as much as recording an event
+    /// and naming the thread.
+    pub fn record_thread_start(&self, thread_id: u64, name: &str) {
+        self.alloc_thread_lifecycle_kinds();
+        let name = self.string_table.alloc(name);
+        let timestamp = self.timestamp(TimestampKind::Instant);
+
+        self.write_raw_event(&RawEvent {
+            event_kind: THREAD_NAME_EVENT_KIND,
+            id: name,
+            thread_id,
+            timestamp,
+            payload: 0,
+        });
+        self.write_raw_event(&RawEvent {
+            event_kind: THREAD_START_KIND_ID,
+            id: name,
+            thread_id,
+            timestamp,
+            payload: 0,
+        });
+    }
+
+    /// Records that the thread `thread_id` stopped, as an instant event of
+    /// kind `THREAD_STOP_EVENT_KIND`, see `record_thread_start()`.
+    pub fn record_thread_stop(&self, thread_id: u64) {
+        self.alloc_thread_lifecycle_kinds();
+        self.record_event(
+            THREAD_STOP_KIND_ID,
+            THREAD_STOP_KIND_ID,
+            thread_id,
+            TimestampKind::Instant,
         );
     }
+
+    #[inline]
+    fn alloc_thread_lifecycle_kinds(&self) {
+        self.thread_lifecycle_kinds.call_once(|| {
+            self.string_table
+                .alloc_internal(THREAD_START_KIND_ID, THREAD_START_EVENT_KIND);
+            self.string_table
+                .alloc_internal(THREAD_STOP_KIND_ID, THREAD_STOP_EVENT_KIND);
+        });
+    }
+
+    /// Attaches the key/value pairs `args` to the event that was recorded for
+    /// `thread_id` last, e.g. `crate = "foo"` and `items = 42` to the start of
+    /// an interval. Readers expose them as `Event::args`, and `crox` shows
+    /// them as the `args` of the event. Calling this again for the same event
+    /// adds more arguments.
+    ///
+    /// The arguments are written as a separate record right after the event,
+    /// taking 32 bytes plus 16 bytes per argument, so unlike an integer
+    /// argument, see `start_recording_interval_event_with_arg()`, they are
+    /// meant for events that are rare enough for this to not matter. Profiles
+    /// without arguments are not affected. The event must have been recorded
+    /// on the calling thread and the `Profiler` must not have been flushed
+    /// since, otherwise the arguments are dropped when reading the profile.
+    /// Arguments for an event that wasn't recorded because of a `Throttle`,
+    /// see `Profiler::set_throttle()`, are dropped right away.
+    pub fn record_args_event(&self, thread_id: u64, args: &[(StringId, ArgValue)]) {
+        if S::DISCARDS_DATA || args.is_empty() {
+            return;
+        }
+
+        self.write_buffered(&serialize_args_record(thread_id, args), Some(thread_id));
+    }
+
+    /// Records an instant event, i.e. an event that marks a single point in
+    /// time and has no corresponding "end" event.
+    #[inline]
+    pub fn record_instant_event(
+        &self,
+        event_kind: impl Into<EventKind>,
+        event_id: StringId,
+        thread_id: u64,
+    ) {
+        self.record_event(event_kind, event_id, thread_id, TimestampKind::Instant);
+    }
+
+    /// Creates a "start" event and returns a `TimingGuard` that will create
+    /// the corresponding "end" event when it is dropped.
+    pub fn start_recording_interval_event<'a>(
+        &'a self,
+        event_kind: impl Into<EventKind>,
+        event_id: StringId,
+        thread_id: u64,
+    ) -> TimingGuard<'a, S, C> {
+        let event_kind = self.event_kind_id(event_kind);
+        self.start_interval(event_kind, event_id, thread_id, 0, false)
+    }
+
+    /// Like `start_recording_interval_event()`, but additionally stores
+    /// `category` in the "start" event, which trace viewers use to group and
+    /// color events, e.g. by the subsystem that recorded them. Exporters like
+    /// `crox` fall back to the event kind for events without a category.
+    ///
+    /// The category is stored in the otherwise unused payload of the "start"
+    /// event, see `RawEvent`, so it takes no extra space in the profile, but
+    /// an event can't have both a category and an integer argument.
+    pub fn start_recording_interval_event_with_category<'a>(
+        &'a self,
+        event_kind: impl Into<EventKind>,
+        event_id: StringId,
+        category: StringId,
+        thread_id: u64,
+    ) -> TimingGuard<'a, S, C> {
+        let event_kind = self.event_kind_id(event_kind);
+        debug_assert!(
+            category != StringId::INVALID,
+            "event recorded with `StringId::INVALID` as category"
+        );
+
+        let payload = RawEvent::category_payload(category);
+        self.start_interval(event_kind, event_id, thread_id, payload, false)
+    }
+
+    /// Like `start_recording_interval_event()`, but additionally stores the
+    /// integer `arg` in the "start" event. This avoids allocating a string for
+    /// values like the number of items in a batch.
+    pub fn start_recording_interval_event_with_arg<'a>(
+        &'a self,
+        event_kind: impl Into<EventKind>,
+        event_id: StringId,
+        thread_id: u64,
+        arg: u64,
+    ) -> TimingGuard<'a, S, C> {
+        let event_kind = self.event_kind_id(event_kind);
+        self.start_interval(event_kind, event_id, thread_id, arg, true)
+    }
+
+    // Records the "start" event of an interval, unless the throttle of its
+    // kind skips it, and returns the guard that records the "end" event
+    #[inline]
+    fn start_interval(
+        &self,
+        event_kind: StringId,
+        event_id: StringId,
+        thread_id: u64,
+        payload: u64,
+        with_arg: bool,
+    ) -> TimingGuard<'_, S, C> {
+        debug_assert_allocated(event_kind, event_id);
+
+        let start = |timestamp: Timestamp| RawEvent {
+            event_kind,
+            id: event_id,
+            thread_id,
+            timestamp: if with_arg {
+                timestamp.with_arg()
+            } else {
+                timestamp
+            },
+            payload,
+        };
+
+        let end = match self.throttle(event_kind).map(|t| (t, t.throttle)) {
+            Some((throttle, Throttle::OneIn(n))) if !throttle.count(n) => {
+                self.skip_event(thread_id);
+                IntervalEnd::Skip
+            }
+            Some((_, Throttle::MinDuration(min_duration))) => {
+                let start_nanos = self.clock.now_nanos();
+                let timestamp = self.timestamp_at(start_nanos, TimestampKind::Start);
+                match self.write_raw_event(&start(timestamp)) {
+                    Some(position) => IntervalEnd::IfLongerThan {
+                        min_nanos: min_duration.as_nanos().min(u128::from(u64::MAX)) as u64,
+                        start_nanos,
+                        position,
+                    },
+                    None => IntervalEnd::Record,
+                }
+            }
+            _ => {
+                self.write_raw_event(&start(self.timestamp(TimestampKind::Start)));
+                IntervalEnd::Record
+            }
+        };
+
+        TimingGuard {
+            profiler: self,
+            event_id,
+            event_kind,
+            thread_id,
+            end,
+        }
+    }
+
+    // Records the "end" event of an interval of a kind throttled with
+    // `Throttle::MinDuration`, or removes its "start" event again if the
+    // interval was too short
+    fn end_interval_if_longer_than(
+        &self,
+        guard: &TimingGuard<'_, S, C>,
+        min_nanos: u64,
+        start_nanos: u64,
+        position: BufferPosition,
+    ) {
+        let end_nanos = self.clock.now_nanos();
+        if end_nanos.saturating_sub(start_nanos) < min_nanos && self.remove_last_event(position) {
+            self.skip_event(guard.thread_id);
+            return;
+        }
+
+        self.write_raw_event(&RawEvent {
+            event_kind: guard.event_kind,
+            id: guard.event_id,
+            thread_id: guard.thread_id,
+            timestamp: self.timestamp_at(end_nanos, TimestampKind::End),
+            payload: 0,
+        });
+    }
+
+    /// Like `start_recording_interval_event()`, but takes a closure that
+    /// returns the event id as a string, e.g.
+    /// `|| format!("typeck({:?})", def_id)`, and only calls it if the
+    /// events are actually recorded. With a `NullSink`, as used by
+    /// `Profiler::null()`, the closure isn't called at all, so that formatting
+    /// the id costs nothing while profiling is disabled.
+    ///
+    /// When recording, the closure is called right away, before the "start"
+    /// event is recorded, and the string is allocated like via
+    /// `alloc_string()`. Calling it when the interval ends instead wouldn't
+    /// make recording any cheaper, and the "start" event would refer to a
+    /// string that is only written at the end: a profile that is read while
+    /// the interval is still open, e.g. after `flush()`, a crash, or via
+    /// `dump_snapshot()`, couldn't resolve the id of the event.
+    pub fn start_recording_interval_event_lazy<'a, E: AsRef<str>>(
+        &'a self,
+        event_kind: impl Into<EventKind>,
+        thread_id: u64,
+        event_id: impl FnOnce() -> E,
+    ) -> TimingGuard<'a, S, C> {
+        let event_kind = self.event_kind_id(event_kind);
+        let event_id = if S::DISCARDS_DATA {
+            // Nothing is recorded, the id only has to be a valid one
+            event_kind
+        } else {
+            self.string_table.alloc(event_id().as_ref())
+        };
+
+        self.start_recording_interval_event(event_kind, event_id, thread_id)
+    }
+
+    /// Like `start_recording_interval_event()`, but uses the id of the calling
+    /// thread as returned by `current_thread_id()`.
+    #[inline]
+    pub fn start_recording_interval_event_for_current_thread<'a>(
+        &'a self,
+        event_kind: impl Into<EventKind>,
+        event_id: StringId,
+    ) -> TimingGuard<'a, S, C> {
+        let thread_id = if S::DISCARDS_DATA {
+            0
+        } else {
+            current_thread_id()
+        };
+
+        self.start_recording_interval_event(event_kind, event_id, thread_id)
+    }
+
+    /// Records a "start" event for the asynchronous task `task_id` instead of
+    /// a thread and returns a `TaskEvent`, which is passed to
+    /// `end_task_event()` to record the "end" event. Both can be called on
+    /// any thread, so that an interval spanning `.await` points is attributed
+    /// to the task even if it moves between the threads of an executor.
+    ///
+    /// The events are stored with `TASK_ID_FLAG | task_id` as their thread
+    /// id, so readers treat each task like a thread of its own, see
+    /// `Event::task_id()`. Task ids must be smaller than `TASK_ID_FLAG`.
+    ///
+    /// Task events are written to the event sink right away instead of being
+    /// collected in the buffer of the thread, so that the "end" event can't
+    /// end up in the `.events` file before the "start" event. This makes them
+    /// more expensive to record than events of threads.
+    pub fn start_task_event(
+        &self,
+        task_id: u64,
+        event_kind: impl Into<EventKind>,
+        event_id: StringId,
+    ) -> TaskEvent {
+        debug_assert!(
+            task_id & TASK_ID_FLAG == 0,
+            "task id {} has the `TASK_ID_FLAG` set",
+            task_id
+        );
+        let event_kind = self.event_kind_id(event_kind);
+        debug_assert_allocated(event_kind, event_id);
+
+        let task_event = TaskEvent {
+            event_kind,
+            event_id,
+            thread_id: task_id | TASK_ID_FLAG,
+        };
+        self.write_task_event(&task_event, TimestampKind::Start);
+
+        task_event
+    }
+
+    /// Records the "end" event of an interval started via
+    /// `start_task_event()`.
+    pub fn end_task_event(&self, task_event: TaskEvent) {
+        self.write_task_event(&task_event, TimestampKind::End);
+    }
+
+    fn write_task_event(&self, task_event: &TaskEvent, timestamp_kind: TimestampKind) {
+        if S::DISCARDS_DATA {
+            return;
+        }
+
+        let raw_event = RawEvent {
+            event_kind: task_event.event_kind,
+            id: task_event.event_id,
+            thread_id: task_event.thread_id,
+            timestamp: self.timestamp(timestamp_kind),
+            payload: 0,
+        };
+
+        self.event_sinks[0].write_bytes(&raw_event.to_bytes());
+
+        if let Some(observer) = &self.observer {
+            observer(&raw_event);
+        }
+    }
+}
+
+#[cfg(any(not(target_arch = "wasm32"), target_os = "wasi"))]
+impl Profiler<FileSerializationSink> {
+    /// Like `Profiler::new()`, but the file sinks buffer up to `buffer_size`
+    /// bytes each before writing to their file.
+    pub fn with_buffer_size(
+        path_stem: &Path,
+        buffer_size: usize,
+    ) -> Result<Profiler<FileSerializationSink>, ProfilerError> {
+        Profiler::with_sink_constructor(
+            path_stem,
+            |path| FileSerializationSink::with_buffer_size(path, buffer_size),
+            InstantClock::new(),
+            &ProfileMetadataBuilder::new(),
+        )
+    }
+
+    /// Like `Profiler::new()`, but keeps the `.events` file from growing
+    /// without bound, e.g. when profiling a long-running daemon: once
+    /// writing would make it larger than `max_file_size` bytes, the events
+    /// continue in `<path_stem>.1.events`, then `<path_stem>.2.events`, and
+    /// so on, see `ProfilerFiles::rotated_events_file()`. Segments left
+    /// behind by an earlier profile at `path_stem` are removed.
+    ///
+    /// Events are only ever split between segments at the boundaries of the
+    /// batches they are written in, so every segment holds complete events.
+    /// `ProfilingData::new()` reads the segments in order and concatenates
+    /// them, which keeps the events in the order they were written.
+    ///
+    /// The string table isn't rotated. All segments share the
+    /// `.string_data` and `.string_index` files, so strings allocated early
+    /// on can be referred to from any segment, and a segment can't be read
+    /// on its own. The string table keeps growing with the number of
+    /// distinct strings, but not with the number of events.
+    pub fn with_rotation(
+        path_stem: &Path,
+        max_file_size: u32,
+    ) -> Result<Profiler<FileSerializationSink>, ProfilerError> {
+        let paths = ProfilerFiles::new(path_stem);
+
+        for segment in 1.. {
+            match fs::remove_file(ProfilerFiles::rotated_events_file(path_stem, segment)) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+                Err(e) => return Err(ProfilerError::EventsFile(e)),
+            }
+        }
+
+        let segment_stem = path_stem.to_path_buf();
+        let event_sink = FileSerializationSink::with_rotation(
+            &paths.events_file,
+            max_file_size,
+            move |segment| ProfilerFiles::rotated_events_file(&segment_stem, segment),
+        )
+        .map_err(ProfilerError::EventsFile)?;
+        let string_data_sink = FileSerializationSink::from_path(&paths.string_data_file)
+            .map_err(ProfilerError::StringDataFile)?;
+        let string_index_sink = FileSerializationSink::from_path(&paths.string_index_file)
+            .map_err(ProfilerError::StringIndexFile)?;
+
+        Profiler::with_sinks(
+            Arc::new(event_sink),
+            Arc::new(string_data_sink),
+            Arc::new(string_index_sink),
+        )
+        .with_written_headers()
+    }
+
+    /// Resumes the profile at `path_stem`, e.g. one written by an earlier
+    /// run of the program, instead of replacing it like `Profiler::new()`
+    /// does. New events and strings are appended to the existing files, and
+    /// new string ids are allocated after the largest ones in the string
+    /// table, so that they don't collide with the existing strings.
+    ///
+    /// The timeline of the profile continues: timestamps count from the
+    /// `ProfileMetadata::start_time` of the profile, so that the time between
+    /// the runs shows up as a gap, but never go back before the last event
+    /// that was recorded, even if the wall clock did. The metadata of the
+    /// profile is kept as it is.
+    ///
+    /// Only profiles in the current file format that were written to three
+    /// separate files can be resumed. Compressed, sharded, rotated and packed
+    /// profiles, and files written by other versions of `measureme`, are
+    /// rejected. Records that were written only partially, because the
+    /// earlier process was killed, are cut off before appending.
+    pub fn open_append(path_stem: &Path) -> Result<Profiler<FileSerializationSink>, ProfilerError> {
+        let paths = ProfilerFiles::new(path_stem);
+        // Appending to the first segment would put the new events before
+        // those of the other segments
+        if ProfilerFiles::rotated_events_file(path_stem, 1).exists() {
+            return Err(ProfilerError::EventsFile(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "`{}` is the first segment of a rotated events file and can't be appended to",
+                    paths.events_file.display()
+                ),
+            )));
+        }
+        let event_data = read_appendable_file(
+            &paths.events_file,
+            FILE_MAGIC_EVENT_STREAM,
+            std::mem::size_of::<RawEvent>(),
+        )
+        .map_err(ProfilerError::EventsFile)?;
+        let string_data =
+            read_appendable_file(&paths.string_data_file, FILE_MAGIC_STRINGTABLE_DATA, 1)
+                .map_err(ProfilerError::StringDataFile)?;
+        let index_data =
+            read_appendable_file(&paths.string_index_file, FILE_MAGIC_STRINGTABLE_INDEX, 8)
+                .map_err(ProfilerError::StringIndexFile)?;
+
+        let event_sink = FileSerializationSink::open_append(&paths.events_file)
+            .map_err(ProfilerError::EventsFile)?;
+        let string_data_sink = FileSerializationSink::open_append(&paths.string_data_file)
+            .map_err(ProfilerError::StringDataFile)?;
+        let string_index_sink = FileSerializationSink::open_append(&paths.string_index_file)
+            .map_err(ProfilerError::StringIndexFile)?;
+        let string_table = StringTableBuilder::resume(
+            Arc::new(string_data_sink),
+            Arc::new(string_index_sink),
+            &index_data,
+        );
+
+        let profiling_data = ProfilingData::from_buffers(event_data, string_data, index_data)
+            .map_err(|e| {
+                ProfilerError::EventsFile(io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+            })?;
+        let last_nanos = profiling_data.last_timestamp_nanos().unwrap_or(0);
+        let metadata = profiling_data.try_metadata().ok();
+
+        let clock = InstantClock::new();
+        let now = SystemTime::now();
+        let elapsed_nanos = metadata
+            .as_ref()
+            .and_then(|metadata| now.duration_since(metadata.start_time).ok())
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+        let clock_offset = elapsed_nanos
+            .saturating_sub(clock.now_nanos())
+            .max(last_nanos);
+        let start_wall_time = match &metadata {
+            Some(metadata) => metadata.start_time,
+            None => now
+                .checked_sub(Duration::from_nanos(clock_offset + clock.now_nanos()))
+                .unwrap_or(now),
+        };
+
+        let profiler = Profiler::from_parts(
+            vec![Arc::new(event_sink)],
+            string_table,
+            clock,
+            clock_offset,
+            start_wall_time,
+        );
+        if metadata.is_none() {
+            profiler.alloc_process_metadata(&ProfileMetadataBuilder::new());
+        }
+
+        Ok(profiler)
+    }
+}
+
+// Reads a trace file for `Profiler::open_append()` and checks that it can be
+// appended to. A partially written record at the end of the file is cut off,
+// so that the appended data starts at a multiple of `record_size`.
+#[cfg(any(not(target_arch = "wasm32"), target_os = "wasi"))]
+fn read_appendable_file(
+    path: &Path,
+    file_magic: &[u8; 4],
+    record_size: usize,
+) -> io::Result<Vec<u8>> {
+    let mut data = fs::read(path)?;
+    let invalid_data = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+    if data.starts_with(FILE_MAGIC_COMPRESSED) {
+        return Err(invalid_data(format!(
+            "`{}` is compressed and can't be appended to",
+            path.display()
+        )));
+    }
+    check_file_magic(path, &data, file_magic)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let version = read_file_header(&data, file_magic).map_err(|e| invalid_data(e.to_string()))?;
+    if version != CURRENT_FILE_FORMAT_VERSION {
+        return Err(invalid_data(format!(
+            "`{}` has file format version '{}', but only version '{}' can be appended to",
+            path.display(),
+            version,
+            CURRENT_FILE_FORMAT_VERSION
+        )));
+    }
+
+    let len = FILE_HEADER_SIZE + (data.len() - FILE_HEADER_SIZE) / record_size * record_size;
+    if len < data.len() {
+        data.truncate(len);
+        fs::OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(len as u64)?;
+    }
+
+    Ok(data)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Profiler<BackgroundSink<FileSerializationSink>> {
+    /// Like `Profiler::new()`, but each trace file is written by a dedicated
+    /// thread, see `BackgroundSink`. The threads are joined when the
+    /// `Profiler` is finished or dropped.
+    pub fn new_background(
+        path_stem: &Path,
+    ) -> Result<Profiler<BackgroundSink<FileSerializationSink>>, ProfilerError> {
+        Profiler::new(path_stem)
+    }
+}
+
+#[cfg(all(
+    feature = "compression",
+    any(not(target_arch = "wasm32"), target_os = "wasi")
+))]
+impl Profiler<CompressedSink<FileSerializationSink>> {
+    /// Like `Profiler::new()`, but zstd-compresses the `.string_data` file
+    /// (behind the `compression` feature), while the `.events` and
+    /// `.string_index` files are written uncompressed.
+    ///
+    /// The string data, mostly query keys and file paths, compresses very
+    /// well and tends to be the largest part of a profile. With query keys
+    /// recorded, the string data of a rustc profile of `regex-syntax` shrank
+    /// from 27.5 MB to 1.5 MB. Events hardly compress, so compressing them
+    /// only costs time. The string data is written in blocks of 256 KiB,
+    /// which `ProfilingData` decompresses when it opens the profile, and the
+    /// string index keeps referring to positions in the uncompressed data.
+    pub fn new_with_compressed_strings(
+        path_stem: &Path,
+    ) -> Result<Profiler<CompressedSink<FileSerializationSink>>, ProfilerError> {
+        let paths = ProfilerFiles::new(path_stem);
+        let event_sink = FileSerializationSink::from_path(&paths.events_file)
+            .map_err(ProfilerError::EventsFile)?;
+        let string_data_sink = FileSerializationSink::from_path(&paths.string_data_file)
+            .map_err(ProfilerError::StringDataFile)?;
+        let string_index_sink = FileSerializationSink::from_path(&paths.string_index_file)
+            .map_err(ProfilerError::StringIndexFile)?;
+
+        Profiler::with_sinks(
+            Arc::new(CompressedSink::pass_through(event_sink)),
+            Arc::new(CompressedSink::new(string_data_sink)),
+            Arc::new(CompressedSink::pass_through(string_index_sink)),
+        )
+        .with_written_headers()
+    }
+}
+
+impl Profiler<InMemorySink> {
+    /// Like `Profiler::new()`, but writes a single `<path_stem>.mm_profile`
+    /// file instead of three files, see `PackedProfile` for its layout. The
+    /// profile is kept in memory and only written when the `Profiler` is
+    /// finished or dropped, so nothing is written if the process crashes.
+    /// Read the file with `ProfilingData::from_packed()`.
+    pub fn new_packed(path_stem: &Path) -> Result<Profiler<InMemorySink>, ProfilerError> {
+        let path = PackedProfile::file_path(path_stem);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(ProfilerError::PackedFile)?;
+        }
+        // Created right away, so that errors are reported here
+        let file = fs::File::create(&path).map_err(ProfilerError::PackedFile)?;
+
+        let event_sink = Arc::new(InMemorySink::new());
+        let string_data_sink = Arc::new(InMemorySink::new());
+        let string_index_sink = Arc::new(InMemorySink::new());
+
+        let mut profiler = Profiler::with_sinks(
+            event_sink.clone(),
+            string_data_sink.clone(),
+            string_index_sink.clone(),
+        );
+
+        profiler.write_packed = Some(Box::new(move || {
+            PackedProfile {
+                events: &event_sink.bytes(),
+                string_data: &string_data_sink.bytes(),
+                string_index: &string_index_sink.bytes(),
+            }
+            .write_to(io::BufWriter::new(&file))
+        }));
+
+        Ok(profiler)
+    }
+}
+
+impl Profiler<RingBufferSink> {
+    /// Creates a `Profiler` that keeps only the most recent `capacity` bytes
+    /// of events in memory, overwriting the oldest ones, e.g. to always
+    /// profile a server and only write out the last few seconds when
+    /// something goes wrong, via `dump_snapshot()`. Nothing is written to
+    /// disk otherwise. See `RingBufferSink` for how the events are
+    /// overwritten.
+    ///
+    /// The string table can't be a ring buffer: an event refers to its
+    /// strings by their `StringId`, so a string must be kept for as long as
+    /// any event that refers to it, and strings are usually allocated once
+    /// and referred to by many events, old and new. The string table is
+    /// therefore kept in memory in full, so it grows with every string
+    /// allocated. Strings that are allocated over and over should be
+    /// allocated once up front, or via reserved ids, for this to stay small.
+    pub fn new_ring_buffer(capacity: usize) -> Profiler<RingBufferSink> {
+        Profiler::with_sinks(
+            Arc::new(RingBufferSink::new(capacity)),
+            Arc::new(RingBufferSink::unbounded()),
+            Arc::new(RingBufferSink::unbounded()),
+        )
+    }
+
+    /// Writes the events currently in the ring buffer, including those still
+    /// buffered by each thread, along with the string table to
+    /// `<path_stem>.events`, `<path_stem>.string_data`, and
+    /// `<path_stem>.string_index`, which can be read via
+    /// `ProfilingData::new()`. Recording continues afterwards and the
+    /// snapshot can be taken any number of times.
+    ///
+    /// The events of the snapshot are a consistent profile, apart from the
+    /// start events whose intervals began before the oldest retained event,
+    /// whose end events are thus unmatched, and thread names, which are lost
+    /// once their records are overwritten. The snapshot isn't marked as
+    /// complete, see `ProfilingData::is_complete()`.
+    pub fn dump_snapshot(&self, path_stem: &Path) -> Result<(), ProfilerError> {
+        self.write_thread_buffers();
+        let events = self.event_sinks[0].to_event_stream();
+
+        // Taken after the events: every string an event refers to is
+        // allocated before the event is recorded, so the string table of the
+        // snapshot has all of them, even with other threads recording
+        // concurrently
+        self.string_table
+            .flush()
+            .map_err(ProfilerError::StringDataFile)?;
+        let (string_data_sink, string_index_sink) = self.string_table.sinks();
+        let string_data = string_data_sink.to_bytes();
+        let string_index = string_index_sink.to_bytes();
+
+        let paths = ProfilerFiles::new(path_stem);
+        if let Some(parent) = paths.events_file.parent() {
+            fs::create_dir_all(parent).map_err(ProfilerError::EventsFile)?;
+        }
+        fs::write(&paths.events_file, events).map_err(ProfilerError::EventsFile)?;
+        fs::write(&paths.string_data_file, string_data).map_err(ProfilerError::StringDataFile)?;
+        fs::write(&paths.string_index_file, string_index).map_err(ProfilerError::StringIndexFile)
+    }
+}
+
+impl<S: SerializationSink, C: Clock> Drop for Profiler<S, C> {
+    fn drop(&mut self) {
+        // Errors can't be reported from here, `finish()` returns them instead
+        let _ = self.shut_down();
+    }
+}
+
+// Catches ids that were used before their string was allocated
+#[inline(always)]
+fn debug_assert_allocated(event_kind: StringId, event_id: StringId) {
+    debug_assert!(
+        event_kind != StringId::INVALID,
+        "event recorded with `StringId::INVALID` as event kind"
+    );
+    debug_assert!(
+        event_id != StringId::INVALID,
+        "event recorded with `StringId::INVALID` as event id"
+    );
+}
+
+/// When dropped, this `TimingGuard` will record an "end" event in the
+/// `Profiler` it was created by. Use `finish()` to record it at a point
+/// that doesn't coincide with the end of a scope, or `cancel()` to not
+/// record it at all.
+#[must_use]
+pub struct TimingGuard<'a, S: SerializationSink, C: Clock = InstantClock> {
+    profiler: &'a Profiler<S, C>,
+    event_id: StringId,
+    event_kind: StringId,
+    thread_id: u64,
+    end: IntervalEnd,
+}
+
+// What a `TimingGuard` records when it is dropped, see `Profiler::set_throttle()`
+enum IntervalEnd {
+    Record,
+    // The "start" event was skipped, so there is nothing to end
+    Skip,
+    // The "start" event was appended to the buffer of the thread at
+    // `position`, and is removed again if the interval is too short
+    IfLongerThan {
+        min_nanos: u64,
+        start_nanos: u64,
+        position: BufferPosition,
+    },
+}
+
+impl<'a, S: SerializationSink, C: Clock> TimingGuard<'a, S, C> {
+    /// Records the "end" event now. This is the same as dropping the guard,
+    /// but makes the point at which the interval ends explicit.
+    #[inline]
+    pub fn finish(self) {
+        drop(self);
+    }
+
+    /// Consumes the guard without recording an "end" event, e.g. for an
+    /// operation that was aborted. The "start" event has been recorded
+    /// already, so `ProfilingData::iter_intervals()` reports it as an
+    /// `IntervalError::UnmatchedStart` instead of yielding an interval, and
+    /// an enclosing interval that ends after it as `IntervalError::Overlapping`.
+    #[inline]
+    pub fn cancel(self) {
+        std::mem::forget(self);
+    }
+}
+
+impl<'a, S: SerializationSink, C: Clock> Drop for TimingGuard<'a, S, C> {
+    #[inline]
+    fn drop(&mut self) {
+        match self.end {
+            IntervalEnd::Record => self.profiler.record_event(
+                self.event_kind,
+                self.event_id,
+                self.thread_id,
+                TimestampKind::End,
+            ),
+            IntervalEnd::Skip => self.profiler.skip_event(self.thread_id),
+            IntervalEnd::IfLongerThan {
+                min_nanos,
+                start_nanos,
+                position,
+            } => self
+                .profiler
+                .end_interval_if_longer_than(self, min_nanos, start_nanos, position),
+        }
+    }
+}
+
+/// Returned by `Profiler::start_task_event()`. Unlike a `TimingGuard`, it
+/// doesn't borrow the `Profiler` and doesn't record anything when it is
+/// dropped, so it can be kept across `.await` points and sent to other
+/// threads. Pass it to `Profiler::end_task_event()` to end the interval.
+#[must_use]
+#[derive(Debug)]
+pub struct TaskEvent {
+    event_kind: StringId,
+    event_id: StringId,
+    thread_id: u64,
+}
+
+impl TaskEvent {
+    /// The task the interval was started for.
+    pub fn task_id(&self) -> u64 {
+        self.thread_id & !TASK_ID_FLAG
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_header::FILE_HEADER_SIZE;
+    use crate::raw_event::RAW_EVENT_SIZE;
+    use crate::{
+        Addr, Event, EventArgValue, InMemorySink, IntervalError, MatchingEvent, ProfilingData,
+    };
+    use std::cell::Cell;
+
+    // Records a profile into `InMemorySink`s via `record` and reads it back
+    fn in_memory_profile<C: Clock>(
+        clock: C,
+        record: impl FnOnce(&mut Profiler<InMemorySink, C>),
+    ) -> ProfilingData {
+        let event_sink = Arc::new(InMemorySink::new());
+        let string_data_sink = Arc::new(InMemorySink::new());
+        let string_index_sink = Arc::new(InMemorySink::new());
+
+        {
+            let mut profiler = Profiler::with_sinks_and_clock(
+                event_sink.clone(),
+                string_data_sink.clone(),
+                string_index_sink.clone(),
+                clock,
+            );
+            record(&mut profiler);
+        }
+
+        ProfilingData::from_buffers(
+            Arc::try_unwrap(event_sink).unwrap().into_bytes(),
+            Arc::try_unwrap(string_data_sink).unwrap().into_bytes(),
+            Arc::try_unwrap(string_index_sink).unwrap().into_bytes(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn event_args() {
+        let profiling_data = in_memory_profile(InstantClock::new(), |profiler| {
+            let event_kind = profiler.alloc_string("Kind");
+            let event_id = profiler.alloc_string("Id");
+
+            // Fills the thread buffer up to the start event, so that it is
+            // full when the arguments are recorded
+            for _ in 1..THREAD_BUFFER_SIZE / RAW_EVENT_SIZE {
+                profiler.record_instant_event(event_kind, event_id, 1);
+            }
+
+            let guard = profiler.start_recording_interval_event(event_kind, event_id, 1);
+
+            // Fills the buffer of another thread in the meantime
+            std::thread::scope(|scope| {
+                scope.spawn(|| {
+                    for _ in 0..=THREAD_BUFFER_SIZE / RAW_EVENT_SIZE {
+                        profiler.record_instant_event(event_kind, event_id, 3);
+                    }
+                });
+            });
+
+            profiler.record_args_event(
+                1,
+                &[
+                    (
+                        profiler.alloc_string("crate"),
+                        ArgValue::Str(profiler.alloc_string("foo")),
+                    ),
+                    (profiler.alloc_string("items"), ArgValue::Int(42)),
+                ],
+            );
+            profiler.record_args_event(
+                1,
+                &[
+                    (profiler.alloc_string("ratio"), ArgValue::Float(0.5)),
+                    (profiler.alloc_string("cached"), ArgValue::Bool(true)),
+                ],
+            );
+            profiler.record_args_event(1, &[]);
+            guard.finish();
+
+            // Not attached to an event of thread 2, so dropped
+            profiler.record_args_event(2, &[(event_id, ArgValue::Int(1))]);
+        });
+
+        let (start, end) = profiling_data
+            .iter_matching_events()
+            .find_map(|e| match e {
+                MatchingEvent::StartStop(start, end) => Some((start, end)),
+                _ => None,
+            })
+            .unwrap();
+
+        let args: Vec<_> = start
+            .args
+            .iter()
+            .map(|(key, value)| (&key[..], value.clone()))
+            .collect();
+        assert_eq!(
+            args,
+            [
+                ("cached", EventArgValue::Bool(true)),
+                ("crate", EventArgValue::Str("foo".into())),
+                ("items", EventArgValue::Int(42)),
+                ("ratio", EventArgValue::Float(0.5)),
+            ]
+        );
+        assert!(end.args.is_empty());
+
+        let mut events = profiling_data.iter();
+        assert_eq!(events.by_ref().filter(|e| !e.args.is_empty()).count(), 1);
+        assert_eq!(events.by_ref().count(), 0);
+        assert_eq!(events.skipped_records(), 0);
+        assert_eq!(
+            profiling_data.iter().count(),
+            2 * (THREAD_BUFFER_SIZE / RAW_EVENT_SIZE + 1)
+        );
+    }
+
+    #[test]
+    fn events_for_current_thread() {
+        let event_sink = Arc::new(InMemorySink::new());
+        let string_data_sink = Arc::new(InMemorySink::new());
+        let string_index_sink = Arc::new(InMemorySink::new());
+
+        let (main_thread_id, other_thread_id) = {
+            let profiler = Profiler::with_sinks(
+                event_sink.clone(),
+                string_data_sink.clone(),
+                string_index_sink.clone(),
+            );
+            let event_kind = profiler.alloc_string("Kind");
+            let event_id = profiler.alloc_string("Id");
+
+            {
+                let _guard = profiler
+                    .start_recording_interval_event_for_current_thread(event_kind, event_id);
+            }
+
+            let other_thread_id = std::thread::scope(|scope| {
+                scope
+                    .spawn(|| {
+                        profiler.record_event_for_current_thread(
+                            event_kind,
+                            event_id,
+                            TimestampKind::Instant,
+                        );
+                        current_thread_id()
+                    })
+                    .join()
+                    .unwrap()
+            });
+
+            (current_thread_id(), other_thread_id)
+        };
+
+        assert_eq!(main_thread_id, current_thread_id());
+        assert_ne!(main_thread_id, other_thread_id);
+
+        let profiling_data = ProfilingData::from_buffers(
+            Arc::try_unwrap(event_sink).unwrap().into_bytes(),
+            Arc::try_unwrap(string_data_sink).unwrap().into_bytes(),
+            Arc::try_unwrap(string_index_sink).unwrap().into_bytes(),
+        )
+        .unwrap();
+
+        let thread_ids: Vec<_> = profiling_data.iter().map(|e| e.thread_id).collect();
+        assert_eq!(
+            thread_ids,
+            [main_thread_id, main_thread_id, other_thread_id]
+        );
+    }
+
+    #[test]
+    fn resolve_strings() {
+        let event_sink = Arc::new(InMemorySink::new());
+        let string_data_sink = Arc::new(InMemorySink::new());
+        let string_index_sink = Arc::new(InMemorySink::new());
+
+        let (regular_id, reserved_id) = {
+            let profiler = Profiler::with_sinks(
+                event_sink.clone(),
+                string_data_sink.clone(),
+                string_index_sink.clone(),
+            );
+
+            let reserved_id =
+                profiler.alloc_string_with_reserved_id(StringId::reserved(42), "reserved");
+            (profiler.alloc_string("regular"), reserved_id)
+        };
+
+        let profiling_data = ProfilingData::from_buffers(
+            Arc::try_unwrap(event_sink).unwrap().into_bytes(),
+            Arc::try_unwrap(string_data_sink).unwrap().into_bytes(),
+            Arc::try_unwrap(string_index_sink).unwrap().into_bytes(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            profiling_data.resolve_string(regular_id).unwrap(),
+            "regular"
+        );
+        assert_eq!(
+            profiling_data.resolve_string(reserved_id).unwrap(),
+            "reserved"
+        );
+        assert_eq!(profiling_data.resolve_string(StringId::reserved(43)), None);
+
+        let metadata = profiling_data.metadata();
+        assert_eq!(metadata.process_id, std::process::id());
+        assert!(metadata.start_time <= std::time::SystemTime::now());
+        assert!(!metadata.cmd.is_empty());
+        assert_eq!(profiling_data.try_metadata(), Ok(metadata));
+
+        let metadata: Vec<_> = profiling_data.metadata_strings().collect();
+        assert_eq!(metadata.len(), 2);
+        assert!(metadata[0].1.contains(r#""process_id": "#));
+        assert_eq!(metadata[1].1, "ThreadName");
+        assert!(metadata
+            .iter()
+            .all(|(id, _)| *id != regular_id && *id != reserved_id));
+    }
+
+    #[test]
+    fn events_from_many_threads() {
+        const THREADS: u64 = 4;
+        // Enough events to fill each thread's buffer several times
+        const EVENTS_PER_THREAD: u64 = 1000;
+
+        let profiling_data = in_memory_profile(InstantClock::new(), |profiler| {
+            let counter_kind = profiler.alloc_string("Counter");
+
+            std::thread::scope(|scope| {
+                for thread_id in 0..THREADS {
+                    let profiler = &profiler;
+                    scope.spawn(move || {
+                        for value in 0..EVENTS_PER_THREAD {
+                            profiler.record_counter(counter_kind, thread_id, value);
+                        }
+                    });
+                }
+            });
+        });
+
+        let mut values = vec![Vec::new(); THREADS as usize];
+        for event in profiling_data.iter() {
+            values[event.thread_id as usize].push(event.value.unwrap());
+        }
+
+        // The events of each thread must be in the order they were recorded in
+        let expected: Vec<_> = (0..EVENTS_PER_THREAD).collect();
+        for thread_values in values {
+            assert_eq!(thread_values, expected);
+        }
+    }
+
+    #[test]
+    fn events_from_many_threads_in_shards() {
+        const THREADS: u64 = 4;
+        const EVENTS_PER_THREAD: u64 = 1000;
+
+        let event_sinks = vec![Arc::new(InMemorySink::new()), Arc::new(InMemorySink::new())];
+        let string_data_sink = Arc::new(InMemorySink::new());
+        let string_index_sink = Arc::new(InMemorySink::new());
+
+        {
+            let profiler = Profiler::with_shards_and_clock(
+                event_sinks.clone(),
+                string_data_sink.clone(),
+                string_index_sink.clone(),
+                InstantClock::new(),
+                &ProfileMetadataBuilder::new(),
+            );
+            let counter_kind = profiler.alloc_string("Counter");
+
+            std::thread::scope(|scope| {
+                for thread_id in 0..THREADS {
+                    let profiler = &profiler;
+                    scope.spawn(move || {
+                        for value in 0..EVENTS_PER_THREAD {
+                            profiler.record_counter(counter_kind, thread_id, value);
+                        }
+                    });
+                }
+            });
+        }
+
+        let event_shards: Vec<_> = event_sinks
+            .into_iter()
+            .map(|sink| Arc::try_unwrap(sink).unwrap().into_bytes())
+            .collect();
+        // Two threads were assigned to each shard
+        let shard_size = FILE_HEADER_SIZE
+            + (2 * EVENTS_PER_THREAD as usize + 1) * std::mem::size_of::<RawEvent>();
+        assert!(event_shards.iter().all(|shard| shard.len() == shard_size));
+
+        let profiling_data = ProfilingData::from_shard_buffers(
+            event_shards,
+            Arc::try_unwrap(string_data_sink).unwrap().into_bytes(),
+            Arc::try_unwrap(string_index_sink).unwrap().into_bytes(),
+        )
+        .unwrap();
+        assert!(profiling_data.is_complete());
+
+        let mut values = vec![Vec::new(); THREADS as usize];
+        for event in profiling_data.iter() {
+            values[event.thread_id as usize].push(event.value.unwrap());
+        }
+
+        let expected: Vec<_> = (0..EVENTS_PER_THREAD).collect();
+        for thread_values in values {
+            assert_eq!(thread_values, expected);
+        }
+    }
+
+    #[test]
+    fn event_args_in_shards() {
+        // Every call advances the clock by 10ns, like `FakeClock`, but can
+        // be shared between threads
+        struct StepClock(AtomicU64);
+
+        impl Clock for StepClock {
+            fn now_nanos(&self) -> u64 {
+                self.0.fetch_add(10, Ordering::Relaxed)
+            }
+        }
+
+        let event_sinks = vec![Arc::new(InMemorySink::new()), Arc::new(InMemorySink::new())];
+        let string_data_sink = Arc::new(InMemorySink::new());
+        let string_index_sink = Arc::new(InMemorySink::new());
+
+        {
+            let profiler = Profiler::with_shards_and_clock(
+                event_sinks.clone(),
+                string_data_sink.clone(),
+                string_index_sink.clone(),
+                StepClock(AtomicU64::new(0)),
+                &ProfileMetadataBuilder::new(),
+            );
+            let event_kind = profiler.alloc_string("Kind");
+            let event_id = profiler.alloc_string("Id");
+            let key = profiler.alloc_string("thread");
+
+            // The interval of thread 1 is nested in the one of thread 0, in
+            // the other shard
+            let guard = profiler.start_recording_interval_event(event_kind, event_id, 0);
+            profiler.record_args_event(0, &[(key, ArgValue::Int(0))]);
+            std::thread::scope(|scope| {
+                scope.spawn(|| {
+                    let guard = profiler.start_recording_interval_event(event_kind, event_id, 1);
+                    profiler.record_args_event(1, &[(key, ArgValue::Int(1))]);
+                    guard.finish();
+                });
+            });
+            guard.finish();
+        }
+
+        let profiling_data = ProfilingData::from_shard_buffers(
+            event_sinks
+                .into_iter()
+                .map(|sink| Arc::try_unwrap(sink).unwrap().into_bytes())
+                .collect(),
+            Arc::try_unwrap(string_data_sink).unwrap().into_bytes(),
+            Arc::try_unwrap(string_index_sink).unwrap().into_bytes(),
+        )
+        .unwrap();
+
+        let thread_arg = |event: Event<'_>| match event.args.get("thread") {
+            Some(EventArgValue::Int(i)) => (event.thread_id, Some(*i)),
+            _ => (event.thread_id, None),
+        };
+        let expected = [(0, Some(0)), (1, Some(1)), (1, None), (0, None)];
+
+        let events: Vec<_> = profiling_data.iter().map(thread_arg).collect();
+        assert_eq!(events, expected);
+        let events: Vec<_> = profiling_data
+            .iter_sorted()
+            .unwrap()
+            .map(thread_arg)
+            .collect();
+        assert_eq!(events, expected);
+        // Both start events are clipped to the range, each yielded when its
+        // thread reaches the range
+        let events: Vec<_> = profiling_data
+            .iter_in_range(25, 100)
+            .map(thread_arg)
+            .collect();
+        assert_eq!(events, [(1, Some(1)), (1, None), (0, Some(0)), (0, None)]);
+    }
+
+    #[test]
+    fn null_profiler() {
+        let profiler = Profiler::null();
+        let event_kind = profiler.alloc_string("Kind");
+        let event_id = profiler.alloc_string("Id");
+        assert_ne!(event_kind, event_id);
+
+        {
+            let _guard = profiler.start_recording_interval_event(event_kind, event_id, 0);
+            let _guard =
+                profiler.start_recording_interval_event_for_current_thread(event_kind, event_id);
+            profiler.record_instant_event(event_kind, event_id, 0);
+            profiler.record_counter(event_kind, 0, 1);
+            profiler.set_thread_name(0, "main");
+        }
+
+        // Nothing has been recorded, so nothing has been buffered either
+        assert!(profiler.thread_buffers.lock().unwrap().is_empty());
+        profiler.finish().unwrap();
+    }
+
+    #[test]
+    fn lazy_event_id() {
+        let formatted = Cell::new(0);
+        let event_id = |key: u32| {
+            let formatted = &formatted;
+            move || {
+                formatted.set(formatted.get() + 1);
+                format!("typeck({})", key)
+            }
+        };
+
+        let profiler = Profiler::null();
+        let event_kind = profiler.alloc_string("Query");
+        drop(profiler.start_recording_interval_event_lazy(event_kind, 0, event_id(1)));
+        assert_eq!(formatted.get(), 0);
+
+        let event_sink = Arc::new(InMemorySink::new());
+        let string_data_sink = Arc::new(InMemorySink::new());
+        let string_index_sink = Arc::new(InMemorySink::new());
+        {
+            let profiler = Profiler::with_sinks(
+                event_sink.clone(),
+                string_data_sink.clone(),
+                string_index_sink.clone(),
+            );
+            let event_kind = profiler.alloc_string("Query");
+            let _guard = profiler.start_recording_interval_event_lazy(event_kind, 0, event_id(2));
+            assert_eq!(formatted.get(), 1);
+        }
+
+        let profiling_data = ProfilingData::from_buffers(
+            Arc::try_unwrap(event_sink).unwrap().into_bytes(),
+            Arc::try_unwrap(string_data_sink).unwrap().into_bytes(),
+            Arc::try_unwrap(string_index_sink).unwrap().into_bytes(),
+        )
+        .unwrap();
+        let intervals: Vec<_> = profiling_data
+            .iter_intervals()
+            .map(|e| e.id.into_owned())
+            .collect();
+        assert_eq!(intervals, ["typeck(2)"]);
+    }
+
+    struct FakeClock(Cell<u64>);
+
+    impl Clock for FakeClock {
+        fn now_nanos(&self) -> u64 {
+            // Every call advances the clock by 10ns
+            let now = self.0.get();
+            self.0.set(now + 10);
+            now
+        }
+    }
+
+    #[test]
+    fn events_with_custom_clock() {
+        let event_sink = Arc::new(InMemorySink::new());
+        let string_data_sink = Arc::new(InMemorySink::new());
+        let string_index_sink = Arc::new(InMemorySink::new());
+
+        let start_wall_time;
+        {
+            let profiler = Profiler::with_sinks_and_clock(
+                event_sink.clone(),
+                string_data_sink.clone(),
+                string_index_sink.clone(),
+                FakeClock(Cell::new(1000)),
+            );
+            let event_kind = profiler.alloc_string("Kind");
+            let event_id = profiler.alloc_string("Id");
+
+            {
+                let _guard = profiler.start_recording_interval_event(event_kind, event_id, 0);
+                profiler.record_instant_event(event_kind, event_id, 0);
+            }
+            profiler.record_counter(event_kind, 0, 5);
+
+            start_wall_time = profiler.start_wall_time();
+        }
+
+        let profiling_data = ProfilingData::from_buffers(
+            Arc::try_unwrap(event_sink).unwrap().into_bytes(),
+            Arc::try_unwrap(string_data_sink).unwrap().into_bytes(),
+            Arc::try_unwrap(string_index_sink).unwrap().into_bytes(),
+        )
+        .unwrap();
+
+        let timestamps: Vec<_> = profiling_data
+            .iter()
+            .map(|e| {
+                e.timestamp
+                    .duration_since(std::time::SystemTime::UNIX_EPOCH)
+                    .unwrap()
+                    .as_nanos()
+            })
+            .collect();
+        // The first reading is taken when the profiler is created
+        assert_eq!(timestamps, [1010, 1020, 1030, 1040]);
+
+        let metadata = profiling_data.metadata();
+        assert_eq!(metadata.start_time, start_wall_time);
+        let first_event = profiling_data.iter().next().unwrap();
+        assert_eq!(
+            metadata.wall_time(&first_event),
+            start_wall_time + std::time::Duration::from_nanos(1010)
+        );
+    }
+
+    #[test]
+    fn throttle_one_in_n() {
+        let profiling_data = in_memory_profile(InstantClock::new(), |profiler| {
+            let query = profiler.alloc_string("Query");
+            let other = profiler.alloc_string("Other");
+            profiler.set_throttle(query, Throttle::OneIn(3));
+
+            for i in 0..7 {
+                let event_id = profiler.alloc_string(&format!("{}", i)[..]);
+                let _query = profiler.start_recording_interval_event(query, event_id, 0);
+                let _other = profiler.start_recording_interval_event(other, event_id, 0);
+            }
+        });
+
+        let mut intervals = profiling_data.iter_intervals();
+        let recorded: Vec<_> = intervals
+            .by_ref()
+            .map(|e| format!("{} {}", e.kind, e.id))
+            .collect();
+        assert!(intervals.errors().is_empty());
+
+        let queries: Vec<_> = recorded.iter().filter(|e| e.starts_with("Query")).collect();
+        assert_eq!(queries, ["Query 0", "Query 3", "Query 6"]);
+        assert_eq!(recorded.len(), 3 + 7);
+    }
+
+    #[test]
+    fn throttle_one_in_n_with_args() {
+        let profiling_data = in_memory_profile(InstantClock::new(), |profiler| {
+            let query = profiler.alloc_string("Query");
+            let start = profiler.alloc_string("start");
+            let end = profiler.alloc_string("end");
+            profiler.set_throttle(query, Throttle::OneIn(2));
+
+            for i in 0..4 {
+                let event_id = profiler.alloc_string(&format!("{}", i)[..]);
+                let guard = profiler.start_recording_interval_event(query, event_id, 0);
+                profiler.record_args_event(0, &[(start, ArgValue::Int(i))]);
+                guard.finish();
+                profiler.record_args_event(0, &[(end, ArgValue::Int(i))]);
+            }
+        });
+
+        // The arguments of the skipped intervals aren't attached to the end
+        // event of the interval before
+        let args: Vec<_> = profiling_data
+            .iter()
+            .map(|e| {
+                let args: Vec<_> = e
+                    .args
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.clone()))
+                    .collect();
+                (e.label.into_owned(), args)
+            })
+            .collect();
+        let arg = |key: &str, i: i64| vec![(key.to_string(), EventArgValue::Int(i))];
+        assert_eq!(
+            args,
+            [
+                ("0".to_string(), arg("start", 0)),
+                ("0".to_string(), arg("end", 0)),
+                ("2".to_string(), arg("start", 2)),
+                ("2".to_string(), arg("end", 2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn throttle_min_duration() {
+        let profiling_data = in_memory_profile(FakeClock(Cell::new(0)), |profiler| {
+            let query = profiler.alloc_string("Query");
+            let other = profiler.alloc_string("Other");
+            profiler.set_throttle(query, Throttle::MinDuration(Duration::from_nanos(100)));
+            let id = |name: &str| profiler.alloc_string(name);
+            // Every reading of the `FakeClock` takes 10ns
+            let wait = |nanos: u64| {
+                for _ in 0..nanos / 10 {
+                    profiler.clock.now_nanos();
+                }
+            };
+
+            // Removed, and so are the short intervals nested in each other
+            drop(profiler.start_recording_interval_event(query, id("short"), 0));
+            {
+                let _outer = profiler.start_recording_interval_event(query, id("outer"), 0);
+                let _inner = profiler.start_recording_interval_event(query, id("inner"), 0);
+            }
+
+            // Long enough
+            {
+                let _long = profiler.start_recording_interval_event(query, id("long"), 0);
+                wait(100);
+            }
+
+            // Too short, but kept for the events recorded within them
+            {
+                let _parent = profiler.start_recording_interval_event(query, id("parent"), 0);
+                let _child = profiler.start_recording_interval_event(other, id("child"), 0);
+            }
+            {
+                let _parent = profiler.start_recording_interval_event(query, id("flushed"), 0);
+                profiler.flush().unwrap();
+            }
+        });
+
+        let mut intervals = profiling_data.iter_intervals();
+        let recorded: Vec<_> = intervals.by_ref().map(|e| e.id.into_owned()).collect();
+        assert!(intervals.errors().is_empty());
+        assert!(profiling_data.validate().is_empty());
+        assert_eq!(recorded, ["long", "child", "parent", "flushed"]);
+    }
+
+    #[test]
+    fn timestamp_overflow() {
+        use crate::raw_event::MAX_TIMESTAMP_NANOS;
+
+        // The first reading is taken when the profiler is created
+        let clock = FakeClock(Cell::new(MAX_TIMESTAMP_NANOS - 15));
+        let profiling_data = in_memory_profile(clock, |profiler| {
+            let event_kind = profiler.alloc_string("Kind");
+
+            profiler.record_event(event_kind, event_kind, 0, TimestampKind::Start);
+            assert!(!profiler.timestamp_overflowed());
+            profiler.record_event(event_kind, event_kind, 0, TimestampKind::End);
+            assert!(profiler.timestamp_overflowed());
+        });
+
+        let events: Vec<_> = profiling_data
+            .iter()
+            .map(|e| {
+                let nanos = e
+                    .timestamp
+                    .duration_since(std::time::SystemTime::UNIX_EPOCH)
+                    .unwrap()
+                    .as_nanos() as u64;
+                (nanos, e.timestamp_kind)
+            })
+            .collect();
+        assert_eq!(
+            events,
+            [
+                (MAX_TIMESTAMP_NANOS - 5, TimestampKind::Start),
+                (MAX_TIMESTAMP_NANOS, TimestampKind::End),
+            ]
+        );
+    }
+
+    #[test]
+    fn pre_measured_intervals() {
+        let profiling_data = in_memory_profile(FakeClock(Cell::new(1000)), |profiler| {
+            let event_kind = profiler.alloc_string("Kind");
+            let outer_id = profiler.alloc_string("Outer");
+            let inner_id = profiler.alloc_string("Inner");
+
+            // Recorded in the order the intervals end, as a replayed trace would be
+            profiler.record_interval(event_kind, inner_id, 3, 200, 300);
+            profiler.record_interval(event_kind, outer_id, 3, 100, 500);
+            assert!(!profiler.timestamp_overflowed());
+        });
+
+        let intervals: Vec<_> = profiling_data
+            .iter_intervals()
+            .map(|e| (e.id.into_owned(), e.thread_id, e.start_ns, e.end_ns))
+            .collect();
+        assert_eq!(
+            intervals,
+            [
+                ("Inner".to_string(), 3, 200, 300),
+                ("Outer".to_string(), 3, 100, 500),
+            ]
+        );
+    }
+
+    #[test]
+    fn events_at_shared_clock() {
+        let shared_epoch = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+
+        // Two processes that started a second apart record events at times
+        // taken from a clock they share
+        let record = |start: Duration, events: &[(&str, Duration)]| {
+            let event_sink = Arc::new(InMemorySink::new());
+            let string_data_sink = Arc::new(InMemorySink::new());
+            let string_index_sink = Arc::new(InMemorySink::new());
+            {
+                let profiler = Profiler::with_sinks(
+                    event_sink.clone(),
+                    string_data_sink.clone(),
+                    string_index_sink.clone(),
+                );
+                profiler.set_metadata(&ProfileMetadata {
+                    start_time: shared_epoch + start,
+                    process_id: 1,
+                    cmd: "rustc".to_string(),
+                    ..ProfileMetadata::default()
+                });
+                let event_kind = profiler.alloc_string("Kind");
+                for &(label, shared_time) in events {
+                    let event_id = profiler.alloc_string(label);
+                    let nanos = (shared_time - start).as_nanos() as u64;
+                    profiler.record_event_at(
+                        event_kind,
+                        event_id,
+                        0,
+                        TimestampKind::Instant,
+                        nanos,
+                    );
+                }
+            }
+
+            ProfilingData::from_buffers(
+                Arc::try_unwrap(event_sink).unwrap().into_bytes(),
+                Arc::try_unwrap(string_data_sink).unwrap().into_bytes(),
+                Arc::try_unwrap(string_index_sink).unwrap().into_bytes(),
+            )
+            .unwrap()
+        };
+
+        let first = record(
+            Duration::from_secs(0),
+            &[
+                ("a1", Duration::from_millis(1100)),
+                ("a2", Duration::from_millis(1500)),
+            ],
+        );
+        let second = record(
+            Duration::from_secs(1),
+            &[("b1", Duration::from_millis(1200))],
+        );
+        assert_eq!(
+            second.iter().next().unwrap().timestamp,
+            SystemTime::UNIX_EPOCH + Duration::from_millis(200)
+        );
+
+        let mut merged = ProfilingData::merge(vec![first, second]);
+        merged.align_start();
+        let mut events: Vec<_> = merged
+            .iter()
+            .map(|e| (e.timestamp, e.label.into_owned()))
+            .collect();
+        events.sort();
+        let labels: Vec<_> = events.iter().map(|(_, label)| &label[..]).collect();
+        assert_eq!(labels, ["a1", "b1", "a2"]);
+        assert_eq!(
+            events[1].0,
+            SystemTime::UNIX_EPOCH + Duration::from_millis(1200)
+        );
+    }
+
+    #[test]
+    fn finished_and_cancelled_guards() {
+        let profiling_data = in_memory_profile(FakeClock(Cell::new(1000)), |profiler| {
+            let event_kind = profiler.alloc_string("Kind");
+            let finished_id = profiler.alloc_string("Finished");
+            let cancelled_id = profiler.alloc_string("Cancelled");
+
+            let finished = profiler.start_recording_interval_event(event_kind, finished_id, 0);
+            let cancelled = profiler.start_recording_interval_event(event_kind, cancelled_id, 0);
+            cancelled.cancel();
+            finished.finish();
+            profiler.record_instant_event(event_kind, finished_id, 0);
+        });
+
+        let events: Vec<_> = profiling_data
+            .iter()
+            .map(|e| (e.label.into_owned(), e.timestamp_kind))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                ("Finished".to_string(), TimestampKind::Start),
+                ("Cancelled".to_string(), TimestampKind::Start),
+                ("Finished".to_string(), TimestampKind::End),
+                ("Finished".to_string(), TimestampKind::Instant),
+            ]
+        );
+
+        let mut intervals = profiling_data.iter_intervals();
+        let finished = intervals.next().unwrap();
+        assert_eq!((finished.start_ns, finished.end_ns), (1010, 1030));
+        assert!(intervals.next().is_none());
+        assert!(matches!(
+            intervals.errors(),
+            [IntervalError::Overlapping(interval), IntervalError::UnmatchedStart(start)]
+                if interval.id == "Finished" && start.label == "Cancelled"
+        ));
+    }
+
+    #[test]
+    fn matching_events_for_current_thread() {
+        let profiling_data = in_memory_profile(InstantClock::new(), |profiler| {
+            let event_kind = profiler.alloc_string("Kind");
+            let event_id = profiler.alloc_string("Outer");
+            let inner_id = profiler.alloc_string("Inner");
+
+            let _outer =
+                profiler.start_recording_interval_event_for_current_thread(event_kind, event_id);
+            let _inner =
+                profiler.start_recording_interval_event_for_current_thread(event_kind, inner_id);
+        });
+
+        let matching_events: Vec<_> = profiling_data
+            .iter_matching_events()
+            .map(|e| match e {
+                MatchingEvent::StartStop(start, end) => {
+                    assert_eq!(start.thread_id, end.thread_id);
+                    (start.label.into_owned(), start.thread_id)
+                }
+                _ => panic!("unexpected event: {:?}", e),
+            })
+            .collect();
+        assert_eq!(
+            matching_events,
+            [
+                ("Inner".to_string(), current_thread_id()),
+                ("Outer".to_string(), current_thread_id()),
+            ]
+        );
+    }
+
+    #[test]
+    fn task_events_across_threads() {
+        let profiling_data = in_memory_profile(InstantClock::new(), |profiler| {
+            let event_kind = profiler.alloc_string("Task");
+            let event_id = profiler.alloc_string("request");
+
+            // Like a task that is resumed on another worker thread after an
+            // `.await`, whose events are written before those of this thread
+            let _guard =
+                profiler.start_recording_interval_event_for_current_thread(event_kind, event_id);
+            let task_event = profiler.start_task_event(7, event_kind, event_id);
+            assert_eq!(task_event.task_id(), 7);
+            std::thread::scope(|scope| {
+                scope.spawn(|| {
+                    profiler.end_task_event(task_event);
+                    profiler.flush().unwrap();
+                });
+            });
+        });
+
+        let task_ids: Vec<_> = profiling_data.iter().map(|e| e.task_id()).collect();
+        assert_eq!(task_ids, [Some(7), Some(7), None, None]);
+        assert!(profiling_data.thread_ids().contains(&(TASK_ID_FLAG | 7)));
+
+        let mut intervals = profiling_data.iter_intervals();
+        let intervals_by_task: Vec<_> = intervals.by_ref().map(|e| e.task_id()).collect();
+        assert_eq!(intervals_by_task, [Some(7), None]);
+        assert!(intervals.errors().is_empty());
+
+        let matching_events_by_task: Vec<_> = profiling_data
+            .iter_matching_events()
+            .map(|e| match e {
+                MatchingEvent::StartStop(start, end) => {
+                    assert_eq!(start.task_id(), end.task_id());
+                    start.task_id()
+                }
+                _ => panic!("unexpected event: {:?}", e),
+            })
+            .collect();
+        assert_eq!(matching_events_by_task, [Some(7), None]);
+    }
+
+    #[test]
+    fn thread_lifecycle_events() {
+        let profiling_data = in_memory_profile(InstantClock::new(), |profiler| {
+            for thread_id in 1..=2 {
+                profiler.record_thread_start(thread_id, &format!("worker {}", thread_id));
+            }
+            profiler.record_thread_stop(2);
+            profiler.record_thread_stop(1);
+        });
+
+        let events: Vec<_> = profiling_data
+            .iter()
+            .map(|e| (e.event_kind.into_owned(), e.label.into_owned(), e.thread_id))
+            .collect();
+        let event =
+            |kind: &str, label: &str, thread_id| (kind.to_string(), label.to_string(), thread_id);
+        assert_eq!(
+            events,
+            [
+                event(THREAD_START_EVENT_KIND, "worker 1", 1),
+                event(THREAD_START_EVENT_KIND, "worker 2", 2),
+                event(THREAD_STOP_EVENT_KIND, THREAD_STOP_EVENT_KIND, 2),
+                event(THREAD_STOP_EVENT_KIND, THREAD_STOP_EVENT_KIND, 1),
+            ]
+        );
+        assert_eq!(
+            profiling_data.thread_names(),
+            [(1, "worker 1"), (2, "worker 2")]
+        );
+        assert!(profiling_data.validate().is_empty());
+    }
+
+    #[test]
+    fn custom_metadata() {
+        let event_sink = Arc::new(InMemorySink::new());
+        let string_data_sink = Arc::new(InMemorySink::new());
+        let string_index_sink = Arc::new(InMemorySink::new());
+
+        let metadata = ProfileMetadataBuilder::new()
+            .include_args(false)
+            .field("build_id", "1234");
+        Profiler::with_shards_and_clock(
+            vec![event_sink.clone()],
+            string_data_sink.clone(),
+            string_index_sink.clone(),
+            InstantClock::new(),
+            &metadata,
+        );
+
+        let profiling_data = ProfilingData::from_buffers(
+            Arc::try_unwrap(event_sink).unwrap().into_bytes(),
+            Arc::try_unwrap(string_data_sink).unwrap().into_bytes(),
+            Arc::try_unwrap(string_index_sink).unwrap().into_bytes(),
+        )
+        .unwrap();
+
+        let metadata = profiling_data.metadata();
+        assert_eq!(metadata.extra("build_id"), Some("1234"));
+        assert_eq!(metadata.process_id, std::process::id());
+        assert_eq!(metadata.cmd, std::env::args().next().unwrap());
+    }
+
+    #[test]
+    fn observed_events() {
+        let event_sink = Arc::new(InMemorySink::new());
+        let observed = Arc::new(Mutex::new(Vec::new()));
+
+        {
+            let mut profiler = Profiler::with_sinks_and_clock(
+                event_sink.clone(),
+                Arc::new(InMemorySink::new()),
+                Arc::new(InMemorySink::new()),
+                FakeClock(Cell::new(0)),
+            );
+            let event_kind = profiler.alloc_string("Kind");
+            let event_id = profiler.alloc_string("Id");
+            profiler.record_instant_event(event_kind, event_id, 1);
+
+            let observed_events = observed.clone();
+            profiler.set_observer(Box::new(move |raw_event| {
+                observed_events.lock().unwrap().push(*raw_event);
+            }));
+
+            // Not observed
+            profiler.set_thread_name(1, "main");
+
+            drop(profiler.start_recording_interval_event(event_kind, event_id, 1));
+            let task_event = profiler.start_task_event(7, event_kind, event_id);
+            profiler.end_task_event(task_event);
+        }
+
+        let observed = observed.lock().unwrap();
+        let kinds: Vec<_> = observed.iter().map(|e| e.timestamp.kind()).collect();
+        assert_eq!(
+            kinds,
+            [
+                TimestampKind::Start,
+                TimestampKind::End,
+                TimestampKind::Start,
+                TimestampKind::End
+            ]
+        );
+
+        // The observer sees the events as they were written
+        let event_data = Arc::try_unwrap(event_sink).unwrap().into_bytes();
+        let written: Vec<_> = event_data[FILE_HEADER_SIZE..]
+            .chunks(RAW_EVENT_SIZE)
+            .map(RawEvent::deserialize)
+            .collect();
+        for raw_event in observed.iter() {
+            assert!(written.contains(raw_event));
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "before its start")]
+    fn pre_measured_interval_ending_before_start() {
+        let profiler = Profiler::null();
+        let event_kind = profiler.alloc_string("Kind");
+        profiler.record_interval(event_kind, event_kind, 0, 500, 100);
+    }
+
+    #[test]
+    fn invalid_string_id_reads_back_as_unknown() {
+        let profiling_data = in_memory_profile(InstantClock::new(), |profiler| {
+            let event_kind = profiler.alloc_string("Kind");
+
+            // As recorded by a release build, which doesn't check the ids
+            profiler.write_raw_event(&RawEvent {
+                event_kind,
+                id: StringId::INVALID,
+                thread_id: 0,
+                timestamp: profiler.timestamp(TimestampKind::Instant),
+                payload: 0,
+            });
+        });
+
+        let events: Vec<_> = profiling_data.iter().collect();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event_kind, "Kind");
+        assert_eq!(events[0].label, "<unknown>");
+        assert_eq!(profiling_data.resolve_string(StringId::INVALID), None);
+        assert!(!profiling_data.is_truncated());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "`StringId::INVALID` as event id")]
+    fn recording_invalid_string_id() {
+        let profiler = Profiler::null();
+        let event_kind = profiler.alloc_string("Kind");
+        profiler.record_instant_event(event_kind, StringId::INVALID, 0);
+    }
+
+    #[test]
+    fn replaced_metadata() {
+        let event_sink = Arc::new(InMemorySink::new());
+        let string_data_sink = Arc::new(InMemorySink::new());
+        let string_index_sink = Arc::new(InMemorySink::new());
+
+        let metadata = ProfileMetadata {
+            start_time: std::time::SystemTime::UNIX_EPOCH,
+            process_id: 42,
+            cmd: "imported".to_string(),
+            ..ProfileMetadata::default()
+        };
+
+        Profiler::with_sinks(
+            event_sink.clone(),
+            string_data_sink.clone(),
+            string_index_sink.clone(),
+        )
+        .set_metadata(&metadata);
+
+        let profiling_data = ProfilingData::from_buffers(
+            Arc::try_unwrap(event_sink).unwrap().into_bytes(),
+            Arc::try_unwrap(string_data_sink).unwrap().into_bytes(),
+            Arc::try_unwrap(string_index_sink).unwrap().into_bytes(),
+        )
+        .unwrap();
+        assert_eq!(profiling_data.try_metadata(), Ok(metadata));
+    }
+
+    #[test]
+    fn registered_event_kinds() {
+        #[derive(Clone, Copy)]
+        enum Kind {
+            Query,
+            GenericActivity,
+        }
+
+        let profiling_data = in_memory_profile(InstantClock::new(), |profiler| {
+            profiler.register_event_kinds(&["Query", "GenericActivity"]);
+
+            let event_id = profiler.alloc_string("id");
+            let string_kind = profiler.alloc_string("StringKind");
+
+            let _guard =
+                profiler.start_recording_interval_event(Kind::GenericActivity as u16, event_id, 0);
+            profiler.record_instant_event(Kind::Query as u16, event_id, 0);
+            // Kinds given as `StringId` still work alongside
+            profiler.record_instant_event(string_kind, event_id, 0);
+        });
+
+        let event_kinds: Vec<_> = profiling_data
+            .iter()
+            .map(|e| e.event_kind.into_owned())
+            .collect();
+        assert_eq!(
+            event_kinds,
+            ["GenericActivity", "Query", "StringKind", "GenericActivity"]
+        );
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "`StringId::INVALID` as event kind")]
+    fn recording_unregistered_event_kind() {
+        let profiler = Profiler::null();
+        profiler.register_event_kinds(&["Query"]);
+        let event_id = profiler.alloc_string("id");
+        profiler.record_instant_event(1u16, event_id, 0);
+    }
+
+    #[test]
+    fn appended_profile() {
+        let dir = std::env::temp_dir().join(format!("measureme-append-{}", std::process::id()));
+        let path_stem = dir.join("profile");
+
+        {
+            let profiler = Profiler::<FileSerializationSink>::new(&path_stem).unwrap();
+            let event_kind = profiler.alloc_string("Kind");
+            let event_id = profiler.alloc_string("first run");
+            profiler.set_thread_name(1, "main");
+            profiler.record_instant_event(event_kind, event_id, 1);
+            profiler.finish().unwrap();
+        }
+        let metadata = ProfilingData::new(&path_stem).unwrap().metadata();
+
+        // A partially written event, as left behind by a killed process
+        let events_file = ProfilerFiles::new(&path_stem).events_file;
+        let mut event_data = fs::read(&events_file).unwrap();
+        event_data.extend_from_slice(&[0xff; 5]);
+        fs::write(&events_file, &event_data).unwrap();
+
+        {
+            let profiler = Profiler::open_append(&path_stem).unwrap();
+            let event_kind = profiler.alloc_string("Kind");
+            let event_id = profiler.alloc_string("second run");
+            profiler.record_instant_event(event_kind, event_id, 1);
+            let _guard = profiler.start_recording_interval_event(event_kind, event_id, 2);
+        }
+
+        let profiling_data = ProfilingData::new(&path_stem).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(profiling_data.is_complete());
+        assert_eq!(profiling_data.metadata(), metadata);
+        assert_eq!(profiling_data.thread_name(1), Some("main"));
+
+        let events: Vec<_> = profiling_data.iter().collect();
+        let labels: Vec<_> = events.iter().map(|e| &e.label[..]).collect();
+        assert_eq!(
+            labels,
+            ["first run", "second run", "second run", "second run"]
+        );
+        assert!(events.iter().all(|e| e.event_kind == "Kind"));
+        assert!(events
+            .windows(2)
+            .all(|pair| pair[0].timestamp <= pair[1].timestamp));
+    }
+
+    #[test]
+    fn rotated_events_file() {
+        let dir = std::env::temp_dir().join(format!("measureme-rotation-{}", std::process::id()));
+        let path_stem = dir.join("profile");
+
+        // Left behind by an earlier, longer profile
+        fs::create_dir_all(&dir).unwrap();
+        for segment in 1..=4 {
+            fs::write(
+                ProfilerFiles::rotated_events_file(&path_stem, segment),
+                b"stale",
+            )
+            .unwrap();
+        }
+
+        // A batch of events fits into a segment, but two don't
+        let max_file_size = (THREAD_BUFFER_SIZE + THREAD_BUFFER_SIZE / 2) as u32;
+        {
+            let profiler = Profiler::with_rotation(&path_stem, max_file_size).unwrap();
+            let event_kind = profiler.alloc_string("Kind");
+            profiler.set_thread_name(1, "main");
+            for i in 0..400 {
+                let event_id = profiler.alloc_string(&format!("event {}", i)[..]);
+                profiler.record_instant_event(event_kind, event_id, 1);
+            }
+        }
+
+        let segments: Vec<_> = (1..=4)
+            .map(|segment| ProfilerFiles::rotated_events_file(&path_stem, segment).exists())
+            .collect();
+        let first_segment_size = fs::metadata(ProfilerFiles::new(&path_stem).events_file)
+            .unwrap()
+            .len();
+        let profiling_data = ProfilingData::new(&path_stem).unwrap();
+        let appended = Profiler::open_append(&path_stem);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(segments, [true, true, false, false]);
+        assert!(first_segment_size <= max_file_size as u64);
+        assert!(profiling_data.is_complete());
+        assert_eq!(profiling_data.thread_name(1), Some("main"));
+
+        let labels: Vec<_> = profiling_data
+            .iter()
+            .map(|e| e.label.into_owned())
+            .collect();
+        let expected: Vec<_> = (0..400).map(|i| format!("event {}", i)).collect();
+        assert_eq!(labels, expected);
+        assert!(matches!(appended, Err(ProfilerError::EventsFile(_))));
+    }
+
+    #[test]
+    fn appending_to_other_file_format_version() {
+        let dir =
+            std::env::temp_dir().join(format!("measureme-append-version-{}", std::process::id()));
+        let path_stem = dir.join("profile");
+
+        Profiler::<FileSerializationSink>::new(&path_stem)
+            .unwrap()
+            .finish()
+            .unwrap();
+        let string_index_file = ProfilerFiles::new(&path_stem).string_index_file;
+        let mut index_data = fs::read(&string_index_file).unwrap();
+        index_data[4..8].copy_from_slice(&0u32.to_le_bytes());
+        fs::write(&string_index_file, &index_data).unwrap();
+
+        let result = Profiler::open_append(&path_stem);
+        let missing = Profiler::open_append(&dir.join("missing"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        match result {
+            Err(ProfilerError::StringIndexFile(e)) => {
+                assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+                assert!(e.to_string().contains("file format version '0'"));
+            }
+            _ => panic!("expected the version of the string index to be rejected"),
+        }
+        assert!(matches!(missing, Err(ProfilerError::EventsFile(_))));
+    }
+
+    // Accepts every write, but fails to flush, like a full disk
+    struct FullDiskSink;
+
+    impl SerializationSink for FullDiskSink {
+        fn from_path(_path: &Path) -> io::Result<Self> {
+            Ok(FullDiskSink)
+        }
+
+        fn write_atomic<W>(&self, _num_bytes: usize, _write: W) -> Addr
+        where
+            W: FnOnce(&mut [u8]),
+        {
+            Addr(0)
+        }
+
+        fn flush(&self) -> io::Result<()> {
+            Err(io::Error::other("no space left"))
+        }
+    }
+
+    #[test]
+    fn header_write_error() {
+        match Profiler::<FullDiskSink>::new(Path::new("profile")) {
+            Err(ProfilerError::HeaderWrite(e)) => assert_eq!(e.to_string(), "no space left"),
+            _ => panic!("expected the failed header write to be reported"),
+        }
+    }
+
+    #[test]
+    fn ring_buffer_snapshot() {
+        let dir = std::env::temp_dir().join(format!("measureme-ring-{}", std::process::id()));
+        let path_stem = dir.join("profile");
+
+        let profiler = Profiler::new_ring_buffer(16 * 1024);
+        let event_kind = profiler.alloc_string("Kind");
+        let record = |events: std::ops::Range<u32>| {
+            for i in events {
+                let event_id = profiler.alloc_string(&format!("event {}", i)[..]);
+                profiler.record_instant_event(event_kind, event_id, 1);
+            }
+        };
+        let snapshot = || {
+            profiler.dump_snapshot(&path_stem).unwrap();
+            let profiling_data = ProfilingData::new(&path_stem).unwrap();
+            let labels: Vec<_> = profiling_data
+                .iter()
+                .map(|e| e.label.into_owned())
+                .collect();
+            assert!(profiling_data.validate().is_empty());
+            labels
+        };
+
+        // Nothing was overwritten yet
+        record(0..10);
+        let expected: Vec<_> = (0..10).map(|i| format!("event {}", i)).collect();
+        assert_eq!(snapshot(), expected);
+
+        // The newest events are kept, all with their strings
+        record(10..2000);
+        let labels = snapshot();
+        assert!(labels.len() < 16 * 1024 / RAW_EVENT_SIZE);
+        let first = 2000 - labels.len();
+        let expected: Vec<_> = (first..2000).map(|i| format!("event {}", i)).collect();
+        assert_eq!(labels, expected);
+
+        // Recording continues after a snapshot
+        record(2000..2100);
+        assert_eq!(snapshot().last().unwrap(), "event 2099");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compressed_string_data() {
+        let dir = std::env::temp_dir().join(format!("measureme-strings-{}", std::process::id()));
+        let path_stem = dir.join("profile");
+
+        {
+            let profiler = Profiler::new_with_compressed_strings(&path_stem).unwrap();
+            let event_kind = profiler.alloc_string("Kind");
+            for i in 0..1000 {
+                let event_id = profiler.alloc_string(&format!("typeck(DefId(0:{}))", i)[..]);
+                profiler.record_instant_event(event_kind, event_id, 1);
+            }
+        }
+
+        let paths = ProfilerFiles::new(&path_stem);
+        let magic = |path: &Path| fs::read(path).unwrap()[..4].to_vec();
+        assert_eq!(magic(&paths.events_file), FILE_MAGIC_EVENT_STREAM);
+        assert_eq!(magic(&paths.string_data_file), FILE_MAGIC_COMPRESSED);
+        assert_eq!(
+            magic(&paths.string_index_file),
+            FILE_MAGIC_STRINGTABLE_INDEX
+        );
+
+        let profiling_data = ProfilingData::new(&path_stem).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(profiling_data.is_complete());
+        let labels: Vec<_> = profiling_data
+            .iter()
+            .map(|e| e.label.into_owned())
+            .collect();
+        let expected: Vec<_> = (0..1000)
+            .map(|i| format!("typeck(DefId(0:{}))", i))
+            .collect();
+        assert_eq!(labels, expected);
+    }
 }
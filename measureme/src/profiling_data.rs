@@ -1,369 +1,2424 @@
-use crate::event::Event;
+use crate::byte_buffer::ByteBuffer;
+use crate::event::{task_id, Event};
+use crate::event_args::{args_record_thread, deserialize_args, ArgValue, EventArgValue};
 use crate::file_header::{
-    read_file_header, write_file_header, CURRENT_FILE_FORMAT_VERSION, FILE_HEADER_SIZE,
-    FILE_MAGIC_EVENT_STREAM,
+    check_file_format_version, check_file_magic, file_header, read_file_header,
+    read_writer_version, strip_file_header, write_file_header, CURRENT_FILE_FORMAT_VERSION,
+    FILE_HEADER_SIZE, FILE_MAGIC_COMPRESSED, FILE_MAGIC_EVENT_STREAM, FILE_MAGIC_PACKED_PROFILE,
+    FILE_MAGIC_STRINGTABLE_DATA, FILE_MAGIC_STRINGTABLE_INDEX,
+};
+use crate::merged_profiling_data::MergedProfilingData;
+use crate::metadata::ProfileMetadata;
+use crate::packed_profile::PackedProfile;
+use crate::serialization::InMemorySink;
+use crate::stringtable::{
+    internal_string_ids, CLEAN_SHUTDOWN_EVENT_KIND, METADATA_STRING_ID, TAGGED_RECORD_EVENT_KIND,
+    THREAD_NAME_EVENT_KIND,
 };
-use crate::serialization::ByteVecSink;
 use crate::{
-    ProfilerFiles, RawEvent, SerializationSink, StringTable, StringTableBuilder, Timestamp,
-    TimestampKind,
+    ProfilerFiles, RawEvent, SerializationSink, StringId, StringTable, StringTableBuilder,
+    Timestamp, TimestampKind, RAW_EVENT_SIZE,
 };
+use byteorder::{ByteOrder, NativeEndian};
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::convert::TryFrom;
 use std::error::Error;
-use std::fs;
+use std::fmt::{self, Write};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom};
 use std::mem;
-use std::path::Path;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
 pub struct ProfilingData {
-    event_data: Vec<u8>,
+    event_data: ByteBuffer,
     string_table: StringTable,
+    thread_names: FxHashMap<u64, String>,
+    truncated: bool,
+    has_footer: bool,
+    // Set for profiles read via `ProfilingData::without_strings()`
+    strings_missing: bool,
+    file_sizes: ProfileFileSizes,
+}
+
+/// The sizes in bytes of the three files a profile consists of, as returned
+/// by `ProfilingData::file_sizes()`. For compressed files these are the
+/// compressed sizes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ProfileFileSizes {
+    pub events: u64,
+    pub string_data: u64,
+    pub string_index: u64,
+}
+
+/// The size of the string table of a profile, as returned by
+/// `ProfilingData::string_table_stats()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StringTableStats {
+    /// The number of strings, i.e. of string ids that have a string. Equal
+    /// strings that were allocated separately count once each.
+    pub count: u64,
+    /// The size of the encoded strings in bytes, i.e. of the `.string_data`
+    /// file without its header and after decompression. Besides the contents
+    /// of the strings, this includes 4 bytes of framing per component.
+    pub total_bytes: u64,
+    /// `total_bytes` divided by `count`, or `0.0` if there are no strings.
+    pub avg_len: f64,
 }
 
 impl ProfilingData {
+    /// Reads the profile written to `path_stem` by a `Profiler`. If there is
+    /// no `.events` file but event shards like `.0.events`, as written by a
+    /// `Profiler` created via `Profiler::new_sharded()`, all shards are read
+    /// and merged. If there are both an `.events` file and further segments
+    /// like `.1.events`, as written by a `Profiler` created via
+    /// `Profiler::with_rotation()`, the segments are read in order and
+    /// concatenated.
+    ///
+    /// If only the `.events` file is left, e.g. of a partial copy, but the
+    /// `.string_data` or `.string_index` file is missing, the events are
+    /// read without their strings, see `ProfilingData::without_strings()`.
     pub fn new(path_stem: &Path) -> Result<ProfilingData, Box<dyn Error>> {
         let paths = ProfilerFiles::new(path_stem);
 
-        let string_data = fs::read(paths.string_data_file).expect("couldn't read string_data file");
-        let index_data =
-            fs::read(paths.string_index_file).expect("couldn't read string_index file");
-        let event_data = fs::read(paths.events_file).expect("couldn't read events file");
+        if !paths.events_file.exists() && ProfilerFiles::shard_events_file(path_stem, 0).exists() {
+            return ProfilingData::from_shard_files(path_stem, &paths);
+        }
+        if paths.events_file.exists() && ProfilerFiles::rotated_events_file(path_stem, 1).exists() {
+            return ProfilingData::from_rotated_files(path_stem, &paths);
+        }
+        if paths.events_file.exists()
+            && !(paths.string_data_file.exists() && paths.string_index_file.exists())
+        {
+            return ProfilingData::without_strings(read_profile_file(
+                &paths.events_file,
+                FILE_MAGIC_EVENT_STREAM,
+            )?);
+        }
+
+        let event_data = open_profile_file(&paths.events_file, FILE_MAGIC_EVENT_STREAM)?;
+        let string_data = open_profile_file(&paths.string_data_file, FILE_MAGIC_STRINGTABLE_DATA)?;
+        let index_data = open_profile_file(&paths.string_index_file, FILE_MAGIC_STRINGTABLE_INDEX)?;
+
+        ProfilingData::from_readers(event_data, string_data, index_data)
+    }
 
-        let event_data_format = read_file_header(&event_data, FILE_MAGIC_EVENT_STREAM)?;
-        if event_data_format != CURRENT_FILE_FORMAT_VERSION {
-            Err(format!(
-                "Event stream file format version '{}' is not supported
-                 by this version of `measureme`.",
-                event_data_format
-            ))?;
+    fn from_shard_files(
+        path_stem: &Path,
+        paths: &ProfilerFiles,
+    ) -> Result<ProfilingData, Box<dyn Error>> {
+        let mut event_shards = Vec::new();
+        loop {
+            let path = ProfilerFiles::shard_events_file(path_stem, event_shards.len());
+            if !path.exists() {
+                break;
+            }
+            event_shards.push(read_profile_file(&path, FILE_MAGIC_EVENT_STREAM)?);
         }
 
-        let string_table = StringTable::new(string_data, index_data)?;
+        ProfilingData::from_shard_buffers(
+            event_shards,
+            read_profile_file(&paths.string_data_file, FILE_MAGIC_STRINGTABLE_DATA)?,
+            read_profile_file(&paths.string_index_file, FILE_MAGIC_STRINGTABLE_INDEX)?,
+        )
+    }
 
-        Ok(ProfilingData {
-            string_table,
-            event_data,
-        })
+    fn from_rotated_files(
+        path_stem: &Path,
+        paths: &ProfilerFiles,
+    ) -> Result<ProfilingData, Box<dyn Error>> {
+        let mut segments = vec![read_profile_file(
+            &paths.events_file,
+            FILE_MAGIC_EVENT_STREAM,
+        )?];
+        loop {
+            let path = ProfilerFiles::rotated_events_file(path_stem, segments.len());
+            if !path.exists() {
+                break;
+            }
+            segments.push(read_profile_file(&path, FILE_MAGIC_EVENT_STREAM)?);
+        }
+
+        ProfilingData::from_rotated_buffers(
+            segments,
+            read_profile_file(&paths.string_data_file, FILE_MAGIC_STRINGTABLE_DATA)?,
+            read_profile_file(&paths.string_index_file, FILE_MAGIC_STRINGTABLE_INDEX)?,
+        )
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = Event<'_>> {
-        ProfilerEventIterator::new(&self)
+    /// Creates a `ProfilingData` from readers that yield the contents of the
+    /// events, string_data, and string_index files, e.g. entries of an archive
+    /// or streams received over the network.
+    ///
+    /// The readers don't need to implement `Seek`, so non-seekable streams
+    /// can be read as well. This is because events refer to strings by id,
+    /// which requires random access into the string tables. All three streams
+    /// are therefore read into memory completely before the profile is parsed,
+    /// and iterating the events of a profile while it is being streamed in
+    /// isn't supported.
+    pub fn from_readers<E: Read, D: Read, I: Read>(
+        mut events: E,
+        mut string_data: D,
+        mut string_index: I,
+    ) -> Result<ProfilingData, Box<dyn Error>> {
+        let mut event_data = Vec::new();
+        events.read_to_end(&mut event_data)?;
+        let mut string_bytes = Vec::new();
+        string_data.read_to_end(&mut string_bytes)?;
+        let mut index_data = Vec::new();
+        string_index.read_to_end(&mut index_data)?;
+
+        ProfilingData::from_buffers(event_data, string_bytes, index_data)
     }
 
-    pub fn iter_matching_events(&self) -> impl Iterator<Item = MatchingEvent<'_>> {
-        MatchingEventsIterator::new(ProfilerEventIterator::new(&self))
+    /// Creates a `ProfilingData` from the raw contents of the events,
+    /// string_data, and string_index files, e.g. as written by an
+    /// `InMemorySink`.
+    pub fn from_buffers(
+        event_data: Vec<u8>,
+        string_data: Vec<u8>,
+        index_data: Vec<u8>,
+    ) -> Result<ProfilingData, Box<dyn Error>> {
+        ProfilingData::from_byte_buffers(event_data.into(), string_data.into(), index_data.into())
     }
-}
 
-struct ProfilerEventIterator<'a> {
-    data: &'a ProfilingData,
-    curr_event_idx: usize,
-}
+    /// Creates a `ProfilingData` from the contents of an `.events` file whose
+    /// string table is lost. String ids can't be resolved without the
+    /// `.string_index` file: it is the only place that records which string
+    /// each id stands for, since the records in `.string_data` don't contain
+    /// their ids.
+    ///
+    /// The events are yielded all the same, with counts, threads and timings
+    /// intact, but the event kind and label of every event are its raw
+    /// `StringId`s formatted like `StringId(1234)`, and thread names and the
+    /// metadata are missing. `ProfilingData::can_resolve_strings()` tells
+    /// such profiles apart.
+    pub fn without_strings(event_data: Vec<u8>) -> Result<ProfilingData, Box<dyn Error>> {
+        let file_sizes = ProfileFileSizes {
+            events: event_data.len() as u64,
+            ..ProfileFileSizes::default()
+        };
+        let (event_data, partial_event) = read_event_stream(event_data.into())?;
 
-impl<'a> ProfilerEventIterator<'a> {
-    pub fn new(data: &'a ProfilingData) -> ProfilerEventIterator<'a> {
-        ProfilerEventIterator {
-            data,
-            curr_event_idx: 0,
-        }
+        ProfilingData::with_string_table(
+            event_data,
+            partial_event,
+            StringTable::empty(),
+            true,
+            file_sizes,
+        )
     }
-}
 
-impl<'a> Iterator for ProfilerEventIterator<'a> {
-    type Item = Event<'a>;
+    /// Creates a `ProfilingData` that memory-maps the trace files instead of
+    /// reading them into memory, so that even very large profiles can be
+    /// opened quickly. Events are read directly out of the mapped `.events`
+    /// file.
+    ///
+    /// The files must not be modified while the `ProfilingData` is alive. If
+    /// mapping a file fails, e.g. on platforms that don't support it, this
+    /// falls back to reading the files like `ProfilingData::new()` does.
+    pub fn from_mmap(path_stem: &Path) -> Result<ProfilingData, Box<dyn Error>> {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let paths = ProfilerFiles::new(path_stem);
+            let rotated = ProfilerFiles::rotated_events_file(path_stem, 1).exists();
+
+            let mapped = ByteBuffer::map(&paths.events_file).and_then(|event_data| {
+                let string_data = ByteBuffer::map(&paths.string_data_file)?;
+                let index_data = ByteBuffer::map(&paths.string_index_file)?;
+                Ok((event_data, string_data, index_data))
+            });
 
-    fn next(&mut self) -> Option<Event<'a>> {
-        let event_start_addr = FILE_HEADER_SIZE + self.curr_event_idx * mem::size_of::<RawEvent>();
-        let event_end_addr = event_start_addr + mem::size_of::<RawEvent>();
-        if event_end_addr > self.data.event_data.len() {
-            return None;
+            // The segments of a rotated profile have to be copied into one
+            // buffer anyway
+            if let (false, Ok((event_data, string_data, index_data))) = (rotated, mapped) {
+                check_file_magic(&paths.events_file, &event_data, FILE_MAGIC_EVENT_STREAM)?;
+                check_file_magic(
+                    &paths.string_data_file,
+                    &string_data,
+                    FILE_MAGIC_STRINGTABLE_DATA,
+                )?;
+                check_file_magic(
+                    &paths.string_index_file,
+                    &index_data,
+                    FILE_MAGIC_STRINGTABLE_INDEX,
+                )?;
+
+                return ProfilingData::from_byte_buffers(event_data, string_data, index_data);
+            }
         }
 
-        self.curr_event_idx += 1;
+        ProfilingData::new(path_stem)
+    }
 
-        let raw_event_bytes = &self.data.event_data[event_start_addr..event_end_addr];
+    /// Reads a profile from the single `<path_stem>.mm_profile` file written
+    /// by a `Profiler` created via `Profiler::new_packed()`.
+    pub fn from_packed(path_stem: &Path) -> Result<ProfilingData, Box<dyn Error>> {
+        let path = PackedProfile::file_path(path_stem);
+        let bytes =
+            fs::read(&path).map_err(|e| format!("couldn't read `{}`: {}", path.display(), e))?;
+        check_file_magic(&path, &bytes, FILE_MAGIC_PACKED_PROFILE)?;
+        let packed = PackedProfile::parse(&bytes)?;
+
+        ProfilingData::from_buffers(
+            packed.events.to_vec(),
+            packed.string_data.to_vec(),
+            packed.string_index.to_vec(),
+        )
+    }
 
-        let mut raw_event = RawEvent::default();
-        unsafe {
-            let raw_event = std::slice::from_raw_parts_mut(
-                &mut raw_event as *mut RawEvent as *mut u8,
-                std::mem::size_of::<RawEvent>(),
-            );
-            raw_event.copy_from_slice(raw_event_bytes);
+    fn from_byte_buffers(
+        event_data: ByteBuffer,
+        string_data: ByteBuffer,
+        index_data: ByteBuffer,
+    ) -> Result<ProfilingData, Box<dyn Error>> {
+        let file_sizes = ProfileFileSizes {
+            events: event_data.len() as u64,
+            string_data: string_data.len() as u64,
+            string_index: index_data.len() as u64,
         };
 
-        let string_table = &self.data.string_table;
+        let (event_data, partial_event) = read_event_stream(event_data)?;
 
-        let mut timestamp = SystemTime::UNIX_EPOCH;
-        timestamp += Duration::from_nanos(raw_event.timestamp.nanos());
-
-        Some(Event {
-            event_kind: string_table.get(raw_event.event_kind).to_string(),
-            label: string_table.get(raw_event.id).to_string(),
-            additional_data: &[],
-            timestamp: timestamp,
-            timestamp_kind: raw_event.timestamp.kind(),
-            thread_id: raw_event.thread_id,
-        })
+        ProfilingData::from_event_stream(
+            event_data,
+            partial_event,
+            string_data,
+            index_data,
+            file_sizes,
+        )
     }
-}
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
-pub enum MatchingEvent<'a> {
-    StartStop(Event<'a>, Event<'a>),
-    Instant(Event<'a>),
-}
-
-struct MatchingEventsIterator<'a> {
-    events: ProfilerEventIterator<'a>,
-    thread_stacks: Vec<Vec<Event<'a>>>,
-}
+    /// Like `ProfilingData::from_buffers()`, but for a profile whose events
+    /// were written into several shards by a `Profiler` created via
+    /// `Profiler::new_sharded()`. The events of all shards are merged as
+    /// described there.
+    pub fn from_shard_buffers(
+        event_shards: Vec<Vec<u8>>,
+        string_data: Vec<u8>,
+        index_data: Vec<u8>,
+    ) -> Result<ProfilingData, Box<dyn Error>> {
+        let file_sizes = ProfileFileSizes {
+            events: event_shards.iter().map(|shard| shard.len() as u64).sum(),
+            string_data: string_data.len() as u64,
+            string_index: index_data.len() as u64,
+        };
 
-impl<'a> MatchingEventsIterator<'a> {
-    pub fn new(events: ProfilerEventIterator<'a>) -> MatchingEventsIterator<'a> {
-        MatchingEventsIterator {
-            events,
-            thread_stacks: vec![],
+        let mut partial_event = false;
+        let mut shards = Vec::with_capacity(event_shards.len());
+        for event_data in event_shards {
+            let (event_data, partial) = read_event_stream(event_data.into())?;
+            partial_event |= partial;
+            shards.push(event_data);
         }
-    }
-}
 
-impl<'a> Iterator for MatchingEventsIterator<'a> {
-    type Item = MatchingEvent<'a>;
-
-    fn next(&mut self) -> Option<MatchingEvent<'a>> {
-        while let Some(event) = self.events.next() {
-            match event.timestamp_kind {
-                TimestampKind::Start => {
-                    let thread_id = event.thread_id as usize;
-                    if thread_id >= self.thread_stacks.len() {
-                        let growth_size = (thread_id + 1) - self.thread_stacks.len();
-                        self.thread_stacks.append(&mut vec![vec![]; growth_size])
-                    }
+        ProfilingData::from_event_stream(
+            merge_event_shards(&shards).into(),
+            partial_event,
+            string_data.into(),
+            index_data.into(),
+            file_sizes,
+        )
+    }
 
-                    self.thread_stacks[thread_id].push(event);
-                }
-                TimestampKind::Instant => {
-                    return Some(MatchingEvent::Instant(event));
-                }
-                TimestampKind::End => {
-                    let thread_id = event.thread_id as usize;
-                    let previous_event = self.thread_stacks[thread_id]
-                        .pop()
-                        .expect("no previous event");
-                    if previous_event.event_kind != event.event_kind
-                        || previous_event.label != event.label
-                    {
-                        panic!(
-                            "the event with label: \"{}\" went out of scope of the parent \
-                             event with label: \"{}\"",
-                            previous_event.label, event.label
-                        );
-                    }
+    /// Like `ProfilingData::from_buffers()`, but for a profile whose events
+    /// were written into several segments by a `Profiler` created via
+    /// `Profiler::with_rotation()`, given in the order they were written.
+    pub fn from_rotated_buffers(
+        event_segments: Vec<Vec<u8>>,
+        string_data: Vec<u8>,
+        index_data: Vec<u8>,
+    ) -> Result<ProfilingData, Box<dyn Error>> {
+        let file_sizes = ProfileFileSizes {
+            events: event_segments
+                .iter()
+                .map(|segment| segment.len() as u64)
+                .sum(),
+            string_data: string_data.len() as u64,
+            string_index: index_data.len() as u64,
+        };
 
-                    return Some(MatchingEvent::StartStop(previous_event, event));
-                }
+        let mut partial_event = false;
+        let mut events = Vec::new();
+        for event_data in event_segments {
+            let (event_data, partial) = read_event_stream(event_data.into())?;
+            partial_event |= partial;
+            if events.is_empty() {
+                // Keeps the writer version of the first segment
+                events.extend_from_slice(&event_data[..FILE_HEADER_SIZE]);
             }
+
+            // A partially written event can only be at the end of a segment
+            let len = event_data.len() - FILE_HEADER_SIZE;
+            let whole_events = len - len % mem::size_of::<RawEvent>();
+            events
+                .extend_from_slice(&event_data[FILE_HEADER_SIZE..FILE_HEADER_SIZE + whole_events]);
         }
 
-        None
+        ProfilingData::from_event_stream(
+            events.into(),
+            partial_event,
+            string_data.into(),
+            index_data.into(),
+            file_sizes,
+        )
     }
-}
 
-/// A `ProfilingDataBuilder` allows for programmatically building
-/// `ProfilingData` objects. This is useful for writing tests that expect
-/// `ProfilingData` with predictable events (and especially timestamps) in it.
-///
-/// `ProfilingDataBuilder` provides a convenient interface but its
-/// implementation might not be efficient, which why it should only be used for
-/// writing tests and other things that are not performance sensitive.
-pub struct ProfilingDataBuilder {
-    event_sink: ByteVecSink,
-    string_table_data_sink: Arc<ByteVecSink>,
-    string_table_index_sink: Arc<ByteVecSink>,
-    string_table: StringTableBuilder<ByteVecSink>,
-}
+    fn from_event_stream(
+        event_data: ByteBuffer,
+        partial_event: bool,
+        string_data: ByteBuffer,
+        index_data: ByteBuffer,
+        file_sizes: ProfileFileSizes,
+    ) -> Result<ProfilingData, Box<dyn Error>> {
+        let string_data = decompress_if_needed(string_data)?;
+        let index_data = decompress_if_needed(index_data)?;
 
-impl ProfilingDataBuilder {
-    pub fn new() -> ProfilingDataBuilder {
-        let event_sink = ByteVecSink::new();
-        let string_table_data_sink = Arc::new(ByteVecSink::new());
-        let string_table_index_sink = Arc::new(ByteVecSink::new());
+        let string_table = StringTable::from_byte_buffers(string_data, &index_data)?;
 
-        // The first thing in every file we generate must be the file header.
-        write_file_header(&event_sink, FILE_MAGIC_EVENT_STREAM);
+        ProfilingData::with_string_table(event_data, partial_event, string_table, false, file_sizes)
+    }
 
-        let string_table = StringTableBuilder::new(
-            string_table_data_sink.clone(),
-            string_table_index_sink.clone(),
-        );
+    fn with_string_table(
+        event_data: ByteBuffer,
+        partial_event: bool,
+        string_table: StringTable,
+        strings_missing: bool,
+        file_sizes: ProfileFileSizes,
+    ) -> Result<ProfilingData, Box<dyn Error>> {
+        let mut truncated = string_table.is_truncated() || partial_event;
+
+        // Later records for the same thread replace earlier ones
+        let mut thread_names = FxHashMap::default();
+        let mut has_footer = false;
+        let mut raw_events = RawEvents::new(&event_data);
+        for raw_event in &mut raw_events {
+            // Only counts if it is the last record
+            has_footer = raw_event.event_kind == CLEAN_SHUTDOWN_EVENT_KIND;
+            if has_footer {
+                continue;
+            }
 
-        ProfilingDataBuilder {
-            event_sink,
-            string_table_data_sink,
-            string_table_index_sink,
-            string_table,
+            if !is_resolvable(&string_table, &raw_event) {
+                // Without a string table, every event is missing its strings
+                truncated |= !strings_missing;
+                continue;
+            }
+
+            if raw_event.event_kind == THREAD_NAME_EVENT_KIND {
+                let name = string_table.get(raw_event.id).to_string().into_owned();
+                thread_names.insert(raw_event.thread_id, name);
+            }
         }
+        truncated |= raw_events.truncated;
+
+        Ok(ProfilingData {
+            string_table,
+            event_data,
+            thread_names,
+            truncated,
+            has_footer,
+            strings_missing,
+            file_sizes,
+        })
     }
 
-    /// Record and interval event. Provide an `inner` function for recording
-    /// nested events.
-    pub fn interval<F>(
-        &mut self,
-        event_kind: &str,
-        event_id: &str,
-        thread_id: u64,
-        start_nanos: u64,
-        end_nanos: u64,
-        inner: F,
-    ) -> &mut Self
-    where
-        F: FnOnce(&mut Self),
-    {
-        let event_kind = self.string_table.alloc(event_kind);
-        let event_id = self.string_table.alloc(event_id);
+    /// The number of records in the `.events` file, computed from its size
+    /// without reading the events. This includes records that the iterators
+    /// don't yield, like thread names and events whose strings are missing,
+    /// so it is an upper bound suitable for progress bars and sanity checks.
+    ///
+    /// It counts every `RAW_EVENT_SIZE` bytes as one record, so tagged
+    /// records, which the iterators skip, count once per `RAW_EVENT_SIZE`
+    /// bytes of their body, too.
+    pub fn approx_event_count(&self) -> u64 {
+        let records = (self.event_data.len() - FILE_HEADER_SIZE) / mem::size_of::<RawEvent>();
+        records as u64 - self.has_footer as u64
+    }
 
-        self.write_raw_event(&RawEvent {
-            event_kind,
-            id: event_id,
-            thread_id,
-            timestamp: Timestamp::new(start_nanos, TimestampKind::Start),
-        });
+    /// The sizes of the files the profile was read from.
+    pub fn file_sizes(&self) -> ProfileFileSizes {
+        self.file_sizes
+    }
 
-        inner(self);
+    /// The number and size of the strings in the string table, computed from
+    /// the sizes of its index and data without resolving any strings or
+    /// reading any events. Many more strings than distinct event ids, or
+    /// long strings, suggest that `Profiler::new_deduplicated()` or
+    /// compression would make the profile smaller. Profiles read via
+    /// `ProfilingData::without_strings()` have no strings.
+    pub fn string_table_stats(&self) -> StringTableStats {
+        let count = self.string_table.string_count() as u64;
+        let total_bytes = self.string_table.string_data_len() as u64;
+
+        StringTableStats {
+            count,
+            total_bytes,
+            avg_len: if count == 0 {
+                0.0
+            } else {
+                total_bytes as f64 / count as f64
+            },
+        }
+    }
 
-        self.write_raw_event(&RawEvent {
-            event_kind,
-            id: event_id,
-            thread_id,
-            timestamp: Timestamp::new(end_nanos, TimestampKind::End),
-        });
+    /// Returns whether the trace files are incomplete, e.g. because the
+    /// profiled process was killed. The iterators of a truncated profile
+    /// stop at the last completely written event and skip events whose
+    /// strings are missing.
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
 
-        self
+    /// The number of events that refer to strings missing from the string
+    /// table, e.g. because the `.string_data` file is truncated or belongs
+    /// to another profile. The iterators skip events whose event kind or
+    /// label is missing, and show a missing category as its string id. Every
+    /// event counts for profiles read via `ProfilingData::without_strings()`.
+    pub fn unresolvable_events(&self) -> usize {
+        let string_table = &self.string_table;
+        RawEvents::new(&self.event_data)
+            .filter(|raw_event| raw_event.event_kind != CLEAN_SHUTDOWN_EVENT_KIND)
+            .filter(|raw_event| {
+                let category_missing = raw_event
+                    .category()
+                    .is_some_and(|category| !string_table.contains(category));
+                self.strings_missing || !is_resolvable(string_table, raw_event) || category_missing
+            })
+            .count()
     }
 
-    /// Record and instant event with the given data.
-    pub fn instant(
-        &mut self,
-        event_kind: &str,
-        event_id: &str,
-        thread_id: u64,
-        timestamp_nanos: u64,
-    ) -> &mut Self {
-        let event_kind = self.string_table.alloc(event_kind);
-        let event_id = self.string_table.alloc(event_id);
+    /// Returns whether the strings of the events are known, which is the case
+    /// unless the profile was read via `ProfilingData::without_strings()`.
+    pub fn can_resolve_strings(&self) -> bool {
+        !self.strings_missing
+    }
 
-        self.write_raw_event(&RawEvent {
-            event_kind,
-            id: event_id,
-            thread_id,
-            timestamp: Timestamp::new(timestamp_nanos, TimestampKind::Instant),
-        });
+    /// Returns whether the profile is known to be complete, i.e. the
+    /// `Profiler` that wrote it was dropped normally and nothing was lost
+    /// afterwards. Profiles of processes that crashed or were killed, and
+    /// profiles written by older versions of `measureme`, are not complete,
+    /// but can be read all the same.
+    pub fn is_complete(&self) -> bool {
+        self.has_footer && !self.truncated
+    }
 
-        self
+    /// Returns the string allocated for `id`, or `None` if no string was
+    /// allocated for it. This works for ids returned by
+    /// `Profiler::alloc_string()` as well as for reserved ids passed to
+    /// `Profiler::alloc_string_with_reserved_id()`.
+    pub fn resolve_string(&self, id: StringId) -> Option<Cow<'_, str>> {
+        if self.string_table.contains(id) {
+            Some(self.string_table.get(id).to_string())
+        } else {
+            None
+        }
     }
 
-    /// Convert this builder into a `ProfilingData` object that can be iterated.
-    pub fn into_profiling_data(self) -> ProfilingData {
-        // Drop the string table, so that the `string_table_data_sink` and
-        // `string_table_index_sink` fields are the only event-sink references
-        // left. This enables us to unwrap the `Arc`s and get the byte data out.
-        drop(self.string_table);
+    /// Returns the components of the string allocated for `id`, e.g. the
+    /// parts of an event id built via `EventIdBuilder`, or `None` if no
+    /// string was allocated for it. Strings without components yield a
+    /// single component with the whole string.
+    pub fn resolve_components(&self, id: StringId) -> Option<Vec<Cow<'_, str>>> {
+        if self.string_table.contains(id) {
+            Some(self.string_table.get(id).components())
+        } else {
+            None
+        }
+    }
 
-        let event_data = self.event_sink.into_bytes();
-        let data_bytes = Arc::try_unwrap(self.string_table_data_sink)
-            .unwrap()
-            .into_bytes();
-        let index_bytes = Arc::try_unwrap(self.string_table_index_sink)
-            .unwrap()
-            .into_bytes();
+    /// Iterates the strings that `measureme` itself recorded about the
+    /// profiling session, ordered by id. The first one is the metadata record
+    /// written by `Profiler::new()`, if present.
+    pub fn metadata_strings(&self) -> impl Iterator<Item = (StringId, Cow<'_, str>)> {
+        internal_string_ids().filter_map(move |id| Some((id, self.resolve_string(id)?)))
+    }
 
-        assert_eq!(
-            read_file_header(&event_data, FILE_MAGIC_EVENT_STREAM).unwrap(),
-            CURRENT_FILE_FORMAT_VERSION
-        );
-        let string_table = StringTable::new(data_bytes, index_bytes).unwrap();
+    /// Returns the name that was recorded for the given thread via
+    /// `Profiler::set_thread_name()`, if any.
+    pub fn thread_name(&self, thread_id: u64) -> Option<&str> {
+        self.thread_names.get(&thread_id).map(|name| &name[..])
+    }
 
-        ProfilingData {
-            event_data,
-            string_table,
-        }
+    /// Combines several profiles, e.g. of the processes of a distributed
+    /// build, into one logical profile.
+    pub fn merge(profiles: Vec<ProfilingData>) -> MergedProfilingData {
+        MergedProfilingData::new(profiles)
     }
 
-    fn write_raw_event(&mut self, raw_event: &RawEvent) {
-        let raw_event_bytes: &[u8] = unsafe {
-            std::slice::from_raw_parts(
-                raw_event as *const _ as *const u8,
-                std::mem::size_of::<RawEvent>(),
-            )
-        };
+    /// The version of `measureme` that wrote the `.events` file, e.g.
+    /// `"0.4.0"`, or `None` for files of format version `1` and older, which
+    /// don't record it. This is meant for bug reports and warnings about
+    /// files written by other versions: whether a file can be read is
+    /// decided by its file format version.
+    pub fn writer_version(&self) -> Option<String> {
+        read_writer_version(&self.event_data)
+    }
 
-        self.event_sink
-            .write_atomic(std::mem::size_of::<RawEvent>(), |bytes| {
-                debug_assert_eq!(bytes.len(), std::mem::size_of::<RawEvent>());
-                bytes.copy_from_slice(raw_event_bytes);
-            });
+    /// Returns the metadata that `Profiler` recorded about the profiled
+    /// process. If the metadata is missing or malformed, a warning is printed
+    /// and default values are returned.
+    pub fn metadata(&self) -> ProfileMetadata {
+        self.try_metadata().unwrap_or_else(|e| {
+            eprintln!("warning: couldn't read the profile metadata: {}", e);
+            ProfileMetadata::default()
+        })
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::borrow::Cow;
+    /// Like `metadata()`, but returns an error instead of default values if
+    /// the metadata is missing or malformed, e.g. for profiles not written
+    /// by a `Profiler`.
+    pub fn try_metadata(&self) -> Result<ProfileMetadata, String> {
+        let metadata = self
+            .resolve_string(StringId::reserved(METADATA_STRING_ID))
+            .ok_or("the metadata record is missing")?;
 
-    fn event(
-        event_kind: &'static str,
-        label: &'static str,
-        thread_id: u64,
-        nanos: u64,
-        timestamp_kind: TimestampKind,
-    ) -> Event<'static> {
-        let timestamp = SystemTime::UNIX_EPOCH + Duration::from_nanos(nanos);
+        ProfileMetadata::parse(&metadata)
+    }
 
-        Event {
-            event_kind: Cow::from(event_kind),
-            label: Cow::from(label),
-            additional_data: &[],
-            timestamp,
-            timestamp_kind,
-            thread_id,
+    /// The largest thread id used by any event, or `None` if there are no
+    /// events.
+    pub(crate) fn max_thread_id(&self) -> Option<u64> {
+        let mut max_thread_id = None;
+        for raw_event in RawEvents::new(&self.event_data) {
+            max_thread_id = max_thread_id.max(Some(raw_event.thread_id));
         }
+        max_thread_id
     }
 
-    #[test]
-    fn build_interval_sequence() {
-        let mut builder = ProfilingDataBuilder::new();
+    pub(crate) fn string_table(&self) -> &StringTable {
+        &self.string_table
+    }
 
-        builder
-            .interval("k1", "id1", 0, 10, 100, |_| {})
-            .interval("k2", "id2", 1, 100, 110, |_| {})
-            .interval("k3", "id3", 0, 120, 140, |_| {});
+    // All records of the `.events` file whose strings are known, including
+    // thread names and the footer
+    pub(crate) fn resolvable_raw_events(&self) -> impl Iterator<Item = RawEvent> + '_ {
+        RawEvents::new(&self.event_data).filter(move |raw_event| {
+            raw_event.event_kind == CLEAN_SHUTDOWN_EVENT_KIND
+                || is_resolvable(&self.string_table, raw_event)
+        })
+    }
 
-        let profiling_data = builder.into_profiling_data();
+    /// The largest timestamp of any record, including thread names and the
+    /// footer, or `None` if there are none.
+    pub(crate) fn last_timestamp_nanos(&self) -> Option<u64> {
+        let mut last_nanos = None;
+        for raw_event in RawEvents::new(&self.event_data) {
+            last_nanos = last_nanos.max(Some(raw_event.timestamp.nanos()));
+        }
+        last_nanos
+    }
 
-        let events: Vec<Event<'_>> = profiling_data.iter().collect();
+    /// The ids of all threads that recorded events, in ascending order.
+    pub fn thread_ids(&self) -> Vec<u64> {
+        let mut thread_ids = FxHashSet::default();
+        for raw_event in RawEvents::new(&self.event_data) {
+            if raw_event.event_kind != THREAD_NAME_EVENT_KIND
+                && raw_event.event_kind != CLEAN_SHUTDOWN_EVENT_KIND
+            {
+                thread_ids.insert(raw_event.thread_id);
+            }
+        }
 
-        assert_eq!(events[0], event("k1", "id1", 0, 10, TimestampKind::Start));
-        assert_eq!(events[1], event("k1", "id1", 0, 100, TimestampKind::End));
-        assert_eq!(events[2], event("k2", "id2", 1, 100, TimestampKind::Start));
-        assert_eq!(events[3], event("k2", "id2", 1, 110, TimestampKind::End));
-        assert_eq!(events[4], event("k3", "id3", 0, 120, TimestampKind::Start));
-        assert_eq!(events[5], event("k3", "id3", 0, 140, TimestampKind::End));
+        let mut thread_ids: Vec<_> = thread_ids.into_iter().collect();
+        thread_ids.sort_unstable();
+        thread_ids
     }
 
-    #[test]
-    fn build_nested_intervals() {
-        let mut b = ProfilingDataBuilder::new();
+    /// Returns all recorded thread names, ordered by thread id.
+    pub fn thread_names(&self) -> Vec<(u64, &str)> {
+        let mut thread_names: Vec<_> = self
+            .thread_names
+            .iter()
+            .map(|(&thread_id, name)| (thread_id, &name[..]))
+            .collect();
+        thread_names.sort_unstable();
+        thread_names
+    }
 
-        b.interval("k1", "id1", 0, 10, 100, |b| {
-            b.interval("k2", "id2", 0, 20, 100, |b| {
-                b.interval("k3", "id3", 0, 30, 90, |_| {});
-            });
-        });
+    /// Iterates the events in the order they are stored in the file.
+    /// Records of types this version of `measureme` doesn't know are skipped,
+    /// which `ProfilerEventIterator::skipped_records()` counts.
+    pub fn iter(&self) -> ProfilerEventIterator<'_> {
+        ProfilerEventIterator::new(self)
+    }
 
-        let profiling_data = b.into_profiling_data();
+    /// Iterates the events of the thread with id `thread_id`, in the order
+    /// they are stored in the file. Events of other threads are skipped
+    /// without resolving any of their strings.
+    ///
+    /// This needs no memory beyond that of the iterator, but every call reads
+    /// the events of all threads, so processing each thread of a profile with
+    /// many threads this way reads the whole profile once per thread. Use
+    /// `ProfilingData::group_by_thread()` to read it only once instead.
+    pub fn iter_thread(&self, thread_id: u64) -> impl Iterator<Item = Event<'_>> {
+        ProfilerEventIterator {
+            thread_filter: Some(thread_id),
+            ..ProfilerEventIterator::new(self)
+        }
+    }
 
-        let events: Vec<Event<'_>> = profiling_data.iter().collect();
+    /// Sorts the events into one `Vec` per thread id in a single pass over
+    /// the profile, keeping the order they are stored in the file, for tools
+    /// that process one thread at a time. Threads without events are
+    /// missing from the map.
+    ///
+    /// Unlike `ProfilingData::iter_thread()`, this holds all events of the
+    /// profile in memory at once: each `Event` takes over 100 bytes, plus
+    /// the strings that can't be borrowed from the string table.
+    pub fn group_by_thread(&self) -> HashMap<u64, Vec<Event<'_>>> {
+        let mut threads: HashMap<u64, Vec<Event<'_>>> = HashMap::new();
+        for event in self.iter() {
+            threads.entry(event.thread_id).or_default().push(event);
+        }
+        threads
+    }
+
+    /// Iterates the events ordered by their timestamps, then their thread
+    /// ids, for tools that need a single timeline across all threads. Events
+    /// are buffered per thread before they are written, so the file order is
+    /// only sorted within each thread. Events with the same timestamp and
+    /// thread id keep the order they are stored in the file.
+    ///
+    /// The events are sorted with an external merge sort, which takes
+    /// O(n log n) time for n events. Up to 2^20 events, 32 MiB worth of raw
+    /// events, are sorted in memory. Larger profiles are sorted in runs of
+    /// that size, which are buffered in temporary files in
+    /// `std::env::temp_dir()` and merged while iterating, so that only one
+    /// event per run is held in memory. The files are removed when the
+    /// iterator is dropped. Errors while writing them are returned right
+    /// away, errors while reading them back end the iteration and are
+    /// reported via `SortedEventIterator::error()`.
+    pub fn iter_sorted(&self) -> io::Result<SortedEventIterator<'_>> {
+        self.iter_sorted_in_runs(SORT_RUN_LEN)
+    }
+
+    fn iter_sorted_in_runs(&self, run_len: usize) -> io::Result<SortedEventIterator<'_>> {
+        let mut raw_events = RawEvents::new(&self.event_data);
+        let mut events = std::iter::from_fn(|| loop {
+            let raw_event = raw_events.next()?;
+            if self.is_event(&raw_event) {
+                return Some((raw_event, raw_events.args()));
+            }
+        });
+        let mut runs = Vec::new();
+
+        loop {
+            let mut run: Vec<SortedEvent> = events.by_ref().take(run_len).collect();
+            // Stable, so that ties keep their file order
+            run.sort_by_key(|(raw_event, _)| sort_key(raw_event));
+
+            if runs.is_empty() && run.len() < run_len {
+                return Ok(SortedEventIterator {
+                    data: self,
+                    events: SortedEvents::InMemory(run.into_iter()),
+                    error: None,
+                });
+            }
+
+            if run.is_empty() {
+                break;
+            }
+
+            let is_last = run.len() < run_len;
+            runs.push(SortedRun::write(&run)?);
+            if is_last {
+                break;
+            }
+        }
+
+        let mut heads = BinaryHeap::with_capacity(runs.len());
+        for (run_idx, run) in runs.iter_mut().enumerate() {
+            if let Some(event) = run.next_event()? {
+                heads.push(run_head(run_idx, &event));
+            }
+        }
+
+        Ok(SortedEventIterator {
+            data: self,
+            events: SortedEvents::Merged { runs, heads },
+            error: None,
+        })
+    }
+
+    pub fn iter_matching_events(&self) -> impl Iterator<Item = MatchingEvent<'_>> {
+        MatchingEventsIterator::new(ProfilerEventIterator::new(self))
+    }
+
+    /// Iterates the interval events with their start and end events matched
+    /// up. Start and end events are paired per thread, taking nesting into
+    /// account, and yielded in the order of their end events. Events that
+    /// can't be paired properly are reported via `IntervalIterator::errors()`
+    /// instead.
+    pub fn iter_intervals(&self) -> IntervalIterator<'_> {
+        IntervalIterator::new(ProfilerEventIterator::new(self))
+    }
+
+    /// Lists the events of the profile as text for snapshot tests of
+    /// instrumented code, which can't compare the binary files since they
+    /// depend on the order in which threads allocated strings and wrote their
+    /// events. All strings are resolved, and the events are grouped by
+    /// thread, one line per event with its timestamp, phase, kind and label:
+    ///
+    /// ```text
+    /// thread "main"
+    ///   0ns start "Query" "typeck"
+    ///   50ns end "Query" "typeck"
+    /// task 7
+    ///   20ns instant "Marker" "resumed" arg=3
+    ///   30ns start "Query" "parse" category="frontend"
+    ///   40ns end "Query" "parse"
+    /// ```
+    ///
+    /// Threads with a name are listed by their name instead of their id,
+    /// since the ids returned by `current_thread_id()` change from run to
+    /// run. Threads come in the order of their names, then their ids, and
+    /// the events of each thread in the order of their timestamps, the
+    /// events with the same timestamp in the order they were recorded.
+    ///
+    /// With `normalize_time`, timestamps are given relative to the earliest
+    /// event of the profile instead of the start of the profiling session.
+    /// They still depend on how long the profiled code took, so snapshot
+    /// tests should record with a `Clock` that advances deterministically,
+    /// see `Profiler::with_clock()`.
+    pub fn to_debug_text(&self, normalize_time: bool) -> String {
+        let mut threads: BTreeMap<(Option<&str>, u64), Vec<Event<'_>>> = BTreeMap::new();
+        for event in self.iter() {
+            let thread_name = self.thread_name(event.thread_id);
+            // Ids of named threads aren't shown, but still tell threads with
+            // the same name apart
+            threads
+                .entry((thread_name, event.thread_id))
+                .or_default()
+                .push(event);
+        }
+
+        let start_ns = match threads.values().flatten().map(|e| e.timestamp).min() {
+            Some(first) if normalize_time => nanos_since_start(first),
+            _ => 0,
+        };
+
+        let mut text = String::new();
+        for ((thread_name, thread_id), events) in &mut threads {
+            match (thread_name, task_id(*thread_id)) {
+                (Some(name), _) => writeln!(text, "thread {:?}", name),
+                (None, Some(task_id)) => writeln!(text, "task {}", task_id),
+                (None, None) => writeln!(text, "thread {}", thread_id),
+            }
+            .unwrap();
+
+            events.sort_by_key(|event| event.timestamp);
+            for event in events.iter() {
+                let phase = match event.timestamp_kind {
+                    TimestampKind::Start => "start",
+                    TimestampKind::End => "end",
+                    TimestampKind::Instant => "instant",
+                    TimestampKind::Counter => "counter",
+                };
+                write!(
+                    text,
+                    "  {}ns {} {:?} {:?}",
+                    nanos_since_start(event.timestamp) - start_ns,
+                    phase,
+                    event.event_kind,
+                    event.label
+                )
+                .unwrap();
+                if let Some(value) = event.value {
+                    write!(text, " value={}", value).unwrap();
+                }
+                if let Some(arg) = event.arg {
+                    write!(text, " arg={}", arg).unwrap();
+                }
+                if let Some(category) = &event.category {
+                    write!(text, " category={:?}", category).unwrap();
+                }
+                text.push('\n');
+            }
+        }
+
+        text
+    }
+
+    /// Checks the timestamps of the profile for signs of a clock that isn't
+    /// monotonic, e.g. because `Instant` went backwards when a thread moved
+    /// to another core. Such profiles can still be read, but the durations
+    /// computed from them may be wrong. The warnings are returned in the
+    /// order of the events they are about.
+    ///
+    /// Start and end events without a counterpart are not reported here,
+    /// `iter_intervals()` reports those.
+    pub fn validate(&self) -> Vec<ValidationWarning<'_>> {
+        let mut warnings = Vec::new();
+        // The timestamp of the previous event and the open intervals of
+        // each thread
+        let mut threads = FxHashMap::<u64, (Option<u64>, Vec<Event<'_>>)>::default();
+
+        for event in self.iter() {
+            let (previous_ns, thread_stack) = threads.entry(event.thread_id).or_default();
+
+            let nanos = nanos_since_start(event.timestamp);
+            let mut out_of_order = previous_ns
+                .replace(nanos)
+                .filter(|&previous| nanos < previous);
+
+            match event.timestamp_kind {
+                TimestampKind::Start => thread_stack.push(event.clone()),
+                TimestampKind::End => {
+                    let start_idx = thread_stack.iter().rposition(|start| {
+                        start.event_kind == event.event_kind && start.label == event.label
+                    });
+
+                    if let Some(start_idx) = start_idx {
+                        let overlapping = start_idx + 1 != thread_stack.len();
+                        let interval = MatchedEvent::new(thread_stack.remove(start_idx), &event);
+
+                        // The end event is necessarily out of order then
+                        if interval.end_ns < interval.start_ns {
+                            out_of_order = None;
+                            warnings.push(ValidationWarning::EndBeforeStart(interval.clone()));
+                        }
+                        if overlapping {
+                            warnings.push(ValidationWarning::Overlapping(interval));
+                        }
+                    }
+                }
+                TimestampKind::Instant | TimestampKind::Counter => {}
+            }
+
+            if let Some(previous_ns) = out_of_order {
+                warnings.push(ValidationWarning::OutOfOrder { event, previous_ns });
+            }
+        }
+
+        warnings
+    }
+
+    /// Iterates the events whose timestamp lies within `start_ns..=end_ns`,
+    /// given in nanoseconds since the start of the profiling session (i.e.
+    /// the same time base as `Event::timestamp`). Interval events that only
+    /// partly overlap the range are included, with their start and end
+    /// clipped to the range.
+    pub fn iter_in_range(&self, start_ns: u64, end_ns: u64) -> impl Iterator<Item = Event<'_>> {
+        ProfilerEventRangeIterator::new(self, start_ns, end_ns, None)
+    }
+
+    /// Iterates the events between two instant events that serve as markers,
+    /// e.g. ones labeled `"phase_start"` and `"phase_end"`: the earliest
+    /// instant event labeled `start_marker` and the earliest one labeled
+    /// `end_marker` that doesn't precede it. The events in between are the
+    /// ones `iter_in_range()` yields for the timestamps of the markers,
+    /// including the markers themselves.
+    pub fn slice_between(
+        &self,
+        start_marker: &str,
+        end_marker: &str,
+    ) -> Result<impl Iterator<Item = Event<'_>>, MarkerError> {
+        let start_ns = self
+            .find_marker(start_marker, 0)
+            .ok_or_else(|| MarkerError::MissingStart(start_marker.to_string()))?;
+        let end_ns = self
+            .find_marker(end_marker, start_ns)
+            .ok_or_else(|| MarkerError::MissingEnd(end_marker.to_string()))?;
+
+        Ok(self.iter_in_range(start_ns, end_ns))
+    }
+
+    // The timestamp of the earliest instant event labeled `marker` that isn't
+    // earlier than `min_ns`. Each label is only resolved once.
+    fn find_marker(&self, marker: &str, min_ns: u64) -> Option<u64> {
+        let mut is_marker = FxHashMap::default();
+        let mut marker_ns: Option<u64> = None;
+
+        for raw_event in RawEvents::new(&self.event_data) {
+            let nanos = raw_event.timestamp.nanos();
+            if raw_event.timestamp.kind() != TimestampKind::Instant
+                || raw_event.event_kind == THREAD_NAME_EVENT_KIND
+                || nanos < min_ns
+                || marker_ns.is_some_and(|marker_ns| marker_ns <= nanos)
+                || !is_resolvable(&self.string_table, &raw_event)
+            {
+                continue;
+            }
+
+            let string_table = &self.string_table;
+            if *is_marker
+                .entry(raw_event.id)
+                .or_insert_with(|| string_table.get(raw_event.id).to_string() == marker)
+            {
+                marker_ns = Some(nanos);
+            }
+        }
+
+        marker_ns
+    }
+
+    /// Iterates the events whose `event_kind` satisfies `pred`. Events that
+    /// don't match are skipped without resolving any of their strings. Since
+    /// start and end events have the same kind, the retained events are still
+    /// properly nested.
+    pub fn iter_matching<'a, F>(&'a self, pred: F) -> impl Iterator<Item = Event<'a>>
+    where
+        F: Fn(StringId) -> bool + 'a,
+    {
+        ProfilerEventIterator::with_kind_filter(self, Box::new(pred))
+    }
+
+    /// Iterates the events with the given `event_kind`. The kind strings are
+    /// resolved lazily and only once per distinct `StringId`.
+    pub fn iter_matching_kind<'a>(
+        &'a self,
+        event_kind: &'a str,
+    ) -> impl Iterator<Item = Event<'a>> {
+        self.iter_matching(self.kind_name_filter(event_kind))
+    }
+
+    /// Like `iter_in_range()`, but only yields events whose `event_kind`
+    /// satisfies `pred`.
+    pub fn iter_in_range_matching<'a, F>(
+        &'a self,
+        start_ns: u64,
+        end_ns: u64,
+        pred: F,
+    ) -> impl Iterator<Item = Event<'a>>
+    where
+        F: Fn(StringId) -> bool + 'a,
+    {
+        ProfilerEventRangeIterator::new(self, start_ns, end_ns, Some(Box::new(pred)))
+    }
+
+    /// Returns a predicate that matches the `StringId`s that resolve to
+    /// `event_kind`, caching the result for each id.
+    pub fn kind_name_filter<'a>(&'a self, event_kind: &'a str) -> impl Fn(StringId) -> bool + 'a {
+        let cache = RefCell::new(FxHashMap::default());
+
+        move |id| {
+            *cache
+                .borrow_mut()
+                .entry(id)
+                .or_insert_with(|| self.event_string(id) == event_kind)
+        }
+    }
+}
+
+/// A predicate on the `event_kind` of events, applied before the rest of an
+/// event is decoded.
+type KindFilter<'a> = Box<dyn Fn(StringId) -> bool + 'a>;
+
+/// The iterator returned by `ProfilingData::iter()`.
+pub struct ProfilerEventIterator<'a> {
+    data: &'a ProfilingData,
+    raw_events: RawEvents<'a>,
+    kind_filter: Option<KindFilter<'a>>,
+    thread_filter: Option<u64>,
+}
+
+impl<'a> ProfilerEventIterator<'a> {
+    pub fn new(data: &'a ProfilingData) -> ProfilerEventIterator<'a> {
+        ProfilerEventIterator {
+            data,
+            raw_events: RawEvents::new(&data.event_data),
+            kind_filter: None,
+            thread_filter: None,
+        }
+    }
+
+    fn with_kind_filter(
+        data: &'a ProfilingData,
+        kind_filter: KindFilter<'a>,
+    ) -> ProfilerEventIterator<'a> {
+        ProfilerEventIterator {
+            kind_filter: Some(kind_filter),
+            ..ProfilerEventIterator::new(data)
+        }
+    }
+
+    /// The number of tagged records of types this version of `measureme`
+    /// doesn't know, e.g. written by a newer version, that were skipped so
+    /// far. The events around them are read as usual.
+    pub fn skipped_records(&self) -> usize {
+        self.raw_events.skipped_records
+    }
+}
+
+impl<'a> Iterator for ProfilerEventIterator<'a> {
+    type Item = Event<'a>;
+
+    fn next(&mut self) -> Option<Event<'a>> {
+        loop {
+            let raw_event = self.raw_events.next()?;
+
+            if !self.data.is_event(&raw_event) {
+                continue;
+            }
+
+            if self.thread_filter.is_some() && self.thread_filter != Some(raw_event.thread_id) {
+                continue;
+            }
+
+            if let Some(kind_filter) = &self.kind_filter {
+                if !kind_filter(raw_event.event_kind) {
+                    continue;
+                }
+            }
+
+            return Some(self.data.decode_event(
+                &raw_event,
+                raw_event.timestamp.nanos(),
+                self.raw_events.args(),
+            ));
+        }
+    }
+}
+
+impl ProfilingData {
+    // Whether `raw_event` is yielded by `ProfilingData::iter()`. Thread names
+    // are exposed via `ProfilingData::thread_name()` and the footer via
+    // `ProfilingData::is_complete()` instead.
+    fn is_event(&self, raw_event: &RawEvent) -> bool {
+        raw_event.event_kind != THREAD_NAME_EVENT_KIND
+            && raw_event.event_kind != CLEAN_SHUTDOWN_EVENT_KIND
+            && self.is_decodable(raw_event)
+    }
+
+    // Whether the iterators yield `raw_event`: events whose strings are
+    // missing are skipped, unless all strings are
+    fn is_decodable(&self, raw_event: &RawEvent) -> bool {
+        self.strings_missing || is_resolvable(&self.string_table, raw_event)
+    }
+
+    // The string of `id`, or the id itself if the string is missing
+    fn event_string(&self, id: StringId) -> Cow<'_, str> {
+        if id == StringId::INVALID || self.string_table.contains(id) {
+            self.string_table.get(id).to_string()
+        } else {
+            Cow::from(format!("{:?}", id))
+        }
+    }
+
+    /// Resolves the strings of `raw_event`, using `nanos` as its timestamp,
+    /// and decodes its arguments from the records at `args`, see
+    /// `RawEvents::args()`.
+    fn decode_event(&self, raw_event: &RawEvent, nanos: u64, args: Range<usize>) -> Event<'_> {
+        let mut timestamp = SystemTime::UNIX_EPOCH;
+        timestamp += Duration::from_nanos(nanos);
+
+        Event {
+            event_kind: self.event_string(raw_event.event_kind),
+            label: self.event_string(raw_event.id),
+            event_kind_id: raw_event.event_kind,
+            label_id: raw_event.id,
+            additional_data: &[],
+            timestamp,
+            timestamp_kind: raw_event.timestamp.kind(),
+            thread_id: raw_event.thread_id,
+            value: match raw_event.timestamp.kind() {
+                TimestampKind::Counter => Some(raw_event.payload),
+                _ => None,
+            },
+            arg: if raw_event.timestamp.has_arg() {
+                Some(raw_event.payload)
+            } else {
+                None
+            },
+            category: raw_event.category().map(|id| self.event_string(id)),
+            args: self.decode_args(event_args(&self.event_data, args)),
+        }
+    }
+
+    fn decode_args(
+        &self,
+        args: impl Iterator<Item = (StringId, ArgValue)>,
+    ) -> BTreeMap<Cow<'_, str>, EventArgValue<'_>> {
+        args.map(|(key, value)| {
+            let value = match value {
+                ArgValue::Str(id) => EventArgValue::Str(self.event_string(id)),
+                ArgValue::Int(i) => EventArgValue::Int(i),
+                ArgValue::Float(f) => EventArgValue::Float(f),
+                ArgValue::Bool(b) => EventArgValue::Bool(b),
+            };
+            (self.event_string(key), value)
+        })
+        .collect()
+    }
+}
+
+/// Iterates the events that fall into `start_ns..=end_ns`. Interval events
+/// that overlap the range but start before or end after it are clipped to the
+/// range, so that every start event still has a matching end event.
+struct ProfilerEventRangeIterator<'a> {
+    data: &'a ProfilingData,
+    raw_events: RawEvents<'a>,
+    start_ns: u64,
+    end_ns: u64,
+    // Events are only ordered by time within a single thread, so the
+    // position relative to the range is tracked per thread.
+    threads: FxHashMap<u64, ThreadRangeState>,
+    // Clipped start events that still need to be yielded, in reverse order,
+    // with the records holding their arguments.
+    clipped_starts: Vec<(RawEvent, Range<usize>)>,
+    peeked: Option<RawEvent>,
+    kind_filter: Option<KindFilter<'a>>,
+}
+
+#[derive(Default)]
+struct ThreadRangeState {
+    in_range: bool,
+    // Start events before the range for which no end event has been seen
+    // yet, with the records holding their arguments.
+    open_before_range: Vec<(RawEvent, Range<usize>)>,
+    // The number of start events after the range whose end event is ignored.
+    skipped_after_range: usize,
+}
+
+impl<'a> ProfilerEventRangeIterator<'a> {
+    fn new(
+        data: &'a ProfilingData,
+        start_ns: u64,
+        end_ns: u64,
+        kind_filter: Option<KindFilter<'a>>,
+    ) -> ProfilerEventRangeIterator<'a> {
+        ProfilerEventRangeIterator {
+            data,
+            raw_events: RawEvents::new(&data.event_data),
+            start_ns,
+            end_ns,
+            threads: FxHashMap::default(),
+            clipped_starts: Vec::new(),
+            peeked: None,
+            kind_filter,
+        }
+    }
+
+    fn next_raw_event(&mut self) -> Option<RawEvent> {
+        if let Some(raw_event) = self.peeked.take() {
+            return Some(raw_event);
+        }
+
+        loop {
+            let raw_event = self.raw_events.next()?;
+
+            if raw_event.event_kind == THREAD_NAME_EVENT_KIND
+                || raw_event.event_kind == CLEAN_SHUTDOWN_EVENT_KIND
+                || !self.data.is_decodable(&raw_event)
+            {
+                continue;
+            }
+
+            if let Some(kind_filter) = &self.kind_filter {
+                if !kind_filter(raw_event.event_kind) {
+                    continue;
+                }
+            }
+
+            return Some(raw_event);
+        }
+    }
+}
+
+impl<'a> Iterator for ProfilerEventRangeIterator<'a> {
+    type Item = Event<'a>;
+
+    fn next(&mut self) -> Option<Event<'a>> {
+        loop {
+            if let Some((raw_event, args)) = self.clipped_starts.pop() {
+                return Some(self.data.decode_event(&raw_event, self.start_ns, args));
+            }
+
+            // Events outside of the range are only decoded as far as needed
+            // for keeping track of which intervals are open.
+            let raw_event = self.next_raw_event()?;
+            let nanos = raw_event.timestamp.nanos();
+            let thread = self.threads.entry(raw_event.thread_id).or_default();
+
+            if !thread.in_range {
+                if nanos < self.start_ns {
+                    match raw_event.timestamp.kind() {
+                        TimestampKind::Start => thread
+                            .open_before_range
+                            .push((raw_event, self.raw_events.args())),
+                        TimestampKind::End => {
+                            thread.open_before_range.pop();
+                        }
+                        TimestampKind::Instant | TimestampKind::Counter => {}
+                    }
+                    continue;
+                }
+
+                // The thread has reached the range, so its intervals that
+                // are still open started before it.
+                thread.in_range = true;
+                self.clipped_starts = std::mem::take(&mut thread.open_before_range);
+                self.clipped_starts.reverse();
+                self.peeked = Some(raw_event);
+                continue;
+            }
+
+            if nanos <= self.end_ns {
+                return Some(
+                    self.data
+                        .decode_event(&raw_event, nanos, self.raw_events.args()),
+                );
+            }
+
+            match raw_event.timestamp.kind() {
+                TimestampKind::Start => thread.skipped_after_range += 1,
+                TimestampKind::End => {
+                    if thread.skipped_after_range > 0 {
+                        thread.skipped_after_range -= 1;
+                    } else {
+                        return Some(self.data.decode_event(
+                            &raw_event,
+                            self.end_ns,
+                            self.raw_events.args(),
+                        ));
+                    }
+                }
+                TimestampKind::Instant | TimestampKind::Counter => {}
+            }
+        }
+    }
+}
+
+// Opens one of the files of a profile and checks its file magic, so that
+// pointing a tool at the wrong file results in a helpful error.
+fn open_profile_file(path: &Path, expected_magic: &[u8; 4]) -> Result<File, Box<dyn Error>> {
+    let mut file =
+        File::open(path).map_err(|e| format!("couldn't open `{}`: {}", path.display(), e))?;
+
+    let mut start = Vec::with_capacity(FILE_HEADER_SIZE);
+    file.by_ref()
+        .take(FILE_HEADER_SIZE as u64)
+        .read_to_end(&mut start)?;
+    check_file_magic(path, &start, expected_magic)?;
+
+    file.seek(SeekFrom::Start(0))?;
+    Ok(file)
+}
+
+// Files written by a `CompressedSink` are decompressed up front, so that the
+// rest of the reader does not have to know about compression.
+fn decompress_if_needed(data: ByteBuffer) -> Result<ByteBuffer, Box<dyn Error>> {
+    if data.len() < FILE_HEADER_SIZE || &data[0..4] != FILE_MAGIC_COMPRESSED {
+        return Ok(data);
+    }
+
+    #[cfg(feature = "compression")]
+    {
+        crate::compressed_sink::decompress(&data).map(ByteBuffer::from)
+    }
+
+    #[cfg(not(feature = "compression"))]
+    {
+        Err(
+            "This profile is compressed. Reading it requires the `compression` \
+             feature of `measureme`."
+                .into(),
+        )
+    }
+}
+
+/// Returns the events in `event_data` as a slice of `RawEvent`s, without
+/// copying them, if the data is suitably aligned. This is always the case for
+/// memory-mapped files, since mappings start at a page boundary and the file
+/// header is a multiple of `RawEvent`'s alignment. A trailing partial record is
+/// not part of the returned slice. Events are stored little-endian, so on
+/// big-endian machines they are always copied.
+#[cfg(target_endian = "little")]
+fn raw_event_slice(event_data: &[u8]) -> Option<&[RawEvent]> {
+    let events = event_data.get(FILE_HEADER_SIZE..)?;
+
+    if !(events.as_ptr() as usize).is_multiple_of(mem::align_of::<RawEvent>()) {
+        return None;
+    }
+
+    let len = events.len() / mem::size_of::<RawEvent>();
+
+    // SAFETY: `RawEvent` is `#[repr(C)]` and consists only of integer fields
+    // (`StringId` and `Timestamp` are `#[repr(C)]` wrappers around integers)
+    // that add up to exactly `size_of::<RawEvent>()` bytes, so it has no
+    // padding and every bit pattern is a valid value. The pointer is aligned
+    // (checked above) and `len` records fit into `events`, which is borrowed
+    // for the lifetime of the returned slice. The in-memory layout is the
+    // little-endian on-disk layout.
+    Some(unsafe { std::slice::from_raw_parts(events.as_ptr() as *const RawEvent, len) })
+}
+
+#[cfg(target_endian = "big")]
+fn raw_event_slice(_event_data: &[u8]) -> Option<&[RawEvent]> {
+    None
+}
+
+// Events of truncated profiles can refer to strings that were never written.
+// `StringId::INVALID` is resolved as `<unknown>` instead.
+#[inline]
+fn is_resolvable(string_table: &StringTable, raw_event: &RawEvent) -> bool {
+    let is_resolvable = |id| id == StringId::INVALID || string_table.contains(id);
+    is_resolvable(raw_event.event_kind) && is_resolvable(raw_event.id)
+}
+
+/// The size of an event in version `0` of the file format, which had no
+/// `payload` field.
+const V0_RAW_EVENT_SIZE: usize = 24;
+
+// Converts a version `0` event stream into the current layout. Version `0`
+// events were `[event_kind: u32, id: u32, thread_id: u64, timestamp: u64]`
+// in the native byte order of the writing machine, with timestamps packed as
+// `[nanos: 62 bits, kind: 2 bits]`. A partially written event at the end is
+// dropped.
+fn upgrade_v0_events(event_data: &[u8]) -> Vec<u8> {
+    let events = strip_file_header(event_data).chunks_exact(V0_RAW_EVENT_SIZE);
+
+    let mut upgraded =
+        Vec::with_capacity(FILE_HEADER_SIZE + events.len() * mem::size_of::<RawEvent>());
+    upgraded.extend_from_slice(FILE_MAGIC_EVENT_STREAM);
+    // Version `0` files don't record the version of the writer
+    upgraded.extend_from_slice(&[CURRENT_FILE_FORMAT_VERSION as u8, 0, 0, 0]);
+
+    for event in events {
+        let timestamp = NativeEndian::read_u64(&event[16..]);
+        let kind = match timestamp & 0b11 {
+            0 => TimestampKind::Start,
+            1 => TimestampKind::End,
+            _ => TimestampKind::Instant,
+        };
+
+        let raw_event = RawEvent {
+            event_kind: StringId::reserved(NativeEndian::read_u32(&event[0..])),
+            id: StringId::reserved(NativeEndian::read_u32(&event[4..])),
+            thread_id: NativeEndian::read_u64(&event[8..]),
+            timestamp: Timestamp::saturating_new(timestamp >> 2, kind).0,
+            payload: 0,
+        };
+        upgraded.extend_from_slice(&raw_event.to_bytes());
+    }
+
+    upgraded
+}
+
+// Reads one of the files of a profile in full, see `open_profile_file()`.
+fn read_profile_file(path: &Path, expected_magic: &[u8; 4]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut data = Vec::new();
+    open_profile_file(path, expected_magic)?.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+// Decompresses an event stream and converts it to the current file format
+// version if necessary. Also returns whether a partially written event at the
+// end of the stream was ignored.
+fn read_event_stream(event_data: ByteBuffer) -> Result<(ByteBuffer, bool), Box<dyn Error>> {
+    let event_data = decompress_if_needed(event_data)?;
+
+    let event_data_format = read_file_header(&event_data, FILE_MAGIC_EVENT_STREAM)?;
+    check_file_format_version(event_data_format)?;
+
+    let event_size = if event_data_format == 0 {
+        V0_RAW_EVENT_SIZE
+    } else {
+        mem::size_of::<RawEvent>()
+    };
+    let partial_event = !(event_data.len() - FILE_HEADER_SIZE).is_multiple_of(event_size);
+
+    let event_data = if event_data_format == 0 {
+        upgrade_v0_events(&event_data).into()
+    } else {
+        event_data
+    };
+
+    Ok((event_data, partial_event))
+}
+
+// Merges the event streams of the shards of a profile into a single stream.
+// Each thread's events are usually all in one shard, in the order they were
+// recorded, so sorting all events by timestamp, keeping the order of events
+// with the same timestamp, keeps them in that order. Events of a thread that
+// ended up in several shards are interleaved by their timestamps. The records
+// holding the arguments of an event stay right after it. The merged stream
+// ends with a footer if every shard does.
+fn merge_event_shards(shards: &[ByteBuffer]) -> Vec<u8> {
+    let mut events = Vec::new();
+    let mut complete_shards = 0;
+    let mut last_timestamp = None;
+
+    for shard in shards {
+        let mut has_footer = false;
+        let mut raw_events = RawEvents::new(shard);
+        while let Some(raw_event) = raw_events.next() {
+            has_footer = raw_event.event_kind == CLEAN_SHUTDOWN_EVENT_KIND;
+            if has_footer {
+                last_timestamp = last_timestamp.max(Some(raw_event.timestamp.nanos()));
+                continue;
+            }
+
+            events.push((raw_event, record_bytes(shard, raw_events.args())));
+        }
+        complete_shards += has_footer as usize;
+    }
+
+    events.sort_by_key(|(raw_event, _)| raw_event.timestamp.nanos());
+
+    let mut merged =
+        Vec::with_capacity(FILE_HEADER_SIZE + (events.len() + 1) * mem::size_of::<RawEvent>());
+    match shards.first() {
+        // Keeps the writer version of the first shard
+        Some(shard) => merged.extend_from_slice(&shard[..FILE_HEADER_SIZE]),
+        None => merged.extend_from_slice(&file_header(FILE_MAGIC_EVENT_STREAM)),
+    }
+
+    for (raw_event, args) in &events {
+        merged.extend_from_slice(&raw_event.to_bytes());
+        merged.extend_from_slice(args);
+    }
+
+    if let (Some(nanos), true) = (last_timestamp, complete_shards == shards.len()) {
+        let footer = RawEvent {
+            event_kind: CLEAN_SHUTDOWN_EVENT_KIND,
+            id: CLEAN_SHUTDOWN_EVENT_KIND,
+            thread_id: 0,
+            timestamp: Timestamp::new(nanos, TimestampKind::Instant),
+            payload: 0,
+        };
+        merged.extend_from_slice(&footer.to_bytes());
+    }
+
+    merged
+}
+
+fn read_raw_event(event_data: &[u8], event_idx: usize) -> Option<RawEvent> {
+    if let Some(events) = raw_event_slice(event_data) {
+        return events.get(event_idx).copied();
+    }
+
+    let event_start_addr = FILE_HEADER_SIZE + event_idx * mem::size_of::<RawEvent>();
+    let event_end_addr = event_start_addr + mem::size_of::<RawEvent>();
+    if event_end_addr > event_data.len() {
+        return None;
+    }
+
+    let raw_event_bytes = &event_data[event_start_addr..event_end_addr];
+
+    Some(RawEvent::deserialize(raw_event_bytes))
+}
+
+/// Iterates the events of an event stream, skipping the tagged records that
+/// are documented for `RawEvent`, along with their bodies.
+#[derive(Clone)]
+struct RawEvents<'a> {
+    event_data: &'a [u8],
+    next_event_idx: usize,
+    skipped_records: usize,
+    // Set if the body of the last tagged record is cut off
+    truncated: bool,
+    // The records holding the arguments of the last event, see
+    // `Profiler::record_args_event()`
+    args: Range<usize>,
+}
+
+impl<'a> RawEvents<'a> {
+    fn new(event_data: &'a [u8]) -> RawEvents<'a> {
+        RawEvents {
+            event_data,
+            next_event_idx: 0,
+            skipped_records: 0,
+            truncated: false,
+            args: 0..0,
+        }
+    }
+
+    // Skips the tagged record at `next_event_idx`, or returns `false` if its
+    // body is cut off
+    fn skip_tagged_record(&mut self, header: &RawEvent) -> bool {
+        // The body is padded to whole records
+        let body_len = usize::try_from(header.thread_id).unwrap_or(usize::MAX);
+        let body_records = body_len.div_ceil(mem::size_of::<RawEvent>());
+        let records = (self.event_data.len() - FILE_HEADER_SIZE) / mem::size_of::<RawEvent>();
+        if body_records > records - self.next_event_idx {
+            self.next_event_idx = records;
+            self.truncated = true;
+            return false;
+        }
+
+        self.next_event_idx += body_records;
+        true
+    }
+
+    /// The records holding the arguments of the event returned by the last
+    /// call to `next()`, as decoded by `event_args()`.
+    fn args(&self) -> Range<usize> {
+        self.args.clone()
+    }
+}
+
+// The arguments in the records at `records` of `event_data`, as returned by
+// `RawEvents::args()`
+fn event_args(
+    event_data: &[u8],
+    records: Range<usize>,
+) -> impl Iterator<Item = (StringId, ArgValue)> + '_ {
+    let (mut idx, end) = (records.start, records.end);
+    std::iter::from_fn(move || {
+        if idx >= end {
+            return None;
+        }
+
+        let header = read_raw_event(event_data, idx)?;
+        let body_start = FILE_HEADER_SIZE + (idx + 1) * mem::size_of::<RawEvent>();
+        let body_len = header.thread_id as usize;
+        idx += 1 + body_len.div_ceil(mem::size_of::<RawEvent>());
+        Some(deserialize_args(
+            &event_data[body_start..body_start + body_len],
+        ))
+    })
+    .flatten()
+}
+
+// The bytes of the records at `records` of `event_data`
+fn record_bytes(event_data: &[u8], records: Range<usize>) -> &[u8] {
+    let start = FILE_HEADER_SIZE + records.start * mem::size_of::<RawEvent>();
+    let end = FILE_HEADER_SIZE + records.end * mem::size_of::<RawEvent>();
+    &event_data[start..end]
+}
+
+impl Iterator for RawEvents<'_> {
+    type Item = RawEvent;
+
+    fn next(&mut self) -> Option<RawEvent> {
+        loop {
+            let raw_event = read_raw_event(self.event_data, self.next_event_idx)?;
+            self.next_event_idx += 1;
+
+            if raw_event.event_kind != TAGGED_RECORD_EVENT_KIND {
+                // The arguments of an event directly follow it
+                let args_start = self.next_event_idx;
+                while let Some(header) = read_raw_event(self.event_data, self.next_event_idx)
+                    .filter(|header| args_record_thread(header) == Some(raw_event.thread_id))
+                {
+                    self.next_event_idx += 1;
+                    if !self.skip_tagged_record(&header) {
+                        self.next_event_idx = args_start;
+                        self.truncated = false;
+                        break;
+                    }
+                }
+                self.args = args_start..self.next_event_idx;
+                return Some(raw_event);
+            }
+
+            if !self.skip_tagged_record(&raw_event) {
+                return None;
+            }
+
+            // Arguments that don't follow their event are dropped, see
+            // `Profiler::record_args_event()`
+            if args_record_thread(&raw_event).is_none() {
+                self.skipped_records += 1;
+            }
+        }
+    }
+}
+
+/// The number of events `ProfilingData::iter_sorted()` sorts in memory.
+const SORT_RUN_LEN: usize = 1 << 20;
+
+fn sort_key(raw_event: &RawEvent) -> (u64, u64) {
+    (raw_event.timestamp.nanos(), raw_event.thread_id)
+}
+
+// An event to be sorted, with the records holding its arguments, see
+// `RawEvents::args()`
+type SortedEvent = (RawEvent, Range<usize>);
+
+// The size of a `SortedEvent` in the temporary files of sorted runs: the
+// event followed by the start and end of its argument records
+const SORTED_EVENT_SIZE: usize = RAW_EVENT_SIZE + 16;
+
+fn sorted_event_to_bytes((raw_event, args): &SortedEvent) -> [u8; SORTED_EVENT_SIZE] {
+    let mut bytes = [0; SORTED_EVENT_SIZE];
+    bytes[..RAW_EVENT_SIZE].copy_from_slice(&raw_event.to_bytes());
+    NativeEndian::write_u64(&mut bytes[RAW_EVENT_SIZE..], args.start as u64);
+    NativeEndian::write_u64(&mut bytes[RAW_EVENT_SIZE + 8..], args.end as u64);
+    bytes
+}
+
+fn sorted_event_from_bytes(bytes: &[u8; SORTED_EVENT_SIZE]) -> SortedEvent {
+    let raw_event = RawEvent::deserialize(&bytes[..RAW_EVENT_SIZE]);
+    let start = NativeEndian::read_u64(&bytes[RAW_EVENT_SIZE..]) as usize;
+    let end = NativeEndian::read_u64(&bytes[RAW_EVENT_SIZE + 8..]) as usize;
+    (raw_event, start..end)
+}
+
+/// The iterator returned by `ProfilingData::iter_sorted()`.
+pub struct SortedEventIterator<'a> {
+    data: &'a ProfilingData,
+    events: SortedEvents,
+    error: Option<io::Error>,
+}
+
+enum SortedEvents {
+    InMemory(std::vec::IntoIter<SortedEvent>),
+    Merged {
+        runs: Vec<SortedRun>,
+        heads: BinaryHeap<RunHead>,
+    },
+}
+
+// The next event of a run, ordered by the run's index on ties, since earlier
+// runs hold the events that come earlier in the file
+type RunHead = Reverse<((u64, u64), usize, [u8; SORTED_EVENT_SIZE])>;
+
+fn run_head(run_idx: usize, event: &SortedEvent) -> RunHead {
+    Reverse((sort_key(&event.0), run_idx, sorted_event_to_bytes(event)))
+}
+
+impl SortedEventIterator<'_> {
+    /// The error that ended the iteration early, if reading back one of the
+    /// temporary files failed.
+    pub fn error(&self) -> Option<&io::Error> {
+        self.error.as_ref()
+    }
+
+    fn next_event(&mut self) -> Option<SortedEvent> {
+        match &mut self.events {
+            SortedEvents::InMemory(events) => events.next(),
+            SortedEvents::Merged { runs, heads } => {
+                let Reverse((_, run_idx, bytes)) = heads.pop()?;
+                match runs[run_idx].next_event() {
+                    Ok(Some(next)) => heads.push(run_head(run_idx, &next)),
+                    Ok(None) => {}
+                    Err(error) => {
+                        heads.clear();
+                        self.error = Some(error);
+                    }
+                }
+                Some(sorted_event_from_bytes(&bytes))
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for SortedEventIterator<'a> {
+    type Item = Event<'a>;
+
+    fn next(&mut self) -> Option<Event<'a>> {
+        let (raw_event, args) = self.next_event()?;
+        Some(
+            self.data
+                .decode_event(&raw_event, raw_event.timestamp.nanos(), args),
+        )
+    }
+}
+
+/// A sorted run of events, buffered in a temporary file.
+struct SortedRun {
+    path: PathBuf,
+    reader: BufReader<File>,
+}
+
+impl SortedRun {
+    fn write(run: &[SortedEvent]) -> io::Result<SortedRun> {
+        use std::io::Write as _;
+
+        static NEXT_RUN_ID: AtomicUsize = AtomicUsize::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "measureme-sort-{}-{}",
+            std::process::id(),
+            NEXT_RUN_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        // Removes the file from here on, even if writing it fails
+        let mut sorted_run = SortedRun {
+            path,
+            reader: BufReader::new(file.try_clone()?),
+        };
+
+        let mut writer = BufWriter::new(&mut file);
+        for event in run {
+            writer.write_all(&sorted_event_to_bytes(event))?;
+        }
+        writer.flush()?;
+        drop(writer);
+
+        sorted_run.reader.seek(SeekFrom::Start(0))?;
+        Ok(sorted_run)
+    }
+
+    fn next_event(&mut self) -> io::Result<Option<SortedEvent>> {
+        let mut bytes = [0; SORTED_EVENT_SIZE];
+        match self.reader.read_exact(&mut bytes) {
+            Ok(()) => Ok(Some(sorted_event_from_bytes(&bytes))),
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+impl Drop for SortedRun {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum MatchingEvent<'a> {
+    StartStop(Event<'a>, Event<'a>),
+    Instant(Event<'a>),
+    Counter(Event<'a>),
+}
+
+struct MatchingEventsIterator<'a> {
+    events: ProfilerEventIterator<'a>,
+    thread_stacks: FxHashMap<u64, Vec<Event<'a>>>,
+}
+
+impl<'a> MatchingEventsIterator<'a> {
+    pub fn new(events: ProfilerEventIterator<'a>) -> MatchingEventsIterator<'a> {
+        MatchingEventsIterator {
+            events,
+            thread_stacks: FxHashMap::default(),
+        }
+    }
+}
+
+impl<'a> Iterator for MatchingEventsIterator<'a> {
+    type Item = MatchingEvent<'a>;
+
+    fn next(&mut self) -> Option<MatchingEvent<'a>> {
+        for event in self.events.by_ref() {
+            match event.timestamp_kind {
+                TimestampKind::Start => {
+                    self.thread_stacks
+                        .entry(event.thread_id)
+                        .or_default()
+                        .push(event);
+                }
+                TimestampKind::Instant => {
+                    return Some(MatchingEvent::Instant(event));
+                }
+                TimestampKind::Counter => {
+                    return Some(MatchingEvent::Counter(event));
+                }
+                TimestampKind::End => {
+                    let thread_stack = self.thread_stacks.entry(event.thread_id).or_default();
+
+                    // The start events above the matching one were never
+                    // ended, e.g. because their `TimingGuard` was cancelled,
+                    // so they are discarded.
+                    let start_idx = match thread_stack.iter().rposition(|start| {
+                        start.event_kind == event.event_kind && start.label == event.label
+                    }) {
+                        Some(start_idx) => start_idx,
+                        // The start event is missing, e.g. because it was
+                        // overwritten in the ring buffer a snapshot was taken
+                        // from, so the end event is skipped.
+                        None => continue,
+                    };
+                    thread_stack.truncate(start_idx + 1);
+                    let previous_event = thread_stack.pop().unwrap();
+
+                    return Some(MatchingEvent::StartStop(previous_event, event));
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// An interval event, as yielded by `ProfilingData::iter_intervals()`. The
+/// timestamps are given in nanoseconds since the start of the profiling
+/// session.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MatchedEvent<'a> {
+    pub kind: Cow<'a, str>,
+    pub id: Cow<'a, str>,
+    pub thread_id: u64,
+    pub start_ns: u64,
+    pub end_ns: u64,
+}
+
+impl<'a> MatchedEvent<'a> {
+    /// Like `Event::task_id()`.
+    pub fn task_id(&self) -> Option<u64> {
+        task_id(self.thread_id)
+    }
+
+    fn new(start: Event<'a>, end: &Event<'a>) -> MatchedEvent<'a> {
+        MatchedEvent {
+            kind: start.event_kind,
+            id: start.label,
+            thread_id: start.thread_id,
+            start_ns: nanos_since_start(start.timestamp),
+            end_ns: nanos_since_start(end.timestamp),
+        }
+    }
+}
+
+fn nanos_since_start(timestamp: SystemTime) -> u64 {
+    timestamp
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64
+}
+
+/// An interval that `ProfilingData::iter_intervals()` couldn't match up
+/// properly.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum IntervalError<'a> {
+    /// A start event without an end event, e.g. because the profiled process
+    /// crashed.
+    UnmatchedStart(Event<'a>),
+    /// An end event without a preceding start event on the same thread.
+    UnmatchedEnd(Event<'a>),
+    /// An interval that ended while an interval that started within it was
+    /// still open, i.e. the two overlap instead of being nested. The interval
+    /// is still yielded by the iterator.
+    Overlapping(MatchedEvent<'a>),
+}
+
+/// The iterator returned by `ProfilingData::iter_intervals()`.
+pub struct IntervalIterator<'a> {
+    events: ProfilerEventIterator<'a>,
+    thread_stacks: FxHashMap<u64, Vec<Event<'a>>>,
+    errors: Vec<IntervalError<'a>>,
+}
+
+impl<'a> IntervalIterator<'a> {
+    fn new(events: ProfilerEventIterator<'a>) -> IntervalIterator<'a> {
+        IntervalIterator {
+            events,
+            thread_stacks: FxHashMap::default(),
+            errors: Vec::new(),
+        }
+    }
+
+    /// The problems found so far. Start events without an end event are only
+    /// known once the iterator is exhausted, so this is only complete after
+    /// `next()` has returned `None`.
+    pub fn errors(&self) -> &[IntervalError<'a>] {
+        &self.errors
+    }
+}
+
+impl<'a> Iterator for IntervalIterator<'a> {
+    type Item = MatchedEvent<'a>;
+
+    fn next(&mut self) -> Option<MatchedEvent<'a>> {
+        for event in self.events.by_ref() {
+            match event.timestamp_kind {
+                TimestampKind::Start => {
+                    self.thread_stacks
+                        .entry(event.thread_id)
+                        .or_default()
+                        .push(event);
+                }
+                TimestampKind::End => {
+                    let thread_stack = self.thread_stacks.entry(event.thread_id).or_default();
+
+                    // Usually this is the innermost open interval, unless the
+                    // intervals overlap.
+                    let start_idx = thread_stack.iter().rposition(|start| {
+                        start.event_kind == event.event_kind && start.label == event.label
+                    });
+
+                    match start_idx {
+                        Some(start_idx) => {
+                            let overlapping = start_idx + 1 != thread_stack.len();
+                            let interval =
+                                MatchedEvent::new(thread_stack.remove(start_idx), &event);
+
+                            if overlapping {
+                                self.errors
+                                    .push(IntervalError::Overlapping(interval.clone()));
+                            }
+
+                            return Some(interval);
+                        }
+                        None => self.errors.push(IntervalError::UnmatchedEnd(event)),
+                    }
+                }
+                TimestampKind::Instant | TimestampKind::Counter => {}
+            }
+        }
+
+        // Whatever is still open now won't be closed anymore
+        let mut thread_stacks: Vec<_> = self.thread_stacks.drain().collect();
+        thread_stacks.sort_unstable_by_key(|&(thread_id, _)| thread_id);
+        self.errors.extend(
+            thread_stacks
+                .into_iter()
+                .flat_map(|(_, stack)| stack)
+                .map(IntervalError::UnmatchedStart),
+        );
+
+        None
+    }
+}
+
+/// Returned by `ProfilingData::slice_between()` if one of the markers
+/// couldn't be found. Both contain the label of the marker.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MarkerError {
+    /// There is no instant event labeled with the start marker.
+    MissingStart(String),
+    /// There is no instant event labeled with the end marker at or after the
+    /// start marker.
+    MissingEnd(String),
+}
+
+impl fmt::Display for MarkerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MarkerError::MissingStart(marker) => {
+                write!(
+                    f,
+                    "no instant event is labeled with the start marker `{}`",
+                    marker
+                )
+            }
+            MarkerError::MissingEnd(marker) => write!(
+                f,
+                "no instant event labeled with the end marker `{}` follows the start marker",
+                marker
+            ),
+        }
+    }
+}
+
+impl Error for MarkerError {}
+
+/// A problem with the timestamps of a profile, as found by
+/// `ProfilingData::validate()`. Timestamps are given in nanoseconds since the
+/// start of the profiling session.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum ValidationWarning<'a> {
+    /// An interval whose end event has an earlier timestamp than its start
+    /// event, i.e. `end_ns < start_ns`.
+    EndBeforeStart(MatchedEvent<'a>),
+    /// An interval that ended while an interval that started within it was
+    /// still open, like `IntervalError::Overlapping`.
+    Overlapping(MatchedEvent<'a>),
+    /// An event with an earlier timestamp than the event recorded right
+    /// before it on the same thread.
+    OutOfOrder { event: Event<'a>, previous_ns: u64 },
+}
+
+impl fmt::Display for ValidationWarning<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationWarning::EndBeforeStart(interval) => write!(
+                f,
+                "`{}` event `{}` on thread {} ends {}ns before it starts",
+                interval.kind,
+                interval.id,
+                interval.thread_id,
+                interval.start_ns - interval.end_ns
+            ),
+            ValidationWarning::Overlapping(interval) => write!(
+                f,
+                "`{}` event `{}` on thread {} ends while an event that started within it is still open",
+                interval.kind, interval.id, interval.thread_id
+            ),
+            ValidationWarning::OutOfOrder { event, previous_ns } => write!(
+                f,
+                "`{}` event `{}` on thread {} at {}ns was recorded after an event at {}ns",
+                event.event_kind,
+                event.label,
+                event.thread_id,
+                nanos_since_start(event.timestamp),
+                previous_ns
+            ),
+        }
+    }
+}
+
+/// A `ProfilingDataBuilder` allows for programmatically building
+/// `ProfilingData` objects. This is useful for writing tests that expect
+/// `ProfilingData` with predictable events (and especially timestamps) in it.
+///
+/// `ProfilingDataBuilder` provides a convenient interface but its
+/// implementation might not be efficient, which why it should only be used for
+/// writing tests and other things that are not performance sensitive.
+pub struct ProfilingDataBuilder {
+    event_sink: InMemorySink,
+    string_table_data_sink: Arc<InMemorySink>,
+    string_table_index_sink: Arc<InMemorySink>,
+    string_table: StringTableBuilder<InMemorySink>,
+}
+
+impl ProfilingDataBuilder {
+    pub fn new() -> ProfilingDataBuilder {
+        let event_sink = InMemorySink::new();
+        let string_table_data_sink = Arc::new(InMemorySink::new());
+        let string_table_index_sink = Arc::new(InMemorySink::new());
+
+        // The first thing in every file we generate must be the file header.
+        write_file_header(&event_sink, FILE_MAGIC_EVENT_STREAM);
+
+        let string_table = StringTableBuilder::new(
+            string_table_data_sink.clone(),
+            string_table_index_sink.clone(),
+        );
+        string_table.alloc_internal(THREAD_NAME_EVENT_KIND, "ThreadName");
+
+        ProfilingDataBuilder {
+            event_sink,
+            string_table_data_sink,
+            string_table_index_sink,
+            string_table,
+        }
+    }
+
+    /// Record and interval event. Provide an `inner` function for recording
+    /// nested events.
+    pub fn interval<F>(
+        &mut self,
+        event_kind: &str,
+        event_id: &str,
+        thread_id: u64,
+        start_nanos: u64,
+        end_nanos: u64,
+        inner: F,
+    ) -> &mut Self
+    where
+        F: FnOnce(&mut Self),
+    {
+        let event_kind = self.string_table.alloc(event_kind);
+        let event_id = self.string_table.alloc(event_id);
+
+        self.write_raw_event(&RawEvent {
+            event_kind,
+            id: event_id,
+            thread_id,
+            timestamp: Timestamp::new(start_nanos, TimestampKind::Start),
+            payload: 0,
+        });
+
+        inner(self);
+
+        self.write_raw_event(&RawEvent {
+            event_kind,
+            id: event_id,
+            thread_id,
+            timestamp: Timestamp::new(end_nanos, TimestampKind::End),
+            payload: 0,
+        });
+
+        self
+    }
+
+    /// Record a start event without the matching end event, e.g. for
+    /// testing how malformed profiles are handled. `interval()` should be
+    /// preferred otherwise.
+    pub fn start(
+        &mut self,
+        event_kind: &str,
+        event_id: &str,
+        thread_id: u64,
+        timestamp_nanos: u64,
+    ) -> &mut Self {
+        self.event(
+            event_kind,
+            event_id,
+            thread_id,
+            timestamp_nanos,
+            TimestampKind::Start,
+        )
+    }
+
+    /// Record an end event without the matching start event, the counterpart
+    /// of `start()`.
+    pub fn end(
+        &mut self,
+        event_kind: &str,
+        event_id: &str,
+        thread_id: u64,
+        timestamp_nanos: u64,
+    ) -> &mut Self {
+        self.event(
+            event_kind,
+            event_id,
+            thread_id,
+            timestamp_nanos,
+            TimestampKind::End,
+        )
+    }
+
+    /// Record an interval event whose start event carries the category
+    /// `category`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn interval_with_category<F>(
+        &mut self,
+        event_kind: &str,
+        event_id: &str,
+        category: &str,
+        thread_id: u64,
+        start_nanos: u64,
+        end_nanos: u64,
+        inner: F,
+    ) -> &mut Self
+    where
+        F: FnOnce(&mut Self),
+    {
+        let event_kind = self.string_table.alloc(event_kind);
+        let event_id = self.string_table.alloc(event_id);
+        let category = self.string_table.alloc(category);
+
+        self.write_raw_event(&RawEvent {
+            event_kind,
+            id: event_id,
+            thread_id,
+            timestamp: Timestamp::new(start_nanos, TimestampKind::Start),
+            payload: RawEvent::category_payload(category),
+        });
+
+        inner(self);
+
+        self.write_raw_event(&RawEvent {
+            event_kind,
+            id: event_id,
+            thread_id,
+            timestamp: Timestamp::new(end_nanos, TimestampKind::End),
+            payload: 0,
+        });
+
+        self
+    }
+
+    /// Record an interval event whose start event carries the integer `arg`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn interval_with_arg<F>(
+        &mut self,
+        event_kind: &str,
+        event_id: &str,
+        thread_id: u64,
+        start_nanos: u64,
+        end_nanos: u64,
+        arg: u64,
+        inner: F,
+    ) -> &mut Self
+    where
+        F: FnOnce(&mut Self),
+    {
+        let event_kind = self.string_table.alloc(event_kind);
+        let event_id = self.string_table.alloc(event_id);
+
+        self.write_raw_event(&RawEvent {
+            event_kind,
+            id: event_id,
+            thread_id,
+            timestamp: Timestamp::new(start_nanos, TimestampKind::Start).with_arg(),
+            payload: arg,
+        });
+
+        inner(self);
+
+        self.write_raw_event(&RawEvent {
+            event_kind,
+            id: event_id,
+            thread_id,
+            timestamp: Timestamp::new(end_nanos, TimestampKind::End),
+            payload: 0,
+        });
+
+        self
+    }
+
+    /// Record and instant event with the given data.
+    pub fn instant(
+        &mut self,
+        event_kind: &str,
+        event_id: &str,
+        thread_id: u64,
+        timestamp_nanos: u64,
+    ) -> &mut Self {
+        self.event(
+            event_kind,
+            event_id,
+            thread_id,
+            timestamp_nanos,
+            TimestampKind::Instant,
+        )
+    }
+
+    /// Record a counter event with the given data.
+    pub fn counter(
+        &mut self,
+        counter_kind: &str,
+        thread_id: u64,
+        timestamp_nanos: u64,
+        value: u64,
+    ) -> &mut Self {
+        let counter_kind = self.string_table.alloc(counter_kind);
+
+        self.write_raw_event(&RawEvent {
+            event_kind: counter_kind,
+            id: counter_kind,
+            thread_id,
+            timestamp: Timestamp::new(timestamp_nanos, TimestampKind::Counter),
+            payload: value,
+        });
+
+        self
+    }
+
+    /// Record a name for the given thread, like `Profiler::set_thread_name()`.
+    pub fn thread_name(&mut self, thread_id: u64, name: &str) -> &mut Self {
+        let name = self.string_table.alloc(name);
+
+        self.write_raw_event(&RawEvent {
+            event_kind: THREAD_NAME_EVENT_KIND,
+            id: name,
+            thread_id,
+            timestamp: Timestamp::new(0, TimestampKind::Instant),
+            payload: 0,
+        });
+
+        self
+    }
+
+    /// Convert this builder into a `ProfilingData` object that can be iterated.
+    pub fn into_profiling_data(mut self) -> ProfilingData {
+        // Mark the profile as complete, like a `Profiler` does when dropped
+        self.write_raw_event(&RawEvent {
+            event_kind: CLEAN_SHUTDOWN_EVENT_KIND,
+            id: CLEAN_SHUTDOWN_EVENT_KIND,
+            thread_id: 0,
+            timestamp: Timestamp::new(0, TimestampKind::Instant),
+            payload: 0,
+        });
+
+        // Drop the string table, so that the `string_table_data_sink` and
+        // `string_table_index_sink` fields are the only event-sink references
+        // left. This enables us to unwrap the `Arc`s and get the byte data out.
+        drop(self.string_table);
+
+        let event_data = self.event_sink.into_bytes();
+        let data_bytes = Arc::try_unwrap(self.string_table_data_sink)
+            .unwrap()
+            .into_bytes();
+        let index_bytes = Arc::try_unwrap(self.string_table_index_sink)
+            .unwrap()
+            .into_bytes();
+
+        ProfilingData::from_buffers(event_data, data_bytes, index_bytes).unwrap()
+    }
+
+    fn event(
+        &mut self,
+        event_kind: &str,
+        event_id: &str,
+        thread_id: u64,
+        timestamp_nanos: u64,
+        timestamp_kind: TimestampKind,
+    ) -> &mut Self {
+        let event_kind = self.string_table.alloc(event_kind);
+        let event_id = self.string_table.alloc(event_id);
+
+        self.write_raw_event(&RawEvent {
+            event_kind,
+            id: event_id,
+            thread_id,
+            timestamp: Timestamp::new(timestamp_nanos, timestamp_kind),
+            payload: 0,
+        });
+
+        self
+    }
+
+    fn write_raw_event(&mut self, raw_event: &RawEvent) {
+        self.event_sink.write_bytes(&raw_event.to_bytes());
+    }
+}
+
+impl Default for ProfilingDataBuilder {
+    fn default() -> ProfilingDataBuilder {
+        ProfilingDataBuilder::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_args::serialize_args_record;
+    use crate::file_header::UnsupportedVersion;
+
+    fn event(
+        event_kind: &'static str,
+        label: &'static str,
+        thread_id: u64,
+        nanos: u64,
+        timestamp_kind: TimestampKind,
+    ) -> Event<'static> {
+        let timestamp = SystemTime::UNIX_EPOCH + Duration::from_nanos(nanos);
+
+        Event {
+            event_kind: Cow::from(event_kind),
+            label: Cow::from(label),
+            event_kind_id: StringId::INVALID,
+            label_id: StringId::INVALID,
+            additional_data: &[],
+            timestamp,
+            timestamp_kind,
+            thread_id,
+            value: None,
+            arg: None,
+            category: None,
+            args: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn build_interval_sequence() {
+        let mut builder = ProfilingDataBuilder::new();
+
+        builder
+            .interval("k1", "id1", 0, 10, 100, |_| {})
+            .interval("k2", "id2", 1, 100, 110, |_| {})
+            .interval("k3", "id3", 0, 120, 140, |_| {});
+
+        let profiling_data = builder.into_profiling_data();
+
+        let events: Vec<Event<'_>> = profiling_data.iter().collect();
+
+        assert_eq!(events[0], event("k1", "id1", 0, 10, TimestampKind::Start));
+        assert_eq!(events[1], event("k1", "id1", 0, 100, TimestampKind::End));
+        assert_eq!(events[2], event("k2", "id2", 1, 100, TimestampKind::Start));
+        assert_eq!(events[3], event("k2", "id2", 1, 110, TimestampKind::End));
+        assert_eq!(events[4], event("k3", "id3", 0, 120, TimestampKind::Start));
+        assert_eq!(events[5], event("k3", "id3", 0, 140, TimestampKind::End));
+    }
+
+    #[test]
+    fn build_nested_intervals() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("k1", "id1", 0, 10, 100, |b| {
+            b.interval("k2", "id2", 0, 20, 100, |b| {
+                b.interval("k3", "id3", 0, 30, 90, |_| {});
+            });
+        });
+
+        let profiling_data = b.into_profiling_data();
+
+        let events: Vec<Event<'_>> = profiling_data.iter().collect();
 
         assert_eq!(events[0], event("k1", "id1", 0, 10, TimestampKind::Start));
         assert_eq!(events[1], event("k2", "id2", 0, 20, TimestampKind::Start));
@@ -374,32 +2429,1122 @@ mod tests {
     }
 
     #[test]
-    fn build_intervals_and_instants() {
+    fn build_intervals_and_instants() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("k1", "id1", 0, 10, 100, |b| {
+            b.interval("k2", "id2", 0, 20, 100, |b| {
+                b.interval("k3", "id3", 0, 30, 90, |b| {
+                    b.instant("k4", "id4", 0, 70);
+                    b.instant("k5", "id5", 0, 75);
+                });
+            })
+            .instant("k6", "id6", 0, 95);
+        });
+
+        let profiling_data = b.into_profiling_data();
+
+        let events: Vec<Event<'_>> = profiling_data.iter().collect();
+
+        assert_eq!(events[0], event("k1", "id1", 0, 10, TimestampKind::Start));
+        assert_eq!(events[1], event("k2", "id2", 0, 20, TimestampKind::Start));
+        assert_eq!(events[2], event("k3", "id3", 0, 30, TimestampKind::Start));
+        assert_eq!(events[3], event("k4", "id4", 0, 70, TimestampKind::Instant));
+        assert_eq!(events[4], event("k5", "id5", 0, 75, TimestampKind::Instant));
+        assert_eq!(events[5], event("k3", "id3", 0, 90, TimestampKind::End));
+        assert_eq!(events[6], event("k2", "id2", 0, 100, TimestampKind::End));
+        assert_eq!(events[7], event("k6", "id6", 0, 95, TimestampKind::Instant));
+        assert_eq!(events[8], event("k1", "id1", 0, 100, TimestampKind::End));
+    }
+
+    #[test]
+    fn build_counters() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("k1", "id1", 0, 10, 100, |b| {
+            b.counter("c1", 0, 20, 1);
+            b.counter("c1", 0, 30, 42);
+        })
+        .counter("c2", 1, 40, u64::MAX >> 2);
+
+        let profiling_data = b.into_profiling_data();
+
+        let events: Vec<Event<'_>> = profiling_data.iter().collect();
+
+        let counter = |kind, thread_id, nanos, value| Event {
+            label: Cow::from(kind),
+            value: Some(value),
+            ..event(kind, kind, thread_id, nanos, TimestampKind::Counter)
+        };
+
+        assert_eq!(events[0], event("k1", "id1", 0, 10, TimestampKind::Start));
+        assert_eq!(events[1], counter("c1", 0, 20, 1));
+        assert_eq!(events[2], counter("c1", 0, 30, 42));
+        assert_eq!(events[3], event("k1", "id1", 0, 100, TimestampKind::End));
+        assert_eq!(events[4], counter("c2", 1, 40, u64::MAX >> 2));
+
+        let matching_events: Vec<_> = profiling_data.iter_matching_events().collect();
+        assert_eq!(
+            matching_events[0],
+            MatchingEvent::Counter(counter("c1", 0, 20, 1))
+        );
+    }
+
+    #[test]
+    fn build_intervals_with_args() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval_with_arg("k1", "id1", 0, 10, 100, 42, |b| {
+            b.interval("k2", "id2", 0, 20, 90, |_| {});
+        })
+        .interval_with_arg("k3", "id3", 0, 110, 120, u64::MAX, |_| {});
+
+        let profiling_data = b.into_profiling_data();
+
+        let events: Vec<Event<'_>> = profiling_data.iter().collect();
+
+        let with_arg = |event, arg| Event {
+            arg: Some(arg),
+            ..event
+        };
+
+        assert_eq!(
+            events[0],
+            with_arg(event("k1", "id1", 0, 10, TimestampKind::Start), 42)
+        );
+        assert_eq!(events[1], event("k2", "id2", 0, 20, TimestampKind::Start));
+        assert_eq!(events[2], event("k2", "id2", 0, 90, TimestampKind::End));
+        assert_eq!(events[3], event("k1", "id1", 0, 100, TimestampKind::End));
+        assert_eq!(
+            events[4],
+            with_arg(event("k3", "id3", 0, 110, TimestampKind::Start), u64::MAX)
+        );
+        assert_eq!(events[5], event("k3", "id3", 0, 120, TimestampKind::End));
+    }
+
+    #[test]
+    fn build_intervals_with_category() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval_with_category("k1", "id1", "frontend", 0, 10, 100, |b| {
+            b.interval("k2", "id2", 0, 20, 90, |_| {});
+        });
+
+        let profiling_data = b.into_profiling_data();
+
+        let events: Vec<Event<'_>> = profiling_data.iter().collect();
+
+        let start = Event {
+            category: Some(Cow::from("frontend")),
+            args: BTreeMap::new(),
+            ..event("k1", "id1", 0, 10, TimestampKind::Start)
+        };
+        assert_eq!(events[0], start);
+        assert_eq!(events[0].category_or_kind(), "frontend");
+        assert_eq!(events[1], event("k2", "id2", 0, 20, TimestampKind::Start));
+        assert_eq!(events[1].category_or_kind(), "k2");
+        assert_eq!(events[3], event("k1", "id1", 0, 100, TimestampKind::End));
+    }
+
+    #[test]
+    fn iter_in_range() {
+        //           <-----range----->
+        //     <-k1->  <-k2->      <-----k3----->
+        //  <---------------------k0---------------->
+        //  0  10  20  30  40  50  60  70  80  90  100
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("k0", "id0", 0, 0, 100, |b| {
+            b.interval("k1", "id1", 0, 10, 20, |_| {})
+                .interval("k2", "id2", 0, 30, 40, |_| {})
+                .instant("k4", "id4", 0, 45)
+                .interval("k3", "id3", 0, 60, 90, |b| {
+                    b.instant("k5", "id5", 0, 80);
+                });
+        })
+        .interval("k6", "id6", 1, 0, 10, |_| {})
+        .interval("k7", "id7", 1, 85, 95, |_| {});
+
+        let profiling_data = b.into_profiling_data();
+
+        let events: Vec<Event<'_>> = profiling_data.iter_in_range(25, 70).collect();
+
+        assert_eq!(
+            events,
+            [
+                event("k0", "id0", 0, 25, TimestampKind::Start),
+                event("k2", "id2", 0, 30, TimestampKind::Start),
+                event("k2", "id2", 0, 40, TimestampKind::End),
+                event("k4", "id4", 0, 45, TimestampKind::Instant),
+                event("k3", "id3", 0, 60, TimestampKind::Start),
+                event("k3", "id3", 0, 70, TimestampKind::End),
+                event("k0", "id0", 0, 70, TimestampKind::End),
+            ]
+        );
+    }
+
+    #[test]
+    fn iter_in_range_inside_single_event() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("k1", "id1", 0, 10, 100, |_| {})
+            .interval("k2", "id2", 1, 20, 30, |_| {});
+
+        let profiling_data = b.into_profiling_data();
+
+        let events: Vec<Event<'_>> = profiling_data.iter_in_range(40, 50).collect();
+
+        assert_eq!(
+            events,
+            [
+                event("k1", "id1", 0, 40, TimestampKind::Start),
+                event("k1", "id1", 0, 50, TimestampKind::End),
+            ]
+        );
+
+        assert!(profiling_data.iter_in_range(200, 300).next().is_none());
+    }
+
+    #[test]
+    fn slice_between() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.thread_name(2, "phase_start")
+            .instant("Marker", "phase_end", 0, 5)
+            .instant("Marker", "phase_start", 0, 20)
+            .interval("k1", "id1", 0, 25, 40, |_| {})
+            .instant("Marker", "phase_end", 0, 50)
+            .interval("k2", "id2", 0, 60, 70, |_| {})
+            .instant("Marker", "phase_start", 0, 80)
+            .instant("Marker", "phase_end", 0, 90)
+            .instant("Marker", "phase_start", 1, 30);
+
+        let profiling_data = b.into_profiling_data();
+
+        let events: Vec<Event<'_>> = profiling_data
+            .slice_between("phase_start", "phase_end")
+            .unwrap()
+            .collect();
+        assert_eq!(
+            events,
+            [
+                event("Marker", "phase_start", 0, 20, TimestampKind::Instant),
+                event("k1", "id1", 0, 25, TimestampKind::Start),
+                event("k1", "id1", 0, 40, TimestampKind::End),
+                event("Marker", "phase_end", 0, 50, TimestampKind::Instant),
+                event("Marker", "phase_start", 1, 30, TimestampKind::Instant),
+            ]
+        );
+
+        assert_eq!(
+            profiling_data
+                .slice_between("phase_end", "phase_start")
+                .unwrap()
+                .count(),
+            2
+        );
+        assert_eq!(
+            profiling_data.slice_between("missing", "phase_end").err(),
+            Some(MarkerError::MissingStart("missing".to_string()))
+        );
+        assert_eq!(
+            profiling_data.slice_between("phase_start", "k1").err(),
+            Some(MarkerError::MissingEnd("k1".to_string()))
+        );
+    }
+
+    #[test]
+    fn debug_text() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.thread_name(2, "worker")
+            .thread_name(5, "main")
+            .interval("Query", "typeck", 5, 100, 150, |b| {
+                b.instant("Marker", "checkpoint", 5, 120);
+            })
+            .interval_with_arg("Codegen", "module", 2, 110, 140, 3, |_| {})
+            .counter("allocated bytes", 1, 130, 4096)
+            .interval("Task", "request", crate::TASK_ID_FLAG | 7, 105, 160, |_| {})
+            .interval_with_category("Query", "parse", "frontend", 5, 160, 170, |_| {});
+
+        let profiling_data = b.into_profiling_data();
+
+        assert_eq!(
+            profiling_data.to_debug_text(true),
+            "\
+thread 1
+  30ns counter \"allocated bytes\" \"allocated bytes\" value=4096
+task 7
+  5ns start \"Task\" \"request\"
+  60ns end \"Task\" \"request\"
+thread \"main\"
+  0ns start \"Query\" \"typeck\"
+  20ns instant \"Marker\" \"checkpoint\"
+  50ns end \"Query\" \"typeck\"
+  60ns start \"Query\" \"parse\" category=\"frontend\"
+  70ns end \"Query\" \"parse\"
+thread \"worker\"
+  10ns start \"Codegen\" \"module\" arg=3
+  40ns end \"Codegen\" \"module\"
+"
+        );
+        assert!(profiling_data
+            .to_debug_text(false)
+            .contains("  100ns start \"Query\" \"typeck\""));
+    }
+
+    #[test]
+    fn iter_matching_kind() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("codegen", "id1", 0, 10, 100, |b| {
+            b.interval("typeck", "id2", 0, 20, 90, |b| {
+                b.interval("codegen", "id3", 0, 30, 40, |_| {})
+                    .instant("typeck", "id4", 0, 50);
+            });
+        })
+        .interval("typeck", "id5", 1, 10, 20, |_| {});
+
+        let profiling_data = b.into_profiling_data();
+
+        let events: Vec<Event<'_>> = profiling_data.iter_matching_kind("codegen").collect();
+        assert_eq!(
+            events,
+            [
+                event("codegen", "id1", 0, 10, TimestampKind::Start),
+                event("codegen", "id3", 0, 30, TimestampKind::Start),
+                event("codegen", "id3", 0, 40, TimestampKind::End),
+                event("codegen", "id1", 0, 100, TimestampKind::End),
+            ]
+        );
+
+        let events: Vec<Event<'_>> = profiling_data.iter_matching_kind("typeck").collect();
+        assert_eq!(
+            events,
+            [
+                event("typeck", "id2", 0, 20, TimestampKind::Start),
+                event("typeck", "id4", 0, 50, TimestampKind::Instant),
+                event("typeck", "id2", 0, 90, TimestampKind::End),
+                event("typeck", "id5", 1, 10, TimestampKind::Start),
+                event("typeck", "id5", 1, 20, TimestampKind::End),
+            ]
+        );
+
+        assert!(profiling_data
+            .iter_matching_kind("unknown")
+            .next()
+            .is_none());
+
+        // Composes with filtering by time
+        let codegen = profiling_data.kind_name_filter("codegen");
+        let events: Vec<Event<'_>> = profiling_data
+            .iter_in_range_matching(35, 60, codegen)
+            .collect();
+        assert_eq!(
+            events,
+            [
+                event("codegen", "id1", 0, 35, TimestampKind::Start),
+                event("codegen", "id3", 0, 35, TimestampKind::Start),
+                event("codegen", "id3", 0, 40, TimestampKind::End),
+                event("codegen", "id1", 0, 60, TimestampKind::End),
+            ]
+        );
+    }
+
+    #[test]
+    fn iter_thread() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.thread_name(3, "unused")
+            .interval("k1", "id1", 0, 10, 100, |b| {
+                b.instant("k2", "id2", 0, 50);
+            })
+            .interval("k1", "id3", 2, 20, 30, |_| {})
+            .counter("c1", 0, 110, 1);
+
+        let profiling_data = b.into_profiling_data();
+
+        let events: Vec<Event<'_>> = profiling_data.iter_thread(2).collect();
+        assert_eq!(
+            events,
+            [
+                event("k1", "id3", 2, 20, TimestampKind::Start),
+                event("k1", "id3", 2, 30, TimestampKind::End),
+            ]
+        );
+        assert!(profiling_data.iter_thread(1).next().is_none());
+
+        // Threads that only have a name recorded have no events
+        assert_eq!(profiling_data.thread_ids(), [0, 2]);
+        let event_count: usize = profiling_data
+            .thread_ids()
+            .into_iter()
+            .map(|thread_id| profiling_data.iter_thread(thread_id).count())
+            .sum();
+        assert_eq!(event_count, profiling_data.iter().count());
+    }
+
+    #[test]
+    fn group_by_thread() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.thread_name(3, "unused")
+            .interval("k1", "id1", 0, 10, 100, |b| {
+                b.instant("k2", "id2", 0, 50);
+            })
+            .interval("k1", "id3", 2, 20, 30, |_| {})
+            .counter("c1", 0, 110, 1);
+
+        let profiling_data = b.into_profiling_data();
+        let threads = profiling_data.group_by_thread();
+
+        let mut thread_ids: Vec<_> = threads.keys().copied().collect();
+        thread_ids.sort_unstable();
+        assert_eq!(thread_ids, profiling_data.thread_ids());
+        for (&thread_id, events) in &threads {
+            let expected: Vec<_> = profiling_data.iter_thread(thread_id).collect();
+            assert_eq!(*events, expected);
+        }
+    }
+
+    #[test]
+    fn iter_sorted() {
+        let mut b = ProfilingDataBuilder::new();
+
+        // As if each thread had buffered its events before writing them
+        b.thread_name(3, "unused")
+            .interval("k1", "id1", 1, 10, 100, |b| {
+                b.instant("k2", "id2", 1, 50);
+            })
+            .interval("k1", "id3", 0, 20, 30, |_| {})
+            .instant("k2", "id4", 0, 50)
+            .instant("k2", "id5", 0, 50)
+            .counter("c1", 2, 5, 1);
+
+        let profiling_data = b.into_profiling_data();
+
+        let mut expected: Vec<_> = profiling_data.iter().collect();
+        expected.sort_by_key(|e| (e.timestamp, e.thread_id));
+        let labels: Vec<_> = expected.iter().map(|e| &e.label[..]).collect();
+        assert_eq!(
+            labels,
+            ["c1", "id1", "id3", "id3", "id4", "id5", "id2", "id1"]
+        );
+
+        let in_memory: Vec<_> = profiling_data.iter_sorted().unwrap().collect();
+        assert_eq!(in_memory, expected);
+
+        // Buffers runs of three events in temporary files
+        let mut merged = profiling_data.iter_sorted_in_runs(3).unwrap();
+        assert_eq!(merged.by_ref().collect::<Vec<_>>(), expected);
+        assert!(merged.error().is_none());
+    }
+
+    fn matched(
+        kind: &'static str,
+        id: &'static str,
+        thread_id: u64,
+        start_ns: u64,
+        end_ns: u64,
+    ) -> MatchedEvent<'static> {
+        MatchedEvent {
+            kind: Cow::from(kind),
+            id: Cow::from(id),
+            thread_id,
+            start_ns,
+            end_ns,
+        }
+    }
+
+    #[test]
+    fn iter_intervals_nested() {
         let mut b = ProfilingDataBuilder::new();
 
         b.interval("k1", "id1", 0, 10, 100, |b| {
-            b.interval("k2", "id2", 0, 20, 100, |b| {
-                b.interval("k3", "id3", 0, 30, 90, |b| {
-                    b.instant("k4", "id4", 0, 70);
-                    b.instant("k5", "id5", 0, 75);
-                });
-            })
-            .instant("k6", "id6", 0, 95);
+            b.interval("k2", "id2", 0, 20, 50, |_| {});
+            b.instant("k3", "id3", 0, 60);
+            b.interval("k2", "id2", 0, 70, 90, |_| {});
         });
+        b.interval("k1", "id1", 1, 15, 25, |_| {});
 
         let profiling_data = b.into_profiling_data();
+        let mut intervals = profiling_data.iter_intervals();
+        let intervals_found: Vec<_> = intervals.by_ref().collect();
 
-        let events: Vec<Event<'_>> = profiling_data.iter().collect();
+        assert_eq!(
+            intervals_found,
+            [
+                matched("k2", "id2", 0, 20, 50),
+                matched("k2", "id2", 0, 70, 90),
+                matched("k1", "id1", 0, 10, 100),
+                matched("k1", "id1", 1, 15, 25),
+            ]
+        );
+        assert_eq!(intervals.errors(), []);
+    }
+
+    #[test]
+    fn iter_intervals_overlapping() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.start("k1", "id1", 0, 10)
+            .start("k2", "id2", 0, 20)
+            .end("k1", "id1", 0, 30)
+            .end("k2", "id2", 0, 40);
+
+        let profiling_data = b.into_profiling_data();
+        let mut intervals = profiling_data.iter_intervals();
+        let intervals_found: Vec<_> = intervals.by_ref().collect();
+
+        assert_eq!(
+            intervals_found,
+            [
+                matched("k1", "id1", 0, 10, 30),
+                matched("k2", "id2", 0, 20, 40)
+            ]
+        );
+        assert_eq!(
+            intervals.errors(),
+            [IntervalError::Overlapping(matched("k1", "id1", 0, 10, 30))]
+        );
+    }
+
+    #[test]
+    fn iter_intervals_orphaned() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.start("k1", "id1", 1, 10)
+            .end("k2", "id2", 0, 20)
+            .interval("k3", "id3", 0, 30, 40, |_| {})
+            .start("k4", "id4", 0, 50)
+            // Ends on a different thread than it started on
+            .end("k4", "id4", 2, 60);
+
+        let profiling_data = b.into_profiling_data();
+        let mut intervals = profiling_data.iter_intervals();
+        let intervals_found: Vec<_> = intervals.by_ref().collect();
+
+        assert_eq!(intervals_found, [matched("k3", "id3", 0, 30, 40)]);
+        assert_eq!(
+            intervals.errors(),
+            [
+                IntervalError::UnmatchedEnd(event("k2", "id2", 0, 20, TimestampKind::End)),
+                IntervalError::UnmatchedEnd(event("k4", "id4", 2, 60, TimestampKind::End)),
+                IntervalError::UnmatchedStart(event("k4", "id4", 0, 50, TimestampKind::Start)),
+                IntervalError::UnmatchedStart(event("k1", "id1", 1, 10, TimestampKind::Start)),
+            ]
+        );
+    }
+
+    #[test]
+    fn iter_matching_events_cancelled_child() {
+        let mut b = ProfilingDataBuilder::new();
+
+        // `id2` never ends, as if its `TimingGuard` was cancelled
+        b.interval("k1", "id1", 0, 10, 100, |b| {
+            b.start("k2", "id2", 0, 20);
+        })
+        .interval("k1", "id3", 0, 110, 120, |_| {});
+
+        let profiling_data = b.into_profiling_data();
+        let matching_events: Vec<_> = profiling_data.iter_matching_events().collect();
+
+        assert_eq!(
+            matching_events,
+            [
+                MatchingEvent::StartStop(
+                    event("k1", "id1", 0, 10, TimestampKind::Start),
+                    event("k1", "id1", 0, 100, TimestampKind::End)
+                ),
+                MatchingEvent::StartStop(
+                    event("k1", "id3", 0, 110, TimestampKind::Start),
+                    event("k1", "id3", 0, 120, TimestampKind::End)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn iter_matching_events_orphaned_end() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.end("k1", "id1", 1, 10)
+            .interval("k1", "id2", 0, 20, 100, |b| {
+                b.end("k1", "id3", 0, 30);
+            });
+
+        let profiling_data = b.into_profiling_data();
+        let matching_events: Vec<_> = profiling_data.iter_matching_events().collect();
+
+        assert_eq!(
+            matching_events,
+            [MatchingEvent::StartStop(
+                event("k1", "id2", 0, 20, TimestampKind::Start),
+                event("k1", "id2", 0, 100, TimestampKind::End)
+            )]
+        );
+    }
+
+    #[test]
+    fn validate_well_formed() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("k1", "id1", 0, 10, 100, |b| {
+            b.interval("k2", "id2", 0, 20, 50, |_| {});
+            b.counter("c1", 0, 60, 1);
+        });
+        // Other threads have their own order
+        b.interval("k1", "id1", 1, 15, 25, |_| {});
+
+        assert_eq!(b.into_profiling_data().validate(), []);
+    }
+
+    #[test]
+    fn validate_end_before_start() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("k1", "id1", 0, 10, 100, |b| {
+            b.interval("k2", "id2", 0, 50, 40, |_| {});
+        });
+
+        let profiling_data = b.into_profiling_data();
+        let warnings = profiling_data.validate();
+
+        assert_eq!(
+            warnings,
+            [ValidationWarning::EndBeforeStart(matched(
+                "k2", "id2", 0, 50, 40
+            ))]
+        );
+        assert_eq!(
+            warnings[0].to_string(),
+            "`k2` event `id2` on thread 0 ends 10ns before it starts"
+        );
+    }
+
+    #[test]
+    fn validate_overlapping() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.start("k1", "id1", 0, 10)
+            .start("k2", "id2", 0, 20)
+            .end("k1", "id1", 0, 30)
+            .end("k2", "id2", 0, 40);
+
+        assert_eq!(
+            b.into_profiling_data().validate(),
+            [ValidationWarning::Overlapping(matched(
+                "k1", "id1", 0, 10, 30
+            ))]
+        );
+    }
+
+    #[test]
+    fn validate_out_of_order() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.instant("k1", "id1", 0, 30)
+            .instant("k2", "id2", 0, 20)
+            // Only compared to the previous event
+            .instant("k3", "id3", 0, 25)
+            .counter("c1", 0, 10, 1)
+            .instant("k1", "id1", 1, 5);
+
+        assert_eq!(
+            b.into_profiling_data().validate(),
+            [
+                ValidationWarning::OutOfOrder {
+                    event: event("k2", "id2", 0, 20, TimestampKind::Instant),
+                    previous_ns: 30,
+                },
+                ValidationWarning::OutOfOrder {
+                    event: Event {
+                        value: Some(1),
+                        ..event("c1", "c1", 0, 10, TimestampKind::Counter)
+                    },
+                    previous_ns: 25,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn event_count_and_file_sizes() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("k1", "id1", 0, 10, 100, |b| {
+            b.instant("k2", "id2", 0, 20);
+        });
+        b.counter("c1", 0, 30, 5);
+
+        let profiling_data = b.into_profiling_data();
+
+        assert_eq!(profiling_data.approx_event_count(), 4);
+        assert_eq!(profiling_data.iter().count(), 4);
+
+        let file_sizes = profiling_data.file_sizes();
+        // The events and the footer
+        assert_eq!(file_sizes.events, (FILE_HEADER_SIZE + 5 * 32) as u64);
+        assert!(file_sizes.string_data > FILE_HEADER_SIZE as u64);
+        assert!(file_sizes.string_index > FILE_HEADER_SIZE as u64);
+    }
+
+    #[test]
+    fn string_table_stats() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("k1", "id1", 0, 10, 100, |b| {
+            b.instant("k1", "id2", 0, 20);
+        });
+        let profiling_data = b.into_profiling_data();
+
+        // "ThreadName", which every profile has, and every string allocated
+        // for an event, including "k1" twice. Each string is a single
+        // component.
+        let strings = ["ThreadName", "k1", "id1", "k1", "id2"];
+        let total_bytes: usize = strings.iter().map(|s| s.len() + 4).sum();
+        assert_eq!(total_bytes, 40);
+        assert_eq!(
+            profiling_data.string_table_stats(),
+            StringTableStats {
+                count: 5,
+                total_bytes: 40,
+                avg_len: 8.0,
+            }
+        );
 
+        let mut b = ProfilingDataBuilder::new();
+        b.instant("k1", "id1", 0, 10);
+        let without_strings =
+            ProfilingData::without_strings(b.into_profiling_data().event_data.to_vec()).unwrap();
+        assert_eq!(
+            without_strings.string_table_stats(),
+            StringTableStats::default()
+        );
+    }
+
+    #[test]
+    fn missing_metadata() {
+        // `ProfilingDataBuilder` doesn't write a metadata record
+        let profiling_data = ProfilingDataBuilder::new().into_profiling_data();
+
+        assert!(profiling_data.try_metadata().is_err());
+        assert_eq!(profiling_data.metadata(), ProfileMetadata::default());
+    }
+
+    #[test]
+    fn build_thread_names() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.thread_name(0, "main")
+            .interval("k1", "id1", 0, 10, 100, |_| {})
+            .thread_name(1, "worker")
+            .thread_name(0, "renamed");
+
+        let profiling_data = b.into_profiling_data();
+
+        assert_eq!(profiling_data.thread_name(0), Some("renamed"));
+        assert_eq!(profiling_data.thread_name(1), Some("worker"));
+        assert_eq!(profiling_data.thread_name(2), None);
+        assert_eq!(
+            profiling_data.thread_names(),
+            vec![(0, "renamed"), (1, "worker")]
+        );
+
+        // The thread name records themselves are not visible as events
+        let events: Vec<Event<'_>> = profiling_data.iter().collect();
+        assert_eq!(events.len(), 2);
         assert_eq!(events[0], event("k1", "id1", 0, 10, TimestampKind::Start));
-        assert_eq!(events[1], event("k2", "id2", 0, 20, TimestampKind::Start));
-        assert_eq!(events[2], event("k3", "id3", 0, 30, TimestampKind::Start));
-        assert_eq!(events[3], event("k4", "id4", 0, 70, TimestampKind::Instant));
-        assert_eq!(events[4], event("k5", "id5", 0, 75, TimestampKind::Instant));
-        assert_eq!(events[5], event("k3", "id3", 0, 90, TimestampKind::End));
-        assert_eq!(events[6], event("k2", "id2", 0, 100, TimestampKind::End));
-        assert_eq!(events[7], event("k6", "id6", 0, 95, TimestampKind::Instant));
-        assert_eq!(events[8], event("k1", "id1", 0, 100, TimestampKind::End));
+        assert_eq!(events[1], event("k1", "id1", 0, 100, TimestampKind::End));
+    }
+
+    #[test]
+    fn read_misaligned_events() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("k1", "id1", 0, 10, 100, |_| {});
+        let data = b.into_profiling_data();
+
+        let expected: Vec<_> = (0..)
+            .map_while(|idx| read_raw_event(&data.event_data, idx))
+            .collect();
+        // The two events and the footer
+        assert_eq!(expected.len(), 3);
+
+        // Place the events at an odd address so that they can't be read in place
+        let len = data.event_data.len();
+        let mut buffer = vec![0u8; len + mem::align_of::<RawEvent>()];
+        let offset = (0..)
+            .find(|o| (buffer.as_ptr() as usize + o) % 2 == 1)
+            .unwrap();
+        buffer[offset..offset + len].copy_from_slice(&data.event_data);
+        let misaligned = &buffer[offset..offset + len];
+        assert!(raw_event_slice(misaligned).is_none());
+
+        let actual: Vec<_> = (0..)
+            .map_while(|idx| read_raw_event(misaligned, idx))
+            .collect();
+        assert_eq!(actual, expected);
+    }
+
+    // Writes a tagged record whose body is `body_len` bytes of `0xFF`, or
+    // only its header if `body_len` is larger than `written`
+    fn tagged_record(b: &mut ProfilingDataBuilder, tag: u32, body_len: u64, written: usize) {
+        b.write_raw_event(&RawEvent {
+            event_kind: TAGGED_RECORD_EVENT_KIND,
+            id: StringId::reserved(tag),
+            thread_id: body_len,
+            timestamp: Timestamp::from_bits(u64::MAX),
+            payload: u64::MAX,
+        });
+        b.event_sink.write_bytes(&vec![0xFF; written]);
+    }
+
+    #[test]
+    fn skip_unknown_records() {
+        assert_eq!(TAGGED_RECORD_EVENT_KIND.as_u32(), 0x8000_0003);
+
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("k1", "id1", 0, 10, 100, |_| {});
+        // A body of 40 bytes, padded to two records
+        tagged_record(&mut b, 1, 40, 2 * crate::RAW_EVENT_SIZE);
+        tagged_record(&mut b, 2, 0, 0);
+        b.instant("k2", "id2", 0, 200);
+        let profiling_data = b.into_profiling_data();
+
+        assert!(profiling_data.is_complete());
+        assert_eq!(profiling_data.thread_ids(), vec![0]);
+
+        let mut events = profiling_data.iter();
+        assert_eq!(
+            events.next(),
+            Some(event("k1", "id1", 0, 10, TimestampKind::Start))
+        );
+        assert_eq!(events.skipped_records(), 0);
+        assert_eq!(
+            events.next(),
+            Some(event("k1", "id1", 0, 100, TimestampKind::End))
+        );
+        assert_eq!(
+            events.next(),
+            Some(event("k2", "id2", 0, 200, TimestampKind::Instant))
+        );
+        assert_eq!(events.skipped_records(), 2);
+        assert_eq!(events.next(), None);
+    }
+
+    #[test]
+    fn truncated_unknown_record() {
+        let mut b = ProfilingDataBuilder::new();
+        b.instant("k1", "id1", 0, 10);
+        // The footer becomes part of the body, which still misses a record
+        tagged_record(&mut b, 1, 64, 0);
+        let profiling_data = b.into_profiling_data();
+
+        assert!(profiling_data.is_truncated());
+        assert!(!profiling_data.is_complete());
+
+        let mut events = profiling_data.iter();
+        assert_eq!(
+            events.next(),
+            Some(event("k1", "id1", 0, 10, TimestampKind::Instant))
+        );
+        assert_eq!(events.next(), None);
+        assert_eq!(events.skipped_records(), 0);
+    }
+
+    #[test]
+    fn truncated_args_record() {
+        let mut b = ProfilingDataBuilder::new();
+        b.instant("k1", "id1", 0, 10);
+        let key = b.string_table.alloc("key");
+        // Only the header of a body of two records, the footer becomes part
+        // of the body, which still misses a record
+        let record = serialize_args_record(0, &[(key, ArgValue::Int(1)); 4]);
+        b.event_sink.write_bytes(&record[..RAW_EVENT_SIZE]);
+        let profiling_data = b.into_profiling_data();
+
+        assert!(profiling_data.is_truncated());
+
+        // The event is read without its arguments
+        let mut events = profiling_data.iter();
+        assert_eq!(
+            events.next(),
+            Some(event("k1", "id1", 0, 10, TimestampKind::Instant))
+        );
+        assert_eq!(events.next(), None);
+        assert_eq!(events.skipped_records(), 0);
+    }
+
+    #[test]
+    fn unresolvable_events() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("k1", "id1", 0, 10, 100, |b| {
+            b.instant("k2", "id2", 0, 50);
+        });
+        let kind = b.string_table.alloc("k3");
+        let missing = StringId::reserved(12345);
+        b.write_raw_event(&RawEvent {
+            event_kind: kind,
+            id: missing,
+            thread_id: 0,
+            timestamp: Timestamp::new(60, TimestampKind::Instant),
+            payload: 0,
+        });
+        b.write_raw_event(&RawEvent {
+            event_kind: kind,
+            id: kind,
+            thread_id: 0,
+            timestamp: Timestamp::new(70, TimestampKind::Start),
+            payload: RawEvent::category_payload(missing),
+        });
+        let data = b.into_profiling_data();
+
+        assert_eq!(data.unresolvable_events(), 2);
+        // Only the event with the missing label is skipped
+        assert_eq!(data.iter().count(), 4);
+    }
+
+    #[test]
+    fn missing_string_table() {
+        let mut b = ProfilingDataBuilder::new();
+        b.thread_name(0, "main")
+            .interval("k1", "id1", 0, 10, 100, |b| {
+                b.instant("k2", "id2", 0, 50);
+            });
+        let data = b.into_profiling_data();
+        assert!(data.can_resolve_strings());
+
+        let dir = std::env::temp_dir().join(format!("measureme-no-strings-{}", std::process::id()));
+        let path_stem = dir.join("profile");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            ProfilerFiles::new(&path_stem).events_file,
+            &data.event_data[..],
+        )
+        .unwrap();
+        let degraded = ProfilingData::new(&path_stem);
+        fs::remove_dir_all(&dir).unwrap();
+        let degraded = degraded.unwrap();
+
+        assert!(!degraded.can_resolve_strings());
+        assert!(degraded.is_complete());
+        assert_eq!(degraded.thread_name(0), None);
+
+        // Everything but the strings is still there
+        let id = |e: &Event<'_>| {
+            data.string_table
+                .ids()
+                .find(|&id| *data.event_string(id) == *e.label)
+        };
+        let expected: Vec<_> = data
+            .iter()
+            .map(|e| {
+                let label = format!("{:?}", id(&e).unwrap());
+                (label, e.timestamp, e.timestamp_kind, e.thread_id)
+            })
+            .collect();
+        let events: Vec<_> = degraded
+            .iter()
+            .map(|e| {
+                (
+                    e.label.into_owned(),
+                    e.timestamp,
+                    e.timestamp_kind,
+                    e.thread_id,
+                )
+            })
+            .collect();
+        assert_eq!(events, expected);
+        assert_eq!(degraded.iter_intervals().count(), 1);
+    }
+
+    #[test]
+    fn read_v0_files() {
+        let string_data_sink = Arc::new(InMemorySink::new());
+        let string_index_sink = Arc::new(InMemorySink::new());
+        {
+            let string_table =
+                StringTableBuilder::new(string_data_sink.clone(), string_index_sink.clone());
+            string_table.alloc_with_reserved_id(StringId::reserved(1), "k1");
+            string_table.alloc_with_reserved_id(StringId::reserved(2), "id1");
+        }
+        let mut string_data = Arc::try_unwrap(string_data_sink).unwrap().into_bytes();
+        let mut index_data = Arc::try_unwrap(string_index_sink).unwrap().into_bytes();
+        string_data[4..8].copy_from_slice(&0u32.to_le_bytes());
+        index_data[4..8].copy_from_slice(&0u32.to_le_bytes());
+
+        // Two events in the 24 byte layout, and part of a third one
+        let mut event_data = FILE_MAGIC_EVENT_STREAM.to_vec();
+        event_data.extend_from_slice(&0u32.to_le_bytes());
+        for &(nanos, kind_bits) in &[(10u64, 0u64), (100, 1)] {
+            event_data.extend_from_slice(&1u32.to_ne_bytes());
+            event_data.extend_from_slice(&2u32.to_ne_bytes());
+            event_data.extend_from_slice(&3u64.to_ne_bytes());
+            event_data.extend_from_slice(&((nanos << 2) | kind_bits).to_ne_bytes());
+        }
+        event_data.extend_from_slice(&[0; 10]);
+
+        let data = ProfilingData::from_buffers(event_data, string_data, index_data).unwrap();
+        assert!(data.is_truncated());
+        assert!(!data.is_complete());
+        assert_eq!(data.writer_version(), None);
+
+        let events: Vec<_> = data.iter().collect();
+        assert_eq!(
+            events,
+            [
+                event("k1", "id1", 3, 10, TimestampKind::Start),
+                event("k1", "id1", 3, 100, TimestampKind::End),
+            ]
+        );
+    }
+
+    // A profile as written by a 32-bit `rustc`, byte by byte. Every field has
+    // the same width on all targets, so it must read the same everywhere.
+    #[test]
+    fn read_32_bit_writer_files() {
+        let mut event_data = b"MMES\x02\x00\x00\x00".to_vec();
+        let mut push_event = |thread_id: u64, timestamp: u64, payload: u64| {
+            event_data.extend_from_slice(&[1, 0, 0, 0, 2, 0, 0, 0]);
+            event_data.extend_from_slice(&thread_id.to_le_bytes());
+            event_data.extend_from_slice(&timestamp.to_le_bytes());
+            event_data.extend_from_slice(&payload.to_le_bytes());
+        };
+        // Timestamps beyond 2^32ns, the id of a task and the arguments of
+        // events don't fit into 32 bits. The low 3 bits of a timestamp are
+        // its flags, see `Timestamp`.
+        let task = crate::TASK_ID_FLAG | 7;
+        push_event(task, 5_000_000_000 << 3, 0);
+        push_event(task, (6_000_000_000 << 3) | 0b001, 0);
+        push_event(3, (7_000_000_000 << 3) | 0b110, 1 << 40);
+        push_event(3, (8_000_000_000 << 3) | 0b011, u64::MAX);
+
+        let mut string_data = b"MMSD\x02\x00\x00\x00".to_vec();
+        string_data.extend_from_slice(b"\x01\x02\x00k1\x00");
+        string_data.extend_from_slice(b"\x01\x03\x00id1\x00");
+
+        let mut index_data = b"MMSI\x02\x00\x00\x00".to_vec();
+        index_data.extend_from_slice(&[1, 0, 0, 0, 8, 0, 0, 0]);
+        index_data.extend_from_slice(&[2, 0, 0, 0, 14, 0, 0, 0]);
+
+        let data = ProfilingData::from_buffers(event_data, string_data, index_data).unwrap();
+        assert!(!data.is_truncated());
+
+        let events: Vec<_> = data
+            .iter()
+            .map(|e| {
+                let nanos = e.timestamp.duration_since(SystemTime::UNIX_EPOCH).unwrap();
+                (
+                    e.thread_id,
+                    nanos.as_nanos(),
+                    e.timestamp_kind,
+                    e.arg,
+                    e.value,
+                )
+            })
+            .collect();
+        assert_eq!(
+            events,
+            [
+                (task, 5_000_000_000, TimestampKind::Start, None, None),
+                (task, 6_000_000_000, TimestampKind::End, None, None),
+                (
+                    3,
+                    7_000_000_000,
+                    TimestampKind::Instant,
+                    Some(1 << 40),
+                    None
+                ),
+                (
+                    3,
+                    8_000_000_000,
+                    TimestampKind::Counter,
+                    None,
+                    Some(u64::MAX)
+                ),
+            ]
+        );
+        assert!(data
+            .iter()
+            .all(|e| e.event_kind == "k1" && e.label == "id1"));
+    }
+
+    #[test]
+    fn writer_version() {
+        let event_sink = Arc::new(InMemorySink::new());
+        let string_data_sink = Arc::new(InMemorySink::new());
+        let string_index_sink = Arc::new(InMemorySink::new());
+        crate::Profiler::with_sinks(
+            event_sink.clone(),
+            string_data_sink.clone(),
+            string_index_sink.clone(),
+        )
+        .finish()
+        .unwrap();
+
+        let mut streams = [
+            Arc::try_unwrap(event_sink).unwrap().into_bytes(),
+            Arc::try_unwrap(string_data_sink).unwrap().into_bytes(),
+            Arc::try_unwrap(string_index_sink).unwrap().into_bytes(),
+        ];
+        let [event_data, string_data, index_data] = streams.clone();
+        let data = ProfilingData::from_buffers(event_data, string_data, index_data).unwrap();
+        assert_eq!(data.writer_version().as_deref(), Some(crate::version()));
+
+        // Version `1` headers have no writer version
+        for stream in &mut streams {
+            stream[4..8].copy_from_slice(&1u32.to_le_bytes());
+        }
+        let [event_data, string_data, index_data] = streams;
+        let data = ProfilingData::from_buffers(event_data, string_data, index_data).unwrap();
+        assert_eq!(data.writer_version(), None);
+        assert!(data.is_complete());
+    }
+
+    #[test]
+    fn reject_newer_versions() {
+        let event_sink = InMemorySink::new();
+        write_file_header(&event_sink, FILE_MAGIC_EVENT_STREAM);
+        let mut event_data = event_sink.into_bytes();
+        event_data[4..8].copy_from_slice(&(CURRENT_FILE_FORMAT_VERSION + 1).to_le_bytes());
+
+        let error = ProfilingData::from_buffers(event_data, Vec::new(), Vec::new())
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.downcast_ref::<UnsupportedVersion>(),
+            Some(&UnsupportedVersion {
+                found: CURRENT_FILE_FORMAT_VERSION + 1,
+                supported: CURRENT_FILE_FORMAT_VERSION,
+            })
+        );
     }
 
+    #[test]
+    #[cfg(feature = "serde")]
+    fn serde_round_trip() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("k1", "id1", 0, 10, 100, |_| {});
+        let profiling_data = b.into_profiling_data();
+
+        let intervals: Vec<_> = profiling_data.iter_intervals().collect();
+        let json = serde_json::to_string(&intervals).unwrap();
+        assert_eq!(
+            json,
+            r#"[{"kind":"k1","id":"id1","thread_id":0,"start_ns":10,"end_ns":100}]"#
+        );
+        let read_back: Vec<MatchedEvent<'_>> = serde_json::from_str(&json).unwrap();
+        assert_eq!(read_back, intervals);
+
+        let json = serde_json::to_string(&profiling_data.iter().next().unwrap()).unwrap();
+        assert!(json.contains(r#""timestamp_kind":"Start""#), "{}", json);
+
+        assert_eq!(
+            serde_json::to_string(&StringId::reserved(42)).unwrap(),
+            "42"
+        );
+        assert_eq!(
+            serde_json::from_str::<StringId>("42").unwrap(),
+            StringId::reserved(42)
+        );
+    }
 }
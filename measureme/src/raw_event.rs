@@ -1,25 +1,90 @@
-use crate::stringtable::StringId;
+use crate::string_id::StringId;
+use byteorder::{ByteOrder, LittleEndian};
 
 #[derive(Clone, Eq, PartialEq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TimestampKind {
     Start = 0,
     End = 1,
     Instant = 2,
+    Counter = 3,
 }
 
+/// A timestamp packed together with the `TimestampKind` of its event and a
+/// flag that tells whether the event carries an integer argument:
+///
+/// ```text
+/// [nanos: 61 bits, has_arg: 1 bit, kind: 2 bits]
+/// ```
+///
+/// i.e. `bits >> 3` is the number of nanoseconds since the start of the
+/// profile, bit 2 is the "has arg" flag and `bits & 0b11` is the
+/// `TimestampKind` as its discriminant. This leaves room for
+/// `MAX_TIMESTAMP_NANOS` nanoseconds, i.e. about 73 years since the start of
+/// the profile. Every `u64` is a valid `Timestamp`.
 #[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 #[repr(C)]
 pub struct Timestamp(u64);
 
+const TIMESTAMP_HAS_ARG_FLAG: u64 = 0b100;
+
+const PAYLOAD_HAS_CATEGORY_FLAG: u64 = 1 << 32;
+
+/// The largest number of nanoseconds a `Timestamp` can represent.
+pub const MAX_TIMESTAMP_NANOS: u64 = u64::MAX >> 3;
+
 impl Timestamp {
+    /// Packs `nanos` and `kind` into a `Timestamp`. Values of `nanos` above
+    /// `MAX_TIMESTAMP_NANOS` are a bug in debug builds and are saturated to
+    /// `MAX_TIMESTAMP_NANOS` otherwise, so that they can't corrupt the other
+    /// bits. Use `saturating_new()` for values that may legitimately be too
+    /// large.
     #[inline]
     pub fn new(nanos: u64, kind: TimestampKind) -> Timestamp {
-        Timestamp((nanos << 2) | kind as u64)
+        debug_assert!(
+            nanos <= MAX_TIMESTAMP_NANOS,
+            "timestamp of {}ns doesn't fit into a `Timestamp`",
+            nanos
+        );
+
+        Timestamp::saturating_new(nanos, kind).0
+    }
+
+    /// Like `new()`, but saturates `nanos` to `MAX_TIMESTAMP_NANOS` even in
+    /// debug builds. The returned flag tells whether `nanos` was saturated.
+    #[inline]
+    pub fn saturating_new(nanos: u64, kind: TimestampKind) -> (Timestamp, bool) {
+        let overflowed = nanos > MAX_TIMESTAMP_NANOS;
+        let nanos = nanos.min(MAX_TIMESTAMP_NANOS);
+
+        (Timestamp((nanos << 3) | kind as u64), overflowed)
+    }
+
+    /// Marks the event as carrying an integer argument in `RawEvent::payload`.
+    #[inline]
+    pub fn with_arg(self) -> Timestamp {
+        Timestamp(self.0 | TIMESTAMP_HAS_ARG_FLAG)
+    }
+
+    /// The packed representation, as stored in the event stream.
+    #[inline]
+    pub fn to_bits(self) -> u64 {
+        self.0
+    }
+
+    #[inline]
+    pub fn from_bits(bits: u64) -> Timestamp {
+        Timestamp(bits)
     }
 
     #[inline]
     pub fn nanos(self) -> u64 {
-        self.0 >> 2
+        self.0 >> 3
+    }
+
+    #[inline]
+    pub fn has_arg(self) -> bool {
+        self.0 & TIMESTAMP_HAS_ARG_FLAG != 0
     }
 
     #[inline]
@@ -28,18 +93,154 @@ impl Timestamp {
             0 => TimestampKind::Start,
             1 => TimestampKind::End,
             2 => TimestampKind::Instant,
+            3 => TimestampKind::Counter,
             _ => unreachable!(),
         }
     }
 }
 
-#[derive(Eq, PartialEq, Debug)]
+/// A single event in the event stream. Each event occupies exactly
+/// `RAW_EVENT_SIZE` (i.e. 32) bytes:
+///
+/// ```text
+/// offset  0: event_kind: u32
+/// offset  4: id:         u32
+/// offset  8: thread_id:  u64
+/// offset 16: timestamp:  u64, see `Timestamp`
+/// offset 24: payload:    u64
+/// ```
+///
+/// All fields are little-endian and have the same width on every target,
+/// regardless of the byte order and word size of the machine that wrote the
+/// profile, so e.g. a 32-bit `rustc` writes the same layout. This layout is
+/// part of the file format, so it only changes along with
+/// `CURRENT_FILE_FORMAT_VERSION`. Tools that parse the `.events` file
+/// themselves can skip the 8 byte file header and read the events as
+/// consecutive records of this layout. `serialize()` and `deserialize()`
+/// convert between a `RawEvent` and its bytes.
+///
+/// For `TimestampKind::Counter` events the `payload` field holds the value of
+/// the counter. For other events it holds the integer argument of the event if
+/// `Timestamp::has_arg()` is set, and is zero otherwise, except for the start
+/// events of intervals with a category: for these, bit 32 of `payload` is set
+/// and the low 32 bits are the `StringId` of the category, see
+/// `Profiler::start_recording_interval_event_with_category()`. Readers that
+/// don't know categories ignore the payload of start events without an
+/// argument, so they still read such profiles, just without the categories,
+/// and the file format version stays the same.
+///
+/// Records that don't fit this layout, which later versions of `measureme`
+/// may add to the event stream, are written as tagged records, so that
+/// readers that don't know them can skip them. A tagged record starts with a
+/// header in the layout of a `RawEvent`, followed by its body:
+///
+/// ```text
+/// offset  0: 0x8000_0003, the event kind of tagged records
+/// offset  4: tag:      u32, the type of the record
+/// offset  8: body_len: u64, the length of the body in bytes
+/// offset 16: 16 bytes whose meaning depends on the type
+/// offset 32: the body, padded with zeros to a multiple of `RAW_EVENT_SIZE`
+/// ```
+///
+/// The padding keeps the events after the record aligned. The only type this
+/// version of `measureme` knows holds the arguments of the event before it,
+/// see `Profiler::record_args_event()`. `ProfilingData` skips all other
+/// records and counts them in `ProfilerEventIterator::skipped_records()`.
+/// Tools that parse the event stream themselves should skip the records they
+/// don't know in the same way.
+///
+/// The in-memory layout matches the on-disk layout on little-endian machines.
+/// It has no padding and every bit pattern is a valid `RawEvent`, which
+/// `ProfilingData` relies on to read events directly out of a memory-mapped
+/// file there. Changing the fields must preserve these properties.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
 #[repr(C)]
 pub struct RawEvent {
     pub event_kind: StringId,
     pub id: StringId,
     pub thread_id: u64,
     pub timestamp: Timestamp,
+    pub payload: u64,
+}
+
+/// The size of a `RawEvent` in the event stream.
+pub const RAW_EVENT_SIZE: usize = 32;
+
+// Checks the layout documented above and the size and alignment that
+// zero-copy reading relies on.
+const _: () = assert!(core::mem::size_of::<RawEvent>() == RAW_EVENT_SIZE);
+const _: () = assert!(core::mem::align_of::<RawEvent>() == 8);
+const _: () = assert!(core::mem::offset_of!(RawEvent, event_kind) == 0);
+const _: () = assert!(core::mem::offset_of!(RawEvent, id) == 4);
+const _: () = assert!(core::mem::offset_of!(RawEvent, thread_id) == 8);
+const _: () = assert!(core::mem::offset_of!(RawEvent, timestamp) == 16);
+const _: () = assert!(core::mem::offset_of!(RawEvent, payload) == 24);
+
+impl RawEvent {
+    /// The `payload` of a start event with the category `category`, for
+    /// writing such events without a `Profiler`, e.g. to a `RingBufferSink`.
+    #[inline]
+    pub fn category_payload(category: StringId) -> u64 {
+        PAYLOAD_HAS_CATEGORY_FLAG | u64::from(category.as_u32())
+    }
+
+    /// The category of a start event, see `category_payload()`, or `None` if
+    /// it has none or isn't a start event.
+    #[inline]
+    pub fn category(&self) -> Option<StringId> {
+        let has_category = self.timestamp.kind() == TimestampKind::Start
+            && !self.timestamp.has_arg()
+            && self.payload & PAYLOAD_HAS_CATEGORY_FLAG != 0;
+
+        if has_category {
+            Some(StringId::reserved(self.payload as u32))
+        } else {
+            None
+        }
+    }
+
+    /// Writes the event to `buf` as it is stored in the event stream. Panics
+    /// if `buf` isn't `RAW_EVENT_SIZE` bytes long.
+    #[inline]
+    pub fn serialize(&self, buf: &mut [u8]) {
+        assert_eq!(buf.len(), RAW_EVENT_SIZE);
+
+        LittleEndian::write_u32(&mut buf[0..4], self.event_kind.as_u32());
+        LittleEndian::write_u32(&mut buf[4..8], self.id.as_u32());
+        LittleEndian::write_u64(&mut buf[8..16], self.thread_id);
+        LittleEndian::write_u64(&mut buf[16..24], self.timestamp.to_bits());
+        LittleEndian::write_u64(&mut buf[24..32], self.payload);
+    }
+
+    /// The inverse of `serialize()`. Every sequence of bytes is a valid
+    /// `RawEvent`, though its string ids may not resolve. Panics if `buf`
+    /// isn't `RAW_EVENT_SIZE` bytes long.
+    #[inline]
+    pub fn deserialize(buf: &[u8]) -> RawEvent {
+        assert_eq!(buf.len(), RAW_EVENT_SIZE);
+
+        RawEvent {
+            event_kind: StringId::reserved(LittleEndian::read_u32(&buf[0..4])),
+            id: StringId::reserved(LittleEndian::read_u32(&buf[4..8])),
+            thread_id: LittleEndian::read_u64(&buf[8..16]),
+            timestamp: Timestamp::from_bits(LittleEndian::read_u64(&buf[16..24])),
+            payload: LittleEndian::read_u64(&buf[24..32]),
+        }
+    }
+
+    /// Like `serialize()`, but returns the bytes.
+    #[inline]
+    pub fn to_bytes(&self) -> [u8; RAW_EVENT_SIZE] {
+        let mut bytes = [0; RAW_EVENT_SIZE];
+        self.serialize(&mut bytes);
+        bytes
+    }
+
+    /// Like `deserialize()`, for exactly `RAW_EVENT_SIZE` bytes.
+    #[inline]
+    pub fn from_bytes(bytes: &[u8; RAW_EVENT_SIZE]) -> RawEvent {
+        RawEvent::deserialize(bytes)
+    }
 }
 
 impl Default for RawEvent {
@@ -49,6 +250,133 @@ impl Default for RawEvent {
             id: StringId::reserved(0),
             thread_id: 0,
             timestamp: Timestamp::new(0, TimestampKind::Instant),
+            payload: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamp_at_max_nanos() {
+        let timestamp = Timestamp::new(MAX_TIMESTAMP_NANOS, TimestampKind::End);
+
+        assert_eq!(timestamp.nanos(), MAX_TIMESTAMP_NANOS);
+        assert_eq!(timestamp.kind(), TimestampKind::End);
+        assert!(!timestamp.has_arg());
+
+        let timestamp = timestamp.with_arg();
+        assert_eq!(timestamp.nanos(), MAX_TIMESTAMP_NANOS);
+        assert_eq!(timestamp.kind(), TimestampKind::End);
+        assert!(timestamp.has_arg());
+    }
+
+    #[test]
+    fn timestamp_saturates() {
+        for &nanos in &[MAX_TIMESTAMP_NANOS + 1, u64::MAX] {
+            let (timestamp, overflowed) = Timestamp::saturating_new(nanos, TimestampKind::Start);
+
+            assert!(overflowed);
+            assert_eq!(timestamp.nanos(), MAX_TIMESTAMP_NANOS);
+            assert_eq!(timestamp.kind(), TimestampKind::Start);
+            assert!(!timestamp.has_arg());
         }
+
+        let (_, overflowed) = Timestamp::saturating_new(MAX_TIMESTAMP_NANOS, TimestampKind::Start);
+        assert!(!overflowed);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn timestamp_overflow_is_a_bug() {
+        Timestamp::new(MAX_TIMESTAMP_NANOS + 1, TimestampKind::Instant);
+    }
+
+    fn raw_event() -> RawEvent {
+        RawEvent {
+            event_kind: StringId::reserved(0x0403_0201),
+            id: StringId::reserved(0x0807_0605),
+            thread_id: 0x100f_0e0d_0c0b_0a09,
+            timestamp: Timestamp::new(42, TimestampKind::Counter).with_arg(),
+            payload: 0x2019_1817_1615_1413,
+        }
+    }
+
+    // Independent of the byte order of the host
+    const RAW_EVENT_BYTES: [u8; RAW_EVENT_SIZE] = [
+        0x01, 0x02, 0x03, 0x04, // event_kind
+        0x05, 0x06, 0x07, 0x08, // id
+        0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f, 0x10, // thread_id
+        0x57, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // timestamp
+        0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x20, // payload
+    ];
+
+    #[test]
+    fn category_payload() {
+        let category = StringId::reserved(7);
+        let start = RawEvent {
+            timestamp: Timestamp::new(0, TimestampKind::Start),
+            payload: RawEvent::category_payload(category),
+            ..RawEvent::default()
+        };
+        assert_eq!(start.category(), Some(category));
+
+        // Only start events without an argument have a category
+        let with_arg = RawEvent {
+            timestamp: start.timestamp.with_arg(),
+            ..start
+        };
+        assert_eq!(with_arg.category(), None);
+        let end = RawEvent {
+            timestamp: Timestamp::new(0, TimestampKind::End),
+            ..start
+        };
+        assert_eq!(end.category(), None);
+        let without = RawEvent {
+            payload: 0,
+            ..start
+        };
+        assert_eq!(without.category(), None);
+    }
+
+    #[test]
+    fn raw_event_byte_layout() {
+        let timestamp = (42 << 3) | 0b100 | 3;
+
+        assert_eq!(timestamp, 0x0157);
+        assert_eq!(raw_event().timestamp.to_bits(), timestamp);
+        assert_eq!(raw_event().to_bytes(), RAW_EVENT_BYTES);
+
+        let mut buf = [0xff; RAW_EVENT_SIZE];
+        raw_event().serialize(&mut buf);
+        assert_eq!(buf, RAW_EVENT_BYTES);
+    }
+
+    #[test]
+    fn decode_raw_event_bytes() {
+        assert_eq!(RawEvent::deserialize(&RAW_EVENT_BYTES), raw_event());
+        assert_eq!(RawEvent::from_bytes(&RAW_EVENT_BYTES), raw_event());
+
+        let timestamp = RawEvent::deserialize(&RAW_EVENT_BYTES).timestamp;
+        assert_eq!(timestamp.nanos(), 42);
+        assert_eq!(timestamp.kind(), TimestampKind::Counter);
+        assert!(timestamp.has_arg());
+    }
+
+    // Zero-copy reading relies on this
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn in_memory_layout_matches_on_disk_layout() {
+        let in_memory: [u8; RAW_EVENT_SIZE] = unsafe { core::mem::transmute(raw_event()) };
+        assert_eq!(in_memory, RAW_EVENT_BYTES);
+    }
+
+    #[test]
+    #[should_panic]
+    fn deserialize_wrong_length() {
+        RawEvent::deserialize(&RAW_EVENT_BYTES[..RAW_EVENT_SIZE - 1]);
     }
 }
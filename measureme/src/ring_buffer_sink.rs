@@ -0,0 +1,233 @@
+//! A sink that keeps only the most recent records in a fixed amount of
+//! memory, for targets without a file system. It doesn't need the `std`
+//! feature.
+
+use crate::file_header::{file_header, FILE_HEADER_SIZE, FILE_MAGIC_EVENT_STREAM};
+use crate::serialization::{Addr, SerializationSink};
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// A `SerializationSink` that holds at most `capacity` bytes in memory. Once
+/// it is full, the oldest records are overwritten to make room for new ones.
+///
+/// Every write is a record that is kept or overwritten as a whole, so when
+/// `RawEvent`s are written to the sink one by one, e.g. via
+/// `sink.write_atomic(RAW_EVENT_SIZE, |bytes| event.serialize(bytes))`, the
+/// retained bytes are always a sequence of complete events. A record that is
+/// larger than the capacity is dropped right away.
+///
+/// `to_event_stream()` returns the retained events as the contents of an
+/// `.events` file, which can be shipped off the device and read via
+/// `ProfilingData::without_strings()`, or along with the string table via
+/// `ProfilingData::from_buffers()`. With the `std` feature, a `Profiler` can
+/// record into a ring buffer as well, see `Profiler::new_ring_buffer()`.
+///
+/// Events are usually buffered per thread and written in batches of up to
+/// 128 events, 4 KiB, plus the arguments of the last event, see
+/// `Profiler::record_args_event()`, each of which is a single record. Such a batch is
+/// overwritten as a whole, so the capacity should be a good deal larger.
+///
+/// The sink is guarded by a spin lock, as there is no `Mutex` without the
+/// `std` feature, so the closure passed to `write_atomic()` must not write
+/// to the same sink.
+pub struct RingBufferSink {
+    capacity: usize,
+    locked: AtomicBool,
+    ring: UnsafeCell<Ring>,
+}
+
+struct Ring {
+    bytes: VecDeque<u8>,
+    // The length of each record in `bytes`, oldest first
+    records: VecDeque<usize>,
+    // The number of bytes ever written, i.e. the address of the next record
+    written: u64,
+    dropped_records: u64,
+    // Whether the oldest record is the header of an event stream, as written
+    // by a `Profiler` before any event
+    header_retained: bool,
+    // Reused to hand out contiguous memory to `write_atomic()`
+    scratch: Vec<u8>,
+}
+
+// SAFETY: `ring` is only accessed while `locked` is held, see `with_ring()`
+unsafe impl Sync for RingBufferSink {}
+
+impl RingBufferSink {
+    pub fn new(capacity: usize) -> RingBufferSink {
+        RingBufferSink {
+            capacity,
+            locked: AtomicBool::new(false),
+            ring: UnsafeCell::new(Ring {
+                bytes: VecDeque::with_capacity(capacity),
+                records: VecDeque::new(),
+                written: 0,
+                dropped_records: 0,
+                header_retained: false,
+                scratch: Vec::new(),
+            }),
+        }
+    }
+
+    /// A sink that never overwrites anything, for the string table of a
+    /// `Profiler` that records its events into a ring buffer.
+    #[cfg(feature = "std")]
+    pub(crate) fn unbounded() -> RingBufferSink {
+        // Without reserving `capacity` bytes up front
+        RingBufferSink {
+            capacity: usize::MAX,
+            ..RingBufferSink::new(0)
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The retained records, oldest first.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.with_ring(|ring| ring.bytes.iter().copied().collect())
+    }
+
+    /// The retained records, prefixed with the header of an `.events` file.
+    /// If the first record written to the sink was that header, as written by
+    /// a `Profiler`, and it hasn't been overwritten yet, it isn't repeated.
+    pub fn to_event_stream(&self) -> Vec<u8> {
+        self.with_ring(|ring| {
+            let mut stream = Vec::with_capacity(FILE_HEADER_SIZE + ring.bytes.len());
+            if !ring.header_retained {
+                stream.extend_from_slice(&file_header(FILE_MAGIC_EVENT_STREAM));
+            }
+            stream.extend(ring.bytes.iter());
+            stream
+        })
+    }
+
+    /// The number of records that were overwritten to make room for newer
+    /// ones, or dropped because they were larger than the capacity.
+    pub fn dropped_records(&self) -> u64 {
+        self.with_ring(|ring| ring.dropped_records)
+    }
+
+    fn with_ring<R>(&self, f: impl FnOnce(&mut Ring) -> R) -> R {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+
+        // SAFETY: holding `locked` gives exclusive access to `ring`
+        let result = f(unsafe { &mut *self.ring.get() });
+
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+}
+
+impl SerializationSink for RingBufferSink {
+    fn write_atomic<W>(&self, num_bytes: usize, write: W) -> Addr
+    where
+        W: FnOnce(&mut [u8]),
+    {
+        self.with_ring(|ring| {
+            let mut scratch = core::mem::take(&mut ring.scratch);
+            scratch.clear();
+            scratch.resize(num_bytes, 0);
+            write(&mut scratch);
+
+            let addr = ring.push(self.capacity, &scratch);
+            ring.scratch = scratch;
+            addr
+        })
+    }
+
+    fn write_bytes(&self, data: &[u8]) -> Addr {
+        self.with_ring(|ring| ring.push(self.capacity, data))
+    }
+}
+
+impl Ring {
+    fn push(&mut self, capacity: usize, record: &[u8]) -> Addr {
+        let addr = Addr(self.written as u32);
+        if self.written == 0 {
+            self.header_retained = record == file_header(FILE_MAGIC_EVENT_STREAM);
+        }
+        self.written += record.len() as u64;
+
+        if record.len() > capacity {
+            self.dropped_records += 1;
+            return addr;
+        }
+
+        while self.bytes.len() + record.len() > capacity {
+            let oldest = self.records.pop_front().unwrap();
+            self.bytes.drain(..oldest);
+            self.dropped_records += 1;
+            self.header_retained = false;
+        }
+
+        self.bytes.extend(record);
+        self.records.push_back(record.len());
+        addr
+    }
+}
+
+impl fmt::Debug for RingBufferSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RingBufferSink")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ProfilingData, RawEvent, StringId, Timestamp, TimestampKind, RAW_EVENT_SIZE};
+
+    fn event(nanos: u64) -> RawEvent {
+        RawEvent {
+            event_kind: StringId::reserved(1),
+            id: StringId::reserved(2),
+            thread_id: 0,
+            timestamp: Timestamp::new(nanos, TimestampKind::Instant),
+            payload: 0,
+        }
+    }
+
+    #[test]
+    fn overwrites_oldest_events() {
+        let sink = RingBufferSink::new(3 * RAW_EVENT_SIZE);
+        for nanos in 0..5 {
+            sink.write_atomic(RAW_EVENT_SIZE, |bytes| event(nanos).serialize(bytes));
+        }
+        // Too large to ever fit
+        sink.write_bytes(&[0; 4 * RAW_EVENT_SIZE]);
+
+        assert_eq!(sink.to_bytes().len(), 3 * RAW_EVENT_SIZE);
+        assert_eq!(sink.dropped_records(), 3);
+
+        let profiling_data = ProfilingData::without_strings(sink.to_event_stream()).unwrap();
+        let timestamps: Vec<_> = profiling_data
+            .iter()
+            .map(|e| e.timestamp.duration_since(std::time::UNIX_EPOCH).unwrap())
+            .map(|d| d.as_nanos())
+            .collect();
+        assert_eq!(timestamps, [2, 3, 4]);
+    }
+
+    #[test]
+    fn records_are_kept_whole() {
+        let sink = RingBufferSink::new(10);
+        assert_eq!(sink.write_bytes(b"abcd"), Addr(0));
+        assert_eq!(sink.write_bytes(b"efgh"), Addr(4));
+        // Only fits once both earlier records are gone
+        assert_eq!(sink.write_bytes(b"ijklmnop"), Addr(8));
+
+        assert_eq!(sink.to_bytes(), b"ijklmnop");
+        assert_eq!(sink.dropped_records(), 2);
+    }
+}
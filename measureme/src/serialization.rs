@@ -1,6 +1,9 @@
-use std::error::Error;
+#[cfg(feature = "std")]
+use std::io;
+#[cfg(feature = "std")]
 use std::path::Path;
-use std::sync::Mutex;
+#[cfg(feature = "std")]
+use std::sync::{Mutex, MutexGuard};
 
 #[derive(Clone, Copy, Eq, PartialEq, Debug)]
 pub struct Addr(pub u32);
@@ -11,25 +14,65 @@ impl Addr {
     }
 }
 
+/// Where the bytes of a profile are written to. Without the `std` feature,
+/// only the methods that write bytes are part of the trait, so that sinks for
+/// targets without a file system, like `RingBufferSink`, don't need to know
+/// about paths.
 pub trait SerializationSink: Sized {
-    fn from_path(path: &Path) -> Result<Self, Box<dyn Error>>;
+    /// Whether the sink throws away everything written to it, like
+    /// `NullSink`. `Profiler` and `StringTableBuilder` don't do any work for
+    /// such sinks.
+    const DISCARDS_DATA: bool = false;
+
+    /// Creates a sink that writes to the file at `path`, as done by
+    /// `Profiler::new()`. Sinks that can't be created from a path, e.g.
+    /// because they only live in memory, can leave this out, which makes it
+    /// fail with `io::ErrorKind::Unsupported`.
+    #[cfg(feature = "std")]
+    fn from_path(path: &Path) -> io::Result<Self> {
+        let _ = path;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "this sink can't be created from a path",
+        ))
+    }
 
     fn write_atomic<W>(&self, num_bytes: usize, write: W) -> Addr
     where
         W: FnOnce(&mut [u8]);
+
+    /// Writes `data` as one contiguous block, just like `write_atomic()`
+    /// does, for records whose size is only known at run time. The default
+    /// implementation copies `data` via `write_atomic()`, sinks can override
+    /// it to avoid the copy.
+    #[inline]
+    fn write_bytes(&self, data: &[u8]) -> Addr {
+        self.write_atomic(data.len(), |bytes| bytes.copy_from_slice(data))
+    }
+
+    /// Writes any buffered data to the underlying storage. Sinks that don't
+    /// buffer don't need to override this.
+    #[cfg(feature = "std")]
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 
 /// A `SerializationSink` that writes to an internal `Vec<u8>` and can be
-/// converted into this raw `Vec<u8>`. This implementation is only meant to be
-/// used for testing and is not very efficient.
-pub struct ByteVecSink {
+/// converted into this raw `Vec<u8>`. This is useful for tests and for
+/// embedders that want to capture profiling data without touching the file
+/// system. It ignores the path passed to `from_path()`, so it is usually
+/// combined with `Profiler::with_sinks()` and `ProfilingData::from_buffers()`.
+#[cfg(feature = "std")]
+pub struct InMemorySink {
     data: Mutex<Vec<u8>>,
 }
 
-impl ByteVecSink {
-    pub fn new() -> ByteVecSink {
-        ByteVecSink {
+#[cfg(feature = "std")]
+impl InMemorySink {
+    pub fn new() -> InMemorySink {
+        InMemorySink {
             data: Mutex::new(Vec::new()),
         }
     }
@@ -37,11 +80,23 @@ impl ByteVecSink {
     pub fn into_bytes(self) -> Vec<u8> {
         self.data.into_inner().unwrap()
     }
+
+    pub(crate) fn bytes(&self) -> MutexGuard<'_, Vec<u8>> {
+        self.data.lock().unwrap()
+    }
 }
 
-impl SerializationSink for ByteVecSink {
-    fn from_path(_path: &Path) -> Result<Self, Box<dyn Error>> {
-        unimplemented!()
+#[cfg(feature = "std")]
+impl Default for InMemorySink {
+    fn default() -> InMemorySink {
+        InMemorySink::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl SerializationSink for InMemorySink {
+    fn from_path(_path: &Path) -> io::Result<Self> {
+        Ok(InMemorySink::new())
     }
 
     fn write_atomic<W>(&self, num_bytes: usize, write: W) -> Addr
@@ -58,10 +113,50 @@ impl SerializationSink for ByteVecSink {
 
         Addr(start as u32)
     }
+
+    fn write_bytes(&self, bytes: &[u8]) -> Addr {
+        let mut data = self.data.lock().unwrap();
+
+        let start = data.len();
+
+        data.extend_from_slice(bytes);
+
+        Addr(start as u32)
+    }
 }
 
-impl std::fmt::Debug for ByteVecSink {
+#[cfg(feature = "std")]
+impl std::fmt::Debug for InMemorySink {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "ByteVecSink")
+        write!(f, "InMemorySink")
+    }
+}
+
+/// A `SerializationSink` that discards everything written to it. A `Profiler`
+/// using it, as returned by `Profiler::null()`, records nothing at a cost
+/// close to zero, so that call sites don't need to check whether profiling
+/// is enabled.
+#[derive(Debug, Default)]
+pub struct NullSink;
+
+impl SerializationSink for NullSink {
+    const DISCARDS_DATA: bool = true;
+
+    #[cfg(feature = "std")]
+    fn from_path(_path: &Path) -> io::Result<Self> {
+        Ok(NullSink)
+    }
+
+    #[inline(always)]
+    fn write_atomic<W>(&self, _num_bytes: usize, _write: W) -> Addr
+    where
+        W: FnOnce(&mut [u8]),
+    {
+        Addr(0)
+    }
+
+    #[inline(always)]
+    fn write_bytes(&self, _data: &[u8]) -> Addr {
+        Addr(0)
     }
 }
@@ -0,0 +1,49 @@
+//! The `StringId` of a string in the string table, which events refer to
+//! their strings by. It is part of the event encoding and thus available
+//! without the `std` feature.
+
+/// A `StringId` is used to identify a string in the `StringTable`.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+#[repr(C)]
+pub struct StringId(pub(crate) u32);
+
+impl StringId {
+    /// An id that never refers to a string, e.g. for fields that are
+    /// initialized before their string is allocated. Recording an event with
+    /// it trips a debug assertion, and `ProfilingData` reads it back as
+    /// `<unknown>`.
+    pub const INVALID: StringId = StringId(u32::MAX);
+
+    #[inline]
+    pub fn reserved(id: u32) -> StringId {
+        StringId(id)
+    }
+
+    /// The raw value of the id, as stored in the event stream. The inverse of
+    /// `reserved()`.
+    #[inline]
+    pub fn as_u32(self) -> u32 {
+        self.0
+    }
+
+    /// The reserved id at `index`, e.g. within a block returned by
+    /// `StringTableBuilder::reserve_range()`:
+    /// `StringId::from_reserved_index(first.reserved_index() + i)`.
+    #[inline]
+    pub fn from_reserved_index(index: u32) -> StringId {
+        assert!(index <= MAX_PRE_RESERVED_STRING_ID);
+        StringId(index)
+    }
+
+    /// The index of a reserved id, the inverse of `from_reserved_index()`.
+    #[inline]
+    pub fn reserved_index(self) -> u32 {
+        assert!(self.0 <= MAX_PRE_RESERVED_STRING_ID);
+        self.0
+    }
+}
+
+/// The maximum id value a prereserved string may be.
+pub(crate) const MAX_PRE_RESERVED_STRING_ID: u32 = u32::MAX / 2;
@@ -20,38 +20,43 @@
 //! to it by id over and over. This is a useful trick for strings which are recorded many times and
 //! it can significantly reduce the size of profile trace files.
 //!
+//! Alternatively, a builder created via `StringTableBuilder::new_deduplicated()` remembers every
+//! string passed to `StringTableBuilder::alloc()` and returns the existing `StringId` when a string
+//! is allocated again. `StringTableBuilder::new_deduplicated_with_capacity()` only remembers a
+//! bounded number of recently used strings instead, so that long-running profiles don't keep every
+//! string in memory.
+//!
+//! String literals can be allocated via `StringTableBuilder::alloc_static()` instead, which
+//! remembers them by their address, so that allocating one again neither copies nor hashes it.
+//!
 //! `StringId`s are partitioned according to type:
 //!
-//! > [0 .. MAX_PRE_RESERVED_STRING_ID, METADATA_STRING_ID, .. ]
+//! > [0 .. MAX_PRE_RESERVED_STRING_ID, METADATA_STRING_ID, .. FIRST_REGULAR_STRING_ID, .. ]
 //!
 //! From `0` to `MAX_PRE_RESERVED_STRING_ID` are the allowed values for reserved strings.
 //! After `MAX_PRE_RESERVED_STRING_ID`, there is one string id (`METADATA_STRING_ID`) which is used
 //! internally by `measureme` to record additional metadata about the profiling session.
-//! After `METADATA_STRING_ID` are all other `StringId` values.
+//! The ids between `METADATA_STRING_ID` and `FIRST_REGULAR_STRING_ID` are used internally by
+//! `measureme` as well, e.g. as the event kind of thread name records.
+//! From `FIRST_REGULAR_STRING_ID` on are all other `StringId` values.
 
+use crate::byte_buffer::ByteBuffer;
 use crate::file_header::{
-    read_file_header, strip_file_header, write_file_header, CURRENT_FILE_FORMAT_VERSION,
-    FILE_MAGIC_STRINGTABLE_DATA, FILE_MAGIC_STRINGTABLE_INDEX,
+    check_file_format_version, read_file_header, strip_file_header, write_file_header,
+    FILE_HEADER_SIZE, FILE_MAGIC_STRINGTABLE_DATA, FILE_MAGIC_STRINGTABLE_INDEX,
 };
 use crate::serialization::{Addr, SerializationSink};
+pub(crate) use crate::string_id::StringId;
+use crate::string_id::MAX_PRE_RESERVED_STRING_ID;
 use byteorder::{ByteOrder, LittleEndian};
 use rustc_hash::FxHashMap;
 use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::error::Error;
+use std::io;
 use std::sync::atomic::{AtomicU32, Ordering};
-use std::sync::Arc;
-
-/// A `StringId` is used to identify a string in the `StringTable`.
-#[derive(Clone, Copy, Eq, PartialEq, Debug, Hash)]
-#[repr(C)]
-pub struct StringId(u32);
-
-impl StringId {
-    #[inline]
-    pub fn reserved(id: u32) -> StringId {
-        StringId(id)
-    }
-}
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 // Tags for the binary encoding of strings
 
@@ -64,17 +69,145 @@ const TAG_STR_VAL: u8 = 1;
 /// Marks a component that contains the ID of another string.
 const TAG_STR_REF: u8 = 2;
 
-/// The maximum id value a prereserved string may be.
-const MAX_PRE_RESERVED_STRING_ID: u32 = std::u32::MAX / 2;
+/// What `StringId::INVALID` reads back as.
+const INVALID_STRING: &str = "<unknown>";
 
 /// The id of the profile metadata string entry.
 pub(crate) const METADATA_STRING_ID: u32 = MAX_PRE_RESERVED_STRING_ID + 1;
 
+/// The event kind of the records written by `Profiler::set_thread_name()`.
+pub(crate) const THREAD_NAME_EVENT_KIND: StringId = StringId(METADATA_STRING_ID + 1);
+
+/// The event kind of the record that a `Profiler` writes last when it is
+/// dropped, marking the profile as complete. No string is allocated for it.
+pub(crate) const CLEAN_SHUTDOWN_EVENT_KIND: StringId = StringId(METADATA_STRING_ID + 2);
+
+/// The event kind of the header of a tagged record, see `RawEvent`. Readers
+/// skip tagged records they don't know, i.e. all but the arguments of events.
+pub(crate) const TAGGED_RECORD_EVENT_KIND: StringId = StringId(METADATA_STRING_ID + 3);
+
+/// The event kinds of the events written by `Profiler::record_thread_start()`
+/// and `Profiler::record_thread_stop()`, allocated when they are first used.
+pub(crate) const THREAD_START_KIND_ID: StringId = StringId(METADATA_STRING_ID + 4);
+pub(crate) const THREAD_STOP_KIND_ID: StringId = StringId(METADATA_STRING_ID + 5);
+
+/// The first id handed out by `StringTableBuilder::alloc()`. The ids before
+/// this and after `METADATA_STRING_ID` are reserved for internal use.
+pub(crate) const FIRST_REGULAR_STRING_ID: u32 = METADATA_STRING_ID + 16;
+
+/// The ids that `measureme` uses internally, starting with `METADATA_STRING_ID`.
+pub(crate) fn internal_string_ids() -> impl Iterator<Item = StringId> {
+    (METADATA_STRING_ID..FIRST_REGULAR_STRING_ID).map(StringId)
+}
+
+/// The number of bytes of string data `StringTableBuilder` collects before
+/// writing it to the data sink.
+const STRING_BATCH_SIZE: usize = 16 * 1024;
+
+/// How long `StringTableBuilder` holds back a batch of strings at most. The
+/// age of a batch is only checked when a string is allocated, so the strings
+/// of an idle builder stay in the batch until it is flushed or dropped.
+const STRING_BATCH_MAX_AGE: Duration = Duration::from_millis(100);
+
 /// Write-only version of the string table
+///
+/// Allocated strings are collected in a batch and written to the sinks in one
+/// go once the batch is large or old enough. `StringId`s are assigned right
+/// away, the batch only delays writing the strings. Call `flush()` to write
+/// out the pending strings.
 pub struct StringTableBuilder<S: SerializationSink> {
     data_sink: Arc<S>,
     index_sink: Arc<S>,
-    id_counter: AtomicU32, // initialized to METADATA_STRING_ID + 1
+    id_counter: AtomicU32,          // initialized to FIRST_REGULAR_STRING_ID
+    reserved_id_counter: AtomicU32, // the next id handed out by `reserve_range()`
+    batch: Mutex<StringBatch>,
+    // Maps the serialized form of strings allocated via `alloc()` to their
+    // ids. Only present for builders created via `new_deduplicated()` or
+    // `new_deduplicated_with_capacity()`.
+    deduplicated: Option<Mutex<DedupCache>>,
+    // Maps the address and length of strings allocated via `alloc_static()`
+    // to their ids.
+    static_ids: Mutex<FxHashMap<(usize, usize), StringId>>,
+}
+
+// The strings known to a deduplicating `StringTableBuilder`. With a capacity,
+// the least recently used string is forgotten once there are more strings
+// than that. Its id stays valid, the string is just written again, with a
+// new id, when it is allocated the next time.
+struct DedupCache {
+    ids: FxHashMap<Arc<[u8]>, (StringId, u64)>,
+    // The strings in `ids` by the time they were last used. Only maintained
+    // if there is a capacity.
+    by_last_use: BTreeMap<u64, Arc<[u8]>>,
+    capacity: Option<usize>,
+    time: u64,
+}
+
+impl DedupCache {
+    fn new(capacity: Option<usize>) -> DedupCache {
+        DedupCache {
+            ids: FxHashMap::default(),
+            by_last_use: BTreeMap::new(),
+            capacity,
+            time: 0,
+        }
+    }
+
+    fn get(&mut self, bytes: &[u8]) -> Option<StringId> {
+        let (id, last_use) = self.ids.get_mut(bytes)?;
+
+        if self.capacity.is_some() {
+            self.time += 1;
+            let string = self.by_last_use.remove(last_use).unwrap();
+            self.by_last_use.insert(self.time, string);
+            *last_use = self.time;
+        }
+
+        Some(*id)
+    }
+
+    fn insert(&mut self, bytes: Vec<u8>, id: StringId) {
+        let string: Arc<[u8]> = bytes.into();
+
+        let capacity = match self.capacity {
+            Some(capacity) => capacity,
+            None => {
+                self.ids.insert(string, (id, 0));
+                return;
+            }
+        };
+
+        self.time += 1;
+        self.ids.insert(string.clone(), (id, self.time));
+        self.by_last_use.insert(self.time, string);
+
+        while self.ids.len() > capacity {
+            let (_, evicted) = self.by_last_use.pop_first().unwrap();
+            self.ids.remove(&evicted);
+        }
+    }
+}
+
+// Strings that have been allocated but not written to the sinks yet. The
+// addresses in `index` are relative to the start of `data`.
+struct StringBatch {
+    data: Vec<u8>,
+    index: Vec<(StringId, Addr)>,
+    started: Instant,
+}
+
+impl StringBatch {
+    fn new() -> StringBatch {
+        StringBatch {
+            data: Vec::new(),
+            index: Vec::new(),
+            started: Instant::now(),
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.data.len() >= STRING_BATCH_SIZE || self.started.elapsed() >= STRING_BATCH_MAX_AGE
+    }
 }
 
 /// Anything that implements `SerializableString` can be written to a
@@ -100,7 +233,7 @@ impl SerializableString for str {
 
     #[inline]
     fn serialize(&self, bytes: &mut [u8]) {
-        assert!(self.len() <= std::u16::MAX as usize);
+        assert!(self.len() <= u16::MAX as usize);
         let last_byte_index = bytes.len() - 1;
         bytes[0] = TAG_STR_VAL;
         LittleEndian::write_u16(&mut bytes[1..3], self.len() as u16);
@@ -109,29 +242,76 @@ impl SerializableString for str {
     }
 }
 
+// A string that has already been serialized, e.g. to look it up in the map of
+// a deduplicating `StringTableBuilder`.
+struct SerializedString<'a>(&'a [u8]);
+
+impl<'a> SerializableString for SerializedString<'a> {
+    #[inline]
+    fn serialized_size(&self) -> usize {
+        self.0.len()
+    }
+
+    #[inline]
+    fn serialize(&self, bytes: &mut [u8]) {
+        bytes.copy_from_slice(self.0);
+    }
+}
+
 /// A single component of a string. Used for building composite table entries.
 pub enum StringComponent<'s> {
     Value(&'s str),
     Ref(StringId),
 }
 
+// A composite string is encoded as its components followed by a terminator:
+//
+// [TAG_STR_VAL, len: u16, utf8_bytes] or [TAG_STR_REF, id: u32] per component,
+// then TAG_TERMINATOR
 impl<'a> SerializableString for [StringComponent<'a>] {
     #[inline]
     fn serialized_size(&self) -> usize {
-        unimplemented!()
+        let components: usize = self
+            .iter()
+            .map(|component| match component {
+                StringComponent::Value(s) => 1 + 2 + s.len(),
+                StringComponent::Ref(_) => 1 + 4,
+            })
+            .sum();
+
+        components + 1 // terminator
     }
 
     #[inline]
-    fn serialize(&self, _bytes: &mut [u8]) {
-        unimplemented!()
+    fn serialize(&self, bytes: &mut [u8]) {
+        let mut pos = 0;
+
+        for component in self {
+            match component {
+                StringComponent::Value(s) => {
+                    assert!(s.len() <= u16::MAX as usize);
+                    bytes[pos] = TAG_STR_VAL;
+                    LittleEndian::write_u16(&mut bytes[pos + 1..pos + 3], s.len() as u16);
+                    bytes[pos + 3..pos + 3 + s.len()].copy_from_slice(s.as_bytes());
+                    pos += 3 + s.len();
+                }
+                StringComponent::Ref(id) => {
+                    bytes[pos] = TAG_STR_REF;
+                    LittleEndian::write_u32(&mut bytes[pos + 1..pos + 5], id.0);
+                    pos += 5;
+                }
+            }
+        }
+
+        bytes[pos] = TAG_TERMINATOR;
     }
 }
 
-fn serialize_index_entry<S: SerializationSink>(sink: &S, id: StringId, addr: Addr) {
-    sink.write_atomic(8, |bytes| {
-        LittleEndian::write_u32(&mut bytes[0..4], id.0);
-        LittleEndian::write_u32(&mut bytes[4..8], addr.0);
-    });
+// An index entry is `[id: u32, addr: u32]`, little-endian like the rest of the
+// string table, so that 32-bit and 64-bit writers produce the same files.
+fn serialize_index_entry(bytes: &mut [u8], id: StringId, addr: Addr) {
+    LittleEndian::write_u32(&mut bytes[0..4], id.0);
+    LittleEndian::write_u32(&mut bytes[4..8], addr.0);
 }
 
 fn deserialize_index_entry(bytes: &[u8]) -> (StringId, Addr) {
@@ -147,13 +327,82 @@ impl<S: SerializationSink> StringTableBuilder<S> {
         write_file_header(&*data_sink, FILE_MAGIC_STRINGTABLE_DATA);
         write_file_header(&*index_sink, FILE_MAGIC_STRINGTABLE_INDEX);
 
+        StringTableBuilder::with_next_ids(data_sink, index_sink, FIRST_REGULAR_STRING_ID, 0)
+    }
+
+    /// Continues the string table whose index so far is `index_data`, for
+    /// sinks that append to the existing files. No file headers are written,
+    /// and ids are handed out after the largest regular and reserved ids in
+    /// the index, so that they don't collide with the existing strings.
+    pub(crate) fn resume(
+        data_sink: Arc<S>,
+        index_sink: Arc<S>,
+        index_data: &[u8],
+    ) -> StringTableBuilder<S> {
+        let mut next_id = FIRST_REGULAR_STRING_ID;
+        let mut next_reserved_id = 0;
+
+        for entry in index_data[FILE_HEADER_SIZE..].chunks_exact(8) {
+            let (id, _) = deserialize_index_entry(entry);
+            if id.0 <= MAX_PRE_RESERVED_STRING_ID {
+                next_reserved_id = next_reserved_id.max(id.0 + 1);
+            } else if id.0 >= FIRST_REGULAR_STRING_ID && id != StringId::INVALID {
+                next_id = next_id.max(id.0 + 1);
+            }
+        }
+
+        StringTableBuilder::with_next_ids(data_sink, index_sink, next_id, next_reserved_id)
+    }
+
+    fn with_next_ids(
+        data_sink: Arc<S>,
+        index_sink: Arc<S>,
+        next_id: u32,
+        next_reserved_id: u32,
+    ) -> StringTableBuilder<S> {
         StringTableBuilder {
             data_sink,
             index_sink,
-            id_counter: AtomicU32::new(METADATA_STRING_ID + 1),
+            id_counter: AtomicU32::new(next_id),
+            reserved_id_counter: AtomicU32::new(next_reserved_id),
+            batch: Mutex::new(StringBatch::new()),
+            deduplicated: None,
+            static_ids: Mutex::new(FxHashMap::default()),
         }
     }
 
+    /// Like `new()`, but `alloc()` returns the existing `StringId` when the
+    /// same string is allocated again instead of writing it a second time.
+    /// This keeps every distinct string in memory for the lifetime of the
+    /// builder, in exchange for smaller string table files when strings
+    /// recur. `alloc_with_reserved_id()` is not affected.
+    pub fn new_deduplicated(data_sink: Arc<S>, index_sink: Arc<S>) -> StringTableBuilder<S> {
+        let mut builder = StringTableBuilder::new(data_sink, index_sink);
+        builder.deduplicated = Some(Mutex::new(DedupCache::new(None)));
+        builder
+    }
+
+    /// Like `new_deduplicated()`, but only remembers the `capacity` most
+    /// recently used strings, so that the memory needed for deduplication is
+    /// bounded no matter how long the profile runs. A string that has been
+    /// forgotten is written again, with a new id, the next time it is
+    /// allocated. Ids handed out before stay valid.
+    ///
+    /// Strings are written to the sinks in batches either way, so this
+    /// bounds the memory of the whole builder. The price is disk space:
+    /// strings that recur only after more than `capacity` other strings end
+    /// up in the string table files several times. Frequently used strings,
+    /// like the names of event kinds, stay in the cache and are written once.
+    pub fn new_deduplicated_with_capacity(
+        data_sink: Arc<S>,
+        index_sink: Arc<S>,
+        capacity: usize,
+    ) -> StringTableBuilder<S> {
+        let mut builder = StringTableBuilder::new(data_sink, index_sink);
+        builder.deduplicated = Some(Mutex::new(DedupCache::new(Some(capacity))));
+        builder
+    }
+
     #[inline]
     pub fn alloc_with_reserved_id<STR: SerializableString + ?Sized>(
         &self,
@@ -165,28 +414,158 @@ impl<S: SerializationSink> StringTableBuilder<S> {
         id
     }
 
+    /// Reserves a block of `count` consecutive ids for use with
+    /// `alloc_with_reserved_id()` and returns the first one, so that the
+    /// variants of an enum can be mapped to `StringId`s without a lookup.
+    /// Blocks are handed out from the start of the reserved id range, so ids
+    /// chosen via `StringId::reserved()` must not be mixed with them.
+    ///
+    /// Reserving an id doesn't allocate a string for it. The caller must call
+    /// `alloc_with_reserved_id()` for each id before events refer to it, as
+    /// events whose strings are missing are skipped when reading the profile.
+    pub fn reserve_range(&self, count: u32) -> StringId {
+        let first = self
+            .reserved_id_counter
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |next| {
+                next.checked_add(count)
+                    .filter(|&end| end <= MAX_PRE_RESERVED_STRING_ID + 1)
+            })
+            .expect("ran out of reserved string ids");
+
+        StringId(first)
+    }
+
     pub(crate) fn alloc_metadata<STR: SerializableString + ?Sized>(&self, s: &STR) -> StringId {
-        let id = StringId(METADATA_STRING_ID);
+        self.alloc_internal(StringId(METADATA_STRING_ID), s)
+    }
+
+    pub(crate) fn alloc_internal<STR: SerializableString + ?Sized>(
+        &self,
+        id: StringId,
+        s: &STR,
+    ) -> StringId {
+        debug_assert!(id.0 >= METADATA_STRING_ID && id.0 < FIRST_REGULAR_STRING_ID);
         self.alloc_unchecked(id, s);
         id
     }
 
     #[inline]
     pub fn alloc<STR: SerializableString + ?Sized>(&self, s: &STR) -> StringId {
-        let id = StringId(self.id_counter.fetch_add(1, Ordering::SeqCst));
-        debug_assert!(id.0 > METADATA_STRING_ID);
+        if let Some(deduplicated) = &self.deduplicated {
+            return self.alloc_deduplicated(deduplicated, s);
+        }
+
+        let id = self.next_id();
         self.alloc_unchecked(id, s);
         id
     }
 
+    /// Like `alloc()`, but remembers the string by its address, so that
+    /// allocating the same `'static` string again returns the existing
+    /// `StringId` without copying or hashing its contents. This is meant for
+    /// string literals like the names of event kinds.
+    ///
+    /// The string is written once, via `alloc()`, the first time its address
+    /// is seen, so equal strings at different addresses are only
+    /// deduplicated by a builder created via `new_deduplicated()`. Each
+    /// address is remembered for the lifetime of the builder, even with a
+    /// capacity.
+    pub fn alloc_static(&self, s: &'static str) -> StringId {
+        // The length is part of the key, as a literal can share its address
+        // with a prefix of itself
+        let key = (s.as_ptr() as usize, s.len());
+
+        // The lock is held until the string is allocated, as in
+        // `alloc_deduplicated()`
+        let mut static_ids = self.static_ids.lock().unwrap();
+        *static_ids.entry(key).or_insert_with(|| self.alloc(s))
+    }
+
+    fn alloc_deduplicated<STR: SerializableString + ?Sized>(
+        &self,
+        deduplicated: &Mutex<DedupCache>,
+        s: &STR,
+    ) -> StringId {
+        let mut bytes = vec![0; s.serialized_size()];
+        s.serialize(&mut bytes);
+
+        // The lock is held until the string is allocated, so that concurrent
+        // allocations of the same string can't end up with different ids.
+        let mut deduplicated = deduplicated.lock().unwrap();
+        if let Some(id) = deduplicated.get(&bytes) {
+            return id;
+        }
+
+        let id = self.next_id();
+        self.alloc_unchecked(id, &SerializedString(&bytes));
+        deduplicated.insert(bytes, id);
+        id
+    }
+
+    #[inline]
+    fn next_id(&self) -> StringId {
+        let id = StringId(self.id_counter.fetch_add(1, Ordering::SeqCst));
+        debug_assert!(id.0 >= FIRST_REGULAR_STRING_ID);
+        assert!(id != StringId::INVALID, "ran out of string ids");
+        id
+    }
+
+    /// Writes the pending batch of strings and flushes the data sink and then
+    /// the index sink, so that the index never refers to string data that
+    /// hasn't been written yet.
+    pub fn flush(&self) -> io::Result<()> {
+        self.write_batch(&mut self.batch.lock().unwrap());
+        self.data_sink.flush()?;
+        self.index_sink.flush()
+    }
+
+    /// The sinks of the string data and the string index.
+    pub(crate) fn sinks(&self) -> (&S, &S) {
+        (&self.data_sink, &self.index_sink)
+    }
+
     #[inline]
     fn alloc_unchecked<STR: SerializableString + ?Sized>(&self, id: StringId, s: &STR) {
+        if S::DISCARDS_DATA {
+            return;
+        }
+
         let size_in_bytes = s.serialized_size();
-        let addr = self.data_sink.write_atomic(size_in_bytes, |mem| {
-            s.serialize(mem);
-        });
 
-        serialize_index_entry(&*self.index_sink, id, addr);
+        let mut batch = self.batch.lock().unwrap();
+
+        let addr = Addr(batch.data.len() as u32);
+        batch.data.resize(addr.as_usize() + size_in_bytes, 0);
+        s.serialize(&mut batch.data[addr.as_usize()..]);
+        batch.index.push((id, addr));
+
+        if batch.is_full() {
+            self.write_batch(&mut batch);
+        }
+    }
+
+    // Writes the string data of the batch and then its index entries, each
+    // with a single call to the sink.
+    fn write_batch(&self, batch: &mut StringBatch) {
+        if !batch.index.is_empty() {
+            let base_addr = self.data_sink.write_bytes(&batch.data);
+
+            let index = &batch.index;
+            self.index_sink.write_atomic(index.len() * 8, |bytes| {
+                for (&(id, addr), entry) in index.iter().zip(bytes.chunks_exact_mut(8)) {
+                    serialize_index_entry(entry, id, Addr(base_addr.0 + addr.0));
+                }
+            });
+        }
+
+        *batch = StringBatch::new();
+    }
+}
+
+impl<S: SerializationSink> Drop for StringTableBuilder<S> {
+    fn drop(&mut self) {
+        let mut batch = std::mem::replace(self.batch.get_mut().unwrap(), StringBatch::new());
+        self.write_batch(&mut batch);
     }
 }
 
@@ -198,6 +577,10 @@ pub struct StringRef<'st> {
 
 impl<'st> StringRef<'st> {
     pub fn to_string(&self) -> Cow<'st, str> {
+        if self.id == StringId::INVALID {
+            return Cow::from(INVALID_STRING);
+        }
+
         let addr = self.table.index[&self.id].as_usize();
         let tag = self.table.string_data[addr];
 
@@ -226,7 +609,47 @@ impl<'st> StringRef<'st> {
         Cow::from(output)
     }
 
+    /// Returns the text of each component of the string: a part of the
+    /// string itself for `StringComponent::Value`, or the whole referenced
+    /// string for `StringComponent::Ref`. Concatenated, the components make
+    /// up the string.
+    pub fn components(&self) -> Vec<Cow<'st, str>> {
+        if self.id == StringId::INVALID {
+            return vec![Cow::from(INVALID_STRING)];
+        }
+
+        let string_data = &self.table.string_data[..];
+        let mut pos = self.table.index[&self.id].as_usize();
+        let mut components = Vec::new();
+
+        loop {
+            match string_data[pos] {
+                TAG_STR_VAL => {
+                    let len = LittleEndian::read_u16(&string_data[pos + 1..pos + 3]) as usize;
+                    let bytes = &string_data[pos + 3..pos + 3 + len];
+                    components.push(Cow::from(std::str::from_utf8(bytes).unwrap()));
+                    pos += 3 + len;
+                }
+
+                TAG_STR_REF => {
+                    let id = StringId(LittleEndian::read_u32(&string_data[pos + 1..pos + 5]));
+                    components.push(self.table.get(id).to_string());
+                    pos += 5;
+                }
+
+                TAG_TERMINATOR => return components,
+
+                _ => unreachable!(),
+            }
+        }
+    }
+
     pub fn write_to_string(&self, output: &mut String) {
+        if self.id == StringId::INVALID {
+            output.push_str(INVALID_STRING);
+            return;
+        }
+
         let addr = self.table.index[&self.id];
 
         let mut pos = addr.as_usize();
@@ -247,7 +670,11 @@ impl<'st> StringRef<'st> {
                 }
 
                 TAG_STR_REF => {
-                    unimplemented!();
+                    let id = StringId(LittleEndian::read_u32(
+                        &self.table.string_data[pos + 1..pos + 5],
+                    ));
+                    self.table.get(id).write_to_string(output);
+                    pos += 5;
                 }
 
                 TAG_TERMINATOR => return,
@@ -261,40 +688,114 @@ impl<'st> StringRef<'st> {
 /// Read-only version of the string table
 pub struct StringTable {
     // TODO: Replace with something lazy
-    string_data: Vec<u8>,
+    string_data: ByteBuffer,
     index: FxHashMap<StringId, Addr>,
+    truncated: bool,
+}
+
+// Checks that the components of the string at `addr` lie within `data`.
+fn is_complete_string(data: &[u8], mut pos: usize) -> bool {
+    loop {
+        match data.get(pos) {
+            Some(&TAG_STR_VAL) => {
+                let len = match data.get(pos + 1..pos + 3) {
+                    Some(len) => LittleEndian::read_u16(len) as usize,
+                    None => return false,
+                };
+                pos += 3 + len;
+            }
+            // Referenced strings are written before the strings that refer to
+            // them, so they are complete if this one is.
+            Some(&TAG_STR_REF) => pos += 5,
+            Some(&TAG_TERMINATOR) => return true,
+            _ => return false,
+        }
+    }
 }
 
 impl StringTable {
     pub fn new(string_data: Vec<u8>, index_data: Vec<u8>) -> Result<StringTable, Box<dyn Error>> {
+        StringTable::from_byte_buffers(string_data.into(), &index_data)
+    }
+
+    pub(crate) fn from_byte_buffers(
+        string_data: ByteBuffer,
+        index_data: &[u8],
+    ) -> Result<StringTable, Box<dyn Error>> {
         let string_data_format = read_file_header(&string_data, FILE_MAGIC_STRINGTABLE_DATA)?;
-        let index_data_format = read_file_header(&index_data, FILE_MAGIC_STRINGTABLE_INDEX)?;
+        let index_data_format = read_file_header(index_data, FILE_MAGIC_STRINGTABLE_INDEX)?;
 
         if string_data_format != index_data_format {
             Err("Mismatch between StringTable DATA and INDEX format version")?;
         }
 
-        if string_data_format != CURRENT_FILE_FORMAT_VERSION {
-            Err(format!(
-                "StringTable file format version '{}' is not supported
-                         by this version of `measureme`.",
-                string_data_format
-            ))?;
+        // The encoding of strings is the same in all supported versions
+        check_file_format_version(string_data_format)?;
+
+        // If the process that wrote the files was killed, the index can end
+        // with a partial entry and its last entries can refer to string data
+        // that was never written. Such entries are ignored, so the affected
+        // ids are simply unknown.
+        let index_entries = strip_file_header(index_data);
+        let mut truncated = !index_entries.len().is_multiple_of(8);
+
+        let mut index = FxHashMap::default();
+        for (id, addr) in index_entries.chunks_exact(8).map(deserialize_index_entry) {
+            if is_complete_string(&string_data, addr.as_usize()) {
+                index.insert(id, addr);
+            } else {
+                truncated = true;
+            }
         }
 
-        assert!(index_data.len() % 8 == 0);
-        let index: FxHashMap<_, _> = strip_file_header(&index_data)
-            .chunks(8)
-            .map(deserialize_index_entry)
-            .collect();
+        Ok(StringTable {
+            string_data,
+            index,
+            truncated,
+        })
+    }
+
+    /// A table without any strings, for profiles whose string table files
+    /// are missing.
+    pub(crate) fn empty() -> StringTable {
+        StringTable {
+            string_data: Vec::new().into(),
+            index: FxHashMap::default(),
+            truncated: false,
+        }
+    }
 
-        Ok(StringTable { string_data, index })
+    /// Returns whether some strings were ignored because the files the table
+    /// was read from are incomplete.
+    pub(crate) fn is_truncated(&self) -> bool {
+        self.truncated
     }
 
     #[inline]
     pub fn get<'a>(&'a self, id: StringId) -> StringRef<'a> {
         StringRef { id, table: self }
     }
+
+    /// Returns whether a string has been allocated for `id`.
+    #[inline]
+    pub fn contains(&self, id: StringId) -> bool {
+        self.index.contains_key(&id)
+    }
+
+    /// The number of strings in the table, i.e. of ids that have one.
+    pub(crate) fn string_count(&self) -> usize {
+        self.index.len()
+    }
+
+    /// The size of the string data without its file header.
+    pub(crate) fn string_data_len(&self) -> usize {
+        self.string_data.len().saturating_sub(FILE_HEADER_SIZE)
+    }
+
+    /// The ids of all strings in the table, in no particular order.
+    pub(crate) fn ids(&self) -> impl Iterator<Item = StringId> + '_ {
+        self.index.keys().copied()
+    }
 }
 
 #[cfg(test)]
@@ -303,10 +804,10 @@ mod tests {
 
     #[test]
     fn simple_strings() {
-        use crate::serialization::ByteVecSink;
+        use crate::serialization::InMemorySink;
 
-        let data_sink = Arc::new(ByteVecSink::new());
-        let index_sink = Arc::new(ByteVecSink::new());
+        let data_sink = Arc::new(InMemorySink::new());
+        let index_sink = Arc::new(InMemorySink::new());
 
         let expected_strings = &[
             "abc",
@@ -343,4 +844,292 @@ mod tests {
             assert_eq!(str_ref.to_string(), write_to);
         }
     }
+
+    #[test]
+    fn reserved_ranges() {
+        use crate::serialization::InMemorySink;
+
+        let data_sink = Arc::new(InMemorySink::new());
+        let index_sink = Arc::new(InMemorySink::new());
+
+        {
+            let builder = StringTableBuilder::new(data_sink.clone(), index_sink.clone());
+
+            let first = builder.reserve_range(3);
+            let second = builder.reserve_range(2);
+            assert_eq!(first, StringId::from_reserved_index(0));
+            assert_eq!(second, StringId::from_reserved_index(3));
+
+            for (i, name) in ["a", "b", "c"].iter().enumerate() {
+                let id = StringId::from_reserved_index(first.reserved_index() + i as u32);
+                builder.alloc_with_reserved_id(id, *name);
+            }
+            builder.alloc_with_reserved_id(second, "d");
+
+            // Regular ids don't overlap with reserved blocks
+            assert!(builder.alloc("e").0 > MAX_PRE_RESERVED_STRING_ID);
+        }
+
+        let data_bytes = Arc::try_unwrap(data_sink).unwrap().into_bytes();
+        let index_bytes = Arc::try_unwrap(index_sink).unwrap().into_bytes();
+        let string_table = StringTable::new(data_bytes, index_bytes).unwrap();
+
+        let strings: Vec<_> = (0..4)
+            .map(|i| {
+                string_table
+                    .get(StringId::from_reserved_index(i))
+                    .to_string()
+            })
+            .collect();
+        assert_eq!(strings, ["a", "b", "c", "d"]);
+        // Reserved, but never allocated
+        assert!(!string_table.contains(StringId::from_reserved_index(4)));
+    }
+
+    #[test]
+    #[should_panic(expected = "ran out of reserved string ids")]
+    fn reserved_ranges_exhausted() {
+        use crate::serialization::NullSink;
+
+        let builder = StringTableBuilder::new(Arc::new(NullSink), Arc::new(NullSink));
+        builder.reserve_range(MAX_PRE_RESERVED_STRING_ID);
+        builder.reserve_range(1);
+        builder.reserve_range(1);
+    }
+
+    #[test]
+    fn batched_strings() {
+        use crate::file_header::FILE_HEADER_SIZE;
+        use crate::serialization::InMemorySink;
+
+        let data_sink = Arc::new(InMemorySink::new());
+        let index_sink = Arc::new(InMemorySink::new());
+
+        // Enough data for several batches
+        let strings: Vec<String> = (0..5000).map(|i| format!("string {}", i)).collect();
+
+        let string_ids: Vec<_> = {
+            let builder = StringTableBuilder::new(data_sink.clone(), index_sink.clone());
+            strings.iter().map(|s| builder.alloc(&s[..])).collect()
+        };
+
+        let data_bytes = Arc::try_unwrap(data_sink).unwrap().into_bytes();
+        let index_bytes = Arc::try_unwrap(index_sink).unwrap().into_bytes();
+
+        // The files look exactly as if each string had been written by itself
+        let mut expected_data = data_bytes[..FILE_HEADER_SIZE].to_vec();
+        let mut expected_index = index_bytes[..FILE_HEADER_SIZE].to_vec();
+        for (s, &id) in strings.iter().zip(&string_ids) {
+            let addr = Addr(expected_data.len() as u32);
+            let start = expected_data.len();
+            expected_data.resize(start + s.serialized_size(), 0);
+            s[..].serialize(&mut expected_data[start..]);
+
+            let start = expected_index.len();
+            expected_index.resize(start + 8, 0);
+            serialize_index_entry(&mut expected_index[start..], id, addr);
+        }
+        assert!(data_bytes == expected_data);
+        assert!(index_bytes == expected_index);
+
+        let string_table = StringTable::new(data_bytes, index_bytes).unwrap();
+        for (s, &id) in strings.iter().zip(&string_ids) {
+            assert_eq!(string_table.get(id).to_string(), &s[..]);
+        }
+    }
+
+    #[test]
+    fn deduplicated_strings() {
+        use crate::serialization::InMemorySink;
+
+        let data_sink = Arc::new(InMemorySink::new());
+        let index_sink = Arc::new(InMemorySink::new());
+
+        let (abc, abc_again, xyz, reserved, reserved_again) = {
+            let builder =
+                StringTableBuilder::new_deduplicated(data_sink.clone(), index_sink.clone());
+            (
+                builder.alloc("abc"),
+                builder.alloc("abc"),
+                builder.alloc("xyz"),
+                builder.alloc_with_reserved_id(StringId::reserved(1), "abc"),
+                builder.alloc_with_reserved_id(StringId::reserved(2), "abc"),
+            )
+        };
+
+        assert_eq!(abc, abc_again);
+        assert_ne!(abc, xyz);
+        // Reserved ids are written as requested, even for known strings
+        assert_eq!(reserved, StringId::reserved(1));
+        assert_eq!(reserved_again, StringId::reserved(2));
+
+        let data_bytes = Arc::try_unwrap(data_sink).unwrap().into_bytes();
+        let index_bytes = Arc::try_unwrap(index_sink).unwrap().into_bytes();
+
+        // One index entry each for "abc" and "xyz" and the two reserved ids
+        assert_eq!(strip_file_header(&index_bytes).len(), 4 * 8);
+
+        let string_table = StringTable::new(data_bytes, index_bytes).unwrap();
+        assert_eq!(string_table.get(abc).to_string(), "abc");
+        assert_eq!(string_table.get(xyz).to_string(), "xyz");
+        assert_eq!(string_table.get(reserved).to_string(), "abc");
+        assert_eq!(string_table.get(reserved_again).to_string(), "abc");
+    }
+
+    #[test]
+    fn deduplicated_strings_with_capacity() {
+        use crate::serialization::InMemorySink;
+
+        let data_sink = Arc::new(InMemorySink::new());
+        let index_sink = Arc::new(InMemorySink::new());
+
+        let (a, a_again, b, c, b_again, c_again) = {
+            let builder = StringTableBuilder::new_deduplicated_with_capacity(
+                data_sink.clone(),
+                index_sink.clone(),
+                2,
+            );
+            let a = builder.alloc("a");
+            let b = builder.alloc("b");
+            // Makes "b" the least recently used string
+            let a_again = builder.alloc("a");
+            // Evicts "b"
+            let c = builder.alloc("c");
+            // Evicts "a"
+            let b_again = builder.alloc("b");
+            let c_again = builder.alloc("c");
+            (a, a_again, b, c, b_again, c_again)
+        };
+
+        assert_eq!(a, a_again);
+        assert_ne!(b, b_again);
+        assert_eq!(c, c_again);
+
+        let data_bytes = Arc::try_unwrap(data_sink).unwrap().into_bytes();
+        let index_bytes = Arc::try_unwrap(index_sink).unwrap().into_bytes();
+
+        // "b" was written twice
+        assert_eq!(strip_file_header(&index_bytes).len(), 4 * 8);
+
+        // Ids handed out before their string was evicted still resolve
+        let string_table = StringTable::new(data_bytes, index_bytes).unwrap();
+        assert_eq!(string_table.get(a).to_string(), "a");
+        assert_eq!(string_table.get(b).to_string(), "b");
+        assert_eq!(string_table.get(c).to_string(), "c");
+        assert_eq!(string_table.get(b_again).to_string(), "b");
+    }
+
+    #[test]
+    fn static_strings() {
+        use crate::serialization::InMemorySink;
+
+        static QUERY: &str = "Query";
+        // The same contents at a different address
+        let query_copy: &'static str = Box::leak(String::from("Query").into_boxed_str());
+
+        for deduplicated in [false, true] {
+            let data_sink = Arc::new(InMemorySink::new());
+            let index_sink = Arc::new(InMemorySink::new());
+
+            let (query, query_again, prefix, copy, regular) = {
+                let builder = if deduplicated {
+                    StringTableBuilder::new_deduplicated(data_sink.clone(), index_sink.clone())
+                } else {
+                    StringTableBuilder::new(data_sink.clone(), index_sink.clone())
+                };
+                (
+                    builder.alloc_static(QUERY),
+                    builder.alloc_static(QUERY),
+                    builder.alloc_static(&QUERY[..2]),
+                    builder.alloc_static(query_copy),
+                    builder.alloc("Query"),
+                )
+            };
+
+            assert_eq!(query, query_again);
+            assert_ne!(query, prefix);
+            // Equal contents are only deduplicated by a deduplicating builder
+            assert_eq!(query == copy, deduplicated);
+            assert_eq!(query == regular, deduplicated);
+
+            let data_bytes = Arc::try_unwrap(data_sink).unwrap().into_bytes();
+            let index_bytes = Arc::try_unwrap(index_sink).unwrap().into_bytes();
+
+            let entries = if deduplicated { 2 } else { 4 };
+            assert_eq!(strip_file_header(&index_bytes).len(), entries * 8);
+
+            let string_table = StringTable::new(data_bytes, index_bytes).unwrap();
+            assert_eq!(string_table.get(query).to_string(), "Query");
+            assert_eq!(string_table.get(prefix).to_string(), "Qu");
+            assert_eq!(string_table.get(copy).to_string(), "Query");
+            assert_eq!(string_table.get(regular).to_string(), "Query");
+        }
+    }
+
+    #[test]
+    fn composite_strings() {
+        use crate::serialization::InMemorySink;
+
+        let data_sink = Arc::new(InMemorySink::new());
+        let index_sink = Arc::new(InMemorySink::new());
+
+        let (inner, outer, empty) = {
+            let builder = StringTableBuilder::new(data_sink.clone(), index_sink.clone());
+            let abc = builder.alloc("abc");
+            let inner =
+                builder.alloc(&[StringComponent::Ref(abc), StringComponent::Value("-")][..]);
+            let outer = builder.alloc(
+                &[
+                    StringComponent::Value("<"),
+                    StringComponent::Ref(inner),
+                    StringComponent::Ref(abc),
+                    StringComponent::Value(">"),
+                ][..],
+            );
+            let empty = builder.alloc(&[][..]);
+            (inner, outer, empty)
+        };
+
+        let data_bytes = Arc::try_unwrap(data_sink).unwrap().into_bytes();
+        let index_bytes = Arc::try_unwrap(index_sink).unwrap().into_bytes();
+
+        let string_table = StringTable::new(data_bytes, index_bytes).unwrap();
+
+        assert!(!string_table.is_truncated());
+        assert_eq!(string_table.get(inner).to_string(), "abc-");
+        assert_eq!(string_table.get(outer).to_string(), "<abc-abc>");
+        assert_eq!(
+            string_table.get(outer).components(),
+            ["<", "abc-", "abc", ">"]
+        );
+        assert_eq!(string_table.get(empty).to_string(), "");
+        assert!(string_table.get(empty).components().is_empty());
+    }
+
+    #[test]
+    fn truncated_string_table() {
+        use crate::serialization::InMemorySink;
+
+        let data_sink = Arc::new(InMemorySink::new());
+        let index_sink = Arc::new(InMemorySink::new());
+
+        let (abc, xyz) = {
+            let builder = StringTableBuilder::new(data_sink.clone(), index_sink.clone());
+            (builder.alloc("abc"), builder.alloc("xyz"))
+        };
+
+        let mut data_bytes = Arc::try_unwrap(data_sink).unwrap().into_bytes();
+        let mut index_bytes = Arc::try_unwrap(index_sink).unwrap().into_bytes();
+
+        // Cut off the end of "xyz" and add a partial index entry
+        data_bytes.truncate(data_bytes.len() - 2);
+        index_bytes.extend_from_slice(&[1, 2, 3]);
+
+        let string_table = StringTable::new(data_bytes, index_bytes).unwrap();
+
+        assert!(string_table.is_truncated());
+        assert!(string_table.contains(abc));
+        assert_eq!(string_table.get(abc).to_string(), "abc");
+        assert!(!string_table.contains(xyz));
+    }
 }
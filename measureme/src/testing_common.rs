@@ -1,6 +1,9 @@
-use crate::{Event, Profiler, ProfilingData, SerializationSink, StringId, TimestampKind};
+use crate::{
+    Event, InMemorySink, Profiler, ProfilingData, SerializationSink, StringId, TimestampKind,
+};
 use rustc_hash::FxHashMap;
 use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::default::Default;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
@@ -17,9 +20,7 @@ fn mk_filestem(file_name_stem: &str) -> PathBuf {
 }
 
 // Generate some profiling data. This is the part that would run in rustc.
-fn generate_profiling_data<S: SerializationSink>(filestem: &Path) -> Vec<Event<'static>> {
-    let profiler = Arc::new(Profiler::<S>::new(Path::new(filestem)).unwrap());
-
+fn generate_profiling_data<S: SerializationSink>(profiler: &Profiler<S>) -> Vec<Event<'static>> {
     let event_id_reserved = StringId::reserved(42);
 
     let event_ids = &[
@@ -29,6 +30,7 @@ fn generate_profiling_data<S: SerializationSink>(filestem: &Path) -> Vec<Event<'
         ),
         (profiler.alloc_string("Query"), event_id_reserved),
     ];
+    let category = profiler.alloc_string("Frontend");
 
     // This and event_ids have to match!
     let mut event_ids_as_str: FxHashMap<_, _> = Default::default();
@@ -40,7 +42,15 @@ fn generate_profiling_data<S: SerializationSink>(filestem: &Path) -> Vec<Event<'
     let mut expected_events = Vec::new();
     let mut started_events = Vec::new();
 
+    profiler.set_thread_name(0, "main");
+    profiler.set_thread_name(1, "worker");
+
     for i in 0..10_000 {
+        // Renaming a thread must replace the previous name
+        if i == 5_000 {
+            profiler.set_thread_name(1, "renamed worker");
+        }
+
         // Allocate some invocation stacks
         for _ in 0..4 {
             let thread_id = (i % 3) as u64;
@@ -53,10 +63,56 @@ fn generate_profiling_data<S: SerializationSink>(filestem: &Path) -> Vec<Event<'
             expected_events.push(Event {
                 event_kind: Cow::from(event_ids_as_str[&event_kind]),
                 label: Cow::from(event_ids_as_str[&event_id]),
+                event_kind_id: event_kind,
+                label_id: event_id,
                 additional_data: &[],
                 timestamp: SystemTime::UNIX_EPOCH, // We can't test this anyway,
                 timestamp_kind: TimestampKind::Start,
                 thread_id,
+                value: None,
+                arg: None,
+                category: None,
+                args: BTreeMap::new(),
+            });
+        }
+
+        // Record an instant event and a counter inside the innermost interval
+        {
+            let thread_id = (i % 3) as u64;
+            let (event_kind, event_id) = event_ids[i % event_ids.len()];
+
+            profiler.record_instant_event(event_kind, event_id, thread_id);
+
+            expected_events.push(Event {
+                event_kind: Cow::from(event_ids_as_str[&event_kind]),
+                label: Cow::from(event_ids_as_str[&event_id]),
+                event_kind_id: event_kind,
+                label_id: event_id,
+                additional_data: &[],
+                timestamp: SystemTime::UNIX_EPOCH, // We can't test this anyway,
+                timestamp_kind: TimestampKind::Instant,
+                thread_id,
+                value: None,
+                arg: None,
+                category: None,
+                args: BTreeMap::new(),
+            });
+
+            profiler.record_counter(event_kind, thread_id, i as u64);
+
+            expected_events.push(Event {
+                event_kind: Cow::from(event_ids_as_str[&event_kind]),
+                label: Cow::from(event_ids_as_str[&event_kind]),
+                event_kind_id: event_kind,
+                label_id: event_kind,
+                additional_data: &[],
+                timestamp: SystemTime::UNIX_EPOCH, // We can't test this anyway,
+                timestamp_kind: TimestampKind::Counter,
+                thread_id,
+                value: Some(i as u64),
+                arg: None,
+                category: None,
+                args: BTreeMap::new(),
             });
         }
 
@@ -66,12 +122,77 @@ fn generate_profiling_data<S: SerializationSink>(filestem: &Path) -> Vec<Event<'
             expected_events.push(Event {
                 event_kind: Cow::from(event_ids_as_str[&event_kind]),
                 label: Cow::from(event_ids_as_str[&event_id]),
+                event_kind_id: event_kind,
+                label_id: event_id,
                 additional_data: &[],
                 timestamp: SystemTime::UNIX_EPOCH, // We can't test this anyway,
                 timestamp_kind: TimestampKind::End,
                 thread_id,
+                value: None,
+                arg: None,
+                category: None,
+                args: BTreeMap::new(),
             });
         }
+
+        // Record an interval event with an integer argument via the RAII API
+        {
+            let thread_id = (i % 3) as u64;
+            let (event_kind, event_id) = event_ids[i % event_ids.len()];
+
+            let _guard = profiler
+                .start_recording_interval_event_with_arg(event_kind, event_id, thread_id, i as u64);
+
+            for (timestamp_kind, arg) in [
+                (TimestampKind::Start, Some(i as u64)),
+                (TimestampKind::End, None),
+            ] {
+                expected_events.push(Event {
+                    event_kind: Cow::from(event_ids_as_str[&event_kind]),
+                    label: Cow::from(event_ids_as_str[&event_id]),
+                    event_kind_id: event_kind,
+                    label_id: event_id,
+                    additional_data: &[],
+                    timestamp: SystemTime::UNIX_EPOCH, // We can't test this anyway,
+                    timestamp_kind,
+                    thread_id,
+                    value: None,
+                    arg,
+                    category: None,
+                    args: BTreeMap::new(),
+                });
+            }
+        }
+
+        // Record an interval event with a category
+        {
+            let thread_id = (i % 3) as u64;
+            let (event_kind, event_id) = event_ids[i % event_ids.len()];
+
+            let _guard = profiler.start_recording_interval_event_with_category(
+                event_kind, event_id, category, thread_id,
+            );
+
+            for (timestamp_kind, category) in [
+                (TimestampKind::Start, Some(Cow::from("Frontend"))),
+                (TimestampKind::End, None),
+            ] {
+                expected_events.push(Event {
+                    event_kind: Cow::from(event_ids_as_str[&event_kind]),
+                    label: Cow::from(event_ids_as_str[&event_id]),
+                    event_kind_id: event_kind,
+                    label_id: event_id,
+                    additional_data: &[],
+                    timestamp: SystemTime::UNIX_EPOCH, // We can't test this anyway,
+                    timestamp_kind,
+                    thread_id,
+                    value: None,
+                    arg: None,
+                    category,
+                    args: BTreeMap::new(),
+                });
+            }
+        }
     }
 
     // An example of allocating the string contents of an event id that has
@@ -83,9 +204,7 @@ fn generate_profiling_data<S: SerializationSink>(filestem: &Path) -> Vec<Event<'
 
 // Process some profiling data. This is the part that would run in a
 // post processing tool.
-fn process_profiling_data(filestem: &Path, expected_events: &[Event<'static>]) {
-    let profiling_data = ProfilingData::new(filestem).unwrap();
-
+fn process_profiling_data(profiling_data: &ProfilingData, expected_events: &[Event<'static>]) {
     let mut count = 0;
 
     for (actual_event, expected_event) in profiling_data.iter().zip(expected_events.iter()) {
@@ -93,17 +212,97 @@ fn process_profiling_data(filestem: &Path, expected_events: &[Event<'static>]) {
 
         assert_eq!(actual_event.event_kind, expected_event.event_kind);
         assert_eq!(actual_event.label, expected_event.label);
+        assert_eq!(actual_event.event_kind_id, expected_event.event_kind_id);
+        assert_eq!(actual_event.label_id, expected_event.label_id);
+        assert_eq!(actual_event.kind_str(), expected_event.event_kind);
+        assert_eq!(actual_event.id_str(), expected_event.label);
         assert_eq!(actual_event.additional_data, expected_event.additional_data);
         assert_eq!(actual_event.timestamp_kind, expected_event.timestamp_kind);
+        assert_eq!(actual_event.value, expected_event.value);
+        assert_eq!(actual_event.arg, expected_event.arg);
+        assert_eq!(actual_event.category, expected_event.category);
 
         count += 1;
     }
 
     assert_eq!(count, expected_events.len());
+
+    assert_eq!(profiling_data.thread_name(0), Some("main"));
+    assert_eq!(profiling_data.thread_name(1), Some("renamed worker"));
+    assert_eq!(profiling_data.thread_name(2), None);
 }
 
 pub fn run_end_to_end_serialization_test<S: SerializationSink>(file_name_stem: &str) {
     let filestem = mk_filestem(file_name_stem);
-    let expected_events = generate_profiling_data::<S>(&filestem);
-    process_profiling_data(&filestem, &expected_events);
+
+    let expected_events = {
+        let profiler = Profiler::<S>::new(Path::new(&filestem)).unwrap();
+        generate_profiling_data(&profiler)
+    };
+
+    let profiling_data = ProfilingData::new(&filestem).unwrap();
+    process_profiling_data(&profiling_data, &expected_events);
+
+    let profiling_data = ProfilingData::from_mmap(&filestem).unwrap();
+    process_profiling_data(&profiling_data, &expected_events);
+}
+
+pub fn run_in_memory_serialization_test() {
+    let event_sink = Arc::new(InMemorySink::new());
+    let string_data_sink = Arc::new(InMemorySink::new());
+    let string_index_sink = Arc::new(InMemorySink::new());
+
+    let expected_events = {
+        let profiler = Profiler::with_sinks(
+            event_sink.clone(),
+            string_data_sink.clone(),
+            string_index_sink.clone(),
+        );
+        generate_profiling_data(&profiler)
+    };
+
+    let event_bytes = Arc::try_unwrap(event_sink).unwrap().into_bytes();
+    let string_data_bytes = Arc::try_unwrap(string_data_sink).unwrap().into_bytes();
+    let string_index_bytes = Arc::try_unwrap(string_index_sink).unwrap().into_bytes();
+
+    let profiling_data = ProfilingData::from_readers(
+        &event_bytes[..],
+        &string_data_bytes[..],
+        &string_index_bytes[..],
+    )
+    .unwrap();
+    process_profiling_data(&profiling_data, &expected_events);
+
+    let profiling_data =
+        ProfilingData::from_buffers(event_bytes, string_data_bytes, string_index_bytes).unwrap();
+    process_profiling_data(&profiling_data, &expected_events);
+}
+
+pub fn run_packed_serialization_test(file_name_stem: &str) {
+    let filestem = mk_filestem(file_name_stem);
+
+    let expected_events = {
+        let profiler = Profiler::new_packed(&filestem).unwrap();
+        generate_profiling_data(&profiler)
+    };
+
+    let profiling_data = ProfilingData::from_packed(&filestem).unwrap();
+    assert!(profiling_data.is_complete());
+    process_profiling_data(&profiling_data, &expected_events);
+}
+
+pub fn run_sharded_serialization_test<S: SerializationSink>(
+    file_name_stem: &str,
+    shard_count: usize,
+) {
+    let filestem = mk_filestem(file_name_stem);
+
+    let expected_events = {
+        let profiler = Profiler::<S>::new_sharded(&filestem, shard_count).unwrap();
+        generate_profiling_data(&profiler)
+    };
+
+    let profiling_data = ProfilingData::new(&filestem).unwrap();
+    assert!(profiling_data.is_complete());
+    process_profiling_data(&profiling_data, &expected_events);
 }
@@ -0,0 +1,75 @@
+//! Throttling the interval events of an event kind via
+//! `Profiler::set_throttle()`, to bound the overhead of profiling hot code.
+
+use crate::stringtable::StringId;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// How `Profiler::set_throttle()` thins out the intervals of an event kind
+/// that are recorded via `Profiler::start_recording_interval_event()` and its
+/// variants.
+///
+/// Throttled intervals are missing from the profile, which changes what the
+/// tools compute from it:
+///
+/// - The counts and total times of the throttled kind only cover the
+///   recorded intervals. With `OneIn(n)` they are about `1/n` of the real
+///   ones, with `MinDuration` they leave out the short intervals.
+/// - The time spent in an interval that wasn't recorded counts as self time
+///   of the interval it was nested in, as if it hadn't been instrumented.
+/// - Intervals nested in an interval that wasn't recorded show up directly
+///   below its parent.
+///
+/// The profile doesn't record which kinds were throttled.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Throttle {
+    /// Records only the first of every `n` intervals of the kind, counted
+    /// across all threads. Skipping an interval costs an atomic increment
+    /// and noting the skip in the buffer of the thread, see
+    /// `Profiler::record_args_event()`, without reading the clock or writing
+    /// anything.
+    OneIn(u32),
+    /// Records only the intervals that last at least this long.
+    ///
+    /// Whether an interval is long enough is only known when it ends, so the
+    /// "start" event is written as usual. When the interval ends too early,
+    /// the "start" event is removed again from the buffer of the thread, see
+    /// `Profiler`, which only works if it is still the last event there:
+    /// short intervals are kept if other events were recorded within them,
+    /// e.g. nested intervals that were long enough, or if the buffer was
+    /// written out in the meantime, e.g. by `Profiler::flush()`. Nested
+    /// intervals that were removed don't count, so short intervals of this
+    /// kind nested in each other are removed as a whole.
+    MinDuration(Duration),
+}
+
+// The throttle of an event kind, with the number of intervals of the kind
+// started so far for `Throttle::OneIn`
+pub(crate) struct KindThrottle {
+    pub(crate) event_kind: StringId,
+    pub(crate) throttle: Throttle,
+    started: AtomicU64,
+}
+
+impl KindThrottle {
+    pub(crate) fn new(event_kind: StringId, throttle: Throttle) -> KindThrottle {
+        if let Throttle::OneIn(n) = throttle {
+            assert!(n > 0, "`Throttle::OneIn(0)` would record no intervals");
+        }
+
+        KindThrottle {
+            event_kind,
+            throttle,
+            started: AtomicU64::new(0),
+        }
+    }
+
+    // Counts an interval started with `Throttle::OneIn(n)` and returns
+    // whether it is one that is recorded
+    #[inline]
+    pub(crate) fn count(&self, n: u32) -> bool {
+        self.started
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(u64::from(n))
+    }
+}
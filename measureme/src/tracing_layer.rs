@@ -0,0 +1,225 @@
+//! A `tracing_subscriber::Layer` that records spans and events into a `Profiler`.
+
+use crate::clock::{Clock, InstantClock};
+use crate::profiler::{current_thread_id, Profiler};
+use crate::raw_event::TimestampKind;
+use crate::serialization::SerializationSink;
+use crate::stringtable::StringId;
+use rustc_hash::FxHashMap;
+use std::fmt::{self, Write};
+use std::sync::{Arc, Mutex};
+use tracing_core::field::{Field, Visit};
+use tracing_core::span::{Attributes, Id, Record};
+use tracing_core::{Event, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Records `tracing` spans as interval events and `tracing` events as instant
+/// events (behind the `tracing` feature).
+///
+/// Each time a span is entered, a "start" event is recorded whose event kind
+/// is the name of the span and whose event id is made up of its fields, e.g.
+/// `path="src/main.rs", line=12`. The matching "end" event is recorded when
+/// the span is exited. The event ids are stored in the span's extensions, so
+/// a span that is entered many times allocates its strings only once.
+///
+/// Events use the name of their callsite as event kind, and their fields,
+/// starting with the message, as event id. Both spans and events are
+/// recorded for the thread that enters them, as in
+/// `Profiler::record_event_for_current_thread()`.
+pub struct MeasuremeLayer<S: SerializationSink, C: Clock = InstantClock> {
+    profiler: Arc<Profiler<S, C>>,
+    // Span and callsite names are `&'static str`s, so they can be cached
+    // for the lifetime of the layer.
+    event_kinds: Mutex<FxHashMap<&'static str, StringId>>,
+}
+
+// What `MeasuremeLayer` keeps in the extensions of each span
+struct SpanEventIds {
+    event_kind: StringId,
+    event_id: StringId,
+    fields: String,
+}
+
+impl<S: SerializationSink, C: Clock> MeasuremeLayer<S, C> {
+    pub fn new(profiler: Arc<Profiler<S, C>>) -> MeasuremeLayer<S, C> {
+        MeasuremeLayer {
+            profiler,
+            event_kinds: Mutex::new(FxHashMap::default()),
+        }
+    }
+
+    /// The `Profiler` the layer records into.
+    pub fn profiler(&self) -> &Arc<Profiler<S, C>> {
+        &self.profiler
+    }
+
+    fn event_kind(&self, name: &'static str) -> StringId {
+        let mut event_kinds = self.event_kinds.lock().unwrap();
+        *event_kinds
+            .entry(name)
+            .or_insert_with(|| self.profiler.alloc_string(name))
+    }
+}
+
+impl<S, C, T> Layer<T> for MeasuremeLayer<S, C>
+where
+    S: SerializationSink + 'static,
+    C: Clock + Send + Sync + 'static,
+    T: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, T>) {
+        let span = ctx.span(id).expect("span passed to on_new_span must exist");
+
+        let mut fields = String::new();
+        attrs.record(&mut FieldFormatter(&mut fields));
+
+        let ids = SpanEventIds {
+            event_kind: self.event_kind(attrs.metadata().name()),
+            event_id: self.profiler.alloc_string(&fields[..]),
+            fields,
+        };
+        span.extensions_mut().insert(ids);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, T>) {
+        let span = ctx.span(id).expect("span passed to on_record must exist");
+        let mut extensions = span.extensions_mut();
+
+        if let Some(ids) = extensions.get_mut::<SpanEventIds>() {
+            values.record(&mut FieldFormatter(&mut ids.fields));
+            ids.event_id = self.profiler.alloc_string(&ids.fields[..]);
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, T>) {
+        self.record_span_event(id, ctx, TimestampKind::Start);
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, T>) {
+        self.record_span_event(id, ctx, TimestampKind::End);
+    }
+
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, T>) {
+        let mut fields = String::new();
+        event.record(&mut FieldFormatter(&mut fields));
+
+        let event_kind = self.event_kind(event.metadata().name());
+        let event_id = self.profiler.alloc_string(&fields[..]);
+        self.profiler
+            .record_instant_event(event_kind, event_id, current_thread_id());
+    }
+}
+
+impl<S: SerializationSink, C: Clock> MeasuremeLayer<S, C> {
+    // A `TimingGuard` borrows the `Profiler`, so it can't be kept in the
+    // extensions of a span. Instead, the "end" event is recorded here, on
+    // exit, just like the guard would when dropped.
+    fn record_span_event<T>(&self, id: &Id, ctx: Context<'_, T>, timestamp_kind: TimestampKind)
+    where
+        T: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+
+        let extensions = span.extensions();
+        if let Some(ids) = extensions.get::<SpanEventIds>() {
+            self.profiler.record_event(
+                ids.event_kind,
+                ids.event_id,
+                current_thread_id(),
+                timestamp_kind,
+            );
+        }
+    }
+}
+
+// Appends fields as `name=value`, separated by `, `. The `message` field of
+// events is written without its name.
+struct FieldFormatter<'a>(&'a mut String);
+
+impl<'a> Visit for FieldFormatter<'a> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_debug(field, &format_args!("{:?}", value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if !self.0.is_empty() {
+            self.0.push_str(", ");
+        }
+
+        if field.name() == "message" {
+            write!(self.0, "{:?}", value).unwrap();
+        } else {
+            write!(self.0, "{}={:?}", field.name(), value).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InMemorySink, ProfilingData};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn spans_and_events() {
+        let event_sink = Arc::new(InMemorySink::new());
+        let string_data_sink = Arc::new(InMemorySink::new());
+        let string_index_sink = Arc::new(InMemorySink::new());
+
+        {
+            let profiler = Arc::new(Profiler::with_sinks(
+                event_sink.clone(),
+                string_data_sink.clone(),
+                string_index_sink.clone(),
+            ));
+            let subscriber =
+                tracing_subscriber::registry().with(MeasuremeLayer::new(profiler.clone()));
+
+            tracing::subscriber::with_default(subscriber, || {
+                let outer = tracing::info_span!("outer", path = "src/main.rs");
+                let _outer = outer.enter();
+
+                for line in 0..2 {
+                    let inner = tracing::info_span!("inner", line);
+                    let _inner = inner.enter();
+                    tracing::info!(answer = 42, "found it");
+                }
+            });
+
+            Arc::try_unwrap(profiler).ok().unwrap().finish().unwrap();
+        }
+
+        let profiling_data = ProfilingData::from_buffers(
+            Arc::try_unwrap(event_sink).unwrap().into_bytes(),
+            Arc::try_unwrap(string_data_sink).unwrap().into_bytes(),
+            Arc::try_unwrap(string_index_sink).unwrap().into_bytes(),
+        )
+        .unwrap();
+
+        let intervals: Vec<_> = profiling_data
+            .iter_intervals()
+            .map(|e| (e.kind.into_owned(), e.id.into_owned()))
+            .collect();
+        assert_eq!(
+            intervals,
+            [
+                ("inner".to_string(), "line=0".to_string()),
+                ("inner".to_string(), "line=1".to_string()),
+                ("outer".to_string(), "path=\"src/main.rs\"".to_string()),
+            ]
+        );
+
+        let instants: Vec<_> = profiling_data
+            .iter()
+            .filter(|e| e.timestamp_kind == TimestampKind::Instant)
+            .map(|e| (e.event_kind.into_owned(), e.label.into_owned()))
+            .collect();
+        assert_eq!(instants.len(), 2);
+        assert!(instants[0].0.starts_with("event "));
+        assert_eq!(instants[0].1, "found it, answer=42");
+    }
+}
@@ -0,0 +1,68 @@
+// A snapshot test of instrumented code, as `ProfilingData::to_debug_text()`
+// is meant to be used.
+
+use measureme::{current_thread_id, Clock, InMemorySink, Profiler, ProfilingData, TimestampKind};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+// Advances by 10ns on every reading, so that the timestamps only depend on
+// what was recorded
+struct StepClock(AtomicU64);
+
+impl Clock for StepClock {
+    fn now_nanos(&self) -> u64 {
+        self.0.fetch_add(10, Ordering::Relaxed)
+    }
+}
+
+// The code under test
+fn compile(profiler: &Profiler<InMemorySink, StepClock>) {
+    let query = profiler.alloc_string("Query");
+    let typeck = profiler.alloc_string("typeck");
+    let codegen = profiler.alloc_string("codegen");
+
+    profiler.set_thread_name(current_thread_id(), "main");
+    let _guard = profiler.start_recording_interval_event_for_current_thread(query, typeck);
+
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            profiler.set_thread_name(current_thread_id(), "codegen worker");
+            profiler.record_event_for_current_thread(query, codegen, TimestampKind::Instant);
+        });
+    });
+}
+
+#[test]
+fn snapshot() {
+    let event_sink = Arc::new(InMemorySink::new());
+    let string_data_sink = Arc::new(InMemorySink::new());
+    let string_index_sink = Arc::new(InMemorySink::new());
+
+    {
+        let profiler = Profiler::with_sinks_and_clock(
+            event_sink.clone(),
+            string_data_sink.clone(),
+            string_index_sink.clone(),
+            StepClock(AtomicU64::new(0)),
+        );
+        compile(&profiler);
+    }
+
+    let profiling_data = ProfilingData::from_buffers(
+        Arc::try_unwrap(event_sink).unwrap().into_bytes(),
+        Arc::try_unwrap(string_data_sink).unwrap().into_bytes(),
+        Arc::try_unwrap(string_index_sink).unwrap().into_bytes(),
+    )
+    .unwrap();
+
+    assert_eq!(
+        profiling_data.to_debug_text(true),
+        r#"thread "codegen worker"
+  20ns instant "Query" "codegen"
+thread "main"
+  0ns start "Query" "typeck"
+  30ns end "Query" "typeck"
+"#
+    );
+}
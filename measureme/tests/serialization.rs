@@ -1,4 +1,7 @@
-use measureme::testing_common::run_end_to_end_serialization_test;
+use measureme::testing_common::{
+    run_end_to_end_serialization_test, run_in_memory_serialization_test,
+    run_packed_serialization_test, run_sharded_serialization_test,
+};
 use measureme::{FileSerializationSink, MmapSerializationSink};
 
 #[test]
@@ -10,3 +13,255 @@ fn test_file_serialization_sink() {
 fn test_mmap_serialization_sink() {
     run_end_to_end_serialization_test::<MmapSerializationSink>("mmap_serialization_sink_test");
 }
+
+#[test]
+fn test_in_memory_sink() {
+    run_in_memory_serialization_test();
+}
+
+#[test]
+fn test_packed_profile() {
+    run_packed_serialization_test("packed_profile_test");
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn test_compressed_sink() {
+    run_end_to_end_serialization_test::<measureme::CompressedSink<FileSerializationSink>>(
+        "compressed_sink_test",
+    );
+}
+
+#[test]
+fn test_sharded_profile() {
+    run_sharded_serialization_test::<FileSerializationSink>("sharded_profile_test", 3);
+}
+
+#[test]
+fn test_background_sink() {
+    run_end_to_end_serialization_test::<measureme::BackgroundSink<FileSerializationSink>>(
+        "background_sink_test",
+    );
+}
+
+#[test]
+fn test_truncated_events_file() {
+    use measureme::{Profiler, ProfilerFiles, ProfilingData, TimestampKind};
+    use std::fs::OpenOptions;
+    use std::path::Path;
+
+    let filestem = Path::new("test-tmp/end_to_end_serialization/truncated_events_test");
+
+    let profiler = Profiler::with_buffer_size(filestem, 64).unwrap();
+    let event_kind = profiler.alloc_string("kind");
+    let event_id = profiler.alloc_string("id");
+    for _ in 0..10 {
+        profiler.record_event(event_kind, event_id, 0, TimestampKind::Instant);
+    }
+    profiler.flush().unwrap();
+
+    // Simulate a crash: the profiler is never dropped and the last event is
+    // only partially written.
+    std::mem::forget(profiler);
+
+    let events_file = ProfilerFiles::new(filestem).events_file;
+    let len = std::fs::metadata(&events_file).unwrap().len();
+    OpenOptions::new()
+        .write(true)
+        .open(&events_file)
+        .unwrap()
+        .set_len(len - 10)
+        .unwrap();
+
+    let profiling_data = ProfilingData::new(filestem).unwrap();
+    assert!(profiling_data.is_truncated());
+    assert!(!profiling_data.is_complete());
+    let events: Vec<_> = profiling_data.iter().collect();
+    assert_eq!(events.len(), 9);
+    assert!(events
+        .iter()
+        .all(|e| e.event_kind == "kind" && e.label == "id"));
+}
+
+#[test]
+fn test_truncated_string_table() {
+    use measureme::{Profiler, ProfilerFiles, ProfilingData, TimestampKind};
+    use std::fs::OpenOptions;
+    use std::path::Path;
+
+    let filestem = Path::new("test-tmp/end_to_end_serialization/truncated_strings_test");
+
+    {
+        let profiler = Profiler::<FileSerializationSink>::new(filestem).unwrap();
+        let event_kind = profiler.alloc_string("kind");
+        let complete = profiler.alloc_string("complete");
+        let incomplete = profiler.alloc_string("incomplete");
+        profiler.record_event(event_kind, complete, 0, TimestampKind::Instant);
+        profiler.record_event(event_kind, incomplete, 0, TimestampKind::Instant);
+        profiler.record_event(event_kind, complete, 0, TimestampKind::Instant);
+    }
+
+    // Cut off the last string, as if the process had been killed while
+    // writing it
+    let string_data_file = ProfilerFiles::new(filestem).string_data_file;
+    let len = std::fs::metadata(&string_data_file).unwrap().len();
+    OpenOptions::new()
+        .write(true)
+        .open(&string_data_file)
+        .unwrap()
+        .set_len(len - 3)
+        .unwrap();
+
+    let profiling_data = ProfilingData::new(filestem).unwrap();
+    assert!(profiling_data.is_truncated());
+    let labels: Vec<_> = profiling_data.iter().map(|e| e.label).collect();
+    assert_eq!(labels, ["complete", "complete"]);
+}
+
+#[test]
+fn test_complete_profile_is_not_truncated() {
+    use measureme::{Profiler, ProfilingData, TimestampKind};
+    use std::path::Path;
+
+    let filestem = Path::new("test-tmp/end_to_end_serialization/not_truncated_test");
+
+    {
+        let profiler = Profiler::<FileSerializationSink>::new(filestem).unwrap();
+        let event_kind = profiler.alloc_string("kind");
+        profiler.record_event(event_kind, event_kind, 0, TimestampKind::Instant);
+    }
+
+    let profiling_data = ProfilingData::new(filestem).unwrap();
+    assert!(!profiling_data.is_truncated());
+    assert!(profiling_data.is_complete());
+    assert_eq!(profiling_data.iter().count(), 1);
+}
+
+#[test]
+fn test_profile_without_clean_shutdown() {
+    use measureme::{Profiler, ProfilingData, TimestampKind};
+    use std::path::Path;
+
+    let filestem = Path::new("test-tmp/end_to_end_serialization/no_clean_shutdown_test");
+
+    let profiler = Profiler::<FileSerializationSink>::new(filestem).unwrap();
+    let event_kind = profiler.alloc_string("kind");
+    profiler.record_event(event_kind, event_kind, 0, TimestampKind::Instant);
+    profiler.flush().unwrap();
+
+    // Everything has been written, but the profiler never shuts down
+    std::mem::forget(profiler);
+
+    let profiling_data = ProfilingData::new(filestem).unwrap();
+    assert!(!profiling_data.is_truncated());
+    assert!(!profiling_data.is_complete());
+    assert_eq!(profiling_data.iter().count(), 1);
+}
+
+#[test]
+fn test_drop_writes_buffered_data() {
+    use measureme::{Profiler, ProfilingData, TimestampKind};
+    use std::path::Path;
+
+    let filestem = Path::new("test-tmp/end_to_end_serialization/drop_test");
+
+    {
+        // Large enough that nothing is written before the profiler is dropped
+        let profiler = Profiler::with_buffer_size(filestem, 1 << 20).unwrap();
+        let event_kind = profiler.alloc_string("kind");
+        for i in 0..1000 {
+            let event_id = profiler.alloc_string(&format!("id{}", i)[..]);
+            profiler.record_event(event_kind, event_id, 0, TimestampKind::Instant);
+        }
+    }
+
+    let profiling_data = ProfilingData::new(filestem).unwrap();
+    assert!(profiling_data.is_complete());
+    let labels: Vec<_> = profiling_data
+        .iter()
+        .map(|e| e.label.into_owned())
+        .collect();
+    let expected: Vec<_> = (0..1000).map(|i| format!("id{}", i)).collect();
+    assert_eq!(labels, expected);
+}
+
+#[test]
+fn test_finish() {
+    use measureme::{Profiler, ProfilingData, TimestampKind};
+    use std::path::Path;
+
+    let filestem = Path::new("test-tmp/end_to_end_serialization/finish_test");
+
+    let profiler = Profiler::with_buffer_size(filestem, 1 << 20).unwrap();
+    let event_kind = profiler.alloc_string("kind");
+    profiler.record_event(event_kind, event_kind, 0, TimestampKind::Instant);
+    profiler.finish().unwrap();
+
+    let profiling_data = ProfilingData::new(filestem).unwrap();
+    assert!(profiling_data.is_complete());
+    assert_eq!(profiling_data.iter().count(), 1);
+}
+
+#[test]
+fn test_write_bytes_is_atomic() {
+    use measureme::{InMemorySink, SerializationSink};
+    use std::sync::Arc;
+
+    const RECORD_LEN: usize = 100;
+
+    let sink = Arc::new(InMemorySink::new());
+
+    let threads: Vec<_> = (0..4u8)
+        .map(|thread| {
+            let sink = sink.clone();
+            std::thread::spawn(move || {
+                for _ in 0..1000 {
+                    let addr = sink.write_bytes(&[thread; RECORD_LEN]);
+                    assert_eq!(addr.as_usize() % RECORD_LEN, 0);
+                }
+            })
+        })
+        .collect();
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    // The bytes of each call end up next to each other
+    let data = Arc::try_unwrap(sink).unwrap().into_bytes();
+    assert_eq!(data.len(), 4 * 1000 * RECORD_LEN);
+    for record in data.chunks(RECORD_LEN) {
+        assert!(record.iter().all(|&b| b == record[0]));
+    }
+}
+
+#[test]
+fn test_not_a_measureme_file() {
+    use measureme::{NotAMeasuremeFile, Profiler, ProfilerFiles, ProfilingData};
+    use std::path::Path;
+
+    let filestem = Path::new("test-tmp/end_to_end_serialization/not_a_measureme_file_test");
+    Profiler::<FileSerializationSink>::new(filestem)
+        .unwrap()
+        .finish()
+        .unwrap();
+
+    // Replace one of the files with something else entirely
+    let string_data_file = ProfilerFiles::new(filestem).string_data_file;
+    std::fs::write(&string_data_file, "fn main() {}\n").unwrap();
+
+    for result in &[
+        ProfilingData::new(filestem),
+        ProfilingData::from_mmap(filestem),
+    ] {
+        let error = result.as_ref().err().unwrap();
+        let error = error.downcast_ref::<NotAMeasuremeFile>().unwrap();
+        assert_eq!(error.path, string_data_file);
+        assert_eq!(error.found, b"fn main(");
+    }
+
+    let error = ProfilingData::new(Path::new("test-tmp/does_not_exist"))
+        .err()
+        .unwrap();
+    assert!(error.to_string().contains("does_not_exist.events"));
+}
@@ -1,9 +1,10 @@
-use measureme::ProfilingData;
 use std::error::Error;
 use std::path::PathBuf;
 
 use structopt::StructOpt;
 
+use tools_lib::profile::load_profile;
+
 #[derive(StructOpt, Debug)]
 struct Opt {
     file_prefix: PathBuf,
@@ -16,7 +17,7 @@ struct Opt {
 fn main() -> Result<(), Box<dyn Error>> {
     let opt = Opt::from_args();
 
-    let data = ProfilingData::new(&opt.file_prefix)?;
+    let data = load_profile(&opt.file_prefix)?;
 
     for event in data.iter() {
         if let Some(thread_id) = opt.thread_id {
@@ -0,0 +1,275 @@
+//! Converts `measureme` profiles into files for the speedscope profile
+//! viewer, as the `speedscope` tool does, e.g. for tools that export several
+//! formats.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Write;
+use std::time::SystemTime;
+
+use measureme::{ProfilingData, TimestampKind};
+
+use serde::Serialize;
+
+#[derive(Serialize)]
+struct SpeedscopeFile {
+    #[serde(rename = "$schema")]
+    schema: &'static str,
+    shared: Shared,
+    profiles: Vec<Profile>,
+    name: String,
+    exporter: &'static str,
+}
+
+#[derive(Serialize)]
+struct Shared {
+    frames: Vec<Frame>,
+}
+
+#[derive(Serialize)]
+struct Frame {
+    name: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Profile {
+    #[serde(rename = "type")]
+    profile_type: &'static str,
+    name: String,
+    unit: &'static str,
+    start_value: u64,
+    end_value: u64,
+    events: Vec<FrameEvent>,
+}
+
+#[derive(Serialize, PartialEq, Debug)]
+struct FrameEvent {
+    #[serde(rename = "type")]
+    event_type: &'static str,
+    frame: usize,
+    at: u64,
+}
+
+/// The events recorded so far for a single thread, plus the stack of frames
+/// that are currently open on that thread.
+#[derive(Default)]
+struct ThreadState {
+    events: Vec<FrameEvent>,
+    stack: Vec<(usize, String)>,
+}
+
+const OPEN_FRAME: &str = "O";
+const CLOSE_FRAME: &str = "C";
+
+fn nanos(timestamp: SystemTime) -> u64 {
+    timestamp
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64
+}
+
+/// Converts the interval events of `data` into a speedscope file with one
+/// "evented" profile per thread. Instant and counter events are ignored since
+/// they have no representation in the evented format.
+fn convert(
+    data: &ProfilingData,
+    name: String,
+    warnings: &mut Vec<String>,
+) -> Result<SpeedscopeFile, String> {
+    let mut frames = Vec::new();
+    let mut frame_indices = HashMap::new();
+    let mut threads: HashMap<u64, ThreadState> = HashMap::new();
+    let mut last_timestamp = 0;
+
+    for event in data.iter() {
+        let at = nanos(event.timestamp);
+        last_timestamp = last_timestamp.max(at);
+
+        match event.timestamp_kind {
+            TimestampKind::Start => {
+                let frame = *frame_indices.entry(event.label.clone()).or_insert_with(|| {
+                    frames.push(Frame {
+                        name: event.label.clone().into_owned(),
+                    });
+                    frames.len() - 1
+                });
+
+                let ThreadState { events, stack } = threads.entry(event.thread_id).or_default();
+                events.push(FrameEvent {
+                    event_type: OPEN_FRAME,
+                    frame,
+                    at,
+                });
+                stack.push((frame, event.label.into_owned()));
+            }
+            TimestampKind::End => {
+                let ThreadState { events, stack } = threads.entry(event.thread_id).or_default();
+
+                match stack.pop() {
+                    Some((frame, ref label)) if *label == event.label => {
+                        events.push(FrameEvent {
+                            event_type: CLOSE_FRAME,
+                            frame,
+                            at,
+                        });
+                    }
+                    Some((_, label)) => {
+                        return Err(format!(
+                            "mismatched events on thread {}: found end of `{}` while `{}` was \
+                             still running",
+                            event.thread_id, event.label, label
+                        ));
+                    }
+                    None => {
+                        return Err(format!(
+                            "mismatched events on thread {}: found end of `{}` without a \
+                             matching start event",
+                            event.thread_id, event.label
+                        ));
+                    }
+                }
+            }
+            TimestampKind::Instant | TimestampKind::Counter => {}
+        }
+    }
+
+    let mut thread_ids: Vec<_> = threads.keys().cloned().collect();
+    thread_ids.sort();
+
+    let profiles = thread_ids
+        .into_iter()
+        .map(|thread_id| {
+            let ThreadState {
+                mut events,
+                mut stack,
+            } = threads.remove(&thread_id).unwrap();
+
+            // Events that were never ended (e.g. because the profiled process
+            // crashed) are closed at the end of the profile.
+            if !stack.is_empty() {
+                warnings.push(format!(
+                    "{} start event(s) without a matching end event on thread {}",
+                    stack.len(),
+                    thread_id
+                ));
+            }
+
+            while let Some((frame, _)) = stack.pop() {
+                events.push(FrameEvent {
+                    event_type: CLOSE_FRAME,
+                    frame,
+                    at: last_timestamp,
+                });
+            }
+
+            Profile {
+                profile_type: "evented",
+                name: match data.thread_name(thread_id) {
+                    Some(thread_name) => thread_name.to_string(),
+                    None => format!("thread {}", thread_id),
+                },
+                unit: "nanoseconds",
+                start_value: events.first().map(|e| e.at).unwrap_or(0),
+                end_value: events.last().map(|e| e.at).unwrap_or(0),
+                events,
+            }
+        })
+        .collect();
+
+    Ok(SpeedscopeFile {
+        schema: "https://www.speedscope.app/file-format-schema.json",
+        shared: Shared { frames },
+        profiles,
+        name,
+        exporter: "measureme speedscope",
+    })
+}
+
+/// Writes the interval events of `data` to `out` as a speedscope file named
+/// `name`, with one "evented" profile per thread. Fails if start and end
+/// events are mismatched. Returns warnings about intervals that were never
+/// ended, which are closed at the end of the profile.
+pub fn write_speedscope<W: Write>(
+    data: &ProfilingData,
+    name: String,
+    out: W,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut warnings = Vec::new();
+    let speedscope_file = convert(data, name, &mut warnings)?;
+    serde_json::to_writer(out, &speedscope_file)?;
+    Ok(warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use measureme::ProfilingDataBuilder;
+
+    #[test]
+    fn nested_events() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("Query", "e1", 0, 100, 200, |b| {
+            b.interval("Query", "e2", 0, 120, 180, |_| {});
+        });
+        b.interval("Query", "e2", 1, 110, 150, |b| {
+            b.instant("QueryCacheHit", "e3", 1, 120);
+        })
+        .thread_name(1, "worker");
+
+        let mut warnings = Vec::new();
+        let file = convert(&b.into_profiling_data(), "test".into(), &mut warnings).unwrap();
+        assert!(warnings.is_empty());
+
+        let frame_names: Vec<_> = file.shared.frames.iter().map(|f| &f.name[..]).collect();
+        assert_eq!(frame_names, ["e1", "e2"]);
+
+        assert_eq!(file.profiles.len(), 2);
+        assert_eq!(file.profiles[0].name, "thread 0");
+        assert_eq!(file.profiles[0].start_value, 100);
+        assert_eq!(file.profiles[0].end_value, 200);
+        assert_eq!(
+            file.profiles[0].events,
+            [
+                FrameEvent {
+                    event_type: OPEN_FRAME,
+                    frame: 0,
+                    at: 100
+                },
+                FrameEvent {
+                    event_type: OPEN_FRAME,
+                    frame: 1,
+                    at: 120
+                },
+                FrameEvent {
+                    event_type: CLOSE_FRAME,
+                    frame: 1,
+                    at: 180
+                },
+                FrameEvent {
+                    event_type: CLOSE_FRAME,
+                    frame: 0,
+                    at: 200
+                },
+            ]
+        );
+
+        assert_eq!(file.profiles[1].name, "worker");
+        assert_eq!(
+            file.profiles[1].events,
+            [
+                FrameEvent {
+                    event_type: OPEN_FRAME,
+                    frame: 1,
+                    at: 110
+                },
+                FrameEvent {
+                    event_type: CLOSE_FRAME,
+                    frame: 1,
+                    at: 150
+                },
+            ]
+        );
+    }
+}
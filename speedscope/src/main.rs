@@ -0,0 +1,41 @@
+use std::error::Error;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use structopt::StructOpt;
+
+use speedscope::write_speedscope;
+use tools_lib::profile::load_profile;
+
+#[derive(StructOpt, Debug)]
+struct Opt {
+    file_prefix: PathBuf,
+
+    /// The output file, defaults to `<file_prefix>.speedscope.json`
+    #[structopt(short = "o", long = "output")]
+    output: Option<PathBuf>,
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let opt = Opt::from_args();
+
+    let profiling_data = load_profile(&opt.file_prefix)?;
+
+    let output = opt.output.clone().unwrap_or_else(|| {
+        let mut path = OsString::from(&opt.file_prefix);
+        path.push(".speedscope.json");
+        PathBuf::from(path)
+    });
+
+    let name = opt.file_prefix.to_string_lossy().into_owned();
+    let mut file = BufWriter::new(File::create(&output)?);
+    for warning in write_speedscope(&profiling_data, name, &mut file)? {
+        eprintln!("warning: {}", warning);
+    }
+
+    file.flush()?;
+
+    Ok(())
+}
@@ -2,12 +2,14 @@ use std::error::Error;
 use std::fs::File;
 use std::io::{BufWriter, Write};
 use std::path::PathBuf;
-
-use measureme::ProfilingData;
+use std::time::Duration;
 
 use structopt::StructOpt;
 
-use tools_lib::stack_collapse::collapse_stacks;
+use tools_lib::profile::load_profile;
+use tools_lib::stack_collapse::{
+    collapse_stacks, collapse_stacks_self_time, collapse_stacks_spilling,
+};
 
 #[derive(StructOpt, Debug)]
 struct Opt {
@@ -16,21 +18,77 @@ struct Opt {
     /// The sampling interval in milliseconds
     #[structopt(short = "i", long = "interval", default_value = "1")]
     interval: u64,
+
+    /// The sampling interval in microseconds, overrides `--interval`
+    #[structopt(long = "interval-us")]
+    interval_us: Option<u64>,
+
+    /// Attribute each sample only to the innermost frame of its stack, writing one line per frame
+    #[structopt(long = "self-time")]
+    self_time: bool,
+
+    /// Write the stacks to temporary files once they take up more than this many MiB of memory
+    #[structopt(long = "memory-budget", raw(conflicts_with = r#""self_time""#))]
+    memory_budget: Option<usize>,
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     let opt = Opt::from_args();
 
-    let profiling_data = ProfilingData::new(&opt.file_prefix)?;
+    let interval = match opt.interval_us {
+        Some(interval_us) => Duration::from_micros(interval_us),
+        None => Duration::from_millis(opt.interval),
+    };
+    if interval == Duration::from_secs(0) {
+        return Err("the sampling interval must not be zero".into());
+    }
 
-    let recorded_stacks = collapse_stacks(profiling_data.iter(), opt.interval);
+    let profiling_data = load_profile(&opt.file_prefix)?;
 
     let mut file = BufWriter::new(File::create("out.stacks_folded")?);
 
+    if let Some(memory_budget) = opt.memory_budget {
+        let spilled = collapse_stacks_spilling(
+            profiling_data.iter(),
+            interval,
+            |_| "rustc".to_string(),
+            memory_budget << 20,
+        )?;
+
+        for (thread_id, unmatched) in &spilled.unmatched {
+            eprintln!(
+                "warning: thread {} has {}, its stacks may be inaccurate",
+                thread_id, unmatched
+            );
+        }
+
+        for stack in spilled.into_sorted_stacks()? {
+            let (unique_stack, count) = stack?;
+            writeln!(file, "{} {}", unique_stack, count)?;
+        }
+        file.flush()?;
+
+        return Ok(());
+    }
+
+    let collapsed = if opt.self_time {
+        collapse_stacks_self_time(profiling_data.iter(), interval)
+    } else {
+        collapse_stacks(profiling_data.iter(), interval)
+    };
+
+    for (thread_id, unmatched) in &collapsed.unmatched {
+        eprintln!(
+            "warning: thread {} has {}, its stacks may be inaccurate",
+            thread_id, unmatched
+        );
+    }
+
     //now that we've got all of the recorded data, print the results to the output file
-    for (unique_stack, count) in recorded_stacks {
+    for (unique_stack, count) in collapsed.sorted_stacks() {
         writeln!(file, "{} {}", unique_stack, count)?;
     }
+    file.flush()?;
 
     Ok(())
 }
@@ -1,14 +1,32 @@
 use crate::query_data::{QueryData, Results};
 use measureme::rustc::*;
-use measureme::{Event, ProfilingData, TimestampKind};
+use measureme::{Event, MergedProfilingData, TimestampKind};
 use std::borrow::Cow;
 use std::collections::HashMap;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
+
+/// The time from `start` to `end`, or zero if `end` is earlier, e.g. because the
+/// clock of the profiled process went backwards. Such durations are counted in
+/// `clamped_durations`.
+pub fn clamped_duration(
+    end: SystemTime,
+    start: SystemTime,
+    clamped_durations: &mut usize,
+) -> Duration {
+    end.duration_since(start).unwrap_or_else(|_| {
+        *clamped_durations += 1;
+        Duration::from_nanos(0)
+    })
+}
+
+pub fn perform_analysis(data: impl Into<MergedProfilingData>) -> Results {
+    let data = data.into();
 
-pub fn perform_analysis(data: ProfilingData) -> Results {
     let mut query_data = HashMap::<String, QueryData>::new();
     let mut threads = HashMap::<_, Vec<Event>>::new();
     let mut total_time = Duration::from_nanos(0);
+    let mut clamped_durations = 0;
+    let mut unmatched_end_events = 0;
 
     let mut record_event_data = |label: &Cow<'_, str>, f: &dyn Fn(&mut QueryData)| {
         if let Some(data) = query_data.get_mut(&label[..]) {
@@ -86,10 +104,11 @@ pub fn perform_analysis(data: ProfilingData) -> Results {
                 {
                     if let Some(prev_event) = thread_stack.last() {
                         //count the time run so far for this event
-                        let duration = event
-                            .timestamp
-                            .duration_since(prev_event.timestamp)
-                            .unwrap_or(Duration::from_nanos(0));
+                        let duration = clamped_duration(
+                            event.timestamp,
+                            prev_event.timestamp,
+                            &mut clamped_durations,
+                        );
 
                         record_event_data(&prev_event.label, &|data| {
                             data.self_time += duration;
@@ -114,19 +133,48 @@ pub fn perform_analysis(data: ProfilingData) -> Results {
                     });
                 }
             }
+            TimestampKind::Counter => {}
             TimestampKind::End => {
-                let thread_stack = threads.get_mut(&event.thread_id).unwrap();
-                let start_event = thread_stack.pop().unwrap();
+                let thread_stack = threads.entry(event.thread_id).or_default();
+
+                //the start events above the matching one were never ended, e.g. because their
+                //`TimingGuard` was cancelled, so they are discarded
+                let start_idx = match thread_stack.iter().rposition(|start| {
+                    start.label == event.label && start.event_kind == event.event_kind
+                }) {
+                    Some(start_idx) => start_idx,
+                    //the start event is missing, e.g. because it was overwritten in the ring
+                    //buffer a snapshot was taken from, so there is nothing to measure
+                    None => {
+                        unmatched_end_events += 1;
+                        continue;
+                    }
+                };
+
+                //the time since the innermost of them last "started" hasn't been recorded yet, so
+                //it counts as self-time of this event
+                if &event.event_kind[..] == QUERY_EVENT_KIND
+                    || &event.event_kind[..] == GENERIC_ACTIVITY_EVENT_KIND
+                {
+                    let unmatched_start = thread_stack[start_idx + 1..].iter().rev().find(|e| {
+                        &e.event_kind[..] == QUERY_EVENT_KIND
+                            || &e.event_kind[..] == GENERIC_ACTIVITY_EVENT_KIND
+                    });
+                    if let Some(unmatched_start) = unmatched_start {
+                        thread_stack[start_idx].timestamp = unmatched_start.timestamp;
+                    }
+                }
 
-                assert_eq!(start_event.label, event.label);
-                assert_eq!(start_event.event_kind, event.event_kind);
+                thread_stack.truncate(start_idx + 1);
+                let start_event = thread_stack.pop().unwrap();
                 assert_eq!(start_event.timestamp_kind, TimestampKind::Start);
 
                 //track the time for this event
-                let duration = event
-                    .timestamp
-                    .duration_since(start_event.timestamp)
-                    .unwrap_or(Duration::from_nanos(0));
+                let duration = clamped_duration(
+                    event.timestamp,
+                    start_event.timestamp,
+                    &mut clamped_durations,
+                );
 
                 if &event.event_kind[..] == QUERY_EVENT_KIND
                     || &event.event_kind[..] == GENERIC_ACTIVITY_EVENT_KIND
@@ -162,13 +210,18 @@ pub fn perform_analysis(data: ProfilingData) -> Results {
     Results {
         query_data: query_data.drain().map(|(_, value)| value).collect(),
         total_time,
+        clamped_durations,
+        unmatched_end_events,
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use measureme::ProfilingDataBuilder;
+    use measureme::{
+        FileSerializationSink, Profiler, ProfilingData, ProfilingDataBuilder, RAW_EVENT_SIZE,
+    };
+    use std::fs;
 
     #[test]
     fn total_time_and_nesting() {
@@ -415,4 +468,115 @@ mod tests {
         assert_eq!(results.query_data_by_label("e1").self_time, Duration::from_nanos(100));
         assert_eq!(results.query_data_by_label("e1").invocation_count, 3);
     }
+
+    #[test]
+    fn clamped_negative_durations() {
+        let mut b = ProfilingDataBuilder::new();
+
+        // `e2` ends before it starts, as if the clock went backwards
+        b.interval(QUERY_EVENT_KIND, "e1", 0, 100, 200, |b| {
+            b.interval(QUERY_EVENT_KIND, "e2", 0, 150, 120, |_| {});
+        });
+
+        let results = perform_analysis(b.into_profiling_data());
+
+        assert_eq!(results.clamped_durations, 1);
+        assert_eq!(results.query_data_by_label("e2").self_time, Duration::from_nanos(0));
+        // 50ns before `e2` and 80ns after its end
+        assert_eq!(results.query_data_by_label("e1").self_time, Duration::from_nanos(130));
+        assert_eq!(results.total_time, Duration::from_nanos(130));
+    }
+
+    #[test]
+    fn cancelled_child_event() {
+        let mut b = ProfilingDataBuilder::new();
+
+        // `e2` never ends, as if its `TimingGuard` was cancelled
+        b.interval(QUERY_EVENT_KIND, "e1", 0, 100, 200, |b| {
+            b.start(QUERY_EVENT_KIND, "e2", 0, 120);
+        });
+        b.interval(QUERY_EVENT_KIND, "e3", 0, 200, 250, |_| {});
+
+        let results = perform_analysis(b.into_profiling_data());
+
+        assert_eq!(results.total_time, Duration::from_nanos(150));
+        assert_eq!(results.query_data_by_label("e1").self_time, Duration::from_nanos(100));
+        assert_eq!(results.query_data_by_label("e1").invocation_count, 1);
+        assert_eq!(results.query_data_by_label("e3").self_time, Duration::from_nanos(50));
+        assert!(results.query_data.iter().all(|data| data.label != "e2"));
+    }
+
+    #[test]
+    fn wrapped_ring_buffer_snapshot() {
+        let dir = std::env::temp_dir().join(format!("summarize-ring-{}", std::process::id()));
+        let path_stem = dir.join("profile");
+
+        let profiler = Profiler::new_ring_buffer(16 * 1024);
+        let event_kind = profiler.alloc_string(QUERY_EVENT_KIND);
+        let cache_hit_kind = profiler.alloc_string(QUERY_CACHE_HIT_EVENT_KIND);
+        let outer_id = profiler.alloc_string("outer");
+        let inner_id = profiler.alloc_string("inner");
+
+        // The start event of `outer` is overwritten before the snapshot is taken
+        let outer = profiler.start_recording_interval_event(event_kind, outer_id, 1);
+        for _ in 0..2000 {
+            profiler.record_instant_event(cache_hit_kind, inner_id, 1);
+        }
+        profiler
+            .start_recording_interval_event(event_kind, inner_id, 1)
+            .finish();
+        outer.finish();
+        profiler.dump_snapshot(&path_stem).unwrap();
+
+        let results = perform_analysis(ProfilingData::new(&path_stem).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(results.unmatched_end_events, 1);
+        assert!(results.query_data.iter().all(|data| data.label != "outer"));
+        assert_eq!(results.query_data_by_label("inner").number_of_cache_misses, 1);
+    }
+
+    #[test]
+    fn truncated_profile() {
+        let dir = std::env::temp_dir().join(format!("summarize-truncated-{}", std::process::id()));
+        let path_stem = dir.join("profile");
+
+        {
+            let profiler = Profiler::<FileSerializationSink>::new(&path_stem).unwrap();
+            let event_kind = profiler.alloc_string(QUERY_EVENT_KIND);
+            let e1 = profiler.alloc_string("e1");
+            let e2 = profiler.alloc_string("e2");
+            let e3 = profiler.alloc_string("e3");
+
+            profiler
+                .start_recording_interval_event(event_kind, e1, 0)
+                .finish();
+            let _e2 = profiler.start_recording_interval_event(event_kind, e2, 0);
+            profiler
+                .start_recording_interval_event(event_kind, e3, 0)
+                .finish();
+        }
+
+        // Cut off the end of `e2`, the clean shutdown event and part of the end of `e3`, as if
+        // the process was killed while writing the events
+        let events_file = fs::OpenOptions::new()
+            .write(true)
+            .open(path_stem.with_extension("events"))
+            .unwrap();
+        let len = events_file.metadata().unwrap().len();
+        events_file
+            .set_len(len - 2 * RAW_EVENT_SIZE as u64 - 10)
+            .unwrap();
+
+        let data = ProfilingData::new(&path_stem).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(data.is_truncated());
+
+        let results = perform_analysis(data);
+
+        assert_eq!(results.unmatched_end_events, 0);
+        assert_eq!(results.query_data_by_label("e1").invocation_count, 1);
+        assert_eq!(results.query_data_by_label("e2").invocation_count, 0);
+        assert!(results.query_data.iter().all(|data| data.label != "e3"));
+    }
 }
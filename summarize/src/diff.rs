@@ -1,3 +1,4 @@
+use crate::kind_analysis::{EventKindData, EventKindResults};
 use crate::query_data::{QueryData, QueryDataDiff, Results};
 use crate::signed_duration::SignedDuration;
 use serde::{Deserialize, Serialize};
@@ -12,8 +13,8 @@ pub struct DiffResults {
 
 fn build_query_lookup(query_data: &[QueryData]) -> HashMap<&str, usize> {
     let mut lookup = HashMap::with_capacity(query_data.len());
-    for i in 0..query_data.len() {
-        lookup.insert(&query_data[i].label[..], i);
+    for (i, data) in query_data.iter().enumerate() {
+        lookup.insert(&data.label[..], i);
     }
 
     lookup
@@ -48,10 +49,159 @@ pub fn calculate_diff(base: Results, change: Results) -> DiffResults {
         })
         .collect();
 
-    query_data.sort_by(|l, r| r.self_time.duration.cmp(&l.self_time.duration));
+    query_data.sort_by_key(|qd| std::cmp::Reverse(qd.self_time.duration));
 
     DiffResults {
         query_data,
         total_time: sd(change.total_time) - sd(base.total_time),
     }
 }
+
+#[derive(Serialize, Deserialize)]
+pub struct EventKindDiff {
+    pub event_kind: String,
+    pub base_total_time: Duration,
+    pub change_total_time: Duration,
+    pub total_time: SignedDuration,
+    pub self_time: SignedDuration,
+    pub invocation_count: i64,
+}
+
+impl EventKindDiff {
+    fn new(base: &EventKindData, change: &EventKindData) -> EventKindDiff {
+        #[inline(always)]
+        fn sd(d: Duration) -> SignedDuration {
+            d.into()
+        }
+
+        EventKindDiff {
+            event_kind: change.event_kind.clone(),
+            base_total_time: base.total_time,
+            change_total_time: change.total_time,
+            total_time: sd(change.total_time) - sd(base.total_time),
+            self_time: sd(change.self_time) - sd(base.self_time),
+            invocation_count: change.invocation_count as i64 - base.invocation_count as i64,
+        }
+    }
+
+    /// The change of the total time relative to the base profile. Event kinds
+    /// that only occur in the changed profile have an infinite increase.
+    pub fn percent_change(&self) -> f64 {
+        let base = self.base_total_time.as_nanos() as f64;
+        let delta = self.total_time.as_nanos() as f64;
+
+        if base == 0.0 {
+            if delta == 0.0 {
+                0.0
+            } else {
+                f64::INFINITY
+            }
+        } else {
+            delta / base * 100.0
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EventKindDiffResults {
+    /// Sorted by descending change in total time, so the largest regressions
+    /// come first.
+    pub event_kinds: Vec<EventKindDiff>,
+    pub total_time: SignedDuration,
+}
+
+pub fn calculate_kind_diff(
+    base: EventKindResults,
+    change: EventKindResults,
+) -> EventKindDiffResults {
+    let mut base_data: HashMap<_, _> = base
+        .event_kinds
+        .into_iter()
+        .map(|d| (d.event_kind.clone(), d))
+        .collect();
+
+    let mut event_kinds: Vec<_> = change
+        .event_kinds
+        .into_iter()
+        .map(|c| {
+            let b = base_data
+                .remove(&c.event_kind)
+                .unwrap_or_else(|| EventKindData::new(c.event_kind.clone()));
+            EventKindDiff::new(&b, &c)
+        })
+        .collect();
+
+    // Whatever is left only occurs in the base profile
+    event_kinds.extend(
+        base_data
+            .into_iter()
+            .map(|(event_kind, b)| EventKindDiff::new(&b, &EventKindData::new(event_kind))),
+    );
+
+    event_kinds.sort_by(|l, r| {
+        r.total_time
+            .cmp(&l.total_time)
+            .then_with(|| l.event_kind.cmp(&r.event_kind))
+    });
+
+    EventKindDiffResults {
+        event_kinds,
+        total_time: SignedDuration::from(change.total_time) - SignedDuration::from(base.total_time),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn results(event_kinds: &[(&str, u64)]) -> EventKindResults {
+        let event_kinds: Vec<_> = event_kinds
+            .iter()
+            .map(|&(event_kind, nanos)| EventKindData {
+                event_kind: event_kind.to_string(),
+                total_time: Duration::from_nanos(nanos),
+                self_time: Duration::from_nanos(nanos),
+                invocation_count: 1,
+            })
+            .collect();
+
+        let total_time = event_kinds.iter().map(|d| d.self_time).sum();
+
+        EventKindResults {
+            event_kinds,
+            total_time,
+            clamped_durations: 0,
+        }
+    }
+
+    #[test]
+    fn kind_diff() {
+        let base = results(&[("a", 100), ("b", 100), ("only_base", 50)]);
+        let change = results(&[("a", 150), ("b", 90), ("only_change", 20)]);
+
+        let diff = calculate_kind_diff(base, change);
+
+        let order: Vec<_> = diff.event_kinds.iter().map(|d| &d.event_kind[..]).collect();
+        assert_eq!(order, ["a", "only_change", "b", "only_base"]);
+
+        assert_eq!(
+            diff.event_kinds[0].total_time,
+            SignedDuration::from_nanos(50)
+        );
+        assert_eq!(diff.event_kinds[0].percent_change(), 50.0);
+
+        assert_eq!(diff.event_kinds[1].base_total_time, Duration::from_nanos(0));
+        assert_eq!(diff.event_kinds[1].percent_change(), f64::INFINITY);
+
+        assert_eq!(diff.event_kinds[2].percent_change(), -10.0);
+
+        assert_eq!(
+            diff.event_kinds[3].change_total_time,
+            Duration::from_nanos(0)
+        );
+        assert_eq!(diff.event_kinds[3].invocation_count, -1);
+        assert_eq!(diff.event_kinds[3].percent_change(), -100.0);
+
+        assert_eq!(diff.total_time, SignedDuration::from_nanos(10));
+    }
+}
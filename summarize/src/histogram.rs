@@ -0,0 +1,256 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use std::time::Duration;
+
+/// The number of buckets each power of two is divided into. The estimated
+/// quantiles are off by less than `1 / SUB_BUCKETS` of the true value.
+const SUB_BUCKETS: u64 = 16;
+
+/// The width of the bars printed by `Durations::ascii_histogram()`.
+const BAR_WIDTH: u64 = 50;
+
+/// The durations of a set of events, from which `min`, `max` and quantiles
+/// are computed. Unless created via `Durations::exact()`, the individual
+/// durations are not kept. Instead they are counted in logarithmically
+/// sized buckets, so that the memory use does not grow with the number of
+/// events, and quantiles are estimated from these buckets.
+pub struct Durations {
+    buckets: Vec<u64>,
+    count: u64,
+    min: u64,
+    max: u64,
+    exact: Option<Vec<u64>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct DurationStats {
+    pub count: u64,
+    pub min: Duration,
+    pub median: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+// Durations below `SUB_BUCKETS` nanoseconds get a bucket each. Above that,
+// each power of two is split into `SUB_BUCKETS` buckets of equal width.
+fn bucket_index(nanos: u64) -> usize {
+    if nanos < SUB_BUCKETS {
+        return nanos as usize;
+    }
+
+    let log2 = 63 - u64::from(nanos.leading_zeros());
+    let sub_bucket_bits = SUB_BUCKETS.trailing_zeros() as u64;
+    let sub_bucket = (nanos >> (log2 - sub_bucket_bits)) - SUB_BUCKETS;
+
+    ((log2 - sub_bucket_bits + 1) * SUB_BUCKETS + sub_bucket) as usize
+}
+
+// The smallest duration that falls into the bucket at `index`
+fn bucket_start(index: usize) -> u64 {
+    let index = index as u64;
+    if index < SUB_BUCKETS {
+        return index;
+    }
+
+    (SUB_BUCKETS + index % SUB_BUCKETS) << (index / SUB_BUCKETS - 1)
+}
+
+impl Default for Durations {
+    fn default() -> Durations {
+        Durations::new()
+    }
+}
+
+impl Durations {
+    pub fn new() -> Durations {
+        Durations {
+            buckets: Vec::new(),
+            count: 0,
+            min: u64::MAX,
+            max: 0,
+            exact: None,
+        }
+    }
+
+    /// Like `new()`, but keeps all durations to compute exact quantiles.
+    pub fn exact() -> Durations {
+        Durations {
+            exact: Some(Vec::new()),
+            ..Durations::new()
+        }
+    }
+
+    pub fn record(&mut self, duration: Duration) {
+        let nanos = duration.as_nanos() as u64;
+
+        let index = bucket_index(nanos);
+        if index >= self.buckets.len() {
+            self.buckets.resize(index + 1, 0);
+        }
+        self.buckets[index] += 1;
+
+        self.count += 1;
+        self.min = self.min.min(nanos);
+        self.max = self.max.max(nanos);
+
+        if let Some(exact) = &mut self.exact {
+            exact.push(nanos);
+        }
+    }
+
+    /// Returns `None` if no durations were recorded.
+    pub fn stats(&mut self) -> Option<DurationStats> {
+        if self.count == 0 {
+            return None;
+        }
+
+        if let Some(exact) = &mut self.exact {
+            exact.sort_unstable();
+        }
+
+        Some(DurationStats {
+            count: self.count,
+            min: Duration::from_nanos(self.min),
+            median: Duration::from_nanos(self.quantile(0.5)),
+            p90: Duration::from_nanos(self.quantile(0.9)),
+            p99: Duration::from_nanos(self.quantile(0.99)),
+            max: Duration::from_nanos(self.max),
+        })
+    }
+
+    // The nearest-rank quantile. Expects `exact` to be sorted.
+    fn quantile(&self, q: f64) -> u64 {
+        let rank = ((q * self.count as f64).ceil() as u64).max(1);
+
+        if let Some(exact) = &self.exact {
+            return exact[rank as usize - 1];
+        }
+
+        let mut seen = 0;
+        for (index, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                // The middle of the bucket, but never outside of the
+                // recorded range
+                let start = bucket_start(index);
+                let end = bucket_start(index + 1);
+                return (start + (end - start) / 2).max(self.min).min(self.max);
+            }
+        }
+
+        self.max
+    }
+
+    /// Renders the number of durations per power of two as rows of `#`s.
+    pub fn ascii_histogram(&self) -> String {
+        let mut rows: Vec<(u64, u64)> = Vec::new();
+        for (index, &count) in self.buckets.iter().enumerate() {
+            let start = bucket_start(index).max(1);
+            let row_start = 1 << (63 - start.leading_zeros());
+            match rows.last_mut() {
+                Some((last_start, last_count)) if *last_start == row_start => *last_count += count,
+                _ => rows.push((row_start, count)),
+            }
+        }
+
+        // Leave out the empty rows below the shortest duration
+        while rows.first().is_some_and(|&(_, count)| count == 0) {
+            rows.remove(0);
+        }
+
+        let max_count = rows.iter().map(|&(_, count)| count).max().unwrap_or(0);
+
+        let mut output = String::new();
+        for (start, count) in rows {
+            let bar = (count * BAR_WIDTH + max_count - 1) / max_count.max(1);
+            let range = format!(
+                "{:.2?} .. {:.2?}",
+                Duration::from_nanos(start),
+                Duration::from_nanos(start * 2)
+            );
+            writeln!(
+                output,
+                "{:>24} | {:<width$} {}",
+                range,
+                "#".repeat(bar as usize),
+                count,
+                width = BAR_WIDTH as usize
+            )
+            .unwrap();
+        }
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets() {
+        let mut previous_index = 0;
+
+        for nanos in 0..100_000 {
+            let index = bucket_index(nanos);
+            assert!(index == previous_index || index == previous_index + 1);
+            assert!(bucket_start(index) <= nanos);
+            assert!(nanos < bucket_start(index + 1));
+            previous_index = index;
+        }
+    }
+
+    #[test]
+    fn exact_stats() {
+        let mut durations = Durations::exact();
+        for nanos in 1..=100 {
+            durations.record(Duration::from_nanos(nanos));
+        }
+
+        assert_eq!(
+            durations.stats(),
+            Some(DurationStats {
+                count: 100,
+                min: Duration::from_nanos(1),
+                median: Duration::from_nanos(50),
+                p90: Duration::from_nanos(90),
+                p99: Duration::from_nanos(99),
+                max: Duration::from_nanos(100),
+            })
+        );
+    }
+
+    #[test]
+    fn estimated_stats() {
+        let mut durations = Durations::new();
+        assert_eq!(durations.stats(), None);
+
+        // A few pathological outliers among many fast events
+        for _ in 0..990 {
+            durations.record(Duration::from_micros(100));
+        }
+        for _ in 0..10 {
+            durations.record(Duration::from_millis(500));
+        }
+
+        let stats = durations.stats().unwrap();
+        assert_eq!(stats.count, 1000);
+        assert_eq!(stats.min, Duration::from_micros(100));
+        assert_eq!(stats.max, Duration::from_millis(500));
+
+        let within = |estimate: Duration, expected: Duration| {
+            let error = (estimate.as_nanos() as f64 - expected.as_nanos() as f64).abs();
+            error <= expected.as_nanos() as f64 / SUB_BUCKETS as f64
+        };
+        assert!(within(stats.median, Duration::from_micros(100)));
+        assert!(within(stats.p90, Duration::from_micros(100)));
+        assert!(within(stats.p99, Duration::from_micros(100)));
+
+        let histogram = durations.ascii_histogram();
+        let rows: Vec<_> = histogram.lines().filter(|row| row.contains('#')).collect();
+        assert_eq!(rows.len(), 2);
+        assert!(rows[0].ends_with(" 990"));
+        assert!(rows[1].ends_with(" 10"));
+    }
+}
@@ -0,0 +1,221 @@
+use crate::analysis::clamped_duration;
+use measureme::{MergedProfilingData, TimestampKind};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct EventKindData {
+    pub event_kind: String,
+    /// The time spent in events of this kind, including the time spent in
+    /// nested events. Time spent in recursive invocations of the same kind is
+    /// only counted once.
+    pub total_time: Duration,
+    /// The time spent in events of this kind, excluding the time spent in
+    /// nested events.
+    pub self_time: Duration,
+    pub invocation_count: usize,
+}
+
+impl EventKindData {
+    pub fn new(event_kind: String) -> EventKindData {
+        EventKindData {
+            event_kind,
+            total_time: Duration::from_nanos(0),
+            self_time: Duration::from_nanos(0),
+            invocation_count: 0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EventKindResults {
+    /// Sorted by descending self time, ties are broken by the name of the
+    /// event kind so that the order is deterministic.
+    pub event_kinds: Vec<EventKindData>,
+    /// The sum of the self time of all events.
+    pub total_time: Duration,
+    /// The number of durations that were negative and counted as zero
+    #[serde(default)]
+    pub clamped_durations: usize,
+}
+
+struct StackEntry {
+    event_kind: String,
+    start: SystemTime,
+    child_time: Duration,
+}
+
+/// Computes total time, self time, and invocation count per event kind. In
+/// contrast to `analysis::perform_analysis()` this considers any interval
+/// event, not only the ones known to `rustc` integration.
+pub fn perform_analysis(data: impl Into<MergedProfilingData>) -> EventKindResults {
+    let data = data.into();
+
+    let mut event_kinds = HashMap::<String, EventKindData>::new();
+    let mut threads = HashMap::<u64, Vec<StackEntry>>::new();
+    let mut total_time = Duration::from_nanos(0);
+    let mut clamped_durations = 0;
+
+    for event in data.iter() {
+        match event.timestamp_kind {
+            TimestampKind::Start => {
+                threads
+                    .entry(event.thread_id)
+                    .or_default()
+                    .push(StackEntry {
+                        event_kind: event.event_kind.into_owned(),
+                        start: event.timestamp,
+                        child_time: Duration::from_nanos(0),
+                    });
+            }
+            TimestampKind::End => {
+                let thread_stack = threads.get_mut(&event.thread_id).unwrap();
+                let start_event = thread_stack.pop().unwrap();
+
+                assert_eq!(start_event.event_kind, event.event_kind);
+
+                let duration =
+                    clamped_duration(event.timestamp, start_event.start, &mut clamped_durations);
+                let self_time = duration
+                    .checked_sub(start_event.child_time)
+                    .unwrap_or(Duration::from_nanos(0));
+
+                // A recursive invocation is already covered by the total time
+                // of the outer invocation of the same kind.
+                let is_recursive = thread_stack
+                    .iter()
+                    .any(|entry| entry.event_kind == start_event.event_kind);
+
+                if let Some(parent) = thread_stack.last_mut() {
+                    parent.child_time += duration;
+                }
+
+                let data = event_kinds
+                    .entry(start_event.event_kind.clone())
+                    .or_insert_with(|| EventKindData::new(start_event.event_kind));
+
+                data.self_time += self_time;
+                data.invocation_count += 1;
+                if !is_recursive {
+                    data.total_time += duration;
+                }
+
+                total_time += self_time;
+            }
+            TimestampKind::Instant | TimestampKind::Counter => {}
+        }
+    }
+
+    let mut event_kinds: Vec<_> = event_kinds.drain().map(|(_, value)| value).collect();
+    event_kinds.sort_by(|l, r| {
+        r.self_time
+            .cmp(&l.self_time)
+            .then_with(|| l.event_kind.cmp(&r.event_kind))
+    });
+
+    EventKindResults {
+        event_kinds,
+        total_time,
+        clamped_durations,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use measureme::ProfilingDataBuilder;
+
+    fn kind_data(results: &EventKindResults, event_kind: &str) -> EventKindData {
+        results
+            .event_kinds
+            .iter()
+            .find(|d| d.event_kind == event_kind)
+            .unwrap()
+            .clone()
+    }
+
+    #[test]
+    fn nested_kinds() {
+        //        <-c->
+        //     <---b--->   <-b->
+        //  <--------a---------->
+        //  100                 200
+
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("a", "e1", 0, 100, 200, |b| {
+            b.interval("b", "e2", 0, 110, 150, |b| {
+                b.interval("c", "e3", 0, 120, 130, |_| {});
+            });
+            b.interval("b", "e4", 0, 170, 180, |_| {});
+        });
+
+        let results = perform_analysis(b.into_profiling_data());
+
+        assert_eq!(results.total_time, Duration::from_nanos(100));
+
+        let a = kind_data(&results, "a");
+        assert_eq!(a.total_time, Duration::from_nanos(100));
+        assert_eq!(a.self_time, Duration::from_nanos(50));
+        assert_eq!(a.invocation_count, 1);
+
+        let b = kind_data(&results, "b");
+        assert_eq!(b.total_time, Duration::from_nanos(50));
+        assert_eq!(b.self_time, Duration::from_nanos(40));
+        assert_eq!(b.invocation_count, 2);
+
+        let c = kind_data(&results, "c");
+        assert_eq!(c.total_time, Duration::from_nanos(10));
+        assert_eq!(c.self_time, Duration::from_nanos(10));
+        assert_eq!(c.invocation_count, 1);
+
+        let order: Vec<_> = results
+            .event_kinds
+            .iter()
+            .map(|d| &d.event_kind[..])
+            .collect();
+        assert_eq!(order, ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn recursive_kinds() {
+        //        <--a-->
+        //     <-----a----->
+        //  <--------a-------->
+        //  100              200
+
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("a", "e1", 0, 100, 200, |b| {
+            b.interval("a", "e2", 0, 120, 180, |b| {
+                b.interval("a", "e3", 0, 140, 160, |_| {});
+            });
+        });
+
+        let results = perform_analysis(b.into_profiling_data());
+
+        let a = kind_data(&results, "a");
+        assert_eq!(a.total_time, Duration::from_nanos(100));
+        assert_eq!(a.self_time, Duration::from_nanos(100));
+        assert_eq!(a.invocation_count, 3);
+    }
+
+    #[test]
+    fn deterministic_order() {
+        let mut b = ProfilingDataBuilder::new();
+
+        b.interval("y", "e1", 0, 100, 110, |_| {});
+        b.interval("x", "e2", 1, 100, 110, |_| {});
+        b.interval("z", "e3", 2, 100, 110, |_| {});
+
+        let results = perform_analysis(b.into_profiling_data());
+
+        let order: Vec<_> = results
+            .event_kinds
+            .iter()
+            .map(|d| &d.event_kind[..])
+            .collect();
+        assert_eq!(order, ["x", "y", "z"]);
+    }
+}
@@ -0,0 +1,80 @@
+//! The analyses behind the `summarize` tool, e.g. for tools that summarize
+//! profiles next to exporting them in other formats.
+
+#[macro_use]
+extern crate prettytable;
+
+use std::io::{self, Write};
+
+use prettytable::Table;
+
+pub mod analysis;
+pub mod diff;
+pub mod histogram;
+pub mod kind_analysis;
+pub mod query_data;
+pub mod signed_duration;
+
+use query_data::Results;
+
+/// Writes `results` to `out` as the table that `summarize summarize` prints,
+/// leaving out the items that take less than `percent_above` percent of the
+/// total time.
+pub fn write_summary_table<W: Write>(
+    mut results: Results,
+    percent_above: f64,
+    out: &mut W,
+) -> io::Result<()> {
+    //order the results by descending self time
+    results
+        .query_data
+        .sort_by_key(|qd| std::cmp::Reverse(qd.self_time));
+
+    let mut table = Table::new();
+
+    table.add_row(row![
+        "Item",
+        "Self time",
+        "% of total time",
+        "Item count",
+        "Cache hits",
+        "Blocked time",
+        "Incremental load time",
+    ]);
+
+    let total_time = results.total_time.as_nanos() as f64;
+    let mut percent_total_time: f64 = 0.0;
+
+    for query_data in results.query_data {
+        let curr_percent = (query_data.self_time.as_nanos() as f64) / total_time * 100.0;
+        if curr_percent < percent_above {
+            break;
+        } //no need to run entire loop if filtering by % time
+
+        percent_total_time += curr_percent;
+
+        table.add_row(row![
+            query_data.label,
+            format!("{:.2?}", query_data.self_time),
+            format!("{:.3}", curr_percent),
+            format!("{}", query_data.invocation_count),
+            format!("{}", query_data.number_of_cache_hits),
+            format!("{:.2?}", query_data.blocked_time),
+            format!("{:.2?}", query_data.incremental_load_time),
+        ]);
+    }
+
+    table.print(out)?;
+
+    writeln!(out, "Total cpu time: {:?}", results.total_time)?;
+
+    if percent_above != 0.0 {
+        writeln!(
+            out,
+            "Filtered results account for {:.3}% of total time.",
+            percent_total_time
+        )?;
+    }
+
+    Ok(())
+}
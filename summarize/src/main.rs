@@ -1,22 +1,20 @@
 #[macro_use]
 extern crate prettytable;
 
-use measureme::ProfilingData;
+use measureme::{MergedProfilingData, ProfilingData, ValidationWarning};
 use std::error::Error;
 use std::fs::File;
-use std::io::{BufReader, BufWriter};
-use std::path::PathBuf;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use prettytable::Table;
 use serde::Serialize;
 use structopt::StructOpt;
 
-mod analysis;
-mod diff;
-mod query_data;
-mod signed_duration;
-
-use query_data::Results;
+use summarize::kind_analysis::EventKindResults;
+use summarize::query_data::Results;
+use summarize::{analysis, diff, histogram, kind_analysis, write_summary_table};
 
 #[derive(StructOpt, Debug)]
 struct DiffOpt {
@@ -28,19 +26,46 @@ struct DiffOpt {
 
     #[structopt(long = "json")]
     json: bool,
+
+    /// Compares the total time per event kind instead of the self time per item
+    #[structopt(short = "k", long = "by-kind")]
+    by_kind: bool,
+
+    /// Exits with an error if the total time of any event kind increases by more than
+    /// this percentage (requires `--by-kind`)
+    #[structopt(long = "threshold", raw(requires = r#""by_kind""#))]
+    threshold: Option<f64>,
 }
 
 #[derive(StructOpt, Debug)]
 struct SummarizeOpt {
-    file_prefix: PathBuf,
+    /// One or more profiles, which are summarized together
+    #[structopt(raw(required = "true"))]
+    file_prefix: Vec<PathBuf>,
 
-    /// Writes the analysis to a json file next to <file_prefix> instead of stdout
+    /// Writes the analysis to a json file next to the first <file_prefix> instead of stdout
     #[structopt(long = "json")]
     json: bool,
 
     /// Filter the output to items whose self-time is greater than this value
     #[structopt(short = "pa", long = "percent-above", default_value = "0.0")]
     percent_above: f64,
+
+    /// Aggregates the results by event kind instead of by item
+    #[structopt(short = "k", long = "by-kind")]
+    by_kind: bool,
+
+    /// Align the timestamps of multiple profiles to the start of the earliest process
+    #[structopt(long = "align-start")]
+    align_start: bool,
+
+    /// Prints the distribution of the durations of all events of this kind instead
+    #[structopt(long = "histogram")]
+    histogram: Option<String>,
+
+    /// Computes exact percentiles for `--histogram` instead of estimating them
+    #[structopt(long = "exact", requires = "histogram")]
+    exact: bool,
 }
 
 #[derive(StructOpt, Debug)]
@@ -53,26 +78,175 @@ enum Opt {
     Summarize(SummarizeOpt),
 }
 
-fn process_results(file: &PathBuf) -> Result<Results, Box<dyn Error>> {
-    if file.ends_with("json") {
-        let reader = BufReader::new(File::open(&file)?);
+fn process_results(file: &Path) -> Result<Results, Box<dyn Error>> {
+    if file.extension() == Some("json".as_ref()) {
+        let reader = BufReader::new(File::open(file)?);
 
         let results: Results = serde_json::from_reader(reader)?;
         Ok(results)
     } else {
-        let data = ProfilingData::new(&file)?;
+        let data = load_profile(file)?;
 
         Ok(analysis::perform_analysis(data))
     }
 }
 
-fn write_results_json(file: &PathBuf, results: impl Serialize) -> Result<(), Box<dyn Error>> {
-    let file = BufWriter::new(File::create(file.with_extension("json"))?);
-    serde_json::to_writer(file, &results)?;
+fn process_kind_results(file: &Path) -> Result<EventKindResults, Box<dyn Error>> {
+    if file.extension() == Some("json".as_ref()) {
+        let reader = BufReader::new(File::open(file)?);
+
+        let results: EventKindResults = serde_json::from_reader(reader)?;
+        Ok(results)
+    } else {
+        let data = load_profile(file)?;
+
+        Ok(kind_analysis::perform_analysis(data))
+    }
+}
+
+fn load_profile(file_prefix: &Path) -> Result<ProfilingData, Box<dyn Error>> {
+    let data = tools_lib::profile::load_profile(file_prefix)?;
+
+    warn_about_timestamps(file_prefix, &data);
+
+    Ok(data)
+}
+
+fn warn_about_timestamps(file_prefix: &Path, data: &ProfilingData) {
+    let (mut end_before_start, mut overlapping, mut out_of_order) = (0, 0, 0);
+    for warning in data.validate() {
+        match warning {
+            ValidationWarning::EndBeforeStart(_) => end_before_start += 1,
+            ValidationWarning::Overlapping(_) => overlapping += 1,
+            ValidationWarning::OutOfOrder { .. } => out_of_order += 1,
+        }
+    }
+
+    let warn = |count: usize, problem: &str| {
+        if count > 0 {
+            eprintln!(
+                "warning: `{}` has {} {}, the clock may not have been monotonic",
+                file_prefix.display(),
+                count,
+                problem
+            );
+        }
+    };
+    warn(end_before_start, "interval(s) that end before they start");
+    warn(overlapping, "overlapping interval(s)");
+    warn(out_of_order, "event(s) with an earlier timestamp than the event before");
+}
+
+fn warn_about_clamped_durations(clamped_durations: usize) {
+    if clamped_durations > 0 {
+        eprintln!(
+            "warning: counted {} negative duration(s) as zero",
+            clamped_durations
+        );
+    }
+}
+
+fn warn_about_unmatched_end_events(unmatched_end_events: usize) {
+    if unmatched_end_events > 0 {
+        eprintln!(
+            "warning: skipped {} end event(s) without a start event",
+            unmatched_end_events
+        );
+    }
+}
+
+fn load_profiles(opt: &SummarizeOpt) -> Result<MergedProfilingData, Box<dyn Error>> {
+    let profiles = opt
+        .file_prefix
+        .iter()
+        .map(|file_prefix| load_profile(file_prefix))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut data = ProfilingData::merge(profiles);
+    if opt.align_start {
+        data.align_start();
+    }
+
+    Ok(data)
+}
+
+fn write_results_json(file: &Path, results: impl Serialize) -> Result<(), Box<dyn Error>> {
+    let mut file = BufWriter::new(File::create(file.with_extension("json"))?);
+    serde_json::to_writer(&mut file, &results)?;
+    file.flush()?;
+    Ok(())
+}
+
+fn diff_by_kind(opt: DiffOpt) -> Result<(), Box<dyn Error>> {
+    let base = process_kind_results(&opt.base)?;
+    let change = process_kind_results(&opt.change)?;
+
+    let results = diff::calculate_kind_diff(base, change);
+
+    let regressions: Vec<_> = match opt.threshold {
+        Some(threshold) => results
+            .event_kinds
+            .iter()
+            .filter(|d| d.percent_change() > threshold)
+            .map(|d| d.event_kind.clone())
+            .collect(),
+        None => Vec::new(),
+    };
+
+    if opt.json {
+        write_results_json(&opt.change, &results)?;
+    } else {
+        let mut table = Table::new();
+
+        table.add_row(row!(
+            "Kind",
+            "Base total time",
+            "Change total time",
+            "Total time",
+            "% change",
+            "Self time",
+            "Item count"
+        ));
+
+        for kind_data in &results.event_kinds {
+            let exclude = opt.exclude.iter().any(|e| kind_data.event_kind.contains(e));
+            if exclude {
+                continue;
+            }
+
+            table.add_row(row![
+                kind_data.event_kind,
+                format!("{:.2?}", kind_data.base_total_time),
+                format!("{:.2?}", kind_data.change_total_time),
+                format!("{:.2?}", kind_data.total_time),
+                format!("{:+.3}", kind_data.percent_change()),
+                format!("{:.2?}", kind_data.self_time),
+                format!("{:+}", kind_data.invocation_count),
+            ]);
+        }
+
+        table.printstd();
+
+        println!("Total cpu time: {:?}", results.total_time);
+    }
+
+    if !regressions.is_empty() {
+        eprintln!(
+            "The following event kinds regressed by more than {}%: {}",
+            opt.threshold.unwrap(),
+            regressions.join(", ")
+        );
+        std::process::exit(1);
+    }
+
     Ok(())
 }
 
 fn diff(opt: DiffOpt) -> Result<(), Box<dyn Error>> {
+    if opt.by_kind {
+        return diff_by_kind(opt);
+    }
+
     let base = process_results(&opt.base)?;
     let change = process_results(&opt.change)?;
 
@@ -125,18 +299,7 @@ fn diff(opt: DiffOpt) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-fn summarize(opt: SummarizeOpt) -> Result<(), Box<dyn Error>> {
-    let data = ProfilingData::new(&opt.file_prefix)?;
-
-    let mut results = analysis::perform_analysis(data);
-
-    //just output the results into a json file
-    if opt.json {
-        write_results_json(&opt.file_prefix, &results)?;
-        return Ok(());
-    }
-
-    let percent_above = opt.percent_above;
+fn check_percent_above(percent_above: f64) {
     //cannot be greater than 100% or less than 0%
     if percent_above > 100.0 {
         eprintln!("Percentage of total time cannot be more than 100.0");
@@ -145,43 +308,49 @@ fn summarize(opt: SummarizeOpt) -> Result<(), Box<dyn Error>> {
         eprintln!("Percentage of total time cannot be less than 0.0");
         std::process::exit(1);
     }
+}
+
+fn summarize_by_kind(opt: SummarizeOpt) -> Result<(), Box<dyn Error>> {
+    let data = load_profiles(&opt)?;
+
+    let results = kind_analysis::perform_analysis(data);
+    warn_about_clamped_durations(results.clamped_durations);
+
+    if opt.json {
+        write_results_json(&opt.file_prefix[0], &results)?;
+        return Ok(());
+    }
 
-    //order the results by descending self time
-    results
-        .query_data
-        .sort_by(|l, r| r.self_time.cmp(&l.self_time));
+    let percent_above = opt.percent_above;
+    check_percent_above(percent_above);
 
     let mut table = Table::new();
 
     table.add_row(row![
-        "Item",
+        "Kind",
+        "Total time",
         "Self time",
         "% of total time",
         "Item count",
-        "Cache hits",
-        "Blocked time",
-        "Incremental load time",
     ]);
 
     let total_time = results.total_time.as_nanos() as f64;
     let mut percent_total_time: f64 = 0.0;
 
-    for query_data in results.query_data {
-        let curr_percent = (query_data.self_time.as_nanos() as f64) / total_time * 100.0;
+    for kind_data in results.event_kinds {
+        let curr_percent = (kind_data.self_time.as_nanos() as f64) / total_time * 100.0;
         if curr_percent < percent_above {
             break;
-        } //no need to run entire loop if filtering by % time
+        } //the results are ordered by descending self time
 
-        percent_total_time = percent_total_time + curr_percent;
+        percent_total_time += curr_percent;
 
         table.add_row(row![
-            query_data.label,
-            format!("{:.2?}", query_data.self_time),
+            kind_data.event_kind,
+            format!("{:.2?}", kind_data.total_time),
+            format!("{:.2?}", kind_data.self_time),
             format!("{:.3}", curr_percent),
-            format!("{}", query_data.invocation_count),
-            format!("{}", query_data.number_of_cache_hits),
-            format!("{:.2?}", query_data.blocked_time),
-            format!("{:.2?}", query_data.incremental_load_time),
+            format!("{}", kind_data.invocation_count),
         ]);
     }
 
@@ -199,6 +368,99 @@ fn summarize(opt: SummarizeOpt) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+fn summarize_histogram(opt: &SummarizeOpt, event_kind: &str) -> Result<(), Box<dyn Error>> {
+    let mut durations = if opt.exact {
+        histogram::Durations::exact()
+    } else {
+        histogram::Durations::new()
+    };
+
+    let mut clamped_durations = 0;
+
+    for file_prefix in &opt.file_prefix {
+        let data = load_profile(file_prefix)?;
+
+        let mut intervals = data.iter_intervals();
+        for interval in &mut intervals {
+            if interval.kind == event_kind {
+                if interval.end_ns < interval.start_ns {
+                    clamped_durations += 1;
+                }
+                durations.record(Duration::from_nanos(
+                    interval.end_ns.saturating_sub(interval.start_ns),
+                ));
+            }
+        }
+
+        if !intervals.errors().is_empty() {
+            eprintln!(
+                "warning: skipped {} start or end event(s) without a matching counterpart",
+                intervals.errors().len()
+            );
+        }
+    }
+
+    warn_about_clamped_durations(clamped_durations);
+
+    let stats = match durations.stats() {
+        Some(stats) => stats,
+        None => return Err(format!("no `{}` events found", event_kind).into()),
+    };
+
+    let mut table = Table::new();
+
+    table.add_row(row!["Count", "Min", "Median", "p90", "p99", "Max"]);
+    table.add_row(row![
+        format!("{}", stats.count),
+        format!("{:.2?}", stats.min),
+        format!("{:.2?}", stats.median),
+        format!("{:.2?}", stats.p90),
+        format!("{:.2?}", stats.p99),
+        format!("{:.2?}", stats.max),
+    ]);
+
+    table.printstd();
+
+    println!();
+    print!("{}", durations.ascii_histogram());
+
+    if !opt.exact {
+        println!();
+        println!("Percentiles are estimated, pass `--exact` to compute them exactly.");
+    }
+
+    Ok(())
+}
+
+fn summarize(opt: SummarizeOpt) -> Result<(), Box<dyn Error>> {
+    if let Some(event_kind) = &opt.histogram {
+        return summarize_histogram(&opt, event_kind);
+    }
+
+    if opt.by_kind {
+        return summarize_by_kind(opt);
+    }
+
+    let data = load_profiles(&opt)?;
+
+    let results = analysis::perform_analysis(data);
+    warn_about_clamped_durations(results.clamped_durations);
+    warn_about_unmatched_end_events(results.unmatched_end_events);
+
+    //just output the results into a json file
+    if opt.json {
+        write_results_json(&opt.file_prefix[0], &results)?;
+        return Ok(());
+    }
+
+    let percent_above = opt.percent_above;
+    check_percent_above(percent_above);
+
+    write_summary_table(results, percent_above, &mut io::stdout())?;
+
+    Ok(())
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
     let opt = Opt::from_args();
 
@@ -100,6 +100,13 @@ impl Sub for QueryData {
 pub struct Results {
     pub query_data: Vec<QueryData>,
     pub total_time: Duration,
+    /// The number of durations that were negative and counted as zero
+    #[serde(default)]
+    pub clamped_durations: usize,
+    /// The number of end events that were skipped because their start event is
+    /// missing
+    #[serde(default)]
+    pub unmatched_end_events: usize,
 }
 
 // For now this is only needed for tests it seems
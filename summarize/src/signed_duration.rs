@@ -21,7 +21,7 @@ impl SignedDuration {
         let is_positive = nanos >= 0;
 
         SignedDuration {
-            duration: Duration::from_nanos(nanos.abs() as u64),
+            duration: Duration::from_nanos(nanos.unsigned_abs() as u64),
             is_positive,
         }
     }
@@ -92,17 +92,17 @@ mod test {
         };
 
         assert_eq!(zero_d, zero_sd.duration);
-        assert_eq!(true, zero_sd.is_positive);
+        assert!(zero_sd.is_positive);
 
         assert_eq!(zero_sd, zero_sd - zero_sd);
 
         assert_eq!(one_d, one_sd.duration);
-        assert_eq!(true, one_sd.is_positive);
+        assert!(one_sd.is_positive);
 
         assert_eq!(one_sd, one_sd - zero_sd);
 
         assert_eq!(one_d, neg_one_sd.duration);
-        assert_eq!(false, neg_one_sd.is_positive);
+        assert!(!neg_one_sd.is_positive);
 
         assert_eq!(neg_one_sd, neg_one_sd - zero_sd);
 
@@ -0,0 +1,175 @@
+//! Exports `measureme` profiles as CSV or as newline-delimited JSON, as the
+//! `to_csv` tool does, e.g. for tools that export several formats.
+
+use std::borrow::Cow;
+use std::error::Error;
+use std::io::{self, Write};
+use std::time::SystemTime;
+
+use measureme::{ProfilingData, TimestampKind};
+
+use serde::Serialize;
+
+struct Row<'a> {
+    event_type: &'static str,
+    thread_id: u64,
+    event_kind: Cow<'a, str>,
+    event_id: Cow<'a, str>,
+    start_ns: u64,
+    end_ns: u64,
+    value: Option<u64>,
+}
+
+// A line of the `--ndjson` output. `ts` is in nanoseconds since the start of
+// the profile, `value` is only present for counters.
+#[derive(Serialize)]
+struct Record<'a> {
+    thread: u64,
+    kind: &'a str,
+    id: &'a str,
+    ts: u64,
+    phase: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<u64>,
+}
+
+fn nanos(timestamp: SystemTime) -> u64 {
+    timestamp
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64
+}
+
+// Quotes fields that contain a separator, a quote or a line break, as
+// described in RFC 4180
+fn escape(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::from(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::from(field)
+    }
+}
+
+/// Writes the events of `data` to `file` as newline-delimited JSON, one
+/// object per event in the order they were recorded. The events are streamed
+/// straight from the profile, so that the whole export never has to be held
+/// in memory.
+pub fn write_ndjson(data: &ProfilingData, file: &mut impl Write) -> Result<(), Box<dyn Error>> {
+    for event in data.iter() {
+        let phase = match event.timestamp_kind {
+            TimestampKind::Start => "start",
+            TimestampKind::End => "end",
+            TimestampKind::Instant => "instant",
+            TimestampKind::Counter => "counter",
+        };
+        let record = Record {
+            thread: event.thread_id,
+            kind: &event.event_kind,
+            id: &event.label,
+            ts: nanos(event.timestamp),
+            phase,
+            value: event.value,
+        };
+
+        serde_json::to_writer(&mut *file, &record)?;
+        writeln!(file)?;
+    }
+
+    Ok(())
+}
+
+/// Writes the interval events of `data` to `file` as CSV, one row per
+/// interval ordered by start time, and with `instants` also its instant and
+/// counter events. Returns warnings about events that were skipped because
+/// their start or end event is missing.
+pub fn write_csv(
+    data: &ProfilingData,
+    instants: bool,
+    file: &mut impl Write,
+) -> io::Result<Vec<String>> {
+    let mut intervals = data.iter_intervals();
+    let mut rows: Vec<_> = intervals
+        .by_ref()
+        .map(|interval| Row {
+            event_type: "interval",
+            thread_id: interval.thread_id,
+            event_kind: interval.kind,
+            event_id: interval.id,
+            start_ns: interval.start_ns,
+            end_ns: interval.end_ns,
+            value: None,
+        })
+        .collect();
+
+    let mut warnings = Vec::new();
+    if !intervals.errors().is_empty() {
+        warnings.push(format!(
+            "skipped {} start or end event(s) without a matching counterpart",
+            intervals.errors().len()
+        ));
+    }
+
+    if instants {
+        rows.extend(data.iter().filter_map(|event| {
+            let event_type = match event.timestamp_kind {
+                TimestampKind::Instant => "instant",
+                TimestampKind::Counter => "counter",
+                TimestampKind::Start | TimestampKind::End => return None,
+            };
+            let timestamp = nanos(event.timestamp);
+
+            Some(Row {
+                event_type,
+                thread_id: event.thread_id,
+                event_kind: event.event_kind,
+                event_id: event.label,
+                start_ns: timestamp,
+                end_ns: timestamp,
+                value: event.value,
+            })
+        }));
+    }
+
+    // Intervals are yielded in the order they end
+    rows.sort_by_key(|row| (row.start_ns, row.thread_id));
+
+    if instants {
+        writeln!(
+            file,
+            "type,thread_id,event_kind,event_id,start_ns,end_ns,duration_ns,value"
+        )?;
+    } else {
+        writeln!(
+            file,
+            "thread_id,event_kind,event_id,start_ns,end_ns,duration_ns"
+        )?;
+    }
+
+    for row in rows {
+        if instants {
+            write!(file, "{},", row.event_type)?;
+        }
+
+        write!(
+            file,
+            "{},{},{},{},{},{}",
+            row.thread_id,
+            escape(&row.event_kind),
+            escape(&row.event_id),
+            row.start_ns,
+            row.end_ns,
+            row.end_ns - row.start_ns
+        )?;
+
+        if instants {
+            match row.value {
+                Some(value) => write!(file, ",{}", value)?,
+                None => write!(file, ",")?,
+            }
+        }
+
+        writeln!(file)?;
+    }
+
+    Ok(warnings)
+}
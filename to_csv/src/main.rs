@@ -0,0 +1,56 @@
+use std::error::Error;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use structopt::StructOpt;
+
+use to_csv::{write_csv, write_ndjson};
+use tools_lib::profile::load_profile;
+
+#[derive(StructOpt, Debug)]
+struct Opt {
+    file_prefix: PathBuf,
+
+    /// The output file, defaults to `<file_prefix>.csv`, or to
+    /// `<file_prefix>.ndjson` with `--ndjson`
+    #[structopt(short = "o", long = "output")]
+    output: Option<PathBuf>,
+
+    /// Also export instant and counter events. This adds a `type` column and
+    /// a `value` column for the value of counters
+    #[structopt(long = "instants")]
+    instants: bool,
+
+    /// Write newline-delimited JSON instead, with one object per event
+    /// (including the start and end events of intervals) in the order they
+    /// were recorded, e.g. for `pandas.read_json(lines=True)`
+    #[structopt(long = "ndjson", raw(conflicts_with = r#""instants""#))]
+    ndjson: bool,
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let opt = Opt::from_args();
+
+    let data = load_profile(&opt.file_prefix)?;
+
+    let output = opt.output.clone().unwrap_or_else(|| {
+        let mut path = OsString::from(&opt.file_prefix);
+        path.push(if opt.ndjson { ".ndjson" } else { ".csv" });
+        PathBuf::from(path)
+    });
+    let mut file = BufWriter::new(File::create(output)?);
+
+    if opt.ndjson {
+        write_ndjson(&data, &mut file)?;
+    } else {
+        for warning in write_csv(&data, opt.instants, &mut file)? {
+            eprintln!("warning: {}", warning);
+        }
+    }
+
+    file.flush()?;
+
+    Ok(())
+}
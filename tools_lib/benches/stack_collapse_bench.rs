@@ -0,0 +1,67 @@
+#![feature(test)]
+
+extern crate test;
+
+use measureme::{ProfilingData, ProfilingDataBuilder};
+use std::time::Duration;
+use tools_lib::stack_collapse::collapse_stacks;
+
+const THREADS: u64 = 8;
+const QUERIES_PER_THREAD: u64 = 2_000;
+
+// Something like a profile of a parallel rustc: each thread runs many
+// queries of about 10ms, each with a few nested queries.
+fn multi_threaded_profile() -> ProfilingData {
+    let mut b = ProfilingDataBuilder::new();
+
+    for thread_id in 0..THREADS {
+        for query in 0..QUERIES_PER_THREAD {
+            let start = query * 10_000_000;
+            b.interval(
+                "Query",
+                "typeck",
+                thread_id,
+                start,
+                start + 9_000_000,
+                |b| {
+                    b.interval(
+                        "Query",
+                        "mir_built",
+                        thread_id,
+                        start + 1_000,
+                        start + 4_000_000,
+                        |b| {
+                            b.interval(
+                                "Query",
+                                "thir_body",
+                                thread_id,
+                                start + 2_000,
+                                start + 3_000_000,
+                                |_| {},
+                            );
+                        },
+                    );
+                    b.interval(
+                        "Query",
+                        "type_of",
+                        thread_id,
+                        start + 5_000_000,
+                        start + 8_000_000,
+                        |_| {},
+                    );
+                },
+            );
+        }
+    }
+
+    b.into_profiling_data()
+}
+
+// Compare to a run with `--no-default-features` to see the effect of
+// collapsing the stacks of each thread in parallel.
+#[bench]
+fn bench_collapse_stacks_many_threads(bencher: &mut test::Bencher) {
+    let profiling_data = multi_threaded_profile();
+
+    bencher.iter(|| collapse_stacks(profiling_data.iter(), Duration::from_millis(1)));
+}
@@ -0,0 +1,45 @@
+//! Reading a profile the way all the tools do: the profile is read via
+//! `ProfilingData::new()`, and problems with it that don't keep it from being
+//! processed are reported as warnings.
+
+use measureme::ProfilingData;
+use std::error::Error;
+use std::path::Path;
+
+/// Reads the profile at `file_prefix` and prints the warnings of
+/// `profile_warnings()` about it to stderr.
+pub fn load_profile(file_prefix: &Path) -> Result<ProfilingData, Box<dyn Error>> {
+    let data = ProfilingData::new(file_prefix)?;
+
+    for warning in profile_warnings(file_prefix, &data) {
+        eprintln!("warning: {}", warning);
+    }
+
+    Ok(data)
+}
+
+/// The warnings about the profile `data` read from `file_prefix`: whether
+/// events may be missing from it, and whether its string table is missing.
+pub fn profile_warnings(file_prefix: &Path, data: &ProfilingData) -> Vec<String> {
+    let mut warnings = Vec::new();
+
+    if data.is_truncated() {
+        warnings.push(format!(
+            "`{}` is incomplete, events that were not completely written are missing",
+            file_prefix.display()
+        ));
+    } else if !data.is_complete() {
+        warnings.push(format!(
+            "`{}` may be incomplete, the profiler was not shut down cleanly",
+            file_prefix.display()
+        ));
+    }
+    if !data.can_resolve_strings() {
+        warnings.push(format!(
+            "the string table of `{}` is missing, events are shown with their string ids",
+            file_prefix.display()
+        ));
+    }
+
+    warnings
+}
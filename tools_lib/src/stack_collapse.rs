@@ -1,57 +1,678 @@
-use std::collections::HashMap;
+//! Collapses the events of a profile into stacks, in the "folded" format
+//! understood by flamegraph tools like inferno: each stack is the root frame
+//! followed by the labels of the open interval events, outermost first,
+//! separated by `;`, e.g. `rustc;typeck;type_of`. Labels are used as they
+//! are, so a label containing `;` adds frames.
+//!
+//! The events of each thread are processed in the order they are passed in,
+//! which is the order they were recorded in. The time between two consecutive
+//! events of a thread is attributed to the stack that was current after the
+//! earlier one:
+//!
+//! * a start event pushes a frame, the matching end event pops it again,
+//!   along with any frames inside of it that were never closed;
+//! * instant and counter events leave the stack unchanged;
+//! * the time before the first event of a thread isn't attributed to any
+//!   stack, neither is time that goes backwards, e.g. because of clock
+//!   adjustments.
+//!
+//! The time of all threads is summed per stack and only then converted into
+//! samples, see `collapse_stacks()`. `collapse_stacks_self_time()` instead
+//! attributes the samples of each stack to its innermost frame only.
+//!
+//! Deep recursion makes for many long stacks, which `CollapsedStacks::fold()`
+//! shortens, see `FoldOptions`.
+//!
+//! The events are processed in a single pass that only holds the current
+//! stack of each thread and the time of each stack, so the memory needed
+//! depends on the number of different stacks rather than the size of the
+//! profile. For profiles with more stacks than fit into memory,
+//! `collapse_stacks_spilling()` writes them to temporary files.
+
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{Duration, SystemTime};
 
 use measureme::{Event, TimestampKind};
 
+/// The result of `collapse_stacks()`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CollapsedStacks {
+    /// The number of samples of each stack, e.g. `rustc;typeck;type_of`
+    pub stacks: HashMap<String, usize>,
+    /// The start and end events that could not be matched up, by thread id.
+    /// Empty unless the profile is malformed, e.g. because it is truncated.
+    pub unmatched: BTreeMap<u64, UnmatchedEvents>,
+}
+
+impl CollapsedStacks {
+    /// The stacks with their number of samples, ordered by stack. Unlike
+    /// iterating over `stacks`, this gives the same order on every run.
+    pub fn sorted_stacks(&self) -> Vec<(&str, usize)> {
+        let mut stacks: Vec<_> = self
+            .stacks
+            .iter()
+            .map(|(stack, &samples)| (&stack[..], samples))
+            .collect();
+        stacks.sort_unstable();
+        stacks
+    }
+
+    /// Shortens the stacks as chosen by `options`. Stacks that end up the
+    /// same are merged, summing up their samples, so a folded frame is as
+    /// wide as the frames it replaces together and the samples of all stacks
+    /// stay the same.
+    pub fn fold(self, options: &FoldOptions) -> CollapsedStacks {
+        if !options.fold_recursion && options.max_depth.is_none() {
+            return self;
+        }
+
+        let mut stacks = HashMap::<String, usize>::new();
+        for (stack, samples) in self.stacks {
+            *stacks.entry(fold_stack(&stack, options)).or_default() += samples;
+        }
+
+        CollapsedStacks {
+            stacks,
+            unmatched: self.unmatched,
+        }
+    }
+}
+
+/// How `CollapsedStacks::fold()` shortens stacks. The default leaves them as
+/// they are.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FoldOptions {
+    /// Replaces each run of identical adjacent frames, as left by a
+    /// recursive query, by a single frame with the number of frames, e.g.
+    /// `rustc;a;b;b;b;c` by `rustc;a;b (x3);c`. The frames of different
+    /// depths of recursion stay apart, `b (x2)` and `b (x3)` are different
+    /// frames.
+    pub fold_recursion: bool,
+    /// Cuts stacks of more than `max_depth` frames, including the root frame,
+    /// after `max_depth` frames and replaces the rest by a single `…` frame.
+    /// The depth is counted after folding recursion.
+    pub max_depth: Option<usize>,
+}
+
+fn fold_stack(stack: &str, options: &FoldOptions) -> String {
+    let mut frames: Vec<Cow<'_, str>> = Vec::new();
+    let mut split = stack.split(';').peekable();
+    while let Some(frame) = split.next() {
+        let mut count = 1;
+        while options.fold_recursion && split.peek() == Some(&frame) {
+            split.next();
+            count += 1;
+        }
+
+        if count == 1 {
+            frames.push(frame.into());
+        } else {
+            frames.push(format!("{} (x{})", frame, count).into());
+        }
+    }
+
+    if let Some(max_depth) = options.max_depth {
+        if frames.len() > max_depth {
+            frames.truncate(max_depth);
+            frames.push("…".into());
+        }
+    }
+
+    frames.join(";")
+}
+
+/// The start and end events of a thread without a counterpart. The stacks of
+/// such a thread are a best-effort reconstruction: an end event without a
+/// start event is ignored and closes no frame, and frames that are never
+/// closed end at the last event of the thread.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UnmatchedEvents {
+    /// The number of start events without an end event
+    pub starts: usize,
+    /// The number of end events without a start event
+    pub ends: usize,
+    /// The event kinds of the unmatched events
+    pub event_kinds: BTreeSet<String>,
+}
+
+impl fmt::Display for UnmatchedEvents {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} start and {} end event(s) without a counterpart",
+            self.starts, self.ends
+        )?;
+
+        let event_kinds: Vec<_> = self.event_kinds.iter().map(|kind| &kind[..]).collect();
+        write!(f, " (event kinds: {})", event_kinds.join(", "))
+    }
+}
+
+/// Collapses the stacks observed in `events` into the number of samples of
+/// length `interval` each stack was observed for.
+///
+/// The time spent in each stack is summed up exactly and only converted into
+/// samples at the end, rounded to the nearest sample. A stack that is entered
+/// many times for less than `interval` each thus still gets the samples that
+/// its total time amounts to. Stacks that amount to less than half a sample
+/// are left out.
 pub fn collapse_stacks<'a>(
     events: impl Iterator<Item = Event<'a>>,
-    interval: u64,
-) -> HashMap<String, usize> {
-    let mut recorded_stacks = HashMap::<String, usize>::new();
-    let mut thread_stacks: HashMap<u64, (SystemTime, Vec<Event>)> = HashMap::new();
+    interval: Duration,
+) -> CollapsedStacks {
+    collapse_stacks_with_root(events, interval, |_| "rustc".to_string())
+}
+
+/// Like `collapse_stacks()`, but the root frame of each stack is given by
+/// `root_frame` for the thread the stack was observed on. This allows to
+/// keep the stacks of different threads apart.
+///
+/// Threads don't share a call stack, so with the `parallel` feature the
+/// events are split up by thread and the stacks of each thread are collapsed
+/// in parallel.
+pub fn collapse_stacks_with_root<'a>(
+    events: impl Iterator<Item = Event<'a>>,
+    interval: Duration,
+    root_frame: impl Fn(u64) -> String + Sync,
+) -> CollapsedStacks {
+    assert!(
+        interval > Duration::from_secs(0),
+        "the interval must not be zero"
+    );
+
+    let stack_times = stack_times(events, &root_frame);
+
+    let stacks = stack_times
+        .times
+        .into_iter()
+        .map(|(stack, time)| (stack, samples(time, interval)))
+        .filter(|&(_, samples)| samples > 0)
+        .collect();
+
+    CollapsedStacks {
+        stacks,
+        unmatched: stack_times.unmatched,
+    }
+}
+
+/// Like `collapse_stacks()`, but each sample is only attributed to the
+/// innermost frame of its stack, the time spent in the frame itself rather
+/// than in the frames inside of it. The returned "stacks" are single frames,
+/// e.g. `type_of`, and samples outside of any frame are attributed to the
+/// `rustc` root frame. A frame that occurs in several stacks, e.g. at
+/// different depths, gets the samples of all of them.
+///
+/// This is computed from the samples of `collapse_stacks()`, so the samples
+/// of all frames add up to those of all stacks, i.e. to the width of the root
+/// frame of the flamegraph. Like the stacks, a frame only gets the samples
+/// its stacks were rounded to, not those of its total time.
+pub fn collapse_stacks_self_time<'a>(
+    events: impl Iterator<Item = Event<'a>>,
+    interval: Duration,
+) -> CollapsedStacks {
+    let collapsed = collapse_stacks(events, interval);
+
+    let mut frames = HashMap::<String, usize>::new();
+    for (stack, samples) in collapsed.stacks {
+        let innermost = stack.rsplit(';').next().unwrap_or(&stack);
+        *frames.entry(innermost.to_string()).or_default() += samples;
+    }
+
+    CollapsedStacks {
+        stacks: frames,
+        unmatched: collapsed.unmatched,
+    }
+}
+
+/// Like `collapse_stacks_with_root()`, for profiles with so many different
+/// stacks that their samples don't fit into memory. Once the stacks take up
+/// more than about `memory_budget` bytes, they are written to a temporary
+/// file in `std::env::temp_dir()`, sorted, and collected anew from there on.
+/// `SpilledStacks::into_sorted_stacks()` merges the files while reading
+/// them. As long as the stacks stay within the budget, nothing is written.
+///
+/// The events are processed on the calling thread in a single pass, which
+/// besides the stacks since the last spill only holds the current stack of
+/// each thread.
+pub fn collapse_stacks_spilling<'a>(
+    events: impl Iterator<Item = Event<'a>>,
+    interval: Duration,
+    root_frame: impl Fn(u64) -> String,
+    memory_budget: usize,
+) -> io::Result<SpilledStacks> {
+    assert!(
+        interval > Duration::from_secs(0),
+        "the interval must not be zero"
+    );
 
+    let mut collapser = StackCollapser::default();
+    let mut runs = Vec::new();
     for event in events {
-        let (next_observation_time, thread_stack) = thread_stacks
-            .entry(event.thread_id)
-            .or_insert((event.timestamp, Vec::new()));
-        //if this event is after the next_observation_time then we need to record the current stacks
-        if event.timestamp > *next_observation_time {
-            let mut stack_string = String::new();
-            stack_string.push_str("rustc");
-
-            for event in thread_stack.iter() {
-                stack_string.push(';');
-                stack_string.push_str(&event.label);
+        collapser.push(event, &root_frame);
+        if collapser.times_bytes > memory_budget {
+            runs.push(StackRun::write(collapser.take_times())?);
+        }
+    }
+
+    let stack_times = collapser.finish();
+    let mut times: Vec<_> = stack_times.times.into_iter().collect();
+    times.sort_unstable();
+    runs.push(StackRun::InMemory(times.into_iter()));
+
+    Ok(SpilledStacks {
+        unmatched: stack_times.unmatched,
+        interval,
+        runs,
+    })
+}
+
+/// The result of `collapse_stacks_spilling()`. The temporary files are
+/// removed when it, or the iterator returned by `into_sorted_stacks()`, is
+/// dropped.
+#[derive(Debug)]
+pub struct SpilledStacks {
+    /// The start and end events that could not be matched up, by thread id,
+    /// as in `CollapsedStacks::unmatched`
+    pub unmatched: BTreeMap<u64, UnmatchedEvents>,
+    interval: Duration,
+    runs: Vec<StackRun>,
+}
+
+impl SpilledStacks {
+    /// The number of times the stacks were written to a temporary file.
+    pub fn spilled_runs(&self) -> usize {
+        self.runs.len() - 1
+    }
+
+    /// The stacks with their number of samples, ordered by stack, as
+    /// `CollapsedStacks::sorted_stacks()` returns them. Stacks that were
+    /// written to several files are only rounded to whole samples once their
+    /// times are summed up again, so the samples are the same as those of
+    /// `collapse_stacks_with_root()`.
+    pub fn into_sorted_stacks(self) -> io::Result<SortedStacks> {
+        let mut sorted = SortedStacks {
+            interval: self.interval,
+            runs: self.runs,
+            heads: BinaryHeap::new(),
+        };
+        for run_idx in 0..sorted.runs.len() {
+            sorted.push_next(run_idx)?;
+        }
+
+        Ok(sorted)
+    }
+}
+
+/// The iterator returned by `SpilledStacks::into_sorted_stacks()`. It ends
+/// after the first error.
+#[derive(Debug)]
+pub struct SortedStacks {
+    interval: Duration,
+    runs: Vec<StackRun>,
+    // The next stack of each run
+    heads: BinaryHeap<Reverse<(String, usize, Duration)>>,
+}
+
+impl SortedStacks {
+    fn push_next(&mut self, run_idx: usize) -> io::Result<()> {
+        if let Some((stack, time)) = self.runs[run_idx].next_stack()? {
+            self.heads.push(Reverse((stack, run_idx, time)));
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for SortedStacks {
+    type Item = io::Result<(String, usize)>;
+
+    fn next(&mut self) -> Option<io::Result<(String, usize)>> {
+        loop {
+            let Reverse((stack, run_idx, mut time)) = self.heads.pop()?;
+            let mut next = self.push_next(run_idx);
+            while next.is_ok() && self.heads.peek().is_some_and(|head| head.0 .0 == stack) {
+                let Reverse((_, run_idx, other_time)) = self.heads.pop().unwrap();
+                time += other_time;
+                next = self.push_next(run_idx);
             }
 
-            let count = recorded_stacks.entry(stack_string).or_default();
+            if let Err(e) = next {
+                self.heads.clear();
+                return Some(Err(e));
+            }
 
-            while event.timestamp > *next_observation_time {
-                *count += 1;
-                *next_observation_time += Duration::from_millis(interval);
+            let samples = samples(time, self.interval);
+            if samples > 0 {
+                return Some(Ok((stack, samples)));
             }
         }
+    }
+}
+
+// The stacks written to a temporary file by `collapse_stacks_spilling()`, or
+// those collected since the last time
+#[derive(Debug)]
+enum StackRun {
+    InMemory(std::vec::IntoIter<(String, Duration)>),
+    File {
+        path: PathBuf,
+        reader: BufReader<File>,
+    },
+}
+
+impl StackRun {
+    // Each stack is written as the nanoseconds spent in it and the length of
+    // the stack, as little endian `u64`s, followed by the stack itself
+    fn write(times: HashMap<String, Duration>) -> io::Result<StackRun> {
+        static NEXT_RUN_ID: AtomicUsize = AtomicUsize::new(0);
+
+        let mut times: Vec<_> = times.into_iter().collect();
+        times.sort_unstable();
+
+        let path = std::env::temp_dir().join(format!(
+            "measureme-stacks-{}-{}",
+            std::process::id(),
+            NEXT_RUN_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        // Removes the file from here on, even if writing it fails
+        let mut run = StackRun::File {
+            path,
+            reader: BufReader::new(file.try_clone()?),
+        };
+
+        let mut writer = BufWriter::new(&mut file);
+        for (stack, time) in times {
+            writer.write_all(&(time.as_nanos() as u64).to_le_bytes())?;
+            writer.write_all(&(stack.len() as u64).to_le_bytes())?;
+            writer.write_all(stack.as_bytes())?;
+        }
+        writer.flush()?;
+        drop(writer);
+
+        if let StackRun::File { reader, .. } = &mut run {
+            reader.seek(SeekFrom::Start(0))?;
+        }
+        Ok(run)
+    }
+
+    fn next_stack(&mut self) -> io::Result<Option<(String, Duration)>> {
+        let reader = match self {
+            StackRun::InMemory(times) => return Ok(times.next()),
+            StackRun::File { reader, .. } => reader,
+        };
+
+        let mut nanos = [0; 8];
+        match reader.read_exact(&mut nanos) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let mut len = [0; 8];
+        reader.read_exact(&mut len)?;
+        let mut stack = vec![0; u64::from_le_bytes(len) as usize];
+        reader.read_exact(&mut stack)?;
+
+        let stack =
+            String::from_utf8(stack).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Some((
+            stack,
+            Duration::from_nanos(u64::from_le_bytes(nanos)),
+        )))
+    }
+}
+
+impl Drop for StackRun {
+    fn drop(&mut self) {
+        if let StackRun::File { path, .. } = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+// The time rounded to the nearest number of samples of length `interval`
+fn samples(time: Duration, interval: Duration) -> usize {
+    let interval = interval.as_nanos();
+    ((time.as_nanos() + interval / 2) / interval) as usize
+}
+
+#[derive(Default)]
+struct StackTimes {
+    times: HashMap<String, Duration>,
+    unmatched: BTreeMap<u64, UnmatchedEvents>,
+}
+
+impl StackTimes {
+    // Adds up the times of both, for the stacks of different threads
+    #[cfg(all(feature = "parallel", not(target_arch = "wasm32")))]
+    fn merge(mut self, mut other: StackTimes) -> StackTimes {
+        if self.times.len() < other.times.len() {
+            std::mem::swap(&mut self, &mut other);
+        }
+
+        for (stack, time) in other.times {
+            *self.times.entry(stack).or_default() += time;
+        }
+        self.unmatched.extend(other.unmatched);
+
+        self
+    }
+}
+
+/// The number of events that are split up by thread at a time to collapse
+/// their stacks in parallel, about 8 MiB of `Event`s.
+#[cfg(all(feature = "parallel", not(target_arch = "wasm32")))]
+const PARALLEL_CHUNK_LEN: usize = 1 << 16;
+
+fn stack_times<'a>(
+    events: impl Iterator<Item = Event<'a>>,
+    root_frame: &(impl Fn(u64) -> String + Sync),
+) -> StackTimes {
+    #[cfg(all(feature = "parallel", not(target_arch = "wasm32")))]
+    {
+        use rayon::prelude::*;
+
+        // Splitting up the events only pays off if they can be processed in
+        // parallel
+        if rayon::current_num_threads() == 1 {
+            return stack_times_of_threads(events, root_frame);
+        }
+
+        // The events are split up a chunk at a time rather than all at once,
+        // so that only the stacks of each thread are kept between chunks, not
+        // the events of the whole profile
+        let mut events = events.peekable();
+        let mut collapsers = HashMap::<u64, StackCollapser<'a>>::new();
+        while events.peek().is_some() {
+            let mut chunk = HashMap::<u64, Vec<Event<'a>>>::new();
+            for event in events.by_ref().take(PARALLEL_CHUNK_LEN) {
+                chunk.entry(event.thread_id).or_default().push(event);
+            }
+
+            let mut threads: Vec<_> = chunk
+                .into_iter()
+                .map(|(thread_id, events)| {
+                    let collapser = collapsers.remove(&thread_id).unwrap_or_default();
+                    (thread_id, collapser, events)
+                })
+                .collect();
+
+            threads.par_iter_mut().for_each(|(_, collapser, events)| {
+                for event in events.drain(..) {
+                    collapser.push(event, root_frame);
+                }
+            });
+
+            for (thread_id, collapser, _) in threads {
+                collapsers.insert(thread_id, collapser);
+            }
+        }
+
+        collapsers
+            .into_par_iter()
+            .map(|(_, collapser)| collapser.finish())
+            .reduce(StackTimes::default, StackTimes::merge)
+    }
+
+    #[cfg(not(all(feature = "parallel", not(target_arch = "wasm32"))))]
+    {
+        stack_times_of_threads(events, root_frame)
+    }
+}
+
+struct Frame<'a> {
+    event_kind: Cow<'a, str>,
+    label: Cow<'a, str>,
+    // The length of `ThreadStack::stack` before this frame was pushed
+    stack_len: usize,
+}
+
+struct ThreadStack<'a> {
+    // The collapsed form of the current stack, e.g. `rustc;typeck;type_of`
+    stack: String,
+    frames: Vec<Frame<'a>>,
+    last_timestamp: SystemTime,
+}
+
+// Sums up the time spent in each stack for any number of threads, keeping
+// track of the stack of each thread separately.
+fn stack_times_of_threads<'a>(
+    events: impl Iterator<Item = Event<'a>>,
+    root_frame: &impl Fn(u64) -> String,
+) -> StackTimes {
+    let mut collapser = StackCollapser::default();
+    for event in events {
+        collapser.push(event, root_frame);
+    }
+    collapser.finish()
+}
+
+// Collapses the stacks of any number of threads one event at a time, holding
+// only the current stack of each thread and the time of each stack.
+#[derive(Default)]
+struct StackCollapser<'a> {
+    stack_times: StackTimes,
+    thread_stacks: HashMap<u64, ThreadStack<'a>>,
+    // Roughly the memory taken up by `stack_times.times`
+    times_bytes: usize,
+}
+
+impl<'a> StackCollapser<'a> {
+    fn push(&mut self, event: Event<'a>, root_frame: &impl Fn(u64) -> String) {
+        let stack_times = &mut self.stack_times;
+        let thread_stack = self
+            .thread_stacks
+            .entry(event.thread_id)
+            .or_insert_with(|| ThreadStack {
+                stack: root_frame(event.thread_id),
+                frames: Vec::new(),
+                last_timestamp: event.timestamp,
+            });
+
+        // The time since the previous event of the thread was spent in the
+        // current stack
+        if let Ok(elapsed) = event.timestamp.duration_since(thread_stack.last_timestamp) {
+            if elapsed > Duration::from_secs(0) {
+                match stack_times.times.get_mut(&thread_stack.stack) {
+                    Some(time) => *time += elapsed,
+                    None => {
+                        self.times_bytes +=
+                            thread_stack.stack.len() + mem::size_of::<(String, Duration)>();
+                        stack_times
+                            .times
+                            .insert(thread_stack.stack.clone(), elapsed);
+                    }
+                }
+            }
+            thread_stack.last_timestamp = event.timestamp;
+        }
 
         match event.timestamp_kind {
             TimestampKind::Start => {
-                thread_stack.push(event);
+                let stack_len = thread_stack.stack.len();
+                thread_stack.stack.push(';');
+                thread_stack.stack.push_str(&event.label);
+                thread_stack.frames.push(Frame {
+                    event_kind: event.event_kind,
+                    label: event.label,
+                    stack_len,
+                });
             }
             TimestampKind::End => {
-                let previous_event = thread_stack.pop().expect("no start event found");
-                assert_eq!(event.label, previous_event.label);
-                assert_eq!(previous_event.timestamp_kind, TimestampKind::Start);
+                // Usually the innermost frame. Frames inside of the matching
+                // one were never closed.
+                let matching = thread_stack.frames.iter().rposition(|frame| {
+                    frame.event_kind == event.event_kind && frame.label == event.label
+                });
+
+                match matching {
+                    Some(index) => {
+                        thread_stack
+                            .stack
+                            .truncate(thread_stack.frames[index].stack_len);
+                        let unclosed = thread_stack.frames.drain(index..).skip(1);
+                        record_unmatched_starts(stack_times, event.thread_id, unclosed);
+                    }
+                    None => {
+                        let unmatched = stack_times.unmatched.entry(event.thread_id).or_default();
+                        unmatched.ends += 1;
+                        unmatched.event_kinds.insert(event.event_kind.into_owned());
+                    }
+                }
             }
-            TimestampKind::Instant => {}
+            TimestampKind::Instant | TimestampKind::Counter => {}
         }
     }
 
-    recorded_stacks
+    // Empties `stack_times.times`, for writing it out before it grows too
+    // large. The stacks of the threads stay as they are.
+    fn take_times(&mut self) -> HashMap<String, Duration> {
+        self.times_bytes = 0;
+        mem::take(&mut self.stack_times.times)
+    }
+
+    fn finish(mut self) -> StackTimes {
+        for (thread_id, thread_stack) in self.thread_stacks {
+            record_unmatched_starts(
+                &mut self.stack_times,
+                thread_id,
+                thread_stack.frames.into_iter(),
+            );
+        }
+
+        self.stack_times
+    }
+}
+
+fn record_unmatched_starts<'a>(
+    stack_times: &mut StackTimes,
+    thread_id: u64,
+    frames: impl Iterator<Item = Frame<'a>>,
+) {
+    for frame in frames {
+        let unmatched = stack_times.unmatched.entry(thread_id).or_default();
+        unmatched.starts += 1;
+        unmatched.event_kinds.insert(frame.event_kind.into_owned());
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use measureme::{Event, TimestampKind};
-    use std::collections::HashMap;
+    use super::{FoldOptions, UnmatchedEvents};
+    use measureme::{Event, StringId, TimestampKind};
+    use std::borrow::Cow;
+    use std::collections::{BTreeMap, HashMap};
     use std::time::{Duration, SystemTime};
 
     #[test]
@@ -60,54 +681,91 @@ mod test {
             Event {
                 event_kind: "Query".into(),
                 label: "EventA".into(),
+                event_kind_id: StringId::INVALID,
+                label_id: StringId::INVALID,
                 additional_data: &[],
                 timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(1),
                 timestamp_kind: TimestampKind::Start,
                 thread_id: 1,
+                value: None,
+                arg: None,
+                category: None,
+                args: BTreeMap::new(),
             },
             Event {
                 event_kind: "Query".into(),
                 label: "EventA".into(),
+                event_kind_id: StringId::INVALID,
+                label_id: StringId::INVALID,
                 additional_data: &[],
                 timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(2),
                 timestamp_kind: TimestampKind::End,
                 thread_id: 1,
+                value: None,
+                arg: None,
+                category: None,
+                args: BTreeMap::new(),
             },
             Event {
                 event_kind: "Query".into(),
                 label: "EventB".into(),
+                event_kind_id: StringId::INVALID,
+                label_id: StringId::INVALID,
                 additional_data: &[],
                 timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(3),
                 timestamp_kind: TimestampKind::Start,
                 thread_id: 1,
+                value: None,
+                arg: None,
+                category: None,
+                args: BTreeMap::new(),
             },
             Event {
                 event_kind: "Query".into(),
                 label: "EventA".into(),
+                event_kind_id: StringId::INVALID,
+                label_id: StringId::INVALID,
                 additional_data: &[],
                 timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(4),
                 timestamp_kind: TimestampKind::Start,
                 thread_id: 1,
+                value: None,
+                arg: None,
+                category: None,
+                args: BTreeMap::new(),
             },
             Event {
                 event_kind: "Query".into(),
                 label: "EventA".into(),
+                event_kind_id: StringId::INVALID,
+                label_id: StringId::INVALID,
                 additional_data: &[],
                 timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(5),
                 timestamp_kind: TimestampKind::End,
                 thread_id: 1,
+                value: None,
+                arg: None,
+                category: None,
+                args: BTreeMap::new(),
             },
             Event {
                 event_kind: "Query".into(),
                 label: "EventB".into(),
+                event_kind_id: StringId::INVALID,
+                label_id: StringId::INVALID,
                 additional_data: &[],
                 timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(6),
                 timestamp_kind: TimestampKind::End,
                 thread_id: 1,
+                value: None,
+                arg: None,
+                category: None,
+                args: BTreeMap::new(),
             },
         ];
 
-        let recorded_stacks = super::collapse_stacks(events.iter().cloned(), 1);
+        let recorded_stacks =
+            super::collapse_stacks(events.iter().cloned(), Duration::from_millis(1));
 
         let mut expected_stacks = HashMap::<String, usize>::new();
         expected_stacks.insert("rustc;EventB;EventA".into(), 1000);
@@ -115,7 +773,42 @@ mod test {
         expected_stacks.insert("rustc;EventA".into(), 1000);
         expected_stacks.insert("rustc".into(), 1000);
 
-        assert_eq!(expected_stacks, recorded_stacks);
+        assert_eq!(expected_stacks, recorded_stacks.stacks);
+    }
+
+    #[test]
+    fn sorted_stacks_test() {
+        let event = |label, secs, timestamp_kind| Event {
+            event_kind: "Query".into(),
+            label: Cow::from(label),
+            event_kind_id: StringId::INVALID,
+            label_id: StringId::INVALID,
+            additional_data: &[],
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+            timestamp_kind,
+            thread_id: 1,
+            value: None,
+            arg: None,
+            category: None,
+            args: BTreeMap::new(),
+        };
+
+        let events = [
+            event("EventB", 1, TimestampKind::Start),
+            event("EventB", 2, TimestampKind::End),
+            event("EventA", 3, TimestampKind::Start),
+            // Splits the time of the stack, but doesn't change it
+            event("Instant", 4, TimestampKind::Instant),
+            event("EventA", 6, TimestampKind::End),
+        ];
+
+        let recorded_stacks =
+            super::collapse_stacks(events.iter().cloned(), Duration::from_secs(1));
+
+        assert_eq!(
+            recorded_stacks.sorted_stacks(),
+            [("rustc", 1), ("rustc;EventA", 3), ("rustc;EventB", 1)]
+        );
     }
 
     #[test]
@@ -124,60 +817,592 @@ mod test {
             Event {
                 event_kind: "Query".into(),
                 label: "EventA".into(),
+                event_kind_id: StringId::INVALID,
+                label_id: StringId::INVALID,
                 additional_data: &[],
                 timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(1),
                 timestamp_kind: TimestampKind::Start,
                 thread_id: 1,
+                value: None,
+                arg: None,
+                category: None,
+                args: BTreeMap::new(),
             },
             Event {
                 event_kind: "Query".into(),
                 label: "EventB".into(),
+                event_kind_id: StringId::INVALID,
+                label_id: StringId::INVALID,
                 additional_data: &[],
                 timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(3),
                 timestamp_kind: TimestampKind::Start,
                 thread_id: 2,
+                value: None,
+                arg: None,
+                category: None,
+                args: BTreeMap::new(),
             },
             Event {
                 event_kind: "Query".into(),
                 label: "EventA".into(),
+                event_kind_id: StringId::INVALID,
+                label_id: StringId::INVALID,
                 additional_data: &[],
                 timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(2),
                 timestamp_kind: TimestampKind::End,
                 thread_id: 1,
+                value: None,
+                arg: None,
+                category: None,
+                args: BTreeMap::new(),
             },
             Event {
                 event_kind: "Query".into(),
                 label: "EventA".into(),
+                event_kind_id: StringId::INVALID,
+                label_id: StringId::INVALID,
                 additional_data: &[],
                 timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(4),
                 timestamp_kind: TimestampKind::Start,
                 thread_id: 2,
+                value: None,
+                arg: None,
+                category: None,
+                args: BTreeMap::new(),
             },
             Event {
                 event_kind: "Query".into(),
                 label: "EventA".into(),
+                event_kind_id: StringId::INVALID,
+                label_id: StringId::INVALID,
                 additional_data: &[],
                 timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(5),
                 timestamp_kind: TimestampKind::End,
                 thread_id: 2,
+                value: None,
+                arg: None,
+                category: None,
+                args: BTreeMap::new(),
             },
             Event {
                 event_kind: "Query".into(),
                 label: "EventB".into(),
+                event_kind_id: StringId::INVALID,
+                label_id: StringId::INVALID,
                 additional_data: &[],
                 timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(6),
                 timestamp_kind: TimestampKind::End,
                 thread_id: 2,
+                value: None,
+                arg: None,
+                category: None,
+                args: BTreeMap::new(),
             },
         ];
 
-        let recorded_stacks = super::collapse_stacks(events.iter().cloned(), 1000);
+        let recorded_stacks =
+            super::collapse_stacks(events.iter().cloned(), Duration::from_secs(1));
 
         let mut expected_stacks = HashMap::<String, usize>::new();
         expected_stacks.insert("rustc;EventB;EventA".into(), 1);
         expected_stacks.insert("rustc;EventB".into(), 2);
         expected_stacks.insert("rustc;EventA".into(), 1);
 
-        assert_eq!(expected_stacks, recorded_stacks);
+        assert_eq!(expected_stacks, recorded_stacks.stacks);
+    }
+
+    #[test]
+    fn instant_events_test() {
+        let events = [
+            Event {
+                event_kind: "Query".into(),
+                label: "EventA".into(),
+                event_kind_id: StringId::INVALID,
+                label_id: StringId::INVALID,
+                additional_data: &[],
+                timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(1),
+                timestamp_kind: TimestampKind::Start,
+                thread_id: 1,
+                value: None,
+                arg: None,
+                category: None,
+                args: BTreeMap::new(),
+            },
+            Event {
+                event_kind: "QueryCacheHit".into(),
+                label: "EventB".into(),
+                event_kind_id: StringId::INVALID,
+                label_id: StringId::INVALID,
+                additional_data: &[],
+                timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(2),
+                timestamp_kind: TimestampKind::Instant,
+                thread_id: 1,
+                value: None,
+                arg: None,
+                category: None,
+                args: BTreeMap::new(),
+            },
+            Event {
+                event_kind: "Query".into(),
+                label: "EventA".into(),
+                event_kind_id: StringId::INVALID,
+                label_id: StringId::INVALID,
+                additional_data: &[],
+                timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(3),
+                timestamp_kind: TimestampKind::End,
+                thread_id: 1,
+                value: None,
+                arg: None,
+                category: None,
+                args: BTreeMap::new(),
+            },
+        ];
+
+        let recorded_stacks =
+            super::collapse_stacks(events.iter().cloned(), Duration::from_secs(1));
+
+        // The instant event neither opens a new frame nor closes `EventA`
+        let mut expected_stacks = HashMap::<String, usize>::new();
+        expected_stacks.insert("rustc;EventA".into(), 2);
+
+        assert_eq!(expected_stacks, recorded_stacks.stacks);
+    }
+
+    #[test]
+    fn root_frame_test() {
+        let events = [
+            Event {
+                event_kind: "Query".into(),
+                label: "EventA".into(),
+                event_kind_id: StringId::INVALID,
+                label_id: StringId::INVALID,
+                additional_data: &[],
+                timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(1),
+                timestamp_kind: TimestampKind::Start,
+                thread_id: 1,
+                value: None,
+                arg: None,
+                category: None,
+                args: BTreeMap::new(),
+            },
+            Event {
+                event_kind: "Query".into(),
+                label: "EventA".into(),
+                event_kind_id: StringId::INVALID,
+                label_id: StringId::INVALID,
+                additional_data: &[],
+                timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(2),
+                timestamp_kind: TimestampKind::Start,
+                thread_id: 2,
+                value: None,
+                arg: None,
+                category: None,
+                args: BTreeMap::new(),
+            },
+            Event {
+                event_kind: "Query".into(),
+                label: "EventA".into(),
+                event_kind_id: StringId::INVALID,
+                label_id: StringId::INVALID,
+                additional_data: &[],
+                timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(3),
+                timestamp_kind: TimestampKind::End,
+                thread_id: 1,
+                value: None,
+                arg: None,
+                category: None,
+                args: BTreeMap::new(),
+            },
+            Event {
+                event_kind: "Query".into(),
+                label: "EventA".into(),
+                event_kind_id: StringId::INVALID,
+                label_id: StringId::INVALID,
+                additional_data: &[],
+                timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(4),
+                timestamp_kind: TimestampKind::End,
+                thread_id: 2,
+                value: None,
+                arg: None,
+                category: None,
+                args: BTreeMap::new(),
+            },
+        ];
+
+        let recorded_stacks = super::collapse_stacks_with_root(
+            events.iter().cloned(),
+            Duration::from_secs(1),
+            |thread_id| format!("thread {}", thread_id),
+        );
+
+        let mut expected_stacks = HashMap::<String, usize>::new();
+        expected_stacks.insert("thread 1;EventA".into(), 2);
+        expected_stacks.insert("thread 2;EventA".into(), 2);
+
+        assert_eq!(expected_stacks, recorded_stacks.stacks);
+    }
+
+    #[test]
+    fn identical_stacks_of_threads_test() {
+        let event = |thread_id, secs, timestamp_kind| Event {
+            event_kind: "Query".into(),
+            label: "EventA".into(),
+            event_kind_id: StringId::INVALID,
+            label_id: StringId::INVALID,
+            additional_data: &[],
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+            timestamp_kind,
+            thread_id,
+            value: None,
+            arg: None,
+            category: None,
+            args: BTreeMap::new(),
+        };
+
+        let events = (1..=8).flat_map(|thread_id| {
+            vec![
+                event(thread_id, 1, TimestampKind::Start),
+                event(thread_id, 3, TimestampKind::End),
+            ]
+        });
+
+        let recorded_stacks = super::collapse_stacks(events, Duration::from_secs(1));
+
+        // The counts of all threads are added up
+        let mut expected_stacks = HashMap::<String, usize>::new();
+        expected_stacks.insert("rustc;EventA".into(), 16);
+
+        assert_eq!(expected_stacks, recorded_stacks.stacks);
+    }
+
+    #[test]
+    fn short_events_test() {
+        let event = |label, micros, timestamp_kind| Event {
+            event_kind: "Query".into(),
+            label: Cow::from(label),
+            event_kind_id: StringId::INVALID,
+            label_id: StringId::INVALID,
+            additional_data: &[],
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_micros(micros),
+            timestamp_kind,
+            thread_id: 1,
+            value: None,
+            arg: None,
+            category: None,
+            args: BTreeMap::new(),
+        };
+
+        // 1000 events of 100µs each, interrupted by a 1ms event every 100
+        // events. None of them lasts a full sample, but together they do.
+        let mut events = Vec::new();
+        let mut now = 0;
+        for i in 0..1000 {
+            events.push(event("EventA", now, TimestampKind::Start));
+            events.push(event("EventA", now + 100, TimestampKind::End));
+            now += 100;
+
+            if i % 100 == 99 {
+                events.push(event("EventB", now, TimestampKind::Start));
+                events.push(event("EventB", now + 1_000, TimestampKind::End));
+                now += 1_000;
+            }
+        }
+
+        let recorded_stacks =
+            super::collapse_stacks(events.iter().cloned(), Duration::from_millis(1));
+
+        let mut expected_stacks = HashMap::<String, usize>::new();
+        expected_stacks.insert("rustc;EventA".into(), 100);
+        expected_stacks.insert("rustc;EventB".into(), 10);
+
+        assert_eq!(expected_stacks, recorded_stacks.stacks);
+
+        // The same with sub-millisecond samples
+        let recorded_stacks =
+            super::collapse_stacks(events.iter().cloned(), Duration::from_micros(50));
+
+        let mut expected_stacks = HashMap::<String, usize>::new();
+        expected_stacks.insert("rustc;EventA".into(), 2_000);
+        expected_stacks.insert("rustc;EventB".into(), 200);
+
+        assert_eq!(expected_stacks, recorded_stacks.stacks);
+    }
+
+    fn event(
+        event_kind: &'static str,
+        label: &'static str,
+        thread_id: u64,
+        secs: u64,
+        timestamp_kind: TimestampKind,
+    ) -> Event<'static> {
+        Event {
+            event_kind: event_kind.into(),
+            label: label.into(),
+            event_kind_id: StringId::INVALID,
+            label_id: StringId::INVALID,
+            additional_data: &[],
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+            timestamp_kind,
+            thread_id,
+            value: None,
+            arg: None,
+            category: None,
+            args: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn self_time_test() {
+        let events = [
+            event("Query", "EventA", 1, 1, TimestampKind::Start),
+            event("Query", "EventB", 1, 2, TimestampKind::Start),
+            event("Query", "EventC", 1, 3, TimestampKind::Start),
+            event("Query", "EventC", 1, 5, TimestampKind::End),
+            event("Query", "EventB", 1, 6, TimestampKind::End),
+            event("Query", "EventC", 1, 6, TimestampKind::Start),
+            event("Query", "EventC", 1, 7, TimestampKind::End),
+            event("Query", "EventA", 1, 9, TimestampKind::End),
+            event("Query", "EventA", 1, 10, TimestampKind::Start),
+            event("Query", "EventA", 1, 11, TimestampKind::End),
+        ];
+
+        let inclusive = super::collapse_stacks(events.iter().cloned(), Duration::from_secs(1));
+        let self_time =
+            super::collapse_stacks_self_time(events.iter().cloned(), Duration::from_secs(1));
+
+        let mut expected_inclusive = HashMap::<String, usize>::new();
+        expected_inclusive.insert("rustc;EventA".into(), 4);
+        expected_inclusive.insert("rustc;EventA;EventB".into(), 2);
+        expected_inclusive.insert("rustc;EventA;EventB;EventC".into(), 2);
+        expected_inclusive.insert("rustc;EventA;EventC".into(), 1);
+        expected_inclusive.insert("rustc".into(), 1);
+        assert_eq!(expected_inclusive, inclusive.stacks);
+
+        // `EventC` ran both inside of `EventB` and directly inside of `EventA`
+        let mut expected_self_time = HashMap::<String, usize>::new();
+        expected_self_time.insert("EventA".into(), 4);
+        expected_self_time.insert("EventB".into(), 2);
+        expected_self_time.insert("EventC".into(), 3);
+        expected_self_time.insert("rustc".into(), 1);
+        assert_eq!(expected_self_time, self_time.stacks);
+
+        // The root frame of the flamegraph is as wide as all stacks together
+        let root: usize = inclusive.stacks.values().sum();
+        assert_eq!(self_time.stacks.values().sum::<usize>(), root);
+    }
+
+    // `EventA` around `depth` recursive `EventB`s around `EventC`, 1s apart
+    fn recursive_events(depth: u64) -> Vec<Event<'static>> {
+        let mut events = vec![event("Query", "EventA", 1, 1, TimestampKind::Start)];
+        for i in 1..=depth {
+            events.push(event("Query", "EventB", 1, 1 + i, TimestampKind::Start));
+        }
+        events.push(event("Query", "EventC", 1, depth + 2, TimestampKind::Start));
+        events.push(event("Query", "EventC", 1, depth + 3, TimestampKind::End));
+        for i in 1..=depth {
+            events.push(event(
+                "Query",
+                "EventB",
+                1,
+                depth + 3 + i,
+                TimestampKind::End,
+            ));
+        }
+        events.push(event(
+            "Query",
+            "EventA",
+            1,
+            2 * depth + 4,
+            TimestampKind::End,
+        ));
+        events
+    }
+
+    #[test]
+    fn fold_recursion_test() {
+        let collapsed =
+            super::collapse_stacks(recursive_events(50).into_iter(), Duration::from_secs(1));
+        let total: usize = collapsed.stacks.values().sum();
+
+        let options = FoldOptions {
+            fold_recursion: true,
+            max_depth: None,
+        };
+        let folded = collapsed.clone().fold(&options);
+
+        assert_eq!(folded.stacks.len(), collapsed.stacks.len());
+        assert_eq!(folded.stacks["rustc;EventA"], 2);
+        assert_eq!(folded.stacks["rustc;EventA;EventB"], 2);
+        assert_eq!(folded.stacks["rustc;EventA;EventB (x2)"], 2);
+        assert_eq!(folded.stacks["rustc;EventA;EventB (x50)"], 2);
+        assert_eq!(folded.stacks["rustc;EventA;EventB (x50);EventC"], 1);
+        assert!(folded
+            .stacks
+            .keys()
+            .all(|stack| stack.split(';').count() <= 4));
+        assert_eq!(folded.stacks.values().sum::<usize>(), total);
+
+        // Nothing to fold
+        assert_eq!(collapsed.clone().fold(&FoldOptions::default()), collapsed);
+    }
+
+    #[test]
+    fn max_depth_test() {
+        let collapsed =
+            super::collapse_stacks(recursive_events(50).into_iter(), Duration::from_secs(1));
+        let total: usize = collapsed.stacks.values().sum();
+
+        let options = FoldOptions {
+            fold_recursion: false,
+            max_depth: Some(4),
+        };
+        let capped = collapsed.clone().fold(&options);
+
+        let mut expected_stacks = HashMap::<String, usize>::new();
+        expected_stacks.insert("rustc;EventA".into(), 2);
+        expected_stacks.insert("rustc;EventA;EventB".into(), 2);
+        expected_stacks.insert("rustc;EventA;EventB;EventB".into(), 2);
+        // The 48 deeper levels of recursion and `EventC`
+        expected_stacks.insert("rustc;EventA;EventB;EventB;…".into(), 48 * 2 + 1);
+        assert_eq!(expected_stacks, capped.stacks);
+        assert_eq!(capped.stacks.values().sum::<usize>(), total);
+
+        // The depth is counted after folding
+        let options = FoldOptions {
+            fold_recursion: true,
+            max_depth: Some(3),
+        };
+        let folded = collapsed.fold(&options);
+        assert_eq!(folded.stacks["rustc;EventA;EventB (x50);…"], 1);
+        assert_eq!(folded.stacks.values().sum::<usize>(), total);
+    }
+
+    #[test]
+    fn spilling_test() {
+        // Every stack is entered twice, for a second each time
+        let mut events = recursive_events(5);
+        let repeated: Vec<_> = events
+            .iter()
+            .cloned()
+            .map(|mut event| {
+                event.timestamp += Duration::from_secs(100);
+                event
+            })
+            .collect();
+        events.extend(repeated);
+
+        let root_frame = |_| "rustc".to_string();
+        let interval = Duration::from_secs(3);
+        let collapsed = super::collapse_stacks(events.iter().cloned(), interval);
+        let expected: Vec<_> = collapsed
+            .sorted_stacks()
+            .into_iter()
+            .map(|(stack, samples)| (stack.to_string(), samples))
+            .collect();
+        assert!(!expected.is_empty());
+
+        // Within the budget everything stays in memory
+        let in_memory =
+            super::collapse_stacks_spilling(events.iter().cloned(), interval, root_frame, 1 << 20)
+                .unwrap();
+        assert_eq!(in_memory.spilled_runs(), 0);
+        let stacks: Vec<_> = in_memory
+            .into_sorted_stacks()
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(stacks, expected);
+
+        // Spills after every new stack, so each stack is summed up from two
+        // files before it's rounded to whole samples
+        let spilled =
+            super::collapse_stacks_spilling(events.into_iter(), interval, root_frame, 0).unwrap();
+        assert!(spilled.spilled_runs() > expected.len());
+        let stacks: Vec<_> = spilled
+            .into_sorted_stacks()
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(stacks, expected);
+    }
+
+    #[test]
+    fn end_without_start_test() {
+        let events = [
+            event("Query", "EventA", 1, 1, TimestampKind::Start),
+            event("Query", "EventB", 1, 2, TimestampKind::End),
+            event("Query", "EventA", 1, 3, TimestampKind::End),
+            // The start event of this one was e.g. cut off
+            event("Query", "EventA", 2, 3, TimestampKind::End),
+        ];
+
+        let recorded_stacks =
+            super::collapse_stacks(events.iter().cloned(), Duration::from_secs(1));
+
+        // The unmatched end event doesn't close `EventA`
+        let mut expected_stacks = HashMap::<String, usize>::new();
+        expected_stacks.insert("rustc;EventA".into(), 2);
+
+        let mut expected_unmatched = BTreeMap::new();
+        expected_unmatched.insert(
+            1,
+            UnmatchedEvents {
+                starts: 0,
+                ends: 1,
+                event_kinds: vec!["Query".to_string()].into_iter().collect(),
+            },
+        );
+        expected_unmatched.insert(
+            2,
+            UnmatchedEvents {
+                starts: 0,
+                ends: 1,
+                event_kinds: vec!["Query".to_string()].into_iter().collect(),
+            },
+        );
+
+        assert_eq!(expected_stacks, recorded_stacks.stacks);
+        assert_eq!(expected_unmatched, recorded_stacks.unmatched);
+    }
+
+    #[test]
+    fn start_without_end_test() {
+        let events = [
+            event("Query", "EventA", 1, 1, TimestampKind::Start),
+            event("GenericActivity", "EventB", 1, 2, TimestampKind::Start),
+            // Closes `EventA` but not `EventB`
+            event("Query", "EventA", 1, 3, TimestampKind::End),
+            // Never closed, e.g. because the profile is truncated
+            event("Query", "EventC", 1, 4, TimestampKind::Start),
+            event("Query", "EventD", 1, 5, TimestampKind::Instant),
+        ];
+
+        let recorded_stacks =
+            super::collapse_stacks(events.iter().cloned(), Duration::from_secs(1));
+
+        let mut expected_stacks = HashMap::<String, usize>::new();
+        expected_stacks.insert("rustc;EventA".into(), 1);
+        expected_stacks.insert("rustc;EventA;EventB".into(), 1);
+        expected_stacks.insert("rustc".into(), 1);
+        expected_stacks.insert("rustc;EventC".into(), 1);
+
+        let mut expected_unmatched = BTreeMap::new();
+        expected_unmatched.insert(
+            1,
+            UnmatchedEvents {
+                starts: 2,
+                ends: 0,
+                event_kinds: vec!["GenericActivity".to_string(), "Query".to_string()]
+                    .into_iter()
+                    .collect(),
+            },
+        );
+
+        assert_eq!(expected_stacks, recorded_stacks.stacks);
+        assert_eq!(expected_unmatched, recorded_stacks.unmatched);
+        assert_eq!(
+            recorded_stacks.unmatched[&1].to_string(),
+            "2 start and 0 end event(s) without a counterpart (event kinds: GenericActivity, Query)"
+        );
     }
 }
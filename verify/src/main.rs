@@ -0,0 +1,225 @@
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::process;
+use std::time::UNIX_EPOCH;
+
+use measureme::{Event, IntervalError, ProfilingData};
+
+use structopt::StructOpt;
+
+/// The number of errors and warnings of each check that are listed, the
+/// others are only counted.
+const MAX_LISTED: usize = 10;
+
+#[derive(StructOpt, Debug)]
+struct Opt {
+    file_prefix: PathBuf,
+
+    /// Exit with an error on warnings too, e.g. events that are out of order
+    #[structopt(long = "strict")]
+    strict: bool,
+}
+
+/// What `verify()` found. Errors mean that events are missing or can't be
+/// read as they were recorded, warnings that the events themselves look
+/// suspicious, usually because of the clock of the profiled process.
+#[derive(Debug, Default)]
+struct Report {
+    events: usize,
+    threads: usize,
+    intervals: usize,
+    errors: Vec<String>,
+    warnings: Vec<String>,
+}
+
+impl Report {
+    // Only lists the first `MAX_LISTED` of `problems`
+    fn push_all<T: Display>(problems: &mut Vec<String>, found: impl Iterator<Item = T>) {
+        let mut count = 0;
+        for problem in found {
+            if count < MAX_LISTED {
+                problems.push(problem.to_string());
+            }
+            count += 1;
+        }
+        if count > MAX_LISTED {
+            problems.push(format!("... and {} more like these", count - MAX_LISTED));
+        }
+    }
+}
+
+fn nanos(event: &Event<'_>) -> u128 {
+    event
+        .timestamp
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+// The error to report for `error`, or `None` for overlapping intervals, which
+// `ProfilingData::validate()` reports as warnings
+fn interval_error(error: &IntervalError<'_>) -> Option<String> {
+    match error {
+        IntervalError::UnmatchedStart(event) => Some(format!(
+            "start of `{}` event `{}` on thread {} at {}ns has no end event",
+            event.event_kind,
+            event.label,
+            event.thread_id,
+            nanos(event)
+        )),
+        IntervalError::UnmatchedEnd(event) => Some(format!(
+            "end of `{}` event `{}` on thread {} at {}ns has no start event",
+            event.event_kind,
+            event.label,
+            event.thread_id,
+            nanos(event)
+        )),
+        IntervalError::Overlapping(_) => None,
+    }
+}
+
+// The file magic and format version are checked when the profile is read
+fn verify(data: &ProfilingData) -> Report {
+    let mut report = Report::default();
+
+    if data.is_truncated() {
+        report.errors.push(
+            "the trace files are truncated, events that were not completely written are missing"
+                .to_string(),
+        );
+    } else if !data.is_complete() {
+        report.warnings.push(
+            "the profiler was not shut down cleanly, events recorded last may be missing"
+                .to_string(),
+        );
+    }
+
+    if !data.can_resolve_strings() {
+        report
+            .errors
+            .push("the string table is missing, events can't be named".to_string());
+    } else {
+        let unresolvable = data.unresolvable_events();
+        if unresolvable > 0 {
+            report.errors.push(format!(
+                "{} event(s) refer to strings missing from the string table",
+                unresolvable
+            ));
+        }
+    }
+
+    let mut events = data.iter();
+    report.events = events.by_ref().count();
+    report.threads = data.thread_ids().len();
+    if events.skipped_records() > 0 {
+        report.warnings.push(format!(
+            "{} record(s) of unknown types were skipped, they were likely written by a newer version of `measureme`",
+            events.skipped_records()
+        ));
+    }
+
+    let mut intervals = data.iter_intervals();
+    report.intervals = intervals.by_ref().count();
+    let unmatched = intervals.errors().iter().filter_map(interval_error);
+    Report::push_all(&mut report.errors, unmatched);
+
+    Report::push_all(&mut report.warnings, data.validate().into_iter());
+
+    report
+}
+
+fn main() {
+    let opt = Opt::from_args();
+
+    let data = match ProfilingData::new(&opt.file_prefix) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!(
+                "error: `{}` can't be read: {}",
+                opt.file_prefix.display(),
+                e
+            );
+            process::exit(1);
+        }
+    };
+
+    let report = verify(&data);
+
+    println!(
+        "`{}`: {} events on {} thread(s), {} interval(s)",
+        opt.file_prefix.display(),
+        report.events,
+        report.threads,
+        report.intervals
+    );
+    for error in &report.errors {
+        println!("error: {}", error);
+    }
+    for warning in &report.warnings {
+        println!("warning: {}", warning);
+    }
+
+    if report.errors.is_empty() && report.warnings.is_empty() {
+        println!("no problems found");
+    }
+
+    if !report.errors.is_empty() || (opt.strict && !report.warnings.is_empty()) {
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use measureme::ProfilingDataBuilder;
+
+    #[test]
+    fn well_formed() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "typeck", 0, 10, 100, |b| {
+            b.instant("Marker", "checkpoint", 0, 50);
+        })
+        .interval("Query", "codegen", 1, 20, 30, |_| {});
+
+        let report = verify(&b.into_profiling_data());
+        assert_eq!(report.events, 5);
+        assert_eq!(report.threads, 2);
+        assert_eq!(report.intervals, 2);
+        assert!(report.errors.is_empty());
+        assert!(report.warnings.is_empty());
+    }
+
+    #[test]
+    fn orphans_and_out_of_order() {
+        let mut b = ProfilingDataBuilder::new();
+        b.interval("Query", "typeck", 0, 10, 100, |_| {})
+            .start("Query", "codegen", 1, 20)
+            .end("Query", "mir_built", 2, 30)
+            .instant("Marker", "checkpoint", 0, 50);
+
+        let report = verify(&b.into_profiling_data());
+        assert_eq!(
+            report.errors,
+            [
+                "end of `Query` event `mir_built` on thread 2 at 30ns has no start event",
+                "start of `Query` event `codegen` on thread 1 at 20ns has no end event",
+            ]
+        );
+        assert_eq!(
+            report.warnings,
+            ["`Marker` event `checkpoint` on thread 0 at 50ns was recorded after an event at 100ns"]
+        );
+    }
+
+    #[test]
+    fn listed_problems() {
+        let mut b = ProfilingDataBuilder::new();
+        for nanos in 0..12 {
+            b.end("Query", "typeck", 0, nanos);
+        }
+
+        let report = verify(&b.into_profiling_data());
+        assert_eq!(report.errors.len(), MAX_LISTED + 1);
+        assert_eq!(report.errors[MAX_LISTED], "... and 2 more like these");
+    }
+}